@@ -1,7 +1,15 @@
 // Necessary because of this issue: https://github.com/rust-lang/cargo/issues/9641
 fn main() -> anyhow::Result<()> {
+    // Host builds only contain the platform independent modules.
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() != Ok("espidf") {
+        return Ok(());
+    }
+
     embuild::build::CfgArgs::output_propagated("ESP_IDF")?;
     embuild::build::LinkArgs::output_propagated("ESP_IDF")?;
+
+    // Lets `utils::coredump` write the task backtraces before the core dump is written.
+    println!("cargo:rustc-link-arg=-Wl,--wrap=esp_panic_handler");
     
     Ok(())
 }
@@ -0,0 +1,432 @@
+//! The format of the crash log written to the `crashlog` flash partition and of the crash
+//! archive served by `GET /debug/coredump`.
+//!
+//! Crash log layout (all integers are little-endian):
+//!
+//! | size          | content                                                 |
+//! |---------------|---------------------------------------------------------|
+//! | 4             | [`LOG_MAGIC`]                                           |
+//! | 2             | format version ([`VERSION`])                            |
+//! | 2             | task count                                              |
+//! | 4             | [`Cause`]                                               |
+//! | 4             | uptime in milliseconds                                  |
+//! | 2             | message length `m`                                      |
+//! | `m`           | UTF-8 message                                           |
+//! | per task      | 16 byte NUL-padded name, `u16` frame count `f`, `f` times `pc: u32, sp: u32` |
+//!
+//! The archive is [`ARCHIVE_MAGIC`] followed by sections, each a `u32` [`SectionKind`], a
+//! `u32` length and the section data.
+
+#![no_std]
+
+#[cfg(feature = "std")]
+extern crate std;
+
+pub const LOG_MAGIC: [u8; 4] = *b"CRLG";
+pub const VERSION: u16 = 1;
+pub const ARCHIVE_MAGIC: [u8; 8] = *b"HUECRASH";
+pub const TASK_NAME_LEN: usize = 16;
+
+/// What caused the crash log to be written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cause {
+    /// A Rust panic.
+    Panic,
+    /// A CPU exception or watchdog handled by the ESP-IDF panic handler.
+    Fault,
+}
+
+impl Cause {
+    fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(Cause::Panic),
+            1 => Some(Cause::Fault),
+            _ => None,
+        }
+    }
+}
+
+/// The kind of a section in the crash archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionKind {
+    /// A crash log in the format of this crate.
+    CrashLog = 1,
+    /// The ESP-IDF core dump in ELF format.
+    CoreDump = 2,
+}
+
+impl SectionKind {
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(SectionKind::CrashLog),
+            2 => Some(SectionKind::CoreDump),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The data doesn't start with the magic bytes, e.g. because the partition is erased.
+    NoMagic,
+    UnsupportedVersion(u16),
+    InvalidCause(u32),
+    InvalidSection(u32),
+    Truncated,
+    InvalidUtf8,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::NoMagic => write!(f, "no crash data found"),
+            Error::UnsupportedVersion(v) => write!(f, "unsupported format version {v}"),
+            Error::InvalidCause(c) => write!(f, "invalid crash cause {c}"),
+            Error::InvalidSection(s) => write!(f, "invalid section kind {s}"),
+            Error::Truncated => write!(f, "data is truncated"),
+            Error::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// Writes a crash log into a fixed buffer without allocating.
+///
+/// Tasks that don't fit into the buffer anymore are left out.
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    task_count: u16,
+}
+
+impl<'a> Writer<'a> {
+    /// Start a crash log, the message is truncated if it doesn't fit into `buf`.
+    pub fn new(buf: &'a mut [u8], cause: Cause, uptime_ms: u32, message: &str) -> Self {
+        let mut writer = Writer {
+            buf,
+            len: 0,
+            task_count: 0,
+        };
+
+        writer.put(&LOG_MAGIC);
+        writer.put(&VERSION.to_le_bytes());
+        writer.put(&0_u16.to_le_bytes());
+        writer.put(&(cause as u32).to_le_bytes());
+        writer.put(&uptime_ms.to_le_bytes());
+
+        let max_len = writer.remaining().saturating_sub(2).min(u16::MAX as usize);
+        let mut message_len = message.len().min(max_len);
+        while !message.is_char_boundary(message_len) {
+            message_len -= 1;
+        }
+        writer.put(&(message_len as u16).to_le_bytes());
+        writer.put(&message.as_bytes()[..message_len]);
+
+        writer
+    }
+
+    /// Add a task with its backtrace as `(pc, sp)` pairs.
+    ///
+    /// Returns `false` if the task didn't fit.
+    pub fn add_task(&mut self, name: &[u8], frames: impl Iterator<Item = (u32, u32)>) -> bool {
+        if self.remaining() < TASK_NAME_LEN + 2 {
+            return false;
+        }
+
+        let mut padded_name = [0; TASK_NAME_LEN];
+        let name_len = name.len().min(TASK_NAME_LEN);
+        padded_name[..name_len].copy_from_slice(&name[..name_len]);
+        self.put(&padded_name);
+
+        let count_offset = self.len;
+        self.put(&0_u16.to_le_bytes());
+
+        let mut frame_count = 0_u16;
+        for (pc, sp) in frames {
+            if self.remaining() < 8 || frame_count == u16::MAX {
+                break;
+            }
+            self.put(&pc.to_le_bytes());
+            self.put(&sp.to_le_bytes());
+            frame_count += 1;
+        }
+        self.buf[count_offset..count_offset + 2].copy_from_slice(&frame_count.to_le_bytes());
+
+        self.task_count += 1;
+        self.buf[6..8].copy_from_slice(&self.task_count.to_le_bytes());
+        true
+    }
+
+    /// The written crash log.
+    pub fn finish(self) -> &'a [u8] {
+        &self.buf[..self.len]
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len() - self.len
+    }
+
+    fn put(&mut self, data: &[u8]) {
+        let count = data.len().min(self.remaining());
+        self.buf[self.len..self.len + count].copy_from_slice(&data[..count]);
+        self.len += count;
+    }
+}
+
+/// A parsed crash log.
+#[derive(Debug, Clone, Copy)]
+pub struct CrashLog<'a> {
+    pub cause: Cause,
+    pub uptime_ms: u32,
+    pub message: &'a str,
+    task_count: u16,
+    tasks: &'a [u8],
+}
+
+impl<'a> CrashLog<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        let mut reader = Reader(data);
+        if reader.take(LOG_MAGIC.len())? != LOG_MAGIC {
+            return Err(Error::NoMagic);
+        }
+
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let task_count = reader.u16()?;
+        let cause = reader.u32()?;
+        let cause = Cause::from_u32(cause).ok_or(Error::InvalidCause(cause))?;
+        let uptime_ms = reader.u32()?;
+        let message_len = reader.u16()? as usize;
+        let message =
+            core::str::from_utf8(reader.take(message_len)?).map_err(|_| Error::InvalidUtf8)?;
+
+        Ok(CrashLog {
+            cause,
+            uptime_ms,
+            message,
+            task_count,
+            tasks: reader.0,
+        })
+    }
+
+    /// The tasks with their backtraces, stops at the first malformed task.
+    pub fn tasks(&self) -> impl Iterator<Item = Result<Task<'a>, Error>> + 'a {
+        let mut reader = Reader(self.tasks);
+        let mut remaining = self.task_count;
+
+        core::iter::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
+            remaining -= 1;
+
+            let task = (|| {
+                let name = reader.take(TASK_NAME_LEN)?;
+                let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                let name =
+                    core::str::from_utf8(&name[..name_len]).map_err(|_| Error::InvalidUtf8)?;
+
+                let frame_count = reader.u16()? as usize;
+                let frames = reader.take(frame_count * 8)?;
+
+                Ok(Task { name, frames })
+            })();
+
+            if task.is_err() {
+                remaining = 0;
+            }
+            Some(task)
+        })
+    }
+}
+
+/// A task in a [`CrashLog`].
+#[derive(Debug, Clone, Copy)]
+pub struct Task<'a> {
+    pub name: &'a str,
+    frames: &'a [u8],
+}
+
+impl<'a> Task<'a> {
+    /// The backtrace as `(pc, sp)` pairs.
+    pub fn frames(&self) -> impl Iterator<Item = (u32, u32)> + 'a {
+        self.frames.chunks_exact(8).map(|frame| {
+            let mut reader = Reader(frame);
+            (reader.u32().unwrap(), reader.u32().unwrap())
+        })
+    }
+}
+
+/// Iterate over the sections of a crash archive.
+pub fn archive_sections(
+    data: &[u8],
+) -> Result<impl Iterator<Item = Result<(SectionKind, &[u8]), Error>>, Error> {
+    let mut reader = Reader(data);
+    if reader.take(ARCHIVE_MAGIC.len())? != ARCHIVE_MAGIC {
+        return Err(Error::NoMagic);
+    }
+
+    Ok(core::iter::from_fn(move || {
+        if reader.0.is_empty() {
+            return None;
+        }
+
+        let section = (|| {
+            let kind = reader.u32()?;
+            let kind = SectionKind::from_u32(kind).ok_or(Error::InvalidSection(kind))?;
+            let len = reader.u32()? as usize;
+            Ok((kind, reader.take(len)?))
+        })();
+
+        if section.is_err() {
+            reader.0 = &[];
+        }
+        Some(section)
+    }))
+}
+
+/// The header of a section in the crash archive.
+pub fn section_header(kind: SectionKind, len: u32) -> [u8; 8] {
+    let mut header = [0; 8];
+    header[..4].copy_from_slice(&(kind as u32).to_le_bytes());
+    header[4..].copy_from_slice(&len.to_le_bytes());
+    header
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < len {
+            return Err(Error::Truncated);
+        }
+        let (data, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(data)
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_log(buf: &mut [u8]) -> &[u8] {
+        let mut writer = Writer::new(buf, Cause::Fault, 12_345, "LoadProhibited");
+        assert!(writer.add_task(b"light", [(0x400d_1000, 0x3ffb_0000)].into_iter()));
+        assert!(writer.add_task(
+            b"a_very_long_task_name",
+            [(0x400d_2000, 0x3ffb_1000), (0x400d_3000, 0x3ffb_1020)].into_iter()
+        ));
+        writer.finish()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buf = [0; 256];
+        let data = write_log(&mut buf);
+
+        let log = CrashLog::parse(data).unwrap();
+        assert_eq!(log.cause, Cause::Fault);
+        assert_eq!(log.uptime_ms, 12_345);
+        assert_eq!(log.message, "LoadProhibited");
+
+        let mut tasks = log.tasks();
+        let task = tasks.next().unwrap().unwrap();
+        assert_eq!(task.name, "light");
+        assert!(task.frames().eq([(0x400d_1000, 0x3ffb_0000)]));
+        let task = tasks.next().unwrap().unwrap();
+        assert_eq!(task.name, "a_very_long_task");
+        assert!(task
+            .frames()
+            .eq([(0x400d_2000, 0x3ffb_1000), (0x400d_3000, 0x3ffb_1020)]));
+        assert!(tasks.next().is_none());
+
+        let mut archive = [0; 512];
+        archive[..8].copy_from_slice(&ARCHIVE_MAGIC);
+        archive[8..16].copy_from_slice(&section_header(SectionKind::CrashLog, data.len() as u32));
+        archive[16..16 + data.len()].copy_from_slice(data);
+        let archive = &archive[..16 + data.len()];
+
+        let mut sections = archive_sections(archive).unwrap();
+        assert_eq!(sections.next(), Some(Ok((SectionKind::CrashLog, data))));
+        assert_eq!(sections.next(), None);
+    }
+
+    #[test]
+    fn leave_out_tasks_that_dont_fit() {
+        let mut buf = [0; 52];
+        let mut writer = Writer::new(&mut buf, Cause::Panic, 0, "panic");
+        assert!(writer.add_task(b"main", [(1, 2), (3, 4), (5, 6)].into_iter()));
+        // The frames of the first task were cut off, the second task doesn't fit at all.
+        assert!(!writer.add_task(b"light", [(7, 8)].into_iter()));
+        let data = writer.finish();
+
+        let log = CrashLog::parse(data).unwrap();
+        let mut tasks = log.tasks();
+        assert!(tasks.next().unwrap().unwrap().frames().eq([(1, 2)]));
+        assert!(tasks.next().is_none());
+    }
+
+    #[test]
+    fn reject_corrupted_logs() {
+        let mut buf = [0; 256];
+        let data = write_log(&mut buf);
+
+        // An erased partition.
+        assert_eq!(CrashLog::parse(&[0xff; 64]).unwrap_err(), Error::NoMagic);
+        assert_eq!(CrashLog::parse(&data[..10]).unwrap_err(), Error::Truncated);
+
+        let mut corrupted = [0; 256];
+        let corrupted = &mut corrupted[..data.len()];
+
+        corrupted.copy_from_slice(data);
+        corrupted[4] = 2;
+        assert_eq!(
+            CrashLog::parse(corrupted).unwrap_err(),
+            Error::UnsupportedVersion(2)
+        );
+
+        corrupted.copy_from_slice(data);
+        corrupted[8] = 7;
+        assert_eq!(
+            CrashLog::parse(corrupted).unwrap_err(),
+            Error::InvalidCause(7)
+        );
+
+        // A frame count beyond the end of the data ends the tasks.
+        corrupted.copy_from_slice(data);
+        let first_frame_count = 18 + "LoadProhibited".len() + TASK_NAME_LEN;
+        corrupted[first_frame_count] = 0xff;
+        let log = CrashLog::parse(corrupted).unwrap();
+        let mut tasks = log.tasks();
+        assert_eq!(tasks.next().unwrap().unwrap_err(), Error::Truncated);
+        assert!(tasks.next().is_none());
+
+        assert_eq!(archive_sections(b"HUECRASX").err(), Some(Error::NoMagic));
+        let mut archive = *b"HUECRASH\x03\x00\x00\x00\x00\x00\x00\x00";
+        {
+            let mut sections = archive_sections(&archive).unwrap();
+            assert_eq!(sections.next(), Some(Err(Error::InvalidSection(3))));
+            assert_eq!(sections.next(), None);
+        }
+
+        archive[8] = SectionKind::CoreDump as u8;
+        archive[12] = 1;
+        let mut sections = archive_sections(&archive).unwrap();
+        assert_eq!(sections.next(), Some(Err(Error::Truncated)));
+    }
+}
@@ -11,14 +11,22 @@ elif [ "${CODESPACE_NAME}" != "" ]; then
     }
 fi
 
+BUILD_MODE=""
 case "$1" in
     ""|"release")
-        cargo build --release
+        cargo build --release --features embedded-symbols
+        BUILD_MODE="release"
         ;;
     "debug")
-        cargo build
+        cargo build --features embedded-symbols
+        BUILD_MODE="debug"
         ;;
     *)
         echo "Wrong argument. Only \"debug\"/\"release\" arguments are supported"
         exit 1;;
-esac
\ No newline at end of file
+esac
+
+# Fill the symbol table reserved in flash by the `embedded-symbols` feature.
+HOST_TARGET=$(rustc -vV | sed -n 's/^host: //p')
+cargo run --release --manifest-path tools/Cargo.toml --target "${HOST_TARGET}" \
+    --bin embed-symbols -- target/xtensa-esp32-espidf/${BUILD_MODE}/esp32-hue
@@ -0,0 +1,76 @@
+//! The HTTP API of the device.
+//!
+//! Only the platform independent parts of the endpoints are built on other targets.
+
+#[cfg(target_os = "espidf")]
+use {
+    crate::hue,
+    embedded_svc::http::server::{HandlerError, Response},
+    embedded_svc::io::Write,
+    esp_idf_svc::http::server::{Configuration, EspHttpServer},
+    esp_idf_sys::EspError,
+    serde::Serialize,
+};
+
+#[cfg(target_os = "espidf")]
+mod debug;
+mod wled;
+
+#[cfg(target_os = "espidf")]
+#[derive(Debug, thiserror::Error)]
+#[error("failed to start http server")]
+pub struct StartError(#[from] EspError);
+
+/// Start the HTTP server with all endpoints, the server stops when dropped.
+#[cfg(target_os = "espidf")]
+pub fn start(hue: &hue::Config) -> Result<EspHttpServer, StartError> {
+    let mut server = EspHttpServer::new(&Configuration {
+        // The Hue API registers a few endpoints for every light.
+        max_uri_handlers: 48,
+        ..Default::default()
+    })?;
+
+    debug::register(&mut server)?;
+    wled::register(&mut server)?;
+    hue::register(&mut server, hue)?;
+
+    Ok(server)
+}
+
+/// Respond with `value` serialized as JSON.
+#[cfg(target_os = "espidf")]
+pub(crate) fn send_json<R: Response>(resp: R, value: &impl Serialize) -> Result<(), HandlerError> {
+    send_bytes(resp, 200, "application/json", &serde_json::to_vec(value)?)
+}
+
+/// Respond with an error `status` and a JSON body containing `message`.
+#[cfg(target_os = "espidf")]
+pub(crate) fn send_error<R: Response>(
+    resp: R,
+    status: u16,
+    message: &str,
+) -> Result<(), HandlerError> {
+    #[derive(Serialize)]
+    struct Error<'a> {
+        error: &'a str,
+    }
+
+    let body = serde_json::to_vec(&Error { error: message })?;
+    send_bytes(resp, status, "application/json", &body)
+}
+
+#[cfg(target_os = "espidf")]
+pub(crate) fn send_bytes<R: Response>(
+    resp: R,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), HandlerError> {
+    let mut writer = resp
+        .status(status)
+        .header("Content-Type", content_type)
+        .into_writer()?;
+    writer.write_all(body)?;
+
+    Ok(())
+}
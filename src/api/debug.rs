@@ -0,0 +1,115 @@
+//! Endpoints for diagnosing the device.
+
+use embedded_svc::http::server::registry::Registry;
+use embedded_svc::http::server::{HandlerError, Response};
+use embedded_svc::http::Method;
+use embedded_svc::io::Write;
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_sys::EspError;
+use serde::Serialize;
+
+use super::{send_bytes, send_error, send_json};
+use crate::light;
+use crate::utils::coredump;
+use crate::utils::crash::{self, CrashReport, ResetReason};
+use crate::utils::errors;
+use crate::utils::executor::stats::ExecutorStats;
+use crate::utils::memory;
+use crate::utils::net;
+
+pub fn register(server: &mut EspHttpServer) -> Result<(), EspError> {
+    server.fn_handler("/debug/crash", Method::Get, |_req, resp| {
+        #[derive(Serialize)]
+        struct CrashInfo {
+            last_crash: Option<CrashReport>,
+            reset_history: Vec<ResetReason>,
+        }
+
+        send_json(
+            resp,
+            &CrashInfo {
+                last_crash: crash::last_crash(),
+                reset_history: crash::reset_history(),
+            },
+        )
+    })?;
+
+    server.fn_handler("/debug/errors", Method::Get, |_req, resp| {
+        send_json(resp, &errors::stats())
+    })?;
+
+    server.fn_handler("/debug/memory", Method::Get, |_req, resp| {
+        match memory::last_report() {
+            Some(report) => send_json(resp, &report),
+            None => send_error(resp, 503, "memory monitor is not running"),
+        }
+    })?;
+
+    server.fn_handler("/debug/executors", Method::Get, |_req, resp| {
+        #[derive(Serialize)]
+        struct Executors {
+            light: Option<ExecutorStats>,
+            network: Option<ExecutorStats>,
+        }
+
+        send_json(
+            resp,
+            &Executors {
+                light: light::executor_stats(),
+                network: net::executor_stats(),
+            },
+        )
+    })?;
+
+    server.fn_handler("/debug/coredump", Method::Get, |_req, resp| send_coredump(resp))?;
+    server.fn_handler("/debug/coredump", Method::Delete, |_req, resp| {
+        coredump::erase()?;
+        send_bytes(resp, 204, "text/plain", &[])
+    })?;
+
+    Ok(())
+}
+
+/// Respond with the crash log and core dump as a crash archive, see the `crashlog` crate.
+///
+/// The core dump is streamed from flash since it can be larger than the free heap.
+fn send_coredump<R: Response>(resp: R) -> Result<(), HandlerError> {
+    let crash_log = coredump::crash_log()?;
+    let core_dump = coredump::core_dump()?;
+    if crash_log.is_none() && core_dump.is_none() {
+        return send_error(resp, 404, "no crash data stored");
+    }
+
+    let mut writer = resp
+        .status(200)
+        .header("Content-Type", "application/octet-stream")
+        .header("Content-Disposition", "attachment; filename=\"crash.bin\"")
+        .into_writer()?;
+    writer.write_all(&crashlog::ARCHIVE_MAGIC)?;
+
+    if let Some(crash_log) = crash_log {
+        let header = crashlog::section_header(
+            crashlog::SectionKind::CrashLog,
+            crash_log.len() as u32,
+        );
+        writer.write_all(&header)?;
+        writer.write_all(&crash_log)?;
+    }
+
+    if let Some(core_dump) = core_dump {
+        let header =
+            crashlog::section_header(crashlog::SectionKind::CoreDump, core_dump.size as u32);
+        writer.write_all(&header)?;
+
+        let mut buf = [0; 1024];
+        let mut offset = 0;
+        while offset < core_dump.size {
+            let len = buf.len().min(core_dump.size - offset);
+            core_dump.read(offset, &mut buf[..len])?;
+            writer.write_all(&buf[..len])?;
+            offset += len;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,190 @@
+//! A subset of the WLED JSON API, so that the WLED apps, Home Assistant and LedFx can
+//! control the light state.
+//!
+//! `GET /json` returns the state, info, effects and palettes, which are also available
+//! one by one under `/json/state`, `/json/info`, `/json/effects` and `/json/palettes`.
+//! `POST /json/state` (or `/json`) changes the state, see [`json`] for the supported
+//! fields. A request with unsupported fields fails with status 400 and changes nothing.
+
+#[cfg(target_os = "espidf")]
+use {
+    super::{send_error, send_json},
+    crate::input,
+    crate::light::{self, frame},
+    crate::utils::net,
+    embedded_svc::http::server::registry::Registry,
+    embedded_svc::http::server::{HandlerError, Request, Response},
+    embedded_svc::http::{Headers, Method},
+    esp_idf_svc::http::server::EspHttpServer,
+    esp_idf_sys::EspError,
+};
+
+use serde::Serialize;
+
+use crate::light::effect::Effect;
+use crate::light::state::{self, MAX_SEGMENTS};
+
+pub mod json;
+
+/// The WLED version the API is compatible with.
+const WLED_VERSION: &str = "0.13.3";
+/// The maximum size of a request body.
+const MAX_BODY_LEN: usize = 4096;
+
+#[cfg(target_os = "espidf")]
+pub fn register(server: &mut EspHttpServer) -> Result<(), EspError> {
+    server.fn_handler("/json", Method::Get, |_req, resp| {
+        #[derive(Serialize)]
+        struct All {
+            state: json::State,
+            info: Info,
+            effects: Vec<&'static str>,
+            palettes: [&'static str; 1],
+        }
+
+        send_json(
+            resp,
+            &All {
+                state: current_state(),
+                info: info(),
+                effects: effects(),
+                palettes: json::PALETTES,
+            },
+        )
+    })?;
+    server.fn_handler("/json/state", Method::Get, |_req, resp| {
+        send_json(resp, &current_state())
+    })?;
+    server.fn_handler("/json/info", Method::Get, |_req, resp| {
+        send_json(resp, &info())
+    })?;
+    server.fn_handler("/json/effects", Method::Get, |_req, resp| {
+        send_json(resp, &effects())
+    })?;
+    server.fn_handler("/json/palettes", Method::Get, |_req, resp| {
+        send_json(resp, &json::PALETTES)
+    })?;
+
+    server.fn_handler("/json", Method::Post, post_state)?;
+    server.fn_handler("/json/state", Method::Post, post_state)?;
+
+    Ok(())
+}
+
+#[cfg(target_os = "espidf")]
+fn post_state<Q: Request, R: Response>(mut req: Q, resp: R) -> Result<(), HandlerError> {
+    let len = req.content_len().unwrap_or(MAX_BODY_LEN + 1);
+    if len > MAX_BODY_LEN {
+        return send_error(resp, 413, "request body is too large or has no length");
+    }
+    let mut body = vec![0; len];
+    let (body, _) = embedded_svc::io::read_max(req.reader(), &mut body)?;
+
+    let update: json::StateUpdate = match serde_json::from_slice(body) {
+        Ok(update) => update,
+        Err(err) => return send_error(resp, 400, &err.to_string()),
+    };
+
+    // Fail before the state is changed if the preset can't be saved.
+    if let Some(Err(err)) = update.psave.map(state::check_preset) {
+        return send_error(resp, 400, &err.to_string());
+    }
+
+    let result = update
+        .ps
+        .map(|id| state::preset(id).ok_or(json::ApplyError::UnknownPreset(id)))
+        .transpose()
+        .and_then(|preset| state::update(|state| json::apply(&update, preset, state, frame::len())))
+        .and_then(|state| match update.psave {
+            Some(id) => Ok(state::save_preset(id, state)?),
+            None => Ok(()),
+        });
+    if let Err(err) = result {
+        return send_error(resp, 400, &err.to_string());
+    }
+
+    if update.v {
+        send_json(resp, &current_state())
+    } else {
+        send_json(resp, &serde_json::json!({ "success": true }))
+    }
+}
+
+fn current_state() -> json::State {
+    let state = state::get();
+    json::State::new(&state, state::active_preset(&state))
+}
+
+fn effects() -> Vec<&'static str> {
+    Effect::ALL.iter().map(|effect| effect.name()).collect()
+}
+
+#[derive(Debug, Serialize)]
+struct Info {
+    ver: &'static str,
+    leds: LedInfo,
+    name: &'static str,
+    udpport: u16,
+    /// Whether a realtime input is shown instead of the light state.
+    live: bool,
+    /// The name of the realtime input that is shown.
+    lm: &'static str,
+    fxcount: usize,
+    palcount: usize,
+    arch: &'static str,
+    brand: &'static str,
+    product: &'static str,
+    /// The MAC address as lowercase hex without separators.
+    mac: String,
+    ip: String,
+    /// The uptime in seconds.
+    uptime: u64,
+    freeheap: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct LedInfo {
+    count: usize,
+    rgbw: bool,
+    fps: u32,
+    maxseg: usize,
+}
+
+#[cfg(target_os = "espidf")]
+fn info() -> Info {
+    let frame_time = light::frame_stats().frame_time;
+    let fps = if frame_time.is_zero() {
+        0
+    } else {
+        (1.0 / frame_time.as_secs_f32()).round() as u32
+    };
+    let source = frame::active_source();
+
+    Info {
+        ver: WLED_VERSION,
+        leds: LedInfo {
+            count: frame::len(),
+            rgbw: false,
+            fps,
+            maxseg: MAX_SEGMENTS,
+        },
+        name: "esp32-hue",
+        udpport: input::wled::packet::PORT,
+        live: source.is_some(),
+        lm: source.unwrap_or(""),
+        fxcount: Effect::ALL.len(),
+        palcount: json::PALETTES.len(),
+        arch: "esp32",
+        brand: "WLED",
+        product: "esp32-hue",
+        mac: net::station_mac()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+        ip: net::station_ip()
+            .map(|ip| ip.to_string())
+            .unwrap_or_default(),
+        uptime: (unsafe { esp_idf_sys::esp_timer_get_time() } / 1_000_000) as u64,
+        freeheap: unsafe { esp_idf_sys::esp_get_free_heap_size() },
+    }
+}
@@ -0,0 +1,389 @@
+//! The state objects of the WLED JSON API and how they map onto the [`LightState`].
+//!
+//! Supported are `on` (including `"t"` to toggle), `bri`, `transition`, `ps`, `psave`,
+//! `v` and the segment fields `id`, `start`, `stop`, `col`, `fx`, `sx`, `ix`, `pal`, `on`
+//! and `bri`. `time` and the segment field `sel` are accepted and ignored. All other
+//! fields, palettes other than the default one and colors given as hex strings are
+//! rejected, instead of being silently dropped.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::light::effect::Effect;
+use crate::light::frame::Pixel;
+use crate::light::state::{LightState, PresetsFull, Segment, MAX_SEGMENTS};
+
+/// The palettes, only the colors of the segments are supported.
+pub const PALETTES: [&str; 1] = ["Default"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApplyError {
+    #[error("preset {0} doesn't exist")]
+    UnknownPreset(u16),
+    #[error(transparent)]
+    PresetsFull(#[from] PresetsFull),
+    #[error("effect {0} doesn't exist")]
+    UnknownEffect(usize),
+    #[error("palette {0} isn't supported")]
+    UnsupportedPalette(u8),
+    #[error("segment {0} doesn't exist, a new segment needs a start and stop")]
+    UnknownSegment(usize),
+    #[error("segment range {start}..{stop} is outside of the {len} leds")]
+    InvalidRange {
+        start: usize,
+        stop: usize,
+        len: usize,
+    },
+    #[error("at most {} segments are supported", MAX_SEGMENTS)]
+    TooManySegments,
+    #[error("a color needs 3 or 4 channels and a segment at most 3 colors")]
+    InvalidColor,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StateUpdate {
+    pub on: Option<OnUpdate>,
+    pub bri: Option<u8>,
+    /// The transition time in units of 100 ms.
+    pub transition: Option<u16>,
+    /// Load a preset, before all other fields are applied.
+    pub ps: Option<u16>,
+    /// Save the resulting state as a preset.
+    pub psave: Option<u16>,
+    /// Respond with the resulting state.
+    #[serde(default)]
+    pub v: bool,
+    pub seg: Option<SegmentUpdates>,
+    /// The time of the client, only used by WLED for timers.
+    #[allow(dead_code)]
+    time: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum OnUpdate {
+    Set(bool),
+    /// Only `"t"`, which toggles the light.
+    Toggle(Toggle),
+}
+
+#[derive(Debug, Deserialize)]
+pub enum Toggle {
+    #[serde(rename = "t")]
+    Toggle,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum SegmentUpdates {
+    /// Changes the first segment, unless it has an `id`.
+    One(SegmentUpdate),
+    /// The segments without an `id` are identified by their position.
+    Many(Vec<SegmentUpdate>),
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SegmentUpdate {
+    pub id: Option<usize>,
+    pub start: Option<usize>,
+    /// The index after the last led, `0` deletes the segment.
+    pub stop: Option<usize>,
+    /// The primary, secondary and tertiary color, an empty color is left unchanged.
+    pub col: Option<Vec<Vec<u8>>>,
+    pub fx: Option<usize>,
+    pub sx: Option<u8>,
+    pub ix: Option<u8>,
+    pub pal: Option<u8>,
+    pub on: Option<bool>,
+    pub bri: Option<u8>,
+    /// Whether the segment is selected in the WLED UI.
+    #[allow(dead_code)]
+    sel: Option<bool>,
+}
+
+/// Apply `update` to `state` of a strip with `len` leds.
+///
+/// `preset` is the preset loaded with `ps`, which the caller looks up.
+pub fn apply(
+    update: &StateUpdate,
+    preset: Option<LightState>,
+    state: &mut LightState,
+    len: usize,
+) -> Result<(), ApplyError> {
+    if let Some(preset) = preset {
+        *state = preset;
+    }
+
+    match update.on {
+        Some(OnUpdate::Set(on)) => state.on = on,
+        Some(OnUpdate::Toggle(_)) => state.on = !state.on,
+        None => (),
+    }
+    // Like WLED, a brightness of zero turns the light off and keeps the brightness.
+    match update.bri {
+        Some(0) => state.on = false,
+        Some(bri) => state.brightness = bri,
+        None => (),
+    }
+    if let Some(transition) = update.transition {
+        state.transition = Duration::from_millis(transition as u64 * 100);
+    }
+
+    match &update.seg {
+        Some(SegmentUpdates::One(segment)) => {
+            apply_segment(segment, segment.id.unwrap_or(0), &mut state.segments, len)?
+        }
+        Some(SegmentUpdates::Many(segments)) => {
+            for (i, segment) in segments.iter().enumerate() {
+                apply_segment(segment, segment.id.unwrap_or(i), &mut state.segments, len)?;
+            }
+        }
+        None => (),
+    }
+
+    Ok(())
+}
+
+/// Apply `update` to segment `id`, which is created if it doesn't exist yet.
+///
+/// Deleting a segment moves all following segments down by one id.
+fn apply_segment(
+    update: &SegmentUpdate,
+    id: usize,
+    segments: &mut Vec<Segment>,
+    len: usize,
+) -> Result<(), ApplyError> {
+    if id < segments.len() && update.stop == Some(0) {
+        segments.remove(id);
+        return Ok(());
+    }
+
+    if id >= segments.len() {
+        let (start, stop) = match (update.start, update.stop) {
+            (Some(start), Some(stop)) => (start, stop),
+            _ => return Err(ApplyError::UnknownSegment(id)),
+        };
+        if segments.len() >= MAX_SEGMENTS {
+            return Err(ApplyError::TooManySegments);
+        }
+        segments.push(Segment::new(start, stop));
+    }
+    let index = id.min(segments.len() - 1);
+    let segment = &mut segments[index];
+
+    let start = update.start.unwrap_or(segment.start);
+    let stop = update.stop.unwrap_or(segment.stop);
+    if start >= stop || stop > len {
+        return Err(ApplyError::InvalidRange { start, stop, len });
+    }
+    segment.start = start;
+    segment.stop = stop;
+
+    if let Some(colors) = &update.col {
+        if colors.len() > segment.colors.len() {
+            return Err(ApplyError::InvalidColor);
+        }
+        for (color, channels) in segment.colors.iter_mut().zip(colors) {
+            if let Some(pixel) = parse_color(channels)? {
+                *color = pixel;
+            }
+        }
+    }
+    if let Some(fx) = update.fx {
+        segment.effect = Effect::from_index(fx).ok_or(ApplyError::UnknownEffect(fx))?;
+    }
+    if let Some(pal) = update.pal {
+        if pal as usize >= PALETTES.len() {
+            return Err(ApplyError::UnsupportedPalette(pal));
+        }
+    }
+    if let Some(sx) = update.sx {
+        segment.speed = sx;
+    }
+    if let Some(ix) = update.ix {
+        segment.intensity = ix;
+    }
+    if let Some(on) = update.on {
+        segment.on = on;
+    }
+    if let Some(bri) = update.bri {
+        segment.brightness = bri;
+    }
+
+    Ok(())
+}
+
+/// Parse `[r, g, b]` or `[r, g, b, w]`, the white channel is mixed into the other
+/// channels since the strip has no white leds.
+fn parse_color(channels: &[u8]) -> Result<Option<Pixel>, ApplyError> {
+    match *channels {
+        [] => Ok(None),
+        [r, g, b] => Ok(Some(Pixel::new(r, g, b))),
+        [r, g, b, w] => Ok(Some(Pixel::new(
+            r.saturating_add(w),
+            g.saturating_add(w),
+            b.saturating_add(w),
+        ))),
+        _ => Err(ApplyError::InvalidColor),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct State {
+    pub on: bool,
+    pub bri: u8,
+    pub transition: u16,
+    /// The active preset, `-1` if none.
+    pub ps: i32,
+    /// The active playlist, playlists aren't supported.
+    pub pl: i32,
+    /// The id of the segment the WLED UI shows first.
+    pub mainseg: usize,
+    pub seg: Vec<SegmentState>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SegmentState {
+    pub id: usize,
+    pub start: usize,
+    pub stop: usize,
+    pub len: usize,
+    pub col: [[u8; 3]; 3],
+    pub fx: usize,
+    pub sx: u8,
+    pub ix: u8,
+    pub pal: u8,
+    pub on: bool,
+    pub bri: u8,
+    pub sel: bool,
+}
+
+impl State {
+    pub fn new(state: &LightState, preset: Option<u16>) -> Self {
+        State {
+            on: state.on,
+            bri: state.brightness,
+            transition: (state.transition.as_millis() / 100).min(u16::MAX as u128) as u16,
+            ps: preset.map_or(-1, i32::from),
+            pl: -1,
+            mainseg: 0,
+            seg: state
+                .segments
+                .iter()
+                .enumerate()
+                .map(|(id, segment)| SegmentState {
+                    id,
+                    start: segment.start,
+                    stop: segment.stop,
+                    len: segment.len(),
+                    col: segment.colors.map(|c| [c.red, c.green, c.blue]),
+                    fx: segment.effect.index(),
+                    sx: segment.speed,
+                    ix: segment.intensity,
+                    pal: 0,
+                    on: segment.on,
+                    bri: segment.brightness,
+                    sel: true,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> LightState {
+        LightState {
+            on: true,
+            brightness: 128,
+            transition: Duration::from_millis(700),
+            segments: vec![Segment::new(0, 10)],
+        }
+    }
+
+    fn apply_json(state: &mut LightState, json: &str) -> Result<(), ApplyError> {
+        let update: StateUpdate = serde_json::from_str(json).unwrap();
+        apply(&update, None, state, 10)
+    }
+
+    #[test]
+    fn apply_global_fields() {
+        let mut state = state();
+        apply_json(
+            &mut state,
+            r#"{"on":"t","bri":200,"transition":3,"v":true}"#,
+        )
+        .unwrap();
+
+        assert!(!state.on);
+        assert_eq!(state.brightness, 200);
+        assert_eq!(state.transition, Duration::from_millis(300));
+
+        apply_json(&mut state, r#"{"on":true,"bri":0}"#).unwrap();
+        assert!(!state.on);
+        assert_eq!(state.brightness, 200);
+    }
+
+    #[test]
+    fn apply_segments() {
+        let mut state = state();
+        apply_json(
+            &mut state,
+            r#"{"seg":[{"stop":5,"col":[[],[0,0,255]],"fx":4,"sx":10},{"start":5,"stop":10,"col":[[1,2,3,4]]}]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(state.segments.len(), 2);
+        let (first, second) = (&state.segments[0], &state.segments[1]);
+        assert_eq!((first.start, first.stop), (0, 5));
+        assert_eq!(first.colors[0], Pixel::new(255, 160, 0));
+        assert_eq!(first.colors[1], Pixel::new(0, 0, 255));
+        assert_eq!(first.effect, Effect::ColorLoop);
+        assert_eq!(first.speed, 10);
+        assert_eq!((second.start, second.stop), (5, 10));
+        assert_eq!(second.colors[0], Pixel::new(5, 6, 7));
+
+        apply_json(&mut state, r#"{"seg":{"id":0,"stop":0}}"#).unwrap();
+        assert_eq!(state.segments.len(), 1);
+        assert_eq!(state.segments[0].start, 5);
+    }
+
+    #[test]
+    fn reject_unsupported() {
+        let mut state = state();
+
+        assert!(serde_json::from_str::<StateUpdate>(r#"{"nl":{"on":true}}"#).is_err());
+        assert!(serde_json::from_str::<StateUpdate>(r#"{"seg":{"col":["ff0000"]}}"#).is_err());
+        assert!(matches!(
+            apply_json(&mut state, r#"{"seg":{"fx":100}}"#),
+            Err(ApplyError::UnknownEffect(100))
+        ));
+        assert!(matches!(
+            apply_json(&mut state, r#"{"seg":{"pal":3}}"#),
+            Err(ApplyError::UnsupportedPalette(3))
+        ));
+        assert!(matches!(
+            apply_json(&mut state, r#"{"seg":{"id":2,"fx":1}}"#),
+            Err(ApplyError::UnknownSegment(2))
+        ));
+        assert!(matches!(
+            apply_json(&mut state, r#"{"seg":{"stop":11}}"#),
+            Err(ApplyError::InvalidRange { .. })
+        ));
+    }
+
+    #[test]
+    fn serialize_state() {
+        let json = serde_json::to_value(State::new(&state(), Some(2))).unwrap();
+
+        assert_eq!(json["ps"], 2);
+        assert_eq!(json["transition"], 7);
+        assert_eq!(json["seg"][0]["len"], 10);
+        assert_eq!(json["seg"][0]["col"][0], serde_json::json!([255, 160, 0]));
+    }
+}
@@ -139,6 +139,12 @@ impl<PIN: OutputPin> Ws2811<PIN> {
 
         self.rmt.start_iter_blocking(iter)
     }
+
+    /// Stop the RMT peripheral and release the pin and channel.
+    pub fn release(mut self) -> Result<(PIN, rmt::CHANNEL0), EspError> {
+        self.rmt.stop()?;
+        self.rmt.release()
+    }
 }
 
 fn nanos_to_ticks(ticks_hz: Hertz, duration: NanoSeconds) -> Result<u16, EspError> {
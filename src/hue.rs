@@ -0,0 +1,202 @@
+//! An emulation of a Philips Hue bridge for Echo devices.
+//!
+//! The bridge is found through SSDP (see [`ssdp`]) and serves the part of the Hue v1 API
+//! that Echo devices use (see [`echo`]), so that "Alexa, discover devices" finds every
+//! segment of the strip as a light without a cloud skill. Echo devices only talk to
+//! bridges on port 80, which the HTTP server of the [`api`](crate::api) listens on.
+//! Searches are answered by a task on the shared network [`EXECUTOR`].
+
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+#[cfg(target_os = "espidf")]
+use {
+    self::echo::{StateUpdate, USERNAME},
+    crate::api::{send_bytes, send_json},
+    crate::light::state,
+    embedded_svc::http::server::registry::Registry,
+    embedded_svc::http::server::{HandlerError, Request, Response},
+    embedded_svc::http::{Headers, Method},
+    esp_idf_svc::http::server::EspHttpServer,
+    esp_idf_sys::EspError,
+};
+
+use self::echo::{ColorMode, LightKind};
+use crate::light::state::MAX_SEGMENTS;
+use crate::utils::executor::spawner::{ExecutorShutDown, Spawner};
+use crate::utils::net::{self, EXECUTOR};
+
+pub mod echo;
+pub mod ssdp;
+
+/// The maximum size of a request body.
+const MAX_BODY_LEN: usize = 1024;
+/// How often the idle SSDP socket is checked for searches.
+const POLL_PERIOD: Duration = Duration::from_millis(100);
+
+/// The color mode each light was last set with.
+static COLOR_MODES: Mutex<[ColorMode; MAX_SEGMENTS]> = Mutex::new([ColorMode::Hs; MAX_SEGMENTS]);
+
+/// Lock the color modes, which stay usable if a thread panicked while holding them.
+fn color_modes() -> MutexGuard<'static, [ColorMode; MAX_SEGMENTS]> {
+    COLOR_MODES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StartError {
+    #[error("failed to bind ssdp socket")]
+    Bind(#[source] io::Error),
+    #[error("failed to spawn ssdp task")]
+    Task(#[from] ExecutorShutDown),
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The kind of Hue light the segments are announced as.
+    pub kind: LightKind,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            kind: LightKind::ExtendedColor,
+        }
+    }
+}
+
+/// Start answering SSDP searches on the executor of `spawner`.
+pub fn start(spawner: &Spawner) -> Result<(), StartError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, ssdp::PORT)).map_err(StartError::Bind)?;
+    socket
+        .join_multicast_v4(&ssdp::MULTICAST_ADDR, &Ipv4Addr::UNSPECIFIED)
+        .map_err(StartError::Bind)?;
+    socket.set_nonblocking(true).map_err(StartError::Bind)?;
+
+    spawner.spawn(run(socket))?;
+
+    Ok(())
+}
+
+async fn run(socket: UdpSocket) {
+    let mut buf = [0; 1024];
+
+    loop {
+        match socket.recv_from(&mut buf) {
+            Ok((len, src)) => handle_search(&socket, &buf[..len], src),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                EXECUTOR.sleep(POLL_PERIOD).await;
+            }
+            Err(err) => {
+                log::error!("failed to receive ssdp request: {}", err);
+                EXECUTOR.sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+fn handle_search(socket: &UdpSocket, request: &[u8], src: SocketAddr) {
+    if !ssdp::is_search(request) {
+        return;
+    }
+    let ip = match net::station_ip() {
+        Some(ip) => ip,
+        None => return,
+    };
+
+    let response = ssdp::search_response(ip, net::station_mac());
+    if let Err(err) = socket.send_to(response.as_bytes(), src) {
+        log::warn!("failed to answer ssdp search from {}: {}", src, err);
+    }
+}
+
+/// Register the endpoints of the Hue API.
+///
+/// Every light has its own endpoints, as the server doesn't match wildcards. Echo devices
+/// always use the username they got when pairing.
+#[cfg(target_os = "espidf")]
+pub fn register(server: &mut EspHttpServer, config: &Config) -> Result<(), EspError> {
+    let kind = config.kind;
+
+    server.fn_handler("/description.xml", Method::Get, |_req, resp| {
+        let ip = net::station_ip().unwrap_or(Ipv4Addr::UNSPECIFIED);
+        let description = echo::description(ip, net::station_mac());
+        send_bytes(resp, 200, "text/xml", description.as_bytes())
+    })?;
+    server.fn_handler("/api", Method::Post, |_req, resp| {
+        send_json(resp, &echo::pair_response())
+    })?;
+    server.fn_handler(
+        &format!("/api/{}/lights", USERNAME),
+        Method::Get,
+        move |_req, resp| {
+            let modes = *color_modes();
+            let lights = echo::lights(&state::get(), kind, &modes, net::station_mac());
+            send_json(resp, &lights)
+        },
+    )?;
+
+    for index in 0..MAX_SEGMENTS {
+        let id = echo::light_id(index);
+
+        server.fn_handler(
+            &format!("/api/{}/lights/{}", USERNAME, id),
+            Method::Get,
+            move |_req, resp| {
+                let mode = color_modes()[index];
+                match echo::light(&state::get(), index, kind, mode, net::station_mac()) {
+                    Some(light) => send_json(resp, &light),
+                    None => send_json(resp, &not_available(index)),
+                }
+            },
+        )?;
+        server.fn_handler(
+            &format!("/api/{}/lights/{}/state", USERNAME, id),
+            Method::Put,
+            move |req, resp| put_state(req, resp, index),
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "espidf")]
+fn put_state<Q: Request, R: Response>(
+    mut req: Q,
+    resp: R,
+    index: usize,
+) -> Result<(), HandlerError> {
+    let len = req.content_len().unwrap_or(MAX_BODY_LEN + 1);
+    if len > MAX_BODY_LEN {
+        return send_json(resp, &echo::invalid_json());
+    }
+    let mut body = vec![0; len];
+    let (body, _) = embedded_svc::io::read_max(req.reader(), &mut body)?;
+
+    let update: StateUpdate = match serde_json::from_slice(body) {
+        Ok(update) => update,
+        Err(_) => return send_json(resp, &echo::invalid_json()),
+    };
+
+    // The color mode isn't locked during the update, which takes the state lock.
+    let mut mode = color_modes()[index];
+    let mut answer = None;
+    let _ = state::update(|state| {
+        answer = echo::apply(&update, index, state, &mut mode);
+        answer.as_ref().map(|_| ()).ok_or(())
+    });
+    if answer.is_some() {
+        color_modes()[index] = mode;
+    }
+
+    match answer {
+        Some(answer) => send_json(resp, &answer),
+        None => send_json(resp, &not_available(index)),
+    }
+}
+
+#[cfg(target_os = "espidf")]
+fn not_available(index: usize) -> serde_json::Value {
+    echo::not_available(&format!("/lights/{}", echo::light_id(index)))
+}
@@ -0,0 +1,516 @@
+//! The part of the Hue v1 API that Echo devices use, in the exact shapes they expect.
+//!
+//! Every segment of the light state is a light, with the id of its index plus one. Echo
+//! devices pair without pressing the link button, list the lights, and then change them
+//! one at a time. They only accept lights with a `uniqueid` of eight MAC-like bytes and an
+//! endpoint, and a `modelid` of an actual Hue light, otherwise they ignore the light
+//! silently.
+//!
+//! There are no captured responses of a real bridge to compare with. The request
+//! fixtures of the tests are written after the requests Echo devices send, and the
+//! responses are only checked for the shapes above, so a change in what Echo devices
+//! accept isn't caught by the tests.
+
+use std::net::Ipv4Addr;
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::light::color;
+use crate::light::effect::Effect;
+use crate::light::frame::Pixel;
+use crate::light::state::{LightState, Segment};
+
+/// The name of the bridge, and of the light if there is only one segment.
+pub const NAME: &str = "esp32-hue";
+/// The user every pairing request gets, Echo devices don't check it.
+pub const USERNAME: &str = "esp32hueEchoCompatibilityUser00000000000";
+
+/// The coldest color temperature of Hue lights, 6500 K.
+pub const MIN_MIREDS: u16 = 153;
+/// The warmest color temperature of Hue lights, 2000 K.
+pub const MAX_MIREDS: u16 = 500;
+
+/// The kind of Hue light the segments are announced as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightKind {
+    /// A color light with color temperatures, a Hue color bulb (LCT015).
+    ExtendedColor,
+    /// A light that can only be dimmed, a Hue white bulb (LWB010).
+    Dimmable,
+}
+
+impl LightKind {
+    fn type_name(self) -> &'static str {
+        match self {
+            LightKind::ExtendedColor => "Extended color light",
+            LightKind::Dimmable => "Dimmable light",
+        }
+    }
+
+    fn model_id(self) -> &'static str {
+        match self {
+            LightKind::ExtendedColor => "LCT015",
+            LightKind::Dimmable => "LWB010",
+        }
+    }
+
+    fn product_name(self) -> &'static str {
+        match self {
+            LightKind::ExtendedColor => "Hue color lamp",
+            LightKind::Dimmable => "Hue white lamp",
+        }
+    }
+}
+
+/// The color mode a light was last set with, which is reported in its state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    Hs,
+    Xy,
+    /// The color temperature in mireds.
+    Ct(u16),
+}
+
+/// The id of the bridge in SSDP answers, the MAC address with `FFFE` in the middle.
+pub fn bridge_id(mac: [u8; 6]) -> String {
+    format!(
+        "{:02X}{:02X}{:02X}FFFE{:02X}{:02X}{:02X}",
+        mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+    )
+}
+
+/// The UPnP uuid of the bridge, the one of Hue bridges with the MAC address at the end.
+pub fn bridge_uuid(mac: [u8; 6]) -> String {
+    format!(
+        "2f402f80-da50-11e1-9b23-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+    )
+}
+
+/// The UPnP description of the bridge at `ip`.
+pub fn description(ip: Ipv4Addr, mac: [u8; 6]) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8" ?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<specVersion><major>1</major><minor>0</minor></specVersion>
+<URLBase>http://{ip}:80/</URLBase>
+<device>
+<deviceType>urn:schemas-upnp-org:device:Basic:1</deviceType>
+<friendlyName>{name} ({ip})</friendlyName>
+<manufacturer>Royal Philips Electronics</manufacturer>
+<manufacturerURL>http://www.philips.com</manufacturerURL>
+<modelDescription>Philips hue Personal Wireless Lighting</modelDescription>
+<modelName>Philips hue bridge 2012</modelName>
+<modelNumber>929000226503</modelNumber>
+<modelURL>http://www.meethue.com</modelURL>
+<serialNumber>{serial}</serialNumber>
+<UDN>uuid:{uuid}</UDN>
+<presentationURL>index.html</presentationURL>
+</device>
+</root>
+"#,
+        ip = ip,
+        name = NAME,
+        serial = bridge_uuid(mac).rsplit('-').next().unwrap_or_default(),
+        uuid = bridge_uuid(mac),
+    )
+}
+
+/// The answer to a pairing request, Echo devices send it without a link button press.
+pub fn pair_response() -> Value {
+    json!([{ "success": { "username": USERNAME } }])
+}
+
+/// All lights, by their id.
+pub fn lights(state: &LightState, kind: LightKind, modes: &[ColorMode], mac: [u8; 6]) -> Value {
+    let lights: Map<String, Value> = (0..state.segments.len())
+        .filter_map(|index| {
+            let mode = modes.get(index).copied().unwrap_or(ColorMode::Hs);
+            Some((light_id(index), light(state, index, kind, mode, mac)?))
+        })
+        .collect();
+    Value::Object(lights)
+}
+
+/// The light of the segment `index`, `None` if it doesn't exist.
+pub fn light(
+    state: &LightState,
+    index: usize,
+    kind: LightKind,
+    mode: ColorMode,
+    mac: [u8; 6],
+) -> Option<Value> {
+    let segment = state.segments.get(index)?;
+    let name = if state.segments.len() == 1 {
+        NAME.to_string()
+    } else {
+        format!("{} {}", NAME, index + 1)
+    };
+    let on = state.on && segment.on;
+    let bri = (segment.brightness as u16 * 254 / 255).max(1);
+
+    let light_state = match kind {
+        LightKind::ExtendedColor => {
+            let color = segment.colors[0];
+            // Another API may have changed the color since the color temperature was set.
+            let mode = match mode {
+                ColorMode::Ct(mireds) if temperature(mireds) != color => ColorMode::Hs,
+                mode => mode,
+            };
+            let (hue, saturation, _) = color::to_hsv(color);
+            let (x, y) = color::to_xy(color);
+            let (colormode, ct) = match mode {
+                ColorMode::Hs => ("hs", MIN_MIREDS),
+                ColorMode::Xy => ("xy", MIN_MIREDS),
+                ColorMode::Ct(mireds) => ("ct", mireds),
+            };
+
+            json!({
+                "on": on,
+                "bri": bri,
+                "hue": (hue / 360.0 * 65535.0).round() as u16,
+                "sat": (saturation * 254.0).round() as u8,
+                "effect": "none",
+                "xy": [round4(x), round4(y)],
+                "ct": ct,
+                "alert": "none",
+                "colormode": colormode,
+                "mode": "homeautomation",
+                "reachable": true,
+            })
+        }
+        LightKind::Dimmable => json!({
+            "on": on,
+            "bri": bri,
+            "alert": "none",
+            "mode": "homeautomation",
+            "reachable": true,
+        }),
+    };
+
+    Some(json!({
+        "state": light_state,
+        "type": kind.type_name(),
+        "name": name,
+        "modelid": kind.model_id(),
+        "manufacturername": "Signify Netherlands B.V.",
+        "productname": kind.product_name(),
+        "uniqueid": unique_id(mac, index),
+        "swversion": "1.50.2_r30933",
+    }))
+}
+
+/// A change of the state of a light, fields that Echo devices don't send are ignored.
+#[derive(Debug, Default, Deserialize)]
+pub struct StateUpdate {
+    pub on: Option<bool>,
+    /// The brightness from 1 to 254.
+    pub bri: Option<u8>,
+    /// The hue from 0 to 65535.
+    pub hue: Option<u16>,
+    /// The saturation from 0 to 254.
+    pub sat: Option<u8>,
+    pub xy: Option<[f32; 2]>,
+    /// The color temperature in mireds.
+    pub ct: Option<u16>,
+}
+
+/// Apply `update` to the segment `index`, `mode` is set to the color mode of the update.
+///
+/// Returns the answer listing every changed field, `None` if the segment doesn't exist.
+pub fn apply(
+    update: &StateUpdate,
+    index: usize,
+    state: &mut LightState,
+    mode: &mut ColorMode,
+) -> Option<Value> {
+    let LightState {
+        on: light_on,
+        segments,
+        ..
+    } = state;
+    let segment = segments.get_mut(index)?;
+    let prefix = format!("/lights/{}/state/", light_id(index));
+    let mut changes = Vec::new();
+
+    if let Some(on) = update.on {
+        segment.on = on;
+        // Turning on a segment of a light that is off turns the light on.
+        if on {
+            *light_on = true;
+        }
+        changes.push(("on", json!(on)));
+    }
+    if let Some(bri) = update.bri {
+        let bri = bri.clamp(1, 254);
+        segment.brightness = ((bri as u16 * 255 + 127) / 254) as u8;
+        changes.push(("bri", json!(bri)));
+    }
+
+    if update.hue.is_some() || update.sat.is_some() {
+        let (current_hue, current_saturation, _) = color::to_hsv(segment.colors[0]);
+        let hue = update
+            .hue
+            .map_or(current_hue, |hue| hue as f32 * 360.0 / 65535.0);
+        let saturation = update
+            .sat
+            .map_or(current_saturation, |sat| sat.min(254) as f32 / 254.0);
+        set_color(segment, color::from_hs(hue, saturation));
+        *mode = ColorMode::Hs;
+
+        if let Some(hue) = update.hue {
+            changes.push(("hue", json!(hue)));
+        }
+        if let Some(sat) = update.sat {
+            changes.push(("sat", json!(sat.min(254))));
+        }
+    }
+    if let Some([x, y]) = update.xy {
+        set_color(segment, color::from_xy(x, y));
+        *mode = ColorMode::Xy;
+        changes.push(("xy", json!([round4(x), round4(y)])));
+    }
+    if let Some(ct) = update.ct {
+        let ct = ct.clamp(MIN_MIREDS, MAX_MIREDS);
+        set_color(segment, temperature(ct));
+        *mode = ColorMode::Ct(ct);
+        changes.push(("ct", json!(ct)));
+    }
+
+    let answer = changes
+        .into_iter()
+        .map(|(field, value)| {
+            let mut success = Map::new();
+            success.insert(format!("{}{}", prefix, field), value);
+            json!({ "success": success })
+        })
+        .collect();
+    Some(Value::Array(answer))
+}
+
+/// The error of the Hue API for a resource that doesn't exist.
+pub fn not_available(address: &str) -> Value {
+    json!([{
+        "error": {
+            "type": 3,
+            "address": address,
+            "description": format!("resource, {}, not available", address),
+        }
+    }])
+}
+
+/// The error of the Hue API for a body that isn't valid JSON.
+pub fn invalid_json() -> Value {
+    json!([{
+        "error": {
+            "type": 2,
+            "address": "/",
+            "description": "body contains invalid json",
+        }
+    }])
+}
+
+/// The id of the light of segment `index`.
+pub fn light_id(index: usize) -> String {
+    (index + 1).to_string()
+}
+
+/// The unique id of the light of segment `index`, the MAC address of the bridge with two
+/// more bytes, and the segment as endpoint.
+fn unique_id(mac: [u8; 6], index: usize) -> String {
+    format!(
+        "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:00:11-{:02x}",
+        mac[0],
+        mac[1],
+        mac[2],
+        mac[3],
+        mac[4],
+        mac[5],
+        index + 1
+    )
+}
+
+/// Show `color` on `segment`, unless its effect shows other colors.
+fn set_color(segment: &mut Segment, color: Pixel) {
+    segment.colors[0] = color;
+    if !segment.effect.uses_colors() {
+        segment.effect = Effect::Solid;
+    }
+}
+
+fn temperature(mireds: u16) -> Pixel {
+    color::from_temperature(color::mireds_to_kelvin(mireds))
+}
+
+/// Round to the four decimals of the Hue API.
+fn round4(value: f32) -> f64 {
+    (value as f64 * 10_000.0).round() / 10_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const MAC: [u8; 6] = [0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56];
+
+    fn state() -> LightState {
+        LightState {
+            on: true,
+            brightness: 255,
+            transition: Duration::from_millis(700),
+            segments: vec![
+                Segment {
+                    colors: [Pixel::new(255, 0, 0); 3],
+                    ..Segment::new(0, 30)
+                },
+                Segment {
+                    on: false,
+                    brightness: 128,
+                    colors: [Pixel::new(0, 0, 255); 3],
+                    ..Segment::new(30, 60)
+                },
+            ],
+        }
+    }
+
+    fn fixture(json: &str) -> Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn pairing_and_description() {
+        // Echo devices don't check the answer beyond the username.
+        let request: Value = fixture(include_str!("fixtures/echo_pair_request.json"));
+        assert_eq!(request["devicetype"], "Echo");
+        assert_eq!(pair_response()[0]["success"]["username"], USERNAME);
+        assert_eq!(USERNAME.len(), 40);
+
+        let description = description(Ipv4Addr::new(192, 168, 1, 42), MAC);
+        assert!(description.contains("<modelName>Philips hue bridge 2012</modelName>"));
+        assert!(description.contains("<modelNumber>929000226503</modelNumber>"));
+        assert!(description.contains("<serialNumber>240ac4123456</serialNumber>"));
+        assert!(description.contains("<UDN>uuid:2f402f80-da50-11e1-9b23-240ac4123456</UDN>"));
+        assert_eq!(bridge_id(MAC), "240AC4FFFE123456");
+    }
+
+    /// Check that `id` has the format of Hue unique ids, eight bytes and an endpoint.
+    fn is_unique_id(id: &str) -> bool {
+        let (address, endpoint) = match id.split_once('-') {
+            Some(parts) => parts,
+            None => return false,
+        };
+        let is_byte = |byte: &str| byte.len() == 2 && byte.chars().all(|c| c.is_ascii_hexdigit());
+        address.split(':').count() == 8 && address.split(':').all(is_byte) && is_byte(endpoint)
+    }
+
+    #[test]
+    fn list_lights() {
+        let modes = [ColorMode::Ct(366), ColorMode::Xy];
+        for kind in [LightKind::ExtendedColor, LightKind::Dimmable] {
+            let lights = lights(&state(), kind, &modes, MAC);
+            let lights = lights.as_object().unwrap();
+            assert_eq!(lights.keys().collect::<Vec<_>>(), ["1", "2"]);
+
+            let mut names = Vec::new();
+            let mut unique_ids = Vec::new();
+            for light in lights.values() {
+                let state = &light["state"];
+                assert!(state["on"].is_boolean());
+                assert!((1..=254).contains(&state["bri"].as_u64().unwrap()));
+                assert_eq!(state["reachable"], true);
+                assert_eq!(state["alert"], "none");
+                assert_eq!(state["mode"], "homeautomation");
+                assert_eq!(light["manufacturername"], "Signify Netherlands B.V.");
+                assert!(!light["productname"].as_str().unwrap().is_empty());
+                assert!(!light["swversion"].as_str().unwrap().is_empty());
+                assert!(is_unique_id(light["uniqueid"].as_str().unwrap()));
+                names.push(light["name"].as_str().unwrap());
+                unique_ids.push(light["uniqueid"].as_str().unwrap());
+
+                let color_fields = ["hue", "sat", "xy", "ct", "colormode", "effect"];
+                match kind {
+                    LightKind::ExtendedColor => {
+                        assert_eq!(light["type"], "Extended color light");
+                        assert_eq!(light["modelid"], "LCT015");
+                        assert!(state["hue"].as_u64().unwrap() <= 65535);
+                        assert!(state["sat"].as_u64().unwrap() <= 254);
+                        let xy = state["xy"].as_array().unwrap();
+                        assert_eq!(xy.len(), 2);
+                        assert!(xy
+                            .iter()
+                            .all(|v| (0.0..=1.0).contains(&v.as_f64().unwrap())));
+                        let ct = state["ct"].as_u64().unwrap() as u16;
+                        assert!((MIN_MIREDS..=MAX_MIREDS).contains(&ct));
+                        assert!(["hs", "xy", "ct"].contains(&state["colormode"].as_str().unwrap()));
+                    }
+                    LightKind::Dimmable => {
+                        assert_eq!(light["type"], "Dimmable light");
+                        assert_eq!(light["modelid"], "LWB010");
+                        assert!(color_fields.iter().all(|field| state.get(field).is_none()));
+                    }
+                }
+            }
+
+            // Echo devices merge lights with the same name or unique id.
+            names.sort_unstable();
+            names.dedup();
+            unique_ids.sort_unstable();
+            unique_ids.dedup();
+            assert_eq!((names.len(), unique_ids.len()), (2, 2));
+        }
+
+        // The color mode is reported as it was set.
+        let lights = lights(&state(), LightKind::ExtendedColor, &modes, MAC);
+        assert_eq!(lights["2"]["state"]["colormode"], "xy");
+        assert_eq!(lights["2"]["state"]["bri"], 127);
+        assert_eq!(lights["2"]["state"]["on"], false);
+        assert!(light(&state(), 2, LightKind::Dimmable, ColorMode::Hs, MAC).is_none());
+    }
+
+    #[test]
+    fn apply_echo_requests() {
+        let original = state();
+        let mut state = original.clone();
+        state.on = false;
+        let mut mode = ColorMode::Hs;
+
+        let requests = fixture(include_str!("fixtures/echo_state_requests.json"));
+        for request in requests.as_array().unwrap() {
+            let update = StateUpdate::deserialize(request).unwrap();
+            let response = apply(&update, 1, &mut state, &mut mode).unwrap();
+
+            // Echo devices expect one success per field, with the path of the field.
+            let request = request.as_object().unwrap();
+            let response = response.as_array().unwrap();
+            assert_eq!(response.len(), request.len());
+            for (field, value) in request {
+                let path = format!("/lights/2/state/{}", field);
+                let success = response
+                    .iter()
+                    .find_map(|entry| entry["success"].get(&path))
+                    .unwrap();
+                assert_eq!(success, value);
+            }
+
+            if update.hue.is_some() {
+                assert_eq!(state.segments[1].colors[0], Pixel::new(0, 255, 0));
+                assert_eq!(mode, ColorMode::Hs);
+            }
+            if update.xy.is_some() {
+                assert_eq!(mode, ColorMode::Xy);
+            }
+        }
+
+        assert!(state.on && !state.segments[1].on);
+        assert_eq!(state.segments[1].brightness, 128);
+        assert_eq!(state.segments[1].colors[0], temperature(383));
+        assert_eq!(mode, ColorMode::Ct(383));
+        // Other segments aren't touched.
+        assert_eq!(state.segments[0], original.segments[0]);
+
+        assert!(apply(&StateUpdate::default(), 2, &mut state, &mut mode).is_none());
+    }
+}
@@ -0,0 +1,101 @@
+//! The SSDP discovery of the bridge.
+//!
+//! Echo devices search with the search targets of UPnP basic devices or root devices, or
+//! for all devices. The answer points them to the description of the bridge, and always
+//! has the search target of basic devices, as Echo devices ignore other answers.
+
+use std::net::Ipv4Addr;
+
+/// The UDP port of SSDP.
+pub const PORT: u16 = 1900;
+/// The multicast group of SSDP.
+pub const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+
+/// The search targets the bridge answers to.
+const SEARCH_TARGETS: [&str; 3] = [
+    "urn:schemas-upnp-org:device:basic:1",
+    "upnp:rootdevice",
+    "ssdp:all",
+];
+
+/// Whether `request` is an `M-SEARCH` request that the bridge answers to.
+pub fn is_search(request: &[u8]) -> bool {
+    let request = String::from_utf8_lossy(request);
+    request.starts_with("M-SEARCH ")
+        && request.lines().any(|line| match line.split_once(':') {
+            Some((name, value)) => {
+                name.trim().eq_ignore_ascii_case("ST") && SEARCH_TARGETS.contains(&value.trim())
+            }
+            None => false,
+        })
+}
+
+/// The answer to a search, by the bridge with `mac` at `ip`.
+pub fn search_response(ip: Ipv4Addr, mac: [u8; 6]) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\n\
+         HOST: 239.255.255.250:1900\r\n\
+         EXT:\r\n\
+         CACHE-CONTROL: max-age=100\r\n\
+         LOCATION: http://{}:80/description.xml\r\n\
+         SERVER: FreeRTOS/6.0.5, UPnP/1.0, IpBridge/1.17.0\r\n\
+         hue-bridgeid: {}\r\n\
+         ST: {}\r\n\
+         USN: uuid:{}::upnp:rootdevice\r\n\
+         \r\n",
+        ip,
+        super::echo::bridge_id(mac),
+        SEARCH_TARGETS[0],
+        super::echo::bridge_uuid(mac),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answer_echo_searches() {
+        assert!(is_search(include_bytes!("fixtures/echo_search_basic.txt")));
+        assert!(is_search(include_bytes!(
+            "fixtures/echo_search_rootdevice.txt"
+        )));
+        assert!(is_search(include_bytes!("fixtures/echo_search_all.txt")));
+        assert!(!is_search(
+            b"M-SEARCH * HTTP/1.1\r\nST: urn:dial-multiscreen-org:service:dial:1\r\n"
+        ));
+    }
+
+    #[test]
+    fn search_response_shape() {
+        let ip = Ipv4Addr::new(10, 0, 0, 7);
+        let response = search_response(ip, [0x02, 0, 0, 0, 0, 0x01]);
+
+        // Echo devices only read CRLF terminated headers.
+        let head = response.strip_suffix("\r\n\r\n").unwrap();
+        let mut lines = head.split("\r\n");
+        assert_eq!(lines.next(), Some("HTTP/1.1 200 OK"));
+        let header = |name: &str| {
+            head.split("\r\n").find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                key.eq_ignore_ascii_case(name).then(|| value.trim())
+            })
+        };
+        assert!(lines.all(|line| !line.contains('\n') && line.contains(':')));
+
+        assert_eq!(
+            header("LOCATION"),
+            Some("http://10.0.0.7:80/description.xml")
+        );
+        assert_eq!(header("ST"), Some("urn:schemas-upnp-org:device:basic:1"));
+        assert!(header("SERVER").unwrap().contains("IpBridge/"));
+        let bridge_id = header("hue-bridgeid").unwrap();
+        assert_eq!(bridge_id.len(), 16);
+        assert!(bridge_id
+            .chars()
+            .all(|c| c.is_ascii_digit() || c.is_ascii_uppercase() && c.is_ascii_hexdigit()));
+        let usn = header("USN").unwrap();
+        assert!(usn.starts_with("uuid:2f402f80-da50-11e1-9b23-"));
+        assert!(usn.ends_with("::upnp:rootdevice"));
+    }
+}
@@ -0,0 +1,13 @@
+//! Realtime inputs that drive the strip directly, e.g. from lighting desks and
+//! sequencers.
+//!
+//! Every input writes into the [`frame`](crate::light::frame) buffer of the light
+//! service, which falls back to its own light state once the input times out.
+
+pub mod artnet;
+pub mod ddp;
+pub mod e131;
+pub mod opc;
+pub mod serial;
+pub mod universe;
+pub mod wled;
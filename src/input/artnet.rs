@@ -0,0 +1,207 @@
+//! An Art-Net 4 node.
+//!
+//! The node answers ArtPoll with one ArtPollReply per group of four output ports and
+//! maps the ArtDmx data of its port addresses onto the strip with a [`UniverseMap`],
+//! whose universes are the 15-bit port addresses. If several controllers send the same
+//! port address, the last packet wins.
+//!
+//! Once an ArtSync arrived, the node is in synchronous mode: ArtDmx data is only staged
+//! and shown with the next ArtSync. The node falls back to showing ArtDmx data directly
+//! if no ArtSync arrived for [`SYNC_TIMEOUT`].
+
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use self::packet::{Packet, PollReply, PORT};
+use super::universe::UniverseMap;
+use crate::light::frame;
+use crate::utils::net;
+use crate::utils::thread::{self, Core, SpawnError, ThreadConfig};
+
+pub mod packet;
+
+/// The name of this input in the [`frame`] buffer.
+pub const SOURCE_NAME: &str = "artnet";
+
+/// The time after the last ArtSync after which data is shown without waiting for one.
+pub const SYNC_TIMEOUT: Duration = Duration::from_secs(4);
+
+/// The node report code of a node that works normally (`RcPowerOk`).
+const REPORT_POWER_OK: u16 = 0x0001;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StartError {
+    #[error("failed to bind art-net socket")]
+    Bind(#[source] io::Error),
+    #[error("failed to spawn art-net thread")]
+    Thread(#[from] SpawnError),
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The mapping of the port addresses onto the strip.
+    pub universes: UniverseMap,
+    /// The name shown by controllers, at most 17 characters.
+    pub short_name: String,
+    /// The description shown by controllers, at most 63 characters.
+    pub long_name: String,
+    /// The time after which the light state is shown again once no ArtDmx arrives.
+    pub timeout: Duration,
+    /// The thread the node runs on.
+    pub thread: ThreadConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            universes: UniverseMap::default(),
+            short_name: "esp32-hue".into(),
+            long_name: "esp32-hue Art-Net LED node".into(),
+            timeout: Duration::from_millis(2500),
+            thread: ThreadConfig {
+                name: "artnet",
+                core: Some(Core::Core0),
+                priority: 8,
+                stack_size: 6 * 1024,
+            },
+        }
+    }
+}
+
+/// Start the node.
+pub fn start(config: Config) -> Result<(), StartError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PORT)).map_err(StartError::Bind)?;
+
+    let thread_config = config.thread.clone();
+    thread::spawn(&thread_config, move || {
+        Node {
+            config,
+            dmx_source: None,
+            last_sync: None,
+            pending: false,
+            replies: 0,
+        }
+        .run(socket)
+    })?;
+
+    Ok(())
+}
+
+struct Node {
+    config: Config,
+    /// The controller that sent the last ArtDmx, only its ArtSync is honored.
+    dmx_source: Option<IpAddr>,
+    /// When the last ArtSync arrived, `None` if the node isn't in synchronous mode.
+    last_sync: Option<Instant>,
+    /// Whether staged data waits for an ArtSync.
+    pending: bool,
+    /// The amount of ArtPollReplies sent, shown in the node report.
+    replies: u16,
+}
+
+impl Node {
+    fn run(mut self, socket: UdpSocket) {
+        // The largest packet we handle is an ArtDmx with 512 channels.
+        let mut buf = [0; 530];
+
+        loop {
+            match socket.recv_from(&mut buf) {
+                Ok((len, src)) => self.handle(&socket, &buf[..len], src),
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(err) => {
+                    log::error!("failed to receive art-net packet: {}", err);
+                    std::thread::sleep(Duration::from_secs(1));
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, socket: &UdpSocket, data: &[u8], src: SocketAddr) {
+        let packet = match Packet::parse(data) {
+            Ok(packet) => packet,
+            Err(err) => {
+                log::debug!("invalid art-net packet from {}: {}", src, err);
+                return;
+            }
+        };
+
+        match packet {
+            Packet::Poll(_) => self.reply_to_poll(socket, src),
+            Packet::Dmx(dmx) => {
+                if !self.config.universes.contains(dmx.port_address) {
+                    return;
+                }
+                self.dmx_source = Some(src.ip());
+
+                frame::write(SOURCE_NAME, |pixels| {
+                    self.config
+                        .universes
+                        .apply(dmx.port_address, dmx.data, pixels)
+                });
+
+                let synchronous =
+                    matches!(self.last_sync, Some(last) if last.elapsed() < SYNC_TIMEOUT);
+                if synchronous {
+                    self.pending = true;
+                } else {
+                    self.last_sync = None;
+                    self.present();
+                }
+            }
+            Packet::Sync => {
+                if matches!(self.dmx_source, Some(ip) if ip != src.ip()) {
+                    return;
+                }
+
+                self.last_sync = Some(Instant::now());
+                if self.pending {
+                    self.present();
+                }
+            }
+        }
+    }
+
+    fn present(&mut self) {
+        frame::present(SOURCE_NAME, self.config.timeout);
+        self.pending = false;
+    }
+
+    /// Art-Net 4 replies are unicast to the controller that polled.
+    fn reply_to_poll(&mut self, socket: &UdpSocket, src: SocketAddr) {
+        let ip = match net::station_ip() {
+            Some(ip) => ip,
+            None => return,
+        };
+        let mac = net::station_mac();
+
+        self.replies = (self.replies + 1) % 10000;
+        let node_report = format!("#{:04x} [{:04}] OK", REPORT_POWER_OK, self.replies);
+
+        let port_addresses = self.config.universes.universes();
+        for (index, ports) in packet::reply_groups(&port_addresses).iter().enumerate() {
+            let reply = PollReply {
+                ip,
+                mac,
+                version: firmware_version(),
+                short_name: &self.config.short_name,
+                long_name: &self.config.long_name,
+                node_report: &node_report,
+                port_addresses: ports,
+                bind_index: index as u8 + 1,
+            };
+
+            if let Err(err) = socket.send_to(&reply.encode(), (src.ip(), PORT)) {
+                log::warn!("failed to send art-net poll reply to {}: {}", src, err);
+                return;
+            }
+        }
+    }
+}
+
+/// The major and minor version of the firmware.
+fn firmware_version() -> u16 {
+    let major: u8 = env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0);
+    let minor: u8 = env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0);
+    u16::from_be_bytes([major, minor])
+}
@@ -0,0 +1,344 @@
+//! Parsing and building of Art-Net 4 packets.
+//!
+//! Only the packets of a DMX output node are supported: ArtPoll, ArtPollReply, ArtDmx
+//! and ArtSync. Unlike the rest of the protocol, the OpCode and the port of an
+//! ArtPollReply are little-endian.
+
+use std::net::Ipv4Addr;
+
+/// The UDP port of Art-Net.
+pub const PORT: u16 = 6454;
+
+/// The protocol version this node implements.
+pub const PROTOCOL_VERSION: u16 = 14;
+
+const ID: [u8; 8] = *b"Art-Net\0";
+
+const OP_POLL: u16 = 0x2000;
+const OP_POLL_REPLY: u16 = 0x2100;
+const OP_DMX: u16 = 0x5000;
+const OP_SYNC: u16 = 0x5200;
+
+/// The length of an ArtPollReply.
+pub const POLL_REPLY_LEN: usize = 239;
+
+/// The amount of ports one ArtPollReply can describe.
+pub const PORTS_PER_REPLY: usize = 4;
+
+const SHORT_NAME_LEN: usize = 18;
+const LONG_NAME_LEN: usize = 64;
+const NODE_REPORT_LEN: usize = 64;
+
+/// The OEM code of nodes without a registered code.
+const OEM_UNKNOWN: u16 = 0x00ff;
+/// The ESTA manufacturer code reserved for prototyping.
+const ESTA_PROTOTYPING: u16 = 0x7ff0;
+
+/// Indicators in normal mode, port addresses set by the configuration.
+const STATUS1: u8 = 0b1101_0000;
+/// Web configuration, DHCP configured and capable, 15-bit port addresses.
+const STATUS2: u8 = 0b0000_1111;
+
+/// The port can output DMX512 data.
+const PORT_TYPE_OUTPUT_DMX: u8 = 0x80;
+/// Data is being output on the port.
+const GOOD_OUTPUT_DATA: u8 = 0x80;
+/// The port outputs continuously, independent of received data.
+const GOOD_OUTPUT_B_CONTINUOUS: u8 = 0x40;
+/// The node is a DMX to/from Art-Net device.
+const STYLE_NODE: u8 = 0x00;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ParseError {
+    #[error("packet is too short")]
+    Truncated,
+    #[error("not an Art-Net packet")]
+    NotArtNet,
+    #[error("unsupported protocol version {0}")]
+    UnsupportedVersion(u16),
+    #[error("unsupported opcode {0:#06x}")]
+    UnsupportedOpCode(u16),
+    #[error("invalid dmx length {0}")]
+    InvalidLength(u16),
+}
+
+/// A parsed Art-Net packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Packet<'a> {
+    Poll(Poll),
+    Dmx(Dmx<'a>),
+    Sync,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Poll {
+    /// The `Flags` field, which controls when nodes send replies and diagnostics.
+    pub flags: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dmx<'a> {
+    /// The sequence number, `0` if the sender doesn't use sequence numbers.
+    pub sequence: u8,
+    /// The physical input port of the sender, informational only.
+    pub physical: u8,
+    /// The 15-bit port address, made of `Net`, `Sub-Net` and `Universe`.
+    pub port_address: u16,
+    /// The channel data, `data[0]` is DMX address one.
+    pub data: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        let header = data.get(..12).ok_or(ParseError::Truncated)?;
+        if header[..8] != ID {
+            return Err(ParseError::NotArtNet);
+        }
+
+        let op_code = u16::from_le_bytes([header[8], header[9]]);
+        if !matches!(op_code, OP_POLL | OP_DMX | OP_SYNC) {
+            return Err(ParseError::UnsupportedOpCode(op_code));
+        }
+        let version = u16::from_be_bytes([header[10], header[11]]);
+        if version < PROTOCOL_VERSION {
+            return Err(ParseError::UnsupportedVersion(version));
+        }
+
+        match op_code {
+            OP_POLL => {
+                // Art-Net 3 controllers don't send the fields after `Flags`.
+                let flags = *data.get(12).ok_or(ParseError::Truncated)?;
+                Ok(Packet::Poll(Poll { flags }))
+            }
+            OP_DMX => Dmx::parse(data).map(Packet::Dmx),
+            _ => Ok(Packet::Sync),
+        }
+    }
+}
+
+impl<'a> Dmx<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        let header = data.get(..18).ok_or(ParseError::Truncated)?;
+
+        let length = u16::from_be_bytes([header[16], header[17]]);
+        if !(2..=512).contains(&length) {
+            return Err(ParseError::InvalidLength(length));
+        }
+        let channels = data
+            .get(18..18 + length as usize)
+            .ok_or(ParseError::Truncated)?;
+
+        Ok(Dmx {
+            sequence: header[12],
+            physical: header[13],
+            port_address: u16::from_le_bytes([header[14], header[15] & 0x7f]),
+            data: channels,
+        })
+    }
+}
+
+/// The description of this node sent in reply to an ArtPoll.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollReply<'a> {
+    pub ip: Ipv4Addr,
+    pub mac: [u8; 6],
+    /// The firmware version.
+    pub version: u16,
+    /// At most 17 characters, longer names are truncated.
+    pub short_name: &'a str,
+    /// At most 63 characters, longer names are truncated.
+    pub long_name: &'a str,
+    /// The status of the node, e.g. `#0001 [0042] OK`.
+    pub node_report: &'a str,
+    /// The output ports, at most [`PORTS_PER_REPLY`] that share the same `Net` and
+    /// `Sub-Net`.
+    pub port_addresses: &'a [u16],
+    /// The index of this reply, starting at one, if the node sends several replies.
+    pub bind_index: u8,
+}
+
+impl PollReply<'_> {
+    pub fn encode(&self) -> [u8; POLL_REPLY_LEN] {
+        let mut p = [0; POLL_REPLY_LEN];
+        let ports = &self.port_addresses[..self.port_addresses.len().min(PORTS_PER_REPLY)];
+        let first = ports.first().copied().unwrap_or(0);
+
+        p[..8].copy_from_slice(&ID);
+        p[8..10].copy_from_slice(&OP_POLL_REPLY.to_le_bytes());
+        p[10..14].copy_from_slice(&self.ip.octets());
+        p[14..16].copy_from_slice(&PORT.to_le_bytes());
+        p[16..18].copy_from_slice(&self.version.to_be_bytes());
+        p[18] = (first >> 8) as u8 & 0x7f;
+        p[19] = (first >> 4) as u8 & 0x0f;
+        p[20..22].copy_from_slice(&OEM_UNKNOWN.to_be_bytes());
+        p[23] = STATUS1;
+        p[24..26].copy_from_slice(&ESTA_PROTOTYPING.to_le_bytes());
+        write_str(&mut p[26..26 + SHORT_NAME_LEN], self.short_name);
+        write_str(&mut p[44..44 + LONG_NAME_LEN], self.long_name);
+        write_str(&mut p[108..108 + NODE_REPORT_LEN], self.node_report);
+        p[172..174].copy_from_slice(&(ports.len() as u16).to_be_bytes());
+        for (i, port_address) in ports.iter().enumerate() {
+            p[174 + i] = PORT_TYPE_OUTPUT_DMX;
+            p[182 + i] = GOOD_OUTPUT_DATA;
+            p[190 + i] = *port_address as u8 & 0x0f;
+            p[213 + i] = GOOD_OUTPUT_B_CONTINUOUS;
+        }
+        p[200] = STYLE_NODE;
+        p[201..207].copy_from_slice(&self.mac);
+        p[207..211].copy_from_slice(&self.ip.octets());
+        p[211] = self.bind_index;
+        p[212] = STATUS2;
+
+        p
+    }
+}
+
+/// Write `s` as a null terminated string, truncated to fit into `buf`.
+fn write_str(buf: &mut [u8], s: &str) {
+    let len = s.len().min(buf.len() - 1);
+    buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+}
+
+/// Split the port addresses into the groups of one ArtPollReply each.
+///
+/// The ports of a reply share the upper 11 bits of their port address (`Net` and
+/// `Sub-Net`), and there are at most [`PORTS_PER_REPLY`] of them.
+pub fn reply_groups(port_addresses: &[u16]) -> Vec<Vec<u16>> {
+    let mut groups: Vec<Vec<u16>> = Vec::new();
+
+    for &address in port_addresses {
+        let group = groups
+            .iter_mut()
+            .find(|g| g.len() < PORTS_PER_REPLY && g[0] >> 4 == address >> 4);
+        match group {
+            Some(group) => group.push(address),
+            None => groups.push(vec![address]),
+        }
+    }
+
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(op_code: u16) -> Vec<u8> {
+        let mut p = Vec::new();
+        p.extend(ID);
+        p.extend(op_code.to_le_bytes());
+        p.extend(PROTOCOL_VERSION.to_be_bytes());
+        p
+    }
+
+    fn dmx_packet(port_address: u16, sequence: u8, data: &[u8]) -> Vec<u8> {
+        let mut p = header(OP_DMX);
+        p.push(sequence);
+        p.push(0);
+        p.extend(port_address.to_le_bytes());
+        p.extend((data.len() as u16).to_be_bytes());
+        p.extend(data);
+        p
+    }
+
+    #[test]
+    fn parse_dmx() {
+        let bytes = dmx_packet(0x1234, 7, &[1, 2, 3, 4]);
+        assert_eq!(
+            Packet::parse(&bytes),
+            Ok(Packet::Dmx(Dmx {
+                sequence: 7,
+                physical: 0,
+                port_address: 0x1234,
+                data: &[1, 2, 3, 4],
+            }))
+        );
+
+        let data = [0xab; 512];
+        match Packet::parse(&dmx_packet(0, 0, &data)).unwrap() {
+            Packet::Dmx(dmx) => assert_eq!(dmx.data, &data[..]),
+            packet => panic!("unexpected packet {packet:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_poll_and_sync() {
+        let mut poll = header(OP_POLL);
+        poll.extend([0b10, 0]);
+        assert_eq!(Packet::parse(&poll), Ok(Packet::Poll(Poll { flags: 0b10 })));
+
+        let mut sync = header(OP_SYNC);
+        sync.extend([0, 0]);
+        assert_eq!(Packet::parse(&sync), Ok(Packet::Sync));
+    }
+
+    #[test]
+    fn reject_invalid_packets() {
+        let bytes = dmx_packet(1, 0, &[1, 2, 3, 4]);
+
+        assert_eq!(Packet::parse(&bytes[..10]), Err(ParseError::Truncated));
+        assert_eq!(Packet::parse(&bytes[..20]), Err(ParseError::Truncated));
+
+        let mut other = bytes.clone();
+        other[0] = b'a';
+        assert_eq!(Packet::parse(&other), Err(ParseError::NotArtNet));
+
+        let mut other = bytes.clone();
+        other[11] = 13;
+        assert_eq!(
+            Packet::parse(&other),
+            Err(ParseError::UnsupportedVersion(13))
+        );
+
+        assert_eq!(
+            Packet::parse(&header(OP_POLL_REPLY)),
+            Err(ParseError::UnsupportedOpCode(OP_POLL_REPLY))
+        );
+
+        let mut other = bytes;
+        other[17] = 1;
+        assert_eq!(Packet::parse(&other), Err(ParseError::InvalidLength(1)));
+    }
+
+    #[test]
+    fn encode_poll_reply() {
+        let reply = PollReply {
+            ip: Ipv4Addr::new(192, 168, 1, 20),
+            mac: [0x24, 0x0a, 0xc4, 1, 2, 3],
+            version: 0x0102,
+            short_name: "esp32-hue",
+            long_name: "a long name that is longer than the sixty-four bytes of the field",
+            node_report: "#0001 [0001] OK",
+            port_addresses: &[0x0123, 0x0124],
+            bind_index: 1,
+        };
+        let p = reply.encode();
+
+        assert_eq!(&p[..8], b"Art-Net\0");
+        assert_eq!(&p[8..10], &[0x00, 0x21]);
+        assert_eq!(&p[10..14], &[192, 168, 1, 20]);
+        assert_eq!(&p[14..16], &[0x36, 0x19]);
+        assert_eq!(&p[16..18], &[1, 2]);
+        // Net and Sub-Net
+        assert_eq!((p[18], p[19]), (0x01, 0x02));
+        assert_eq!(&p[26..36], b"esp32-hue\0");
+        assert_eq!(p[44 + 62], b'e');
+        assert_eq!(p[44 + 63], 0);
+        assert_eq!(&p[108..124], b"#0001 [0001] OK\0");
+        assert_eq!(&p[172..174], &[0, 2]);
+        assert_eq!(&p[174..178], &[0x80, 0x80, 0, 0]);
+        assert_eq!(&p[190..194], &[3, 4, 0, 0]);
+        assert_eq!(&p[201..207], &[0x24, 0x0a, 0xc4, 1, 2, 3]);
+        assert_eq!(&p[207..211], &[192, 168, 1, 20]);
+        assert_eq!(p[211], 1);
+    }
+
+    #[test]
+    fn group_ports_into_replies() {
+        assert_eq!(
+            reply_groups(&[0, 1, 2, 3, 4, 5, 0x10, 0x100]),
+            vec![vec![0, 1, 2, 3], vec![4, 5], vec![0x10], vec![0x100]]
+        );
+        assert!(reply_groups(&[]).is_empty());
+    }
+}
@@ -0,0 +1,210 @@
+//! A DDP (Distributed Display Protocol) receiver.
+//!
+//! DDP addresses the whole strip as one byte array, so a frame may be sent in any amount
+//! of packets with arbitrary offsets. The data is written into the staging buffer of the
+//! [`frame`] and only shown once a packet with the push flag arrives, which senders set
+//! on the last packet of a frame. Status and configuration queries are answered with
+//! JSON replies.
+
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use serde_json::json;
+
+use self::packet::{Packet, ID_ALL, ID_CONFIG, ID_DISPLAY, ID_STATUS, PORT};
+use crate::light::frame::{self, Pixel};
+use crate::utils::net;
+use crate::utils::thread::{self, Core, SpawnError, ThreadConfig};
+
+pub mod packet;
+
+/// The name of this input in the [`frame`] buffer.
+pub const SOURCE_NAME: &str = "ddp";
+
+#[derive(Debug, thiserror::Error)]
+pub enum StartError {
+    #[error("failed to bind ddp socket")]
+    Bind(#[source] io::Error),
+    #[error("failed to spawn ddp thread")]
+    Thread(#[from] SpawnError),
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The time after which the light state is shown again once no frame is pushed.
+    pub timeout: Duration,
+    /// The thread the receiver runs on.
+    pub thread: ThreadConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            timeout: Duration::from_millis(2500),
+            thread: ThreadConfig {
+                name: "ddp",
+                core: Some(Core::Core0),
+                priority: 8,
+                stack_size: 6 * 1024,
+            },
+        }
+    }
+}
+
+/// Start receiving DDP frames.
+pub fn start(config: Config) -> Result<(), StartError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PORT)).map_err(StartError::Bind)?;
+
+    let thread_config = config.thread.clone();
+    thread::spawn(&thread_config, move || run(socket, config))?;
+
+    Ok(())
+}
+
+fn run(socket: UdpSocket, config: Config) {
+    // Senders split frames into packets that fit into an ethernet frame, e.g. xLights
+    // sends at most 1440 bytes of data.
+    let mut buf = [0; 1500];
+
+    loop {
+        match socket.recv_from(&mut buf) {
+            Ok((len, src)) => handle(&socket, &config, &buf[..len], src),
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(err) => {
+                log::error!("failed to receive ddp packet: {}", err);
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+fn handle(socket: &UdpSocket, config: &Config, data: &[u8], src: SocketAddr) {
+    let packet = match Packet::parse(data) {
+        Ok(packet) => packet,
+        Err(err) => {
+            log::debug!("invalid ddp packet from {}: {}", src, err);
+            return;
+        }
+    };
+
+    if packet.reply {
+        return;
+    }
+    if packet.query {
+        reply_to_query(socket, packet.id, src);
+        return;
+    }
+    if !matches!(packet.id, ID_DISPLAY | ID_ALL) {
+        return;
+    }
+
+    let stride = packet::bytes_per_pixel(packet.data_type);
+    frame::write(SOURCE_NAME, |pixels| {
+        write_pixels(pixels, packet.offset as usize, packet.data, stride)
+    });
+
+    if packet.push {
+        frame::present(SOURCE_NAME, config.timeout);
+    }
+}
+
+/// Write `data`, which starts at byte `offset` of the frame, into `pixels`.
+///
+/// Channels after the third of a pixel (e.g. white) are ignored, as is data past the
+/// end of the strip. The offset comes from the client and may be anything, so only
+/// positions within the strip are computed.
+fn write_pixels(pixels: &mut [Pixel], offset: usize, data: &[u8], stride: usize) {
+    let len = pixels.len().saturating_mul(stride);
+    if offset >= len {
+        return;
+    }
+
+    for (position, &value) in (offset..len).zip(data) {
+        let pixel = &mut pixels[position / stride];
+        match position % stride {
+            0 => pixel.red = value,
+            1 => pixel.green = value,
+            2 => pixel.blue = value,
+            _ => {}
+        }
+    }
+}
+
+fn reply_to_query(socket: &UdpSocket, id: u8, src: SocketAddr) {
+    let json = match id {
+        ID_STATUS => {
+            let mac = net::station_mac()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(":");
+            json!({
+                "status": {
+                    "man": "esp32-hue",
+                    "mod": "esp32-hue",
+                    "ver": env!("CARGO_PKG_VERSION"),
+                    "mac": mac,
+                }
+            })
+        }
+        ID_CONFIG => {
+            let ip = net::station_ip().unwrap_or(Ipv4Addr::UNSPECIFIED);
+            json!({
+                "config": {
+                    "ip": ip.to_string(),
+                    "ports": [{ "port": 0, "ts": 0, "l": frame::len(), "ss": 0 }],
+                }
+            })
+        }
+        _ => return,
+    };
+
+    let reply = packet::reply(id, json.to_string().as_bytes());
+    if let Err(err) = socket.send_to(&reply, src) {
+        log::warn!("failed to send ddp reply to {}: {}", src, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_at_offsets() {
+        let mut pixels = vec![Pixel::new(0, 0, 0); 4];
+
+        // A packet that ends in the middle of a pixel, continued by the next one.
+        write_pixels(&mut pixels, 0, &[1, 2, 3, 4], 3);
+        write_pixels(&mut pixels, 4, &[5, 6, 7, 8, 9, 10, 11, 12, 13, 14], 3);
+        assert_eq!(
+            pixels,
+            [
+                Pixel::new(1, 2, 3),
+                Pixel::new(4, 5, 6),
+                Pixel::new(7, 8, 9),
+                Pixel::new(10, 11, 12),
+            ]
+        );
+
+        // The white channel is ignored.
+        write_pixels(&mut pixels, 4, &[20, 21, 22, 23], 4);
+        assert_eq!(pixels[1], Pixel::new(20, 21, 22));
+        assert_eq!(pixels[2], Pixel::new(7, 8, 9));
+    }
+
+    #[test]
+    fn ignore_offsets_past_the_strip() {
+        let mut pixels = vec![Pixel::new(0, 0, 0); 4];
+
+        // Offsets near the maximum would wrap around to the start of the strip.
+        write_pixels(&mut pixels, u32::MAX as usize, &[1, 2, 3, 4, 5, 6], 3);
+        write_pixels(&mut pixels, usize::MAX, &[1, 2, 3], 3);
+        write_pixels(&mut pixels, 12, &[1, 2, 3], 3);
+        assert_eq!(pixels, [Pixel::new(0, 0, 0); 4]);
+
+        // Data past the end of the strip is cut off.
+        write_pixels(&mut pixels, 9, &[1, 2, 3, 4, 5, 6], 3);
+        assert_eq!(pixels[3], Pixel::new(1, 2, 3));
+    }
+}
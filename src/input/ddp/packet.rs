@@ -0,0 +1,187 @@
+//! Parsing and building of DDP (Distributed Display Protocol) packets.
+
+/// The UDP port of DDP.
+pub const PORT: u16 = 4048;
+
+const VERSION_MASK: u8 = 0b1100_0000;
+const VERSION_1: u8 = 0b0100_0000;
+const FLAG_TIMECODE: u8 = 1 << 4;
+const FLAG_STORAGE: u8 = 1 << 3;
+const FLAG_REPLY: u8 = 1 << 2;
+const FLAG_QUERY: u8 = 1 << 1;
+const FLAG_PUSH: u8 = 1 << 0;
+
+/// The destination of the pixel data of the default output device.
+pub const ID_DISPLAY: u8 = 1;
+/// The destination of JSON control messages.
+pub const ID_CONTROL: u8 = 246;
+/// The destination of the JSON configuration.
+pub const ID_CONFIG: u8 = 250;
+/// The destination of the JSON status.
+pub const ID_STATUS: u8 = 251;
+/// The destination that addresses all devices.
+pub const ID_ALL: u8 = 255;
+
+const DATA_TYPE_RGBW: u8 = 3;
+const DATA_SIZE_8BIT: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ParseError {
+    #[error("packet is too short")]
+    Truncated,
+    #[error("unsupported protocol version {0}")]
+    UnsupportedVersion(u8),
+}
+
+/// A parsed DDP packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packet<'a> {
+    /// The sequence number from 1 to 15, `0` if the sender doesn't use them.
+    pub sequence: u8,
+    /// The data should be shown, set on the last packet of a frame.
+    pub push: bool,
+    /// The sender asks for the data at the destination instead of sending data.
+    pub query: bool,
+    /// The packet is a reply of another device.
+    pub reply: bool,
+    /// The data should be stored, not supported.
+    pub storage: bool,
+    /// The pixel format of the data, see [`bytes_per_pixel`].
+    pub data_type: u8,
+    /// The destination, e.g. [`ID_DISPLAY`].
+    pub id: u8,
+    /// The offset of `data` into the frame, in bytes.
+    pub offset: u32,
+    pub data: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        let header = data.get(..10).ok_or(ParseError::Truncated)?;
+        let flags = header[0];
+        if flags & VERSION_MASK != VERSION_1 {
+            return Err(ParseError::UnsupportedVersion((flags & VERSION_MASK) >> 6));
+        }
+
+        // The timecode is ignored, data is shown as it arrives.
+        let header_len = match flags & FLAG_TIMECODE {
+            0 => 10,
+            _ => 14,
+        };
+        let length = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let payload = data
+            .get(header_len..header_len + length)
+            .ok_or(ParseError::Truncated)?;
+
+        Ok(Packet {
+            sequence: header[1] & 0x0f,
+            push: flags & FLAG_PUSH != 0,
+            query: flags & FLAG_QUERY != 0,
+            reply: flags & FLAG_REPLY != 0,
+            storage: flags & FLAG_STORAGE != 0,
+            data_type: header[2],
+            id: header[3],
+            offset: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+            data: payload,
+        })
+    }
+}
+
+/// The amount of bytes per pixel of a data type.
+///
+/// Only 8-bit RGB and RGBW are distinguished, many senders leave the data type
+/// undefined and send RGB.
+pub fn bytes_per_pixel(data_type: u8) -> usize {
+    let kind = (data_type >> 3) & 0b111;
+    let size = data_type & 0b111;
+    match (kind, size) {
+        (DATA_TYPE_RGBW, DATA_SIZE_8BIT) => 4,
+        _ => 3,
+    }
+}
+
+/// Build the reply to a query of destination `id`, the data is JSON.
+pub fn reply(id: u8, json: &[u8]) -> Vec<u8> {
+    let mut p = Vec::with_capacity(10 + json.len());
+    p.push(VERSION_1 | FLAG_REPLY | FLAG_PUSH);
+    p.push(0);
+    p.push(0);
+    p.push(id);
+    p.extend(0_u32.to_be_bytes());
+    p.extend((json.len() as u16).to_be_bytes());
+    p.extend(json);
+    p
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_packet(flags: u8, offset: u32, data: &[u8]) -> Vec<u8> {
+        let mut p = vec![VERSION_1 | flags, 3, 0x0b, ID_DISPLAY];
+        p.extend(offset.to_be_bytes());
+        p.extend((data.len() as u16).to_be_bytes());
+        p.extend(data);
+        p
+    }
+
+    #[test]
+    fn parse_data() {
+        let bytes = data_packet(FLAG_PUSH, 1440, &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(
+            Packet::parse(&bytes),
+            Ok(Packet {
+                sequence: 3,
+                push: true,
+                query: false,
+                reply: false,
+                storage: false,
+                data_type: 0x0b,
+                id: ID_DISPLAY,
+                offset: 1440,
+                data: &[1, 2, 3, 4, 5, 6],
+            })
+        );
+        assert_eq!(bytes_per_pixel(0x0b), 3);
+        assert_eq!(bytes_per_pixel(0x1b), 4);
+        assert_eq!(bytes_per_pixel(0), 3);
+    }
+
+    #[test]
+    fn parse_timecode_and_query() {
+        let mut bytes = vec![VERSION_1 | FLAG_TIMECODE | FLAG_QUERY, 0, 0, ID_STATUS];
+        bytes.extend(0_u32.to_be_bytes());
+        bytes.extend(0_u16.to_be_bytes());
+        bytes.extend([0, 0, 0, 42]);
+
+        let packet = Packet::parse(&bytes).unwrap();
+        assert!(packet.query);
+        assert!(!packet.push);
+        assert_eq!(packet.id, ID_STATUS);
+        assert!(packet.data.is_empty());
+    }
+
+    #[test]
+    fn reject_invalid_packets() {
+        let bytes = data_packet(0, 0, &[1, 2, 3]);
+        assert_eq!(Packet::parse(&bytes[..9]), Err(ParseError::Truncated));
+        assert_eq!(Packet::parse(&bytes[..12]), Err(ParseError::Truncated));
+
+        let mut other = bytes;
+        other[0] = 0x80;
+        assert_eq!(
+            Packet::parse(&other),
+            Err(ParseError::UnsupportedVersion(2))
+        );
+    }
+
+    #[test]
+    fn build_reply() {
+        let bytes = reply(ID_STATUS, b"{}");
+        let packet = Packet::parse(&bytes).unwrap();
+        assert!(packet.reply);
+        assert!(packet.push);
+        assert_eq!(packet.id, ID_STATUS);
+        assert_eq!(packet.data, b"{}");
+    }
+}
@@ -0,0 +1,193 @@
+//! An E1.31 (sACN) receiver.
+//!
+//! The configured universes are received by unicast and by multicast and mapped onto
+//! the strip with a [`UniverseMap`]. Data is shown directly or, if the source requests
+//! it, once the synchronization packet of its sync universe arrives.
+
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use self::packet::{Packet, PORT, START_CODE_DMX};
+use self::sources::Sources;
+use super::universe::UniverseMap;
+use crate::light::frame;
+use crate::utils::thread::{self, Core, SpawnError, ThreadConfig};
+
+pub mod packet;
+pub mod sources;
+
+/// The name of this input in the [`frame`] buffer.
+pub const SOURCE_NAME: &str = "e131";
+
+#[derive(Debug, thiserror::Error)]
+pub enum StartError {
+    #[error("failed to bind e1.31 socket")]
+    Bind(#[source] io::Error),
+    #[error("failed to join the multicast group of universe {0}")]
+    Multicast(u16, #[source] io::Error),
+    #[error("failed to spawn e1.31 thread")]
+    Thread(#[from] SpawnError),
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub universes: UniverseMap,
+    /// The time after which a source that stopped sending is dropped.
+    pub source_timeout: Duration,
+    /// The thread the receiver runs on.
+    pub thread: ThreadConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            universes: UniverseMap::default(),
+            source_timeout: sources::DEFAULT_TIMEOUT,
+            thread: ThreadConfig {
+                name: "e131",
+                core: Some(Core::Core0),
+                priority: 8,
+                stack_size: 6 * 1024,
+            },
+        }
+    }
+}
+
+/// Start receiving the configured universes.
+pub fn start(config: Config) -> Result<(), StartError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PORT)).map_err(StartError::Bind)?;
+    for universe in config.universes.universes() {
+        join_universe(&socket, universe).map_err(|err| StartError::Multicast(universe, err))?;
+    }
+    // Wake up regularly to notice sources that timed out.
+    socket
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .map_err(StartError::Bind)?;
+
+    let thread_config = config.thread.clone();
+    thread::spawn(&thread_config, move || {
+        Receiver {
+            sources: Sources::new(config.source_timeout),
+            config,
+            sync_joined: Vec::new(),
+            pending_sync: None,
+            active: false,
+        }
+        .run(socket)
+    })?;
+
+    Ok(())
+}
+
+/// The multicast group of a universe.
+fn multicast_addr(universe: u16) -> Ipv4Addr {
+    let [high, low] = universe.to_be_bytes();
+    Ipv4Addr::new(239, 255, high, low)
+}
+
+fn join_universe(socket: &UdpSocket, universe: u16) -> io::Result<()> {
+    socket.join_multicast_v4(&multicast_addr(universe), &Ipv4Addr::UNSPECIFIED)
+}
+
+struct Receiver {
+    config: Config,
+    sources: Sources,
+    /// The sync universes whose multicast group was joined.
+    sync_joined: Vec<u16>,
+    /// The sync universe of staged data that wasn't presented yet.
+    pending_sync: Option<u16>,
+    /// Whether a frame of this input is shown.
+    active: bool,
+}
+
+impl Receiver {
+    fn run(mut self, socket: UdpSocket) {
+        // The largest E1.31 packet is a data packet with 512 slots.
+        let mut buf = [0; 638];
+
+        loop {
+            match socket.recv(&mut buf) {
+                Ok(len) => self.handle(&socket, &buf[..len]),
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(err) => {
+                    log::error!("failed to receive e1.31 packet: {}", err);
+                    std::thread::sleep(Duration::from_secs(1));
+                }
+            }
+
+            self.sources.expire(Instant::now());
+            self.release_if_idle();
+        }
+    }
+
+    fn handle(&mut self, socket: &UdpSocket, data: &[u8]) {
+        let packet = match Packet::parse(data) {
+            Ok(packet) => packet,
+            Err(err) => {
+                log::debug!("invalid e1.31 packet: {}", err);
+                return;
+            }
+        };
+
+        match packet {
+            Packet::Data(packet) => {
+                if packet.start_code != START_CODE_DMX
+                    || !self.config.universes.contains(packet.universe)
+                {
+                    return;
+                }
+                if !self.sources.accept(&packet, Instant::now()) {
+                    self.release_if_idle();
+                    return;
+                }
+
+                frame::write(SOURCE_NAME, |pixels| {
+                    self.config
+                        .universes
+                        .apply(packet.universe, packet.slots, pixels)
+                });
+
+                match packet.sync_address {
+                    0 => self.present(),
+                    sync_address => {
+                        self.join_sync_universe(socket, sync_address);
+                        self.pending_sync = Some(sync_address);
+                    }
+                }
+            }
+            Packet::Sync(packet) => {
+                if self.pending_sync == Some(packet.sync_address) {
+                    self.present();
+                }
+            }
+        }
+    }
+
+    fn present(&mut self) {
+        frame::present(SOURCE_NAME, self.config.source_timeout);
+        self.pending_sync = None;
+        self.active = true;
+    }
+
+    /// Fall back to the light state once all sources stopped sending.
+    fn release_if_idle(&mut self) {
+        if self.active && self.sources.is_empty() {
+            frame::release(SOURCE_NAME);
+            self.active = false;
+            self.pending_sync = None;
+        }
+    }
+
+    /// Sync packets are sent to the multicast group of the sync universe.
+    fn join_sync_universe(&mut self, socket: &UdpSocket, universe: u16) {
+        if self.sync_joined.contains(&universe) || self.config.universes.contains(universe) {
+            return;
+        }
+
+        if let Err(err) = join_universe(socket, universe) {
+            log::warn!("failed to join sync universe {}: {}", universe, err);
+        }
+        self.sync_joined.push(universe);
+    }
+}
@@ -0,0 +1,335 @@
+//! Parsing of E1.31 (ANSI E1.31-2018) data and synchronization packets.
+
+/// The UDP port of E1.31.
+pub const PORT: u16 = 5568;
+
+const ACN_PACKET_IDENTIFIER: [u8; 12] = *b"ASC-E1.17\0\0\0";
+const VECTOR_ROOT_E131_DATA: u32 = 0x0000_0004;
+const VECTOR_ROOT_E131_EXTENDED: u32 = 0x0000_0008;
+const VECTOR_E131_DATA_PACKET: u32 = 0x0000_0002;
+const VECTOR_E131_EXTENDED_SYNCHRONIZATION: u32 = 0x0000_0001;
+const VECTOR_DMP_SET_PROPERTY: u8 = 0x02;
+
+const OPTION_PREVIEW_DATA: u8 = 1 << 7;
+const OPTION_STREAM_TERMINATED: u8 = 1 << 6;
+
+/// The start code of DMX512 level data.
+pub const START_CODE_DMX: u8 = 0x00;
+
+/// The highest priority of a source.
+pub const MAX_PRIORITY: u8 = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ParseError {
+    #[error("packet is too short")]
+    Truncated,
+    #[error("not an E1.31 packet")]
+    NotE131,
+    #[error("unsupported vector {0:#x}")]
+    UnsupportedVector(u32),
+    #[error("invalid {0} layer")]
+    InvalidLayer(&'static str),
+}
+
+/// A parsed E1.31 packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Packet<'a> {
+    Data(DataPacket<'a>),
+    Sync(SyncPacket),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataPacket<'a> {
+    /// The component identifier of the source.
+    pub cid: [u8; 16],
+    /// The user-assigned name of the source.
+    pub source_name: &'a str,
+    /// The priority of the source, from 0 to [`MAX_PRIORITY`].
+    pub priority: u8,
+    /// The universe the data should be synchronized with, `0` if it isn't synchronized.
+    pub sync_address: u16,
+    pub sequence: u8,
+    /// The data is meant for visualizers and must not be shown on the strip.
+    pub preview: bool,
+    /// The source stopped sending this universe.
+    pub stream_terminated: bool,
+    pub universe: u16,
+    pub start_code: u8,
+    /// The slots after the start code, `slots[0]` is DMX address one.
+    pub slots: &'a [u8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncPacket {
+    pub cid: [u8; 16],
+    pub sequence: u8,
+    /// The universe the synchronized data packets refer to.
+    pub sync_address: u16,
+}
+
+impl<'a> Packet<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        // Root layer
+        let root = data.get(..38).ok_or(ParseError::Truncated)?;
+        if u16_at(root, 0) != 0x0010 || u16_at(root, 2) != 0 || root[4..16] != ACN_PACKET_IDENTIFIER
+        {
+            return Err(ParseError::NotE131);
+        }
+        let root_len = pdu_len(root, 16);
+        if root_len > data.len() - 16 {
+            return Err(ParseError::Truncated);
+        }
+        let data = &data[..16 + root_len];
+
+        let mut cid = [0; 16];
+        cid.copy_from_slice(&root[22..38]);
+
+        match u32_at(root, 18) {
+            VECTOR_ROOT_E131_DATA => DataPacket::parse(cid, data).map(Packet::Data),
+            VECTOR_ROOT_E131_EXTENDED => SyncPacket::parse(cid, data).map(Packet::Sync),
+            vector => Err(ParseError::UnsupportedVector(vector)),
+        }
+    }
+}
+
+impl<'a> DataPacket<'a> {
+    fn parse(cid: [u8; 16], data: &'a [u8]) -> Result<Self, ParseError> {
+        // Framing layer
+        let framing = data.get(38..115).ok_or(ParseError::Truncated)?;
+        if pdu_len(data, 38) != data.len() - 38 {
+            return Err(ParseError::InvalidLayer("framing"));
+        }
+        match u32_at(framing, 2) {
+            VECTOR_E131_DATA_PACKET => (),
+            vector => return Err(ParseError::UnsupportedVector(vector)),
+        }
+
+        let source_name = &framing[6..70];
+        let name_len = source_name.iter().position(|&b| b == 0).unwrap_or(64);
+        let source_name = core::str::from_utf8(&source_name[..name_len]).unwrap_or("");
+        let options = framing[74];
+
+        // DMP layer
+        let dmp = data.get(115..126).ok_or(ParseError::Truncated)?;
+        if pdu_len(data, 115) != data.len() - 115
+            || dmp[2] != VECTOR_DMP_SET_PROPERTY
+            || dmp[3] != 0xa1
+            || u16_at(dmp, 4) != 0
+            || u16_at(dmp, 6) != 1
+        {
+            return Err(ParseError::InvalidLayer("dmp"));
+        }
+        // The property values include the start code.
+        let value_count = u16_at(dmp, 8) as usize;
+        if value_count == 0 || value_count > 513 || 125 + value_count > data.len() {
+            return Err(ParseError::InvalidLayer("dmp"));
+        }
+
+        Ok(DataPacket {
+            cid,
+            source_name,
+            priority: framing[70],
+            sync_address: u16_at(framing, 71),
+            sequence: framing[73],
+            preview: options & OPTION_PREVIEW_DATA != 0,
+            stream_terminated: options & OPTION_STREAM_TERMINATED != 0,
+            universe: u16_at(framing, 75),
+            start_code: dmp[10],
+            slots: &data[126..125 + value_count],
+        })
+    }
+}
+
+impl SyncPacket {
+    fn parse(cid: [u8; 16], data: &[u8]) -> Result<Self, ParseError> {
+        let framing = data.get(38..49).ok_or(ParseError::Truncated)?;
+        match u32_at(framing, 2) {
+            VECTOR_E131_EXTENDED_SYNCHRONIZATION => (),
+            vector => return Err(ParseError::UnsupportedVector(vector)),
+        }
+
+        Ok(SyncPacket {
+            cid,
+            sequence: framing[6],
+            sync_address: u16_at(framing, 7),
+        })
+    }
+}
+
+/// The length of the PDU at `offset`, the upper four bits are flags.
+fn pdu_len(data: &[u8], offset: usize) -> usize {
+    (u16_at(data, offset) & 0x0fff) as usize
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// Whether a packet with sequence number `new` is older than the last one, `last`.
+///
+/// Implements the sequence numbering of E1.31 section 6.7.2, which accepts packets that
+/// are more than 20 packets behind, e.g. because the source restarted.
+pub fn is_out_of_order(last: u8, new: u8) -> bool {
+    let diff = new.wrapping_sub(last) as i8;
+    diff <= 0 && diff > -20
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub const CID: [u8; 16] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+
+    /// Build a data packet as sent by sACN sources.
+    pub fn data_packet(
+        universe: u16,
+        priority: u8,
+        sequence: u8,
+        options: u8,
+        slots: &[u8],
+    ) -> Vec<u8> {
+        let mut p = Vec::new();
+        let flags_len = |len: usize| (0x7000 | len as u16).to_be_bytes();
+
+        // Root layer
+        p.extend(0x0010_u16.to_be_bytes());
+        p.extend(0_u16.to_be_bytes());
+        p.extend(ACN_PACKET_IDENTIFIER);
+        p.extend(flags_len(110 + slots.len()));
+        p.extend(VECTOR_ROOT_E131_DATA.to_be_bytes());
+        p.extend(CID);
+        // Framing layer
+        p.extend(flags_len(88 + slots.len()));
+        p.extend(VECTOR_E131_DATA_PACKET.to_be_bytes());
+        let mut name = [0; 64];
+        name[..7].copy_from_slice(b"xLights");
+        p.extend(name);
+        p.push(priority);
+        p.extend(0_u16.to_be_bytes());
+        p.push(sequence);
+        p.push(options);
+        p.extend(universe.to_be_bytes());
+        // DMP layer
+        p.extend(flags_len(11 + slots.len()));
+        p.push(VECTOR_DMP_SET_PROPERTY);
+        p.push(0xa1);
+        p.extend(0_u16.to_be_bytes());
+        p.extend(1_u16.to_be_bytes());
+        p.extend((slots.len() as u16 + 1).to_be_bytes());
+        p.push(START_CODE_DMX);
+        p.extend(slots);
+
+        p
+    }
+
+    #[test]
+    fn parse_data_packet() {
+        let bytes = data_packet(7, 150, 42, 0, &[1, 2, 3, 4]);
+        let packet = match Packet::parse(&bytes).unwrap() {
+            Packet::Data(packet) => packet,
+            packet => panic!("unexpected packet {packet:?}"),
+        };
+
+        assert_eq!(packet.cid, CID);
+        assert_eq!(packet.source_name, "xLights");
+        assert_eq!(packet.priority, 150);
+        assert_eq!(packet.sync_address, 0);
+        assert_eq!(packet.sequence, 42);
+        assert!(!packet.preview);
+        assert!(!packet.stream_terminated);
+        assert_eq!(packet.universe, 7);
+        assert_eq!(packet.start_code, START_CODE_DMX);
+        assert_eq!(packet.slots, &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn parse_options() {
+        let bytes = data_packet(1, 100, 0, OPTION_PREVIEW_DATA | OPTION_STREAM_TERMINATED, &[]);
+        let packet = match Packet::parse(&bytes).unwrap() {
+            Packet::Data(packet) => packet,
+            packet => panic!("unexpected packet {packet:?}"),
+        };
+
+        assert!(packet.preview);
+        assert!(packet.stream_terminated);
+        assert!(packet.slots.is_empty());
+    }
+
+    #[test]
+    fn parse_full_universe() {
+        let slots = [0xab; 512];
+        let bytes = data_packet(1, 100, 0, 0, &slots);
+        assert_eq!(bytes.len(), 638);
+
+        match Packet::parse(&bytes).unwrap() {
+            Packet::Data(packet) => assert_eq!(packet.slots, &slots[..]),
+            packet => panic!("unexpected packet {packet:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_sync_packet() {
+        let mut bytes = Vec::new();
+        bytes.extend(0x0010_u16.to_be_bytes());
+        bytes.extend(0_u16.to_be_bytes());
+        bytes.extend(ACN_PACKET_IDENTIFIER);
+        bytes.extend(0x7021_u16.to_be_bytes());
+        bytes.extend(VECTOR_ROOT_E131_EXTENDED.to_be_bytes());
+        bytes.extend(CID);
+        bytes.extend(0x700b_u16.to_be_bytes());
+        bytes.extend(VECTOR_E131_EXTENDED_SYNCHRONIZATION.to_be_bytes());
+        bytes.push(9);
+        bytes.extend(1000_u16.to_be_bytes());
+        bytes.extend([0, 0]);
+
+        assert_eq!(
+            Packet::parse(&bytes),
+            Ok(Packet::Sync(SyncPacket {
+                cid: CID,
+                sequence: 9,
+                sync_address: 1000,
+            }))
+        );
+    }
+
+    #[test]
+    fn reject_invalid_packets() {
+        let bytes = data_packet(1, 100, 0, 0, &[1, 2, 3]);
+
+        assert_eq!(Packet::parse(&bytes[..20]), Err(ParseError::Truncated));
+        assert_eq!(Packet::parse(&bytes[..120]), Err(ParseError::Truncated));
+
+        let mut other = bytes.clone();
+        other[4] = b'X';
+        assert_eq!(Packet::parse(&other), Err(ParseError::NotE131));
+
+        let mut other = bytes.clone();
+        other[21] = 0x05;
+        assert_eq!(Packet::parse(&other), Err(ParseError::UnsupportedVector(5)));
+
+        // Property value count larger than the packet.
+        let mut other = bytes;
+        other[124] = 0xff;
+        assert_eq!(Packet::parse(&other), Err(ParseError::InvalidLayer("dmp")));
+    }
+
+    #[test]
+    fn sequence_numbers() {
+        assert!(!is_out_of_order(10, 11));
+        assert!(is_out_of_order(10, 10));
+        assert!(is_out_of_order(10, 9));
+        assert!(!is_out_of_order(255, 0));
+        assert!(is_out_of_order(0, 255));
+        // A source that restarted is accepted again.
+        assert!(!is_out_of_order(100, 50));
+    }
+}
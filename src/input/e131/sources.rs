@@ -0,0 +1,163 @@
+//! Arbitration between multiple sources sending the same universe.
+//!
+//! Only the data of the sources with the highest priority of a universe is shown. If
+//! several sources share the highest priority, the last packet wins. A source is dropped
+//! once it terminated its stream or didn't send a packet within the source timeout.
+
+use std::time::{Duration, Instant};
+
+use super::packet::{is_out_of_order, DataPacket};
+
+/// The network data loss timeout of E1.31 section 6.7.1.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(2500);
+
+#[derive(Debug, Clone)]
+struct Source {
+    cid: [u8; 16],
+    universe: u16,
+    priority: u8,
+    sequence: u8,
+    last_seen: Instant,
+}
+
+/// The sources of all universes.
+#[derive(Debug, Clone)]
+pub struct Sources {
+    sources: Vec<Source>,
+    timeout: Duration,
+}
+
+impl Sources {
+    pub fn new(timeout: Duration) -> Self {
+        Sources {
+            sources: Vec::new(),
+            timeout,
+        }
+    }
+
+    /// Track the source of `packet` and decide whether its data is shown.
+    pub fn accept(&mut self, packet: &DataPacket<'_>, now: Instant) -> bool {
+        if packet.preview {
+            return false;
+        }
+
+        let index = self
+            .sources
+            .iter()
+            .position(|s| s.cid == packet.cid && s.universe == packet.universe);
+
+        if let Some(index) = index {
+            if is_out_of_order(self.sources[index].sequence, packet.sequence) {
+                return false;
+            }
+        }
+
+        if packet.stream_terminated {
+            if let Some(index) = index {
+                self.sources.swap_remove(index);
+            }
+            return false;
+        }
+
+        let source = Source {
+            cid: packet.cid,
+            universe: packet.universe,
+            priority: packet.priority,
+            sequence: packet.sequence,
+            last_seen: now,
+        };
+        match index {
+            Some(index) => self.sources[index] = source,
+            None => self.sources.push(source),
+        }
+
+        let highest = self
+            .sources
+            .iter()
+            .filter(|s| s.universe == packet.universe && now - s.last_seen <= self.timeout)
+            .map(|s| s.priority)
+            .max()
+            .unwrap_or(0);
+        packet.priority >= highest
+    }
+
+    /// Drop the sources that timed out.
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        self.sources
+            .retain(|s| now.saturating_duration_since(s.last_seen) <= timeout);
+    }
+
+    /// Whether any source is sending.
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::packet::tests::{data_packet, CID};
+    use super::super::packet::Packet;
+    use super::*;
+
+    fn accept(sources: &mut Sources, bytes: &[u8], now: Instant) -> bool {
+        match Packet::parse(bytes).unwrap() {
+            Packet::Data(packet) => sources.accept(&packet, now),
+            packet => panic!("unexpected packet {packet:?}"),
+        }
+    }
+
+    fn with_cid(mut bytes: Vec<u8>, last_byte: u8) -> Vec<u8> {
+        bytes[22 + 15] = last_byte;
+        bytes
+    }
+
+    #[test]
+    fn sequence_and_termination() {
+        let mut sources = Sources::new(DEFAULT_TIMEOUT);
+        let now = Instant::now();
+
+        assert!(accept(&mut sources, &data_packet(1, 100, 5, 0, &[]), now));
+        assert!(accept(&mut sources, &data_packet(1, 100, 6, 0, &[]), now));
+        assert!(!accept(&mut sources, &data_packet(1, 100, 4, 0, &[]), now));
+
+        // Preview data is never shown.
+        assert!(!accept(&mut sources, &data_packet(1, 100, 7, 1 << 7, &[]), now));
+
+        assert!(!accept(&mut sources, &data_packet(1, 100, 8, 1 << 6, &[]), now));
+        assert!(sources.is_empty());
+    }
+
+    #[test]
+    fn highest_priority_wins() {
+        let mut sources = Sources::new(DEFAULT_TIMEOUT);
+        let now = Instant::now();
+
+        let low = |seq| with_cid(data_packet(1, 50, seq, 0, &[]), 1);
+        let high = |seq| with_cid(data_packet(1, 150, seq, 0, &[]), 2);
+        assert_ne!(low(0)[22..38], CID);
+
+        assert!(accept(&mut sources, &low(0), now));
+        assert!(accept(&mut sources, &high(0), now));
+        assert!(!accept(&mut sources, &low(1), now));
+
+        // Other universes are independent.
+        assert!(accept(&mut sources, &data_packet(2, 10, 0, 0, &[]), now));
+
+        // Once the high priority source timed out, the low priority source is shown.
+        let later = now + DEFAULT_TIMEOUT + Duration::from_millis(1);
+        assert!(accept(&mut sources, &low(2), later));
+    }
+
+    #[test]
+    fn expire_sources() {
+        let mut sources = Sources::new(DEFAULT_TIMEOUT);
+        let now = Instant::now();
+
+        assert!(accept(&mut sources, &data_packet(1, 100, 0, 0, &[]), now));
+        sources.expire(now + Duration::from_secs(1));
+        assert!(!sources.is_empty());
+        sources.expire(now + Duration::from_secs(3));
+        assert!(sources.is_empty());
+    }
+}
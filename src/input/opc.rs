@@ -0,0 +1,216 @@
+//! An Open Pixel Control server.
+//!
+//! The server runs on the shared network [`EXECUTOR`], every client connection is a task
+//! of it. As the executor has no I/O reactor, the sockets are non-blocking and polled
+//! while idle.
+//! Received frames go through the [`Output`] layer, which optionally interpolates
+//! between frames and applies a color correction, before they are shown on the strip.
+//! Clients can change both with the system-exclusive commands of Fadecandy.
+
+use std::io::{self, ErrorKind, Read};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use self::output::Output;
+use self::protocol::{ColorCorrection, Command, Message, CHANNEL_BROADCAST, CHANNEL_STRIP, PORT};
+use crate::light::frame;
+use crate::utils::executor::spawner::{ExecutorShutDown, Spawner};
+use crate::utils::net::EXECUTOR;
+use crate::utils::timer::MissedTickBehavior;
+
+pub mod output;
+pub mod protocol;
+
+/// The name of this input in the [`frame`] buffer.
+pub const SOURCE_NAME: &str = "opc";
+
+/// How often idle connections are checked for new data.
+const POLL_PERIOD: Duration = Duration::from_millis(5);
+/// How often the listener is checked for new connections.
+const ACCEPT_PERIOD: Duration = Duration::from_millis(100);
+
+#[derive(Debug, thiserror::Error)]
+pub enum StartError {
+    #[error("failed to bind opc socket")]
+    Bind(#[source] io::Error),
+    #[error("failed to spawn opc task")]
+    Task(#[from] ExecutorShutDown),
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Further connections are closed immediately.
+    pub max_clients: usize,
+    /// The color correction until a client sets another one.
+    pub color_correction: ColorCorrection,
+    /// Whether frames are interpolated until a client changes it.
+    pub interpolation: bool,
+    /// How often interpolated frames are rendered, per second.
+    pub frame_rate: u32,
+    /// The time after which the light state is shown again once no frame arrives.
+    pub timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_clients: 4,
+            color_correction: ColorCorrection::default(),
+            interpolation: false,
+            frame_rate: 60,
+            timeout: Duration::from_millis(2500),
+        }
+    }
+}
+
+struct Server {
+    config: Config,
+    output: spin::Mutex<Output>,
+    clients: AtomicUsize,
+}
+
+/// Start accepting OPC clients on the executor of `spawner`.
+pub fn start(config: Config, spawner: &Spawner) -> Result<(), StartError> {
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, PORT)).map_err(StartError::Bind)?;
+    listener.set_nonblocking(true).map_err(StartError::Bind)?;
+
+    let server = Arc::new(Server {
+        output: spin::Mutex::new(Output::new(
+            frame::len(),
+            &config.color_correction,
+            config.interpolation,
+        )),
+        clients: AtomicUsize::new(0),
+        config,
+    });
+
+    spawner.spawn(render(server.clone()))?;
+    spawner.spawn(accept(listener, spawner.clone(), server))?;
+
+    Ok(())
+}
+
+async fn accept(listener: TcpListener, spawner: Spawner, server: Arc<Server>) {
+    loop {
+        let (stream, addr) = match listener.accept() {
+            Ok(client) => client,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                EXECUTOR.sleep(ACCEPT_PERIOD).await;
+                continue;
+            }
+            Err(err) => {
+                log::error!("failed to accept opc client: {}", err);
+                EXECUTOR.sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        if server.clients.load(Ordering::Relaxed) >= server.config.max_clients {
+            log::warn!("rejected opc client {}, too many clients", addr);
+            continue;
+        }
+        if let Err(err) = stream.set_nonblocking(true) {
+            log::error!("failed to set up opc client {}: {}", addr, err);
+            continue;
+        }
+
+        server.clients.fetch_add(1, Ordering::Relaxed);
+        if spawner.spawn(serve(stream, addr, server.clone())).is_err() {
+            return;
+        }
+    }
+}
+
+async fn serve(stream: TcpStream, addr: SocketAddr, server: Arc<Server>) {
+    log::info!("opc client {} connected", addr);
+
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        match (&stream).read(&mut chunk) {
+            Ok(0) => break,
+            Ok(len) => {
+                buf.extend_from_slice(&chunk[..len]);
+                server.handle_messages(&mut buf);
+                // A client that keeps sending must not starve the other network tasks.
+                EXECUTOR.yield_now().await;
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                EXECUTOR.sleep(POLL_PERIOD).await;
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => {
+                log::warn!("failed to receive from opc client {}: {}", addr, err);
+                break;
+            }
+        }
+    }
+
+    log::info!("opc client {} disconnected", addr);
+    // Fall back to the light state once the last client is gone.
+    if server.clients.fetch_sub(1, Ordering::Relaxed) == 1 {
+        frame::release(SOURCE_NAME);
+    }
+}
+
+/// Render interpolated frames while a client is sending.
+async fn render(server: Arc<Server>) {
+    let mut interval = EXECUTOR.interval(Duration::from_secs(1) / server.config.frame_rate.max(1));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+
+        let output = server.output.lock();
+        let elapsed = match output.updated() {
+            Some(updated) if output.interpolation() => updated.elapsed(),
+            _ => continue,
+        };
+        if elapsed < server.config.timeout {
+            server.present(&output, server.config.timeout - elapsed);
+        }
+    }
+}
+
+impl Server {
+    /// Handle all whole messages in `buf` and remove them from it.
+    fn handle_messages(&self, buf: &mut Vec<u8>) {
+        let mut consumed = 0;
+        while let Some((message, len)) = protocol::decode(&buf[consumed..]) {
+            consumed += len;
+            match message {
+                Ok(message) => self.handle(message),
+                Err(err) => log::debug!("invalid opc message: {}", err),
+            }
+        }
+        buf.drain(..consumed);
+    }
+
+    fn handle(&self, message: Message<'_>) {
+        if !matches!(message.channel, CHANNEL_BROADCAST | CHANNEL_STRIP) {
+            return;
+        }
+
+        let mut output = self.output.lock();
+        match message.command {
+            Command::SetPixelColors(data) => {
+                output.set_pixels(data, Instant::now());
+                // Interpolated frames are presented by the render task.
+                if !output.interpolation() {
+                    self.present(&output, self.config.timeout);
+                }
+            }
+            Command::ColorCorrection(correction) => output.set_color_correction(&correction),
+            Command::FirmwareConfig(config) => output.set_interpolation(config.interpolation),
+            Command::Unsupported => {}
+        }
+    }
+
+    fn present(&self, output: &Output, timeout: Duration) {
+        let now = Instant::now();
+        frame::write(SOURCE_NAME, |pixels| output.render(now, pixels));
+        frame::present(SOURCE_NAME, timeout);
+    }
+}
@@ -0,0 +1,180 @@
+//! The firmware-side processing of OPC frames: interpolation and color correction.
+//!
+//! Like the Fadecandy firmware, the received frames are keyframes. With interpolation
+//! enabled, the output fades from the previous to the latest keyframe over the time
+//! between the last two keyframes, which smoothens animations that are sent at a lower
+//! frame rate than the strip is refreshed. The color correction is applied last.
+
+use std::time::{Duration, Instant};
+
+use super::protocol::ColorCorrection;
+use crate::light::frame::Pixel;
+
+/// Keyframes further apart than this are not interpolated but shown immediately.
+const MAX_INTERPOLATION: Duration = Duration::from_millis(250);
+
+pub struct Output {
+    previous: Vec<Pixel>,
+    next: Vec<Pixel>,
+    /// When `next` arrived.
+    updated: Option<Instant>,
+    /// The time between the last two keyframes.
+    interval: Duration,
+    interpolation: bool,
+    /// The lookup table of the color correction, per channel.
+    lut: [[u8; 256]; 3],
+}
+
+impl Output {
+    pub fn new(len: usize, correction: &ColorCorrection, interpolation: bool) -> Self {
+        let mut output = Output {
+            previous: vec![Pixel::new(0, 0, 0); len],
+            next: vec![Pixel::new(0, 0, 0); len],
+            updated: None,
+            interval: Duration::ZERO,
+            interpolation,
+            lut: [[0; 256]; 3],
+        };
+        output.set_color_correction(correction);
+        output
+    }
+
+    pub fn set_color_correction(&mut self, correction: &ColorCorrection) {
+        let gamma = if correction.gamma > 0.0 {
+            correction.gamma
+        } else {
+            1.0
+        };
+
+        for (lut, whitepoint) in self.lut.iter_mut().zip(correction.whitepoint) {
+            let scale = whitepoint.clamp(0.0, 1.0) * 255.0;
+            for (i, value) in lut.iter_mut().enumerate() {
+                *value = ((i as f32 / 255.0).powf(gamma) * scale).round() as u8;
+            }
+        }
+    }
+
+    pub fn interpolation(&self) -> bool {
+        self.interpolation
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: bool) {
+        self.interpolation = interpolation;
+    }
+
+    /// When the last keyframe arrived.
+    pub fn updated(&self) -> Option<Instant> {
+        self.updated
+    }
+
+    /// Set the next keyframe from RGB triplets, pixels without data keep their color.
+    pub fn set_pixels(&mut self, data: &[u8], now: Instant) {
+        // Continue from what is shown right now, so a keyframe that arrives early
+        // doesn't make the output jump.
+        let alpha = self.alpha(now);
+        for (previous, next) in self.previous.iter_mut().zip(&self.next) {
+            *previous = lerp(*previous, *next, alpha);
+        }
+
+        for (pixel, rgb) in self.next.iter_mut().zip(data.chunks_exact(3)) {
+            *pixel = Pixel::new(rgb[0], rgb[1], rgb[2]);
+        }
+
+        self.interval = match self.updated {
+            Some(updated) if self.interpolation => now.saturating_duration_since(updated),
+            _ => Duration::ZERO,
+        };
+        self.updated = Some(now);
+    }
+
+    /// Render the output at `now` into `pixels`.
+    pub fn render(&self, now: Instant, pixels: &mut [Pixel]) {
+        let alpha = self.alpha(now);
+        let colors = self.previous.iter().zip(&self.next);
+
+        for (pixel, (previous, next)) in pixels.iter_mut().zip(colors) {
+            let color = lerp(*previous, *next, alpha);
+            *pixel = Pixel::new(
+                self.lut[0][color.red as usize],
+                self.lut[1][color.green as usize],
+                self.lut[2][color.blue as usize],
+            );
+        }
+    }
+
+    /// How far the output has faded from the previous to the next keyframe, from 0 to
+    /// 256.
+    fn alpha(&self, now: Instant) -> u32 {
+        let updated = match self.updated {
+            Some(updated) => updated,
+            None => return 256,
+        };
+        if !self.interpolation || self.interval.is_zero() || self.interval > MAX_INTERPOLATION {
+            return 256;
+        }
+
+        let elapsed = now.saturating_duration_since(updated);
+        (elapsed.as_micros() * 256 / self.interval.as_micros()).min(256) as u32
+    }
+}
+
+fn lerp(from: Pixel, to: Pixel, alpha: u32) -> Pixel {
+    let channel =
+        |from: u8, to: u8| ((from as u32 * (256 - alpha) + to as u32 * alpha) / 256) as u8;
+    Pixel::new(
+        channel(from.red, to.red),
+        channel(from.green, to.green),
+        channel(from.blue, to.blue),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolate_keyframes() {
+        let mut output = Output::new(2, &ColorCorrection::default(), true);
+        let mut pixels = [Pixel::new(0, 0, 0); 2];
+        let start = Instant::now();
+
+        // The first keyframe is shown immediately.
+        output.set_pixels(&[100, 0, 0], start);
+        output.render(start, &mut pixels);
+        assert_eq!(pixels, [Pixel::new(100, 0, 0), Pixel::new(0, 0, 0)]);
+
+        let second = start + Duration::from_millis(100);
+        output.set_pixels(&[200, 0, 0, 0, 0, 100], second);
+        output.render(second, &mut pixels);
+        assert_eq!(pixels, [Pixel::new(100, 0, 0), Pixel::new(0, 0, 0)]);
+
+        output.render(second + Duration::from_millis(50), &mut pixels);
+        assert_eq!(pixels, [Pixel::new(150, 0, 0), Pixel::new(0, 0, 50)]);
+
+        output.render(second + Duration::from_millis(200), &mut pixels);
+        assert_eq!(pixels, [Pixel::new(200, 0, 0), Pixel::new(0, 0, 100)]);
+
+        output.set_interpolation(false);
+        output.set_pixels(&[0, 0, 0], second + Duration::from_millis(250));
+        output.render(second + Duration::from_millis(250), &mut pixels);
+        assert_eq!(pixels, [Pixel::new(0, 0, 0), Pixel::new(0, 0, 100)]);
+    }
+
+    #[test]
+    fn color_correction() {
+        let correction = ColorCorrection {
+            gamma: 2.0,
+            whitepoint: [1.0, 0.5, 0.0],
+        };
+        let mut output = Output::new(1, &correction, false);
+        let mut pixels = [Pixel::new(0, 0, 0)];
+
+        output.set_pixels(&[255, 255, 255], Instant::now());
+        output.render(Instant::now(), &mut pixels);
+        assert_eq!(pixels, [Pixel::new(255, 128, 0)]);
+
+        output.set_pixels(&[128, 0, 0], Instant::now());
+        output.render(Instant::now(), &mut pixels);
+        assert_eq!(pixels, [Pixel::new(64, 0, 0)]);
+    }
+}
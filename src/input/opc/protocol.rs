@@ -0,0 +1,194 @@
+//! Decoding of Open Pixel Control messages.
+//!
+//! Besides `set pixel colors`, the system-exclusive commands of Fadecandy to set the
+//! color correction and the firmware configuration are supported.
+
+use serde::Deserialize;
+
+/// The TCP port of OPC.
+pub const PORT: u16 = 7890;
+
+/// The channel that addresses all outputs.
+pub const CHANNEL_BROADCAST: u8 = 0;
+/// The channel of the strip.
+pub const CHANNEL_STRIP: u8 = 1;
+
+const HEADER_LEN: usize = 4;
+
+const COMMAND_SET_PIXEL_COLORS: u8 = 0;
+const COMMAND_SYSTEM_EXCLUSIVE: u8 = 255;
+
+const SYSTEM_FADECANDY: u16 = 0x0001;
+const FADECANDY_COLOR_CORRECTION: u16 = 0x0001;
+const FADECANDY_FIRMWARE_CONFIG: u16 = 0x0002;
+
+const FIRMWARE_DISABLE_INTERPOLATION: u8 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ParseError {
+    #[error("system exclusive message is too short")]
+    Truncated,
+    #[error("invalid color correction")]
+    InvalidColorCorrection,
+}
+
+/// An OPC message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Message<'a> {
+    pub channel: u8,
+    pub command: Command<'a>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command<'a> {
+    /// RGB triplets, starting at the first pixel.
+    SetPixelColors(&'a [u8]),
+    ColorCorrection(ColorCorrection),
+    FirmwareConfig(FirmwareConfig),
+    /// A command or system-exclusive message that isn't supported, which is ignored.
+    Unsupported,
+}
+
+/// The color correction applied to every pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct ColorCorrection {
+    /// The exponent of the gamma curve, `1.0` disables it.
+    #[serde(default = "default_gamma")]
+    pub gamma: f32,
+    /// The brightness of red, green and blue, from `0.0` to `1.0`.
+    #[serde(default = "default_whitepoint")]
+    pub whitepoint: [f32; 3],
+}
+
+impl Default for ColorCorrection {
+    fn default() -> Self {
+        ColorCorrection {
+            gamma: default_gamma(),
+            whitepoint: default_whitepoint(),
+        }
+    }
+}
+
+fn default_gamma() -> f32 {
+    1.0
+}
+
+fn default_whitepoint() -> [f32; 3] {
+    [1.0; 3]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirmwareConfig {
+    /// Whether frames are interpolated, see [`super::output::Output`].
+    pub interpolation: bool,
+}
+
+/// Decode the message at the start of `buf`.
+///
+/// Returns the message and its length in bytes, or `None` if `buf` doesn't contain the
+/// whole message yet.
+pub fn decode(buf: &[u8]) -> Option<(Result<Message<'_>, ParseError>, usize)> {
+    let header = buf.get(..HEADER_LEN)?;
+    let len = HEADER_LEN + u16::from_be_bytes([header[2], header[3]]) as usize;
+    let data = buf.get(HEADER_LEN..len)?;
+
+    let command = match header[1] {
+        COMMAND_SET_PIXEL_COLORS => Ok(Command::SetPixelColors(data)),
+        COMMAND_SYSTEM_EXCLUSIVE => parse_system_exclusive(data),
+        _ => Ok(Command::Unsupported),
+    };
+    let message = command.map(|command| Message {
+        channel: header[0],
+        command,
+    });
+
+    Some((message, len))
+}
+
+fn parse_system_exclusive(data: &[u8]) -> Result<Command<'_>, ParseError> {
+    let header = data.get(..4).ok_or(ParseError::Truncated)?;
+    let system = u16::from_be_bytes([header[0], header[1]]);
+    let command = u16::from_be_bytes([header[2], header[3]]);
+
+    match (system, command) {
+        (SYSTEM_FADECANDY, FADECANDY_COLOR_CORRECTION) => serde_json::from_slice(&data[4..])
+            .map(Command::ColorCorrection)
+            .map_err(|_| ParseError::InvalidColorCorrection),
+        (SYSTEM_FADECANDY, FADECANDY_FIRMWARE_CONFIG) => {
+            let flags = *data.get(4).ok_or(ParseError::Truncated)?;
+            Ok(Command::FirmwareConfig(FirmwareConfig {
+                interpolation: flags & FIRMWARE_DISABLE_INTERPOLATION == 0,
+            }))
+        }
+        _ => Ok(Command::Unsupported),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(channel: u8, command: u8, data: &[u8]) -> Vec<u8> {
+        let mut m = vec![channel, command];
+        m.extend((data.len() as u16).to_be_bytes());
+        m.extend(data);
+        m
+    }
+
+    fn sysex(command: u16, data: &[u8]) -> Vec<u8> {
+        let mut d = Vec::new();
+        d.extend(SYSTEM_FADECANDY.to_be_bytes());
+        d.extend(command.to_be_bytes());
+        d.extend(data);
+        message(0, COMMAND_SYSTEM_EXCLUSIVE, &d)
+    }
+
+    #[test]
+    fn decode_partial_messages() {
+        let mut buf = message(1, COMMAND_SET_PIXEL_COLORS, &[1, 2, 3, 4, 5, 6]);
+        buf.extend(message(0, 42, &[]));
+
+        assert_eq!(decode(&buf[..3]), None);
+        assert_eq!(decode(&buf[..9]), None);
+
+        let expected = Message {
+            channel: 1,
+            command: Command::SetPixelColors(&[1, 2, 3, 4, 5, 6]),
+        };
+        assert_eq!(decode(&buf), Some((Ok(expected), 10)));
+
+        let unsupported = Message {
+            channel: 0,
+            command: Command::Unsupported,
+        };
+        assert_eq!(decode(&buf[10..]), Some((Ok(unsupported), 4)));
+    }
+
+    #[test]
+    fn decode_system_exclusive() {
+        let buf = sysex(
+            FADECANDY_COLOR_CORRECTION,
+            br#"{"gamma": 2.5, "linearSlope": 1}"#,
+        );
+        let expected = Command::ColorCorrection(ColorCorrection {
+            gamma: 2.5,
+            whitepoint: [1.0; 3],
+        });
+        assert_eq!(decode(&buf).unwrap().0.unwrap().command, expected);
+
+        let buf = sysex(FADECANDY_FIRMWARE_CONFIG, &[FIRMWARE_DISABLE_INTERPOLATION]);
+        let expected = Command::FirmwareConfig(FirmwareConfig {
+            interpolation: false,
+        });
+        assert_eq!(decode(&buf).unwrap().0.unwrap().command, expected);
+
+        let buf = sysex(FADECANDY_COLOR_CORRECTION, b"not json");
+        assert_eq!(
+            decode(&buf).unwrap().0,
+            Err(ParseError::InvalidColorCorrection)
+        );
+
+        let buf = message(0, COMMAND_SYSTEM_EXCLUSIVE, &[0, 1]);
+        assert_eq!(decode(&buf).unwrap().0, Err(ParseError::Truncated));
+    }
+}
@@ -0,0 +1,294 @@
+//! A serial input for ambilight setups, e.g. Prismatik, HyperHDR or Hyperion on a PC
+//! connected over USB.
+//!
+//! Frames are received on a UART as Adalight or TPM2 frames. Both parsers resynchronize
+//! on their own after corrupted or lost bytes. With [`Protocol::Auto`] every byte is fed
+//! to both of them until one produces a frame, then only that protocol is accepted until
+//! the timeout. While no frame arrives, the Adalight hello message is sent every second,
+//! which some hosts wait for before they start sending.
+//!
+//! The USB port of most boards is connected to UART0, which is also used by the console.
+//! Log output then also goes to the host, which ignores it.
+//!
+//! Only the protocol decoding is built on other targets.
+
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "espidf")]
+use {
+    crate::light::frame,
+    crate::utils::thread::{self, SpawnError, ThreadConfig},
+    esp_idf_sys::{self as sys, esp, EspError},
+};
+
+pub mod adalight;
+pub mod tpm2;
+
+/// The name of this input in the [`frame`] buffer.
+pub const SOURCE_NAME: &str = "serial";
+
+/// How long a read waits for data.
+#[cfg(target_os = "espidf")]
+const READ_TIMEOUT_MS: u32 = 50;
+#[cfg(target_os = "espidf")]
+const RX_BUFFER_SIZE: i32 = 2048;
+#[cfg(target_os = "espidf")]
+const HELLO_PERIOD: Duration = Duration::from_secs(1);
+
+#[cfg(target_os = "espidf")]
+#[derive(Debug, thiserror::Error)]
+pub enum StartError {
+    #[error("failed to set up uart")]
+    Uart(#[from] EspError),
+    #[error("failed to spawn serial thread")]
+    Thread(#[from] SpawnError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Adalight,
+    Tpm2,
+    /// Accept the protocol that sends the first frame.
+    Auto,
+}
+
+#[cfg(target_os = "espidf")]
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub port: sys::uart_port_t,
+    pub baud_rate: u32,
+    /// The TX pin, `None` keeps the default pin of the UART.
+    pub tx_pin: Option<i32>,
+    /// The RX pin, `None` keeps the default pin of the UART.
+    pub rx_pin: Option<i32>,
+    pub protocol: Protocol,
+    /// The time after which the light state is shown again once no frame arrives.
+    pub timeout: Duration,
+    /// The thread the input runs on.
+    pub thread: ThreadConfig,
+}
+
+#[cfg(target_os = "espidf")]
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            port: 0,
+            baud_rate: 115_200,
+            tx_pin: None,
+            rx_pin: None,
+            protocol: Protocol::Auto,
+            timeout: Duration::from_millis(2500),
+            thread: ThreadConfig {
+                name: "serial",
+                core: None,
+                priority: 8,
+                stack_size: 4 * 1024,
+            },
+        }
+    }
+}
+
+/// Install the UART driver and start receiving frames.
+#[cfg(target_os = "espidf")]
+pub fn start(config: Config) -> Result<(), StartError> {
+    let uart_config = sys::uart_config_t {
+        baud_rate: config.baud_rate as _,
+        data_bits: sys::uart_word_length_t_UART_DATA_8_BITS,
+        parity: sys::uart_parity_t_UART_PARITY_DISABLE,
+        stop_bits: sys::uart_stop_bits_t_UART_STOP_BITS_1,
+        flow_ctrl: sys::uart_hw_flowcontrol_t_UART_HW_FLOWCTRL_DISABLE,
+        ..Default::default()
+    };
+    let pin = |pin: Option<i32>| pin.unwrap_or(sys::UART_PIN_NO_CHANGE);
+
+    unsafe {
+        esp!(sys::uart_param_config(config.port, &uart_config))?;
+        esp!(sys::uart_set_pin(
+            config.port,
+            pin(config.tx_pin),
+            pin(config.rx_pin),
+            sys::UART_PIN_NO_CHANGE,
+            sys::UART_PIN_NO_CHANGE,
+        ))?;
+        esp!(sys::uart_driver_install(
+            config.port,
+            RX_BUFFER_SIZE,
+            0,
+            0,
+            core::ptr::null_mut(),
+            0,
+        ))?;
+    }
+
+    let thread_config = config.thread.clone();
+    thread::spawn(&thread_config, move || run(config))?;
+
+    Ok(())
+}
+
+#[cfg(target_os = "espidf")]
+fn run(config: Config) {
+    let mut decoder = Decoder::new(config.protocol, frame::len(), config.timeout);
+
+    let read_timeout = READ_TIMEOUT_MS * sys::configTICK_RATE_HZ / 1000;
+    let mut buf = [0; 256];
+    let mut last_frame: Option<Instant> = None;
+    let mut last_hello: Option<Instant> = None;
+
+    loop {
+        let len = unsafe {
+            sys::uart_read_bytes(
+                config.port,
+                buf.as_mut_ptr() as *mut _,
+                buf.len() as _,
+                read_timeout,
+            )
+        };
+        if len < 0 {
+            log::error!("failed to read from uart {}", config.port);
+            std::thread::sleep(Duration::from_secs(1));
+            continue;
+        }
+
+        let now = Instant::now();
+        for &byte in &buf[..len as usize] {
+            if let Some(data) = decoder.push(byte, now) {
+                show(data, config.timeout);
+                last_frame = Some(now);
+            }
+        }
+
+        let idle = last_frame.map_or(true, |last| last.elapsed() >= config.timeout);
+        let hello_due = last_hello.map_or(true, |last| last.elapsed() >= HELLO_PERIOD);
+        let use_adalight = matches!(config.protocol, Protocol::Adalight | Protocol::Auto);
+        if use_adalight && idle && hello_due {
+            unsafe {
+                sys::uart_write_bytes(
+                    config.port,
+                    adalight::HELLO.as_ptr() as *const _,
+                    adalight::HELLO.len() as _,
+                );
+            }
+            last_hello = Some(Instant::now());
+        }
+    }
+}
+
+/// Feeds the received bytes to the parsers of the configured protocol.
+///
+/// With [`Protocol::Auto`] the protocol of the first frame is locked in until no frame
+/// arrived for `timeout`. Otherwise data of one protocol that happens to contain a valid
+/// frame of the other one would show frames of both.
+struct Decoder {
+    protocol: Protocol,
+    adalight: adalight::Parser,
+    tpm2: tpm2::Parser,
+    /// The protocol of the last frame and when it arrived.
+    locked: Option<(Protocol, Instant)>,
+    timeout: Duration,
+}
+
+impl Decoder {
+    fn new(protocol: Protocol, max_pixels: usize, timeout: Duration) -> Self {
+        Decoder {
+            protocol,
+            adalight: adalight::Parser::new(max_pixels),
+            tpm2: tpm2::Parser::new(max_pixels),
+            locked: None,
+            timeout,
+        }
+    }
+
+    /// Feed the next byte received at `now`, returns the RGB data of a frame once it is
+    /// complete.
+    fn push(&mut self, byte: u8, now: Instant) -> Option<&[u8]> {
+        let protocol = match (self.protocol, self.locked) {
+            (Protocol::Auto, Some((locked, last)))
+                if now.saturating_duration_since(last) < self.timeout =>
+            {
+                locked
+            }
+            (protocol, _) => protocol,
+        };
+
+        if matches!(protocol, Protocol::Adalight | Protocol::Auto) {
+            if let Some(data) = self.adalight.push(byte) {
+                self.locked = Some((Protocol::Adalight, now));
+                return Some(data);
+            }
+        }
+        if matches!(protocol, Protocol::Tpm2 | Protocol::Auto) {
+            if let Some(data) = self.tpm2.push(byte) {
+                self.locked = Some((Protocol::Tpm2, now));
+                return Some(data);
+            }
+        }
+        None
+    }
+}
+
+/// Show the RGB triplets of a frame, starting at the first pixel.
+#[cfg(target_os = "espidf")]
+fn show(data: &[u8], timeout: Duration) {
+    frame::write(SOURCE_NAME, |pixels| {
+        for (pixel, rgb) in pixels.iter_mut().zip(data.chunks_exact(3)) {
+            *pixel = frame::Pixel::new(rgb[0], rgb[1], rgb[2]);
+        }
+    });
+    frame::present(SOURCE_NAME, timeout);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An Adalight frame with one pixel.
+    const ADALIGHT_FRAME: [u8; 9] = [b'A', b'd', b'a', 0, 0, 0x55, 1, 2, 3];
+
+    fn tpm2_frame(data: &[u8]) -> Vec<u8> {
+        let mut f = vec![0xc9, 0xda];
+        f.extend((data.len() as u16).to_be_bytes());
+        f.extend(data);
+        f.push(0x36);
+        f
+    }
+
+    fn frames(decoder: &mut Decoder, bytes: &[u8], now: Instant) -> Vec<Vec<u8>> {
+        bytes
+            .iter()
+            .filter_map(|&b| decoder.push(b, now).map(|data| data.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn lock_onto_the_first_protocol() {
+        let timeout = Duration::from_secs(1);
+        let mut decoder = Decoder::new(Protocol::Auto, 10, timeout);
+        let start = Instant::now();
+
+        // TPM2 data that contains a whole Adalight frame only produces the TPM2 frame.
+        let tpm2 = tpm2_frame(&[4, 5, 6]);
+        assert_eq!(frames(&mut decoder, &tpm2, start), [vec![4, 5, 6]]);
+        let nested = tpm2_frame(&ADALIGHT_FRAME);
+        assert_eq!(
+            frames(&mut decoder, &nested, start),
+            [ADALIGHT_FRAME.to_vec()]
+        );
+
+        // Adalight frames are ignored until TPM2 frames stop for the timeout.
+        let later = start + timeout / 2;
+        assert!(frames(&mut decoder, &ADALIGHT_FRAME, later).is_empty());
+        let idle = start + timeout;
+        assert_eq!(frames(&mut decoder, &ADALIGHT_FRAME, idle), [vec![1, 2, 3]]);
+        assert!(frames(&mut decoder, &tpm2, idle).is_empty());
+    }
+
+    #[test]
+    fn only_accept_the_configured_protocol() {
+        let mut decoder = Decoder::new(Protocol::Adalight, 10, Duration::from_secs(1));
+        let now = Instant::now();
+
+        assert!(frames(&mut decoder, &tpm2_frame(&[4, 5, 6]), now).is_empty());
+        assert_eq!(frames(&mut decoder, &ADALIGHT_FRAME, now), [vec![1, 2, 3]]);
+    }
+}
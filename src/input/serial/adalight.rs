@@ -0,0 +1,140 @@
+//! Parsing of the Adalight protocol, as sent by Prismatik, HyperHDR and Hyperion.
+//!
+//! A frame is the magic word `Ada`, the amount of LEDs minus one as big-endian `u16`, a
+//! checksum of the two count bytes and the RGB triplets of all LEDs. The parser looks
+//! for a header with a valid checksum in a sliding window, so it resynchronizes with the
+//! next frame after lost or corrupted bytes.
+
+const MAGIC: [u8; 3] = *b"Ada";
+const HEADER_LEN: usize = 6;
+
+/// The message the Adalight firmware sends while it waits for data.
+pub const HELLO: &[u8] = b"Ada\n";
+
+pub struct Parser {
+    /// The last bytes received while looking for a header.
+    header: [u8; HEADER_LEN],
+    header_len: usize,
+    /// The amount of data bytes of the current frame, `None` while looking for a header.
+    frame_len: Option<usize>,
+    received: usize,
+    data: Vec<u8>,
+    max_len: usize,
+}
+
+impl Parser {
+    /// Create a parser that keeps the data of at most `max_pixels` pixels per frame.
+    pub fn new(max_pixels: usize) -> Self {
+        Parser {
+            header: [0; HEADER_LEN],
+            header_len: 0,
+            frame_len: None,
+            received: 0,
+            data: Vec::with_capacity(max_pixels * 3),
+            max_len: max_pixels * 3,
+        }
+    }
+
+    /// Feed the next byte, returns the RGB data of a frame once it is complete.
+    pub fn push(&mut self, byte: u8) -> Option<&[u8]> {
+        let frame_len = match self.frame_len {
+            Some(frame_len) => frame_len,
+            None => {
+                self.push_header(byte);
+                return None;
+            }
+        };
+
+        if self.received < self.max_len {
+            self.data.push(byte);
+        }
+        self.received += 1;
+
+        if self.received < frame_len {
+            return None;
+        }
+        self.frame_len = None;
+        Some(&self.data)
+    }
+
+    fn push_header(&mut self, byte: u8) {
+        if self.header_len == HEADER_LEN {
+            self.header.copy_within(1.., 0);
+            self.header_len -= 1;
+        }
+        self.header[self.header_len] = byte;
+        self.header_len += 1;
+
+        let [a, d, a2, high, low, checksum] = self.header;
+        if self.header_len < HEADER_LEN || [a, d, a2] != MAGIC || high ^ low ^ 0x55 != checksum {
+            return;
+        }
+
+        self.header_len = 0;
+        self.frame_len = Some((u16::from_be_bytes([high, low]) as usize + 1) * 3);
+        self.received = 0;
+        self.data.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(pixels: &[[u8; 3]]) -> Vec<u8> {
+        let [high, low] = (pixels.len() as u16 - 1).to_be_bytes();
+        let mut f = MAGIC.to_vec();
+        f.extend([high, low, high ^ low ^ 0x55]);
+        f.extend(pixels.iter().flatten());
+        f
+    }
+
+    fn frames(parser: &mut Parser, bytes: &[u8]) -> Vec<Vec<u8>> {
+        bytes
+            .iter()
+            .filter_map(|&b| parser.push(b).map(|data| data.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_frames() {
+        let mut parser = Parser::new(10);
+        let mut bytes = frame(&[[1, 2, 3], [4, 5, 6]]);
+        bytes.extend(frame(&[[7, 8, 9]]));
+
+        assert_eq!(
+            frames(&mut parser, &bytes),
+            [vec![1, 2, 3, 4, 5, 6], vec![7, 8, 9]]
+        );
+    }
+
+    #[test]
+    fn resynchronize_after_corruption() {
+        let mut parser = Parser::new(10);
+
+        let mut bytes = b"garbage Ad".to_vec();
+        // A header with an invalid checksum is skipped.
+        bytes.extend(b"Ada\x00\x00\x00");
+        bytes.extend(frame(&[[1, 1, 1]]));
+        // A frame that lost a byte swallows the start of the next header.
+        let mut truncated = frame(&[[2, 2, 2], [3, 3, 3]]);
+        truncated.remove(8);
+        bytes.extend(truncated);
+        bytes.extend(frame(&[[4, 4, 4]]));
+        bytes.extend(frame(&[[5, 5, 5]]));
+
+        let frames = frames(&mut parser, &bytes);
+        assert_eq!(frames[0], [1, 1, 1]);
+        assert_eq!(frames.last().unwrap(), &[5, 5, 5]);
+        assert!(!frames.iter().any(|f| f == &[4, 4, 4]));
+    }
+
+    #[test]
+    fn keep_at_most_max_pixels() {
+        let mut parser = Parser::new(1);
+        let mut bytes = frame(&[[1, 2, 3], [4, 5, 6]]);
+        bytes.extend(frame(&[[7, 8, 9]]));
+
+        assert_eq!(frames(&mut parser, &bytes), [vec![1, 2, 3], vec![7, 8, 9]]);
+    }
+}
@@ -0,0 +1,138 @@
+//! Parsing of data frames of the TPM2 serial protocol.
+//!
+//! A frame is the block start byte, the packet type, the data size as big-endian `u16`,
+//! the data and the block end byte. Command and response packets are skipped. A frame
+//! that doesn't end with the block end byte is dropped, and the parser looks for the
+//! next block start.
+
+const BLOCK_START: u8 = 0xc9;
+const BLOCK_END: u8 = 0x36;
+const TYPE_DATA: u8 = 0xda;
+const HEADER_LEN: usize = 4;
+
+pub struct Parser {
+    /// The last bytes received while looking for a header.
+    header: [u8; HEADER_LEN],
+    header_len: usize,
+    /// The size of the data of the current frame, `None` while looking for a header.
+    frame_len: Option<usize>,
+    received: usize,
+    data: Vec<u8>,
+    max_len: usize,
+}
+
+impl Parser {
+    /// Create a parser that keeps the data of at most `max_pixels` pixels per frame.
+    pub fn new(max_pixels: usize) -> Self {
+        Parser {
+            header: [0; HEADER_LEN],
+            header_len: 0,
+            frame_len: None,
+            received: 0,
+            data: Vec::with_capacity(max_pixels * 3),
+            max_len: max_pixels * 3,
+        }
+    }
+
+    /// Feed the next byte, returns the RGB data of a frame once it is complete.
+    pub fn push(&mut self, byte: u8) -> Option<&[u8]> {
+        let frame_len = match self.frame_len {
+            Some(frame_len) => frame_len,
+            None => {
+                self.push_header(byte);
+                return None;
+            }
+        };
+
+        if self.received < frame_len {
+            if self.received < self.max_len {
+                self.data.push(byte);
+            }
+            self.received += 1;
+            return None;
+        }
+
+        self.frame_len = None;
+        (byte == BLOCK_END).then_some(&self.data[..])
+    }
+
+    fn push_header(&mut self, byte: u8) {
+        if self.header_len == HEADER_LEN {
+            self.header.copy_within(1.., 0);
+            self.header_len -= 1;
+        }
+        self.header[self.header_len] = byte;
+        self.header_len += 1;
+
+        let [start, packet_type, high, low] = self.header;
+        if self.header_len < HEADER_LEN || start != BLOCK_START || packet_type != TYPE_DATA {
+            return;
+        }
+
+        self.header_len = 0;
+        self.frame_len = Some(u16::from_be_bytes([high, low]) as usize);
+        self.received = 0;
+        self.data.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(data: &[u8]) -> Vec<u8> {
+        let mut f = vec![BLOCK_START, TYPE_DATA];
+        f.extend((data.len() as u16).to_be_bytes());
+        f.extend(data);
+        f.push(BLOCK_END);
+        f
+    }
+
+    fn frames(parser: &mut Parser, bytes: &[u8]) -> Vec<Vec<u8>> {
+        bytes
+            .iter()
+            .filter_map(|&b| parser.push(b).map(|data| data.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_frames() {
+        let mut parser = Parser::new(10);
+        let mut bytes = frame(&[1, 2, 3, 4, 5, 6]);
+        // A command packet is skipped.
+        bytes.extend([BLOCK_START, 0xc0, 0, 1, 0x0a, BLOCK_END]);
+        bytes.extend(frame(&[7, 8, 9]));
+
+        assert_eq!(
+            frames(&mut parser, &bytes),
+            [vec![1, 2, 3, 4, 5, 6], vec![7, 8, 9]]
+        );
+    }
+
+    #[test]
+    fn resynchronize_after_corruption() {
+        let mut parser = Parser::new(10);
+
+        let mut bytes = vec![0x00, BLOCK_START, 0x12, BLOCK_END];
+        bytes.extend(frame(&[1, 1, 1]));
+        // A frame that lost a byte doesn't end with the block end byte and is dropped.
+        let mut truncated = frame(&[2, 2, 2]);
+        truncated.remove(5);
+        bytes.extend(truncated);
+        bytes.extend(frame(&[3, 3, 3]));
+        bytes.extend(frame(&[4, 4, 4]));
+
+        let frames = frames(&mut parser, &bytes);
+        assert_eq!(frames[0], [1, 1, 1]);
+        assert!(!frames.iter().any(|f| f.starts_with(&[2, 2])));
+        assert_eq!(frames.last().unwrap(), &[4, 4, 4]);
+    }
+
+    #[test]
+    fn keep_at_most_max_pixels() {
+        let mut parser = Parser::new(1);
+        let bytes = frame(&[1, 2, 3, 4, 5, 6]);
+
+        assert_eq!(frames(&mut parser, &bytes), [vec![1, 2, 3]]);
+    }
+}
@@ -0,0 +1,205 @@
+//! Maps DMX universes onto the pixels of the strip, shared by the DMX-over-IP inputs.
+
+use crate::light::frame::Pixel;
+
+/// The amount of slots (channels) in a DMX universe.
+pub const UNIVERSE_SIZE: usize = 512;
+
+/// Maps the slots of one universe onto consecutive pixels.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UniverseMapping {
+    pub universe: u16,
+    /// The DMX address of the first pixel, starting at one.
+    pub start_address: u16,
+    /// The index of the first pixel on the strip.
+    pub first_pixel: usize,
+    pub pixel_count: usize,
+    /// The amount of channels per pixel, the first three are red, green and blue.
+    pub channel_stride: u16,
+}
+
+/// A set of [`UniverseMapping`]s.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UniverseMap {
+    mappings: Vec<UniverseMapping>,
+}
+
+impl UniverseMap {
+    pub fn new(mappings: Vec<UniverseMapping>) -> Self {
+        UniverseMap { mappings }
+    }
+
+    /// Map `pixel_count` pixels onto as many consecutive universes as needed, starting at
+    /// `start_address` of `first_universe`.
+    ///
+    /// A pixel never spans two universes, e.g. with RGB pixels 170 pixels fit into a
+    /// universe and the last two slots are unused.
+    pub fn contiguous(
+        first_universe: u16,
+        start_address: u16,
+        pixel_count: usize,
+        channel_stride: u16,
+    ) -> Self {
+        let stride = channel_stride.max(3) as usize;
+        let mut mappings = Vec::new();
+
+        let mut universe = first_universe;
+        let mut address = start_address.max(1);
+        let mut first_pixel = 0;
+        while first_pixel < pixel_count {
+            let slots = UNIVERSE_SIZE.saturating_sub(address as usize - 1);
+            let count = (slots / stride).min(pixel_count - first_pixel);
+
+            if count > 0 {
+                mappings.push(UniverseMapping {
+                    universe,
+                    start_address: address,
+                    first_pixel,
+                    pixel_count: count,
+                    channel_stride: stride as u16,
+                });
+            }
+
+            first_pixel += count;
+            universe = match universe.checked_add(1) {
+                Some(universe) => universe,
+                None => break,
+            };
+            address = 1;
+        }
+
+        UniverseMap { mappings }
+    }
+
+    pub fn mappings(&self) -> &[UniverseMapping] {
+        &self.mappings
+    }
+
+    /// The universes that are mapped, without duplicates.
+    pub fn universes(&self) -> Vec<u16> {
+        let mut universes: Vec<_> = self.mappings.iter().map(|m| m.universe).collect();
+        universes.sort_unstable();
+        universes.dedup();
+        universes
+    }
+
+    /// Whether `universe` is mapped onto any pixels.
+    pub fn contains(&self, universe: u16) -> bool {
+        self.mappings.iter().any(|m| m.universe == universe)
+    }
+
+    /// Write the `slots` of `universe` into `pixels`, `slots[0]` is the slot at DMX
+    /// address one.
+    ///
+    /// Pixels outside of the strip or whose channels aren't all in `slots` are left
+    /// untouched. Returns whether `universe` is mapped.
+    pub fn apply(&self, universe: u16, slots: &[u8], pixels: &mut [Pixel]) -> bool {
+        let mut mapped = false;
+
+        for mapping in self.mappings.iter().filter(|m| m.universe == universe) {
+            mapped = true;
+
+            let stride = mapping.channel_stride.max(3) as usize;
+            let start = mapping.start_address.max(1) as usize - 1;
+            let channels = slots
+                .get(start..)
+                .unwrap_or_default()
+                .chunks_exact(stride)
+                .take(mapping.pixel_count);
+
+            let end = pixels.len().min(mapping.first_pixel + mapping.pixel_count);
+            let targets = pixels.get_mut(mapping.first_pixel..end).unwrap_or_default();
+
+            for (pixel, channels) in targets.iter_mut().zip(channels) {
+                *pixel = Pixel::new(channels[0], channels[1], channels[2]);
+            }
+        }
+
+        mapped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb(r: u8, g: u8, b: u8) -> Pixel {
+        Pixel::new(r, g, b)
+    }
+
+    #[test]
+    fn contiguous_splits_universes() {
+        let map = UniverseMap::contiguous(1, 1, 300, 3);
+        assert_eq!(
+            map.mappings(),
+            &[
+                UniverseMapping {
+                    universe: 1,
+                    start_address: 1,
+                    first_pixel: 0,
+                    pixel_count: 170,
+                    channel_stride: 3,
+                },
+                UniverseMapping {
+                    universe: 2,
+                    start_address: 1,
+                    first_pixel: 170,
+                    pixel_count: 130,
+                    channel_stride: 3,
+                },
+            ]
+        );
+        assert_eq!(map.universes(), vec![1, 2]);
+    }
+
+    #[test]
+    fn contiguous_with_offset_and_stride() {
+        let map = UniverseMap::contiguous(5, 501, 10, 4);
+        // Slots 501..=512 hold three RGBW pixels.
+        assert_eq!(map.mappings()[0].pixel_count, 3);
+        assert_eq!(map.mappings()[1].universe, 6);
+        assert_eq!(map.mappings()[1].first_pixel, 3);
+        assert_eq!(map.mappings()[1].pixel_count, 7);
+    }
+
+    #[test]
+    fn apply_maps_slots() {
+        let map = UniverseMap::contiguous(1, 4, 3, 3);
+        let mut pixels = [rgb(9, 9, 9); 4];
+        let slots = [0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        assert!(map.apply(1, &slots, &mut pixels));
+        assert_eq!(
+            pixels,
+            [rgb(1, 2, 3), rgb(4, 5, 6), rgb(7, 8, 9), rgb(9, 9, 9)]
+        );
+
+        assert!(!map.apply(2, &slots, &mut pixels));
+    }
+
+    #[test]
+    fn apply_handles_short_data_and_strip() {
+        let map = UniverseMap::new(vec![UniverseMapping {
+            universe: 1,
+            start_address: 1,
+            first_pixel: 1,
+            pixel_count: 4,
+            channel_stride: 4,
+        }]);
+        let mut pixels = [rgb(0, 0, 0); 3];
+
+        // Only one complete RGBW pixel in the data.
+        assert!(map.apply(1, &[1, 2, 3, 4, 5, 6], &mut pixels));
+        assert_eq!(pixels, [rgb(0, 0, 0), rgb(1, 2, 3), rgb(0, 0, 0)]);
+
+        // More pixels than the strip has.
+        let slots: Vec<u8> = (0..16).collect();
+        assert!(map.apply(1, &slots, &mut pixels));
+        assert_eq!(pixels, [rgb(0, 0, 0), rgb(0, 1, 2), rgb(4, 5, 6)]);
+
+        // A start address past the universe continues in the next one.
+        let map = UniverseMap::contiguous(1, 600, 3, 3);
+        assert_eq!(map.mappings()[0].universe, 2);
+        assert_eq!(map.mappings()[0].start_address, 1);
+    }
+}
@@ -0,0 +1,102 @@
+//! A receiver of the WLED realtime UDP protocols (WARLS, DRGB, DRGBW and DNRGB), as sent
+//! by LedFx and many audio-reactive senders.
+//!
+//! Every packet is shown right away and sets its own timeout, pixels that a packet
+//! doesn't contain keep their previous color. Long strips are updated in chunks with
+//! DNRGB packets, which start at an arbitrary pixel.
+
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use self::packet::{Packet, Timeout, PORT};
+use crate::light::frame;
+use crate::utils::thread::{self, Core, SpawnError, ThreadConfig};
+
+pub mod packet;
+
+/// The name of this input in the [`frame`] buffer.
+pub const SOURCE_NAME: &str = "wled";
+
+/// The timeout of packets that keep their frame until the next one, practically forever.
+const NO_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum StartError {
+    #[error("failed to bind wled realtime socket")]
+    Bind(#[source] io::Error),
+    #[error("failed to spawn wled realtime thread")]
+    Thread(#[from] SpawnError),
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The thread the receiver runs on.
+    pub thread: ThreadConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            thread: ThreadConfig {
+                name: "wled",
+                core: Some(Core::Core0),
+                priority: 8,
+                stack_size: 4 * 1024,
+            },
+        }
+    }
+}
+
+/// Start receiving WLED realtime packets.
+pub fn start(config: Config) -> Result<(), StartError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PORT)).map_err(StartError::Bind)?;
+
+    thread::spawn(&config.thread, move || run(socket))?;
+
+    Ok(())
+}
+
+fn run(socket: UdpSocket) {
+    // DRGB fits 490 pixels into one packet.
+    let mut buf = [0; 1500];
+
+    loop {
+        match socket.recv_from(&mut buf) {
+            Ok((len, src)) => handle(&buf[..len], src),
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(err) => {
+                log::error!("failed to receive wled realtime packet: {}", err);
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+fn handle(data: &[u8], src: SocketAddr) {
+    let packet = match Packet::parse(data) {
+        Ok(packet) => packet,
+        Err(err) => {
+            log::debug!("invalid wled realtime packet from {}: {}", src, err);
+            return;
+        }
+    };
+
+    let timeout = match packet.timeout {
+        Timeout::Release => {
+            frame::release(SOURCE_NAME);
+            return;
+        }
+        Timeout::After(timeout) => timeout,
+        Timeout::Never => NO_TIMEOUT,
+    };
+
+    frame::write(SOURCE_NAME, |pixels| {
+        packet.for_each_pixel(|i, color| {
+            if let Some(pixel) = pixels.get_mut(i) {
+                *pixel = color;
+            }
+        })
+    });
+    frame::present(SOURCE_NAME, timeout);
+}
@@ -0,0 +1,180 @@
+//! Parsing of the WLED realtime UDP protocols.
+//!
+//! Every packet starts with the protocol and a timeout in seconds, followed by the pixel
+//! data of the protocol:
+//! - WARLS: `[index, r, g, b]` per pixel, for the first 256 pixels.
+//! - DRGB: `[r, g, b]` per pixel, starting at the first pixel.
+//! - DRGBW: `[r, g, b, w]` per pixel, starting at the first pixel.
+//! - DNRGB: the index of the first pixel as big-endian `u16`, then `[r, g, b]` per pixel.
+
+use std::time::Duration;
+
+use crate::light::frame::Pixel;
+
+/// The UDP port of the WLED realtime protocols.
+pub const PORT: u16 = 21324;
+
+const PROTOCOL_WARLS: u8 = 1;
+const PROTOCOL_DRGB: u8 = 2;
+const PROTOCOL_DRGBW: u8 = 3;
+const PROTOCOL_DNRGB: u8 = 4;
+
+/// The timeout byte that keeps the frame until the next packet.
+const TIMEOUT_NEVER: u8 = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ParseError {
+    #[error("packet is too short")]
+    Truncated,
+    #[error("unsupported protocol {0}")]
+    UnsupportedProtocol(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Warls,
+    Drgb,
+    Drgbw,
+    /// DRGB with the index of the first pixel.
+    Dnrgb {
+        start: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timeout {
+    /// Stop showing realtime data immediately.
+    Release,
+    /// Show the light state again after this time without packets.
+    After(Duration),
+    /// Keep the frame until the next packet.
+    Never,
+}
+
+/// A parsed realtime packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packet<'a> {
+    pub protocol: Protocol,
+    pub timeout: Timeout,
+    pub data: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        let (&protocol, rest) = data.split_first().ok_or(ParseError::Truncated)?;
+        let (&timeout, data) = rest.split_first().ok_or(ParseError::Truncated)?;
+
+        let (protocol, data) = match protocol {
+            PROTOCOL_WARLS => (Protocol::Warls, data),
+            PROTOCOL_DRGB => (Protocol::Drgb, data),
+            PROTOCOL_DRGBW => (Protocol::Drgbw, data),
+            PROTOCOL_DNRGB => {
+                let start = data.get(..2).ok_or(ParseError::Truncated)?;
+                let start = u16::from_be_bytes([start[0], start[1]]) as usize;
+                (Protocol::Dnrgb { start }, &data[2..])
+            }
+            _ => return Err(ParseError::UnsupportedProtocol(protocol)),
+        };
+
+        let timeout = match timeout {
+            0 => Timeout::Release,
+            TIMEOUT_NEVER => Timeout::Never,
+            seconds => Timeout::After(Duration::from_secs(seconds as u64)),
+        };
+
+        Ok(Packet {
+            protocol,
+            timeout,
+            data,
+        })
+    }
+
+    /// Call `f` with the index and color of every pixel in the packet.
+    ///
+    /// The white channel of DRGBW is mixed into the other channels, since the strip has
+    /// no white leds. Incomplete pixels at the end are ignored.
+    pub fn for_each_pixel(&self, mut f: impl FnMut(usize, Pixel)) {
+        match self.protocol {
+            Protocol::Warls => {
+                for chunk in self.data.chunks_exact(4) {
+                    f(chunk[0] as usize, Pixel::new(chunk[1], chunk[2], chunk[3]));
+                }
+            }
+            Protocol::Drgb => {
+                for (i, rgb) in self.data.chunks_exact(3).enumerate() {
+                    f(i, Pixel::new(rgb[0], rgb[1], rgb[2]));
+                }
+            }
+            Protocol::Drgbw => {
+                for (i, rgbw) in self.data.chunks_exact(4).enumerate() {
+                    let w = rgbw[3];
+                    f(
+                        i,
+                        Pixel::new(
+                            rgbw[0].saturating_add(w),
+                            rgbw[1].saturating_add(w),
+                            rgbw[2].saturating_add(w),
+                        ),
+                    );
+                }
+            }
+            Protocol::Dnrgb { start } => {
+                for (i, rgb) in self.data.chunks_exact(3).enumerate() {
+                    f(start + i, Pixel::new(rgb[0], rgb[1], rgb[2]));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixels(data: &[u8]) -> Vec<(usize, Pixel)> {
+        let mut pixels = Vec::new();
+        Packet::parse(data)
+            .unwrap()
+            .for_each_pixel(|i, pixel| pixels.push((i, pixel)));
+        pixels
+    }
+
+    #[test]
+    fn parse_protocols() {
+        assert_eq!(
+            pixels(&[1, 2, 5, 1, 2, 3, 0, 4, 5, 6]),
+            [(5, Pixel::new(1, 2, 3)), (0, Pixel::new(4, 5, 6))]
+        );
+        assert_eq!(
+            pixels(&[2, 2, 1, 2, 3, 4, 5, 6, 7]),
+            [(0, Pixel::new(1, 2, 3)), (1, Pixel::new(4, 5, 6))]
+        );
+        assert_eq!(
+            pixels(&[3, 2, 1, 2, 3, 10, 250, 5, 6, 0]),
+            [(0, Pixel::new(11, 12, 13)), (1, Pixel::new(250, 5, 6))]
+        );
+        assert_eq!(
+            pixels(&[4, 2, 0x01, 0x2c, 1, 2, 3, 4, 5, 6]),
+            [(300, Pixel::new(1, 2, 3)), (301, Pixel::new(4, 5, 6))]
+        );
+    }
+
+    #[test]
+    fn parse_timeout() {
+        let timeout = |byte| Packet::parse(&[2, byte]).unwrap().timeout;
+
+        assert_eq!(timeout(0), Timeout::Release);
+        assert_eq!(timeout(3), Timeout::After(Duration::from_secs(3)));
+        assert_eq!(timeout(255), Timeout::Never);
+    }
+
+    #[test]
+    fn reject_invalid() {
+        assert_eq!(Packet::parse(&[2]), Err(ParseError::Truncated));
+        assert_eq!(Packet::parse(&[4, 2, 0]), Err(ParseError::Truncated));
+        assert_eq!(
+            Packet::parse(&[0, 2, 0, 0]),
+            Err(ParseError::UnsupportedProtocol(0))
+        );
+    }
+}
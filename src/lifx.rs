@@ -0,0 +1,376 @@
+//! An emulation of a LIFX multizone strip on the LIFX LAN protocol.
+//!
+//! The power, color and brightness of the light state can be read and changed like a
+//! LIFX bulb. A color sets the primary color of all segments, like the other control
+//! APIs. The extended multizone messages address every pixel as a zone and are shown
+//! through the [`frame`] buffer, until the light state is changed again, by LIFX or any
+//! other API. Transitions of zones aren't supported and are shown immediately.
+//!
+//! The service runs on the shared network [`EXECUTOR`] and polls its non-blocking socket
+//! while idle.
+
+use std::convert::Infallible;
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use self::protocol::{Apply, Header, Hsbk, Message, Packet, EXTENDED_ZONES, PORT, SERVICE_UDP};
+use crate::light::color;
+use crate::light::effect::{self, Effect};
+use crate::light::frame::{self, Pixel};
+use crate::light::state::{self, LightState};
+use crate::utils::executor::spawner::{ExecutorShutDown, Spawner};
+use crate::utils::net::{self, EXECUTOR};
+
+pub mod protocol;
+
+/// The name of this service in the [`frame`] buffer.
+pub const SOURCE_NAME: &str = "lifx";
+
+/// The vendor id of LIFX.
+const VENDOR: u32 = 1;
+/// The product id of the LIFX Z, a strip with extended multizone support.
+const PRODUCT: u32 = 32;
+/// The reported firmware version, clients use extended multizone messages from 2.77 on.
+const FIRMWARE_VERSION: (u16, u16) = (3, 70);
+/// The color temperature reported until a client sets one.
+const DEFAULT_KELVIN: u16 = 3500;
+/// How often the idle socket is checked for messages, zone messages of animations arrive
+/// many times per second.
+const POLL_PERIOD: Duration = Duration::from_millis(10);
+
+#[derive(Debug, thiserror::Error)]
+pub enum StartError {
+    #[error("failed to bind lifx socket")]
+    Bind(#[source] io::Error),
+    #[error("failed to spawn lifx task")]
+    Task(#[from] ExecutorShutDown),
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The label of the device shown by LIFX apps.
+    pub label: String,
+    /// How long zones are shown without a new message.
+    pub zones_timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            label: "esp32-hue".into(),
+            // Zones set by an app stay until they are changed, practically forever.
+            zones_timeout: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// Start answering LIFX LAN messages on the executor of `spawner`.
+pub fn start(config: Config, spawner: &Spawner) -> Result<(), StartError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PORT)).map_err(StartError::Bind)?;
+    socket.set_nonblocking(true).map_err(StartError::Bind)?;
+
+    spawner.spawn(run(socket, config))?;
+
+    Ok(())
+}
+
+struct Device {
+    config: Config,
+    mac: [u8; 6],
+    /// The color temperature last set, the light state only keeps the resulting color.
+    kelvin: u16,
+    /// The zones last set, which are reported back to clients.
+    zones: Vec<Hsbk>,
+}
+
+async fn run(socket: UdpSocket, config: Config) {
+    let mut device = Device {
+        config,
+        mac: net::station_mac(),
+        kelvin: DEFAULT_KELVIN,
+        zones: Vec::new(),
+    };
+    let mut receiver = state::receiver();
+    let mut buf = [0; 1024];
+
+    loop {
+        // Zones would otherwise stay lit when the light is e.g. turned off through Hue.
+        if receiver.has_changed() {
+            receiver.get();
+            frame::release(SOURCE_NAME);
+        }
+
+        match socket.recv_from(&mut buf) {
+            Ok((len, src)) => device.handle(&socket, &buf[..len], src),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                EXECUTOR.sleep(POLL_PERIOD).await;
+            }
+            Err(err) => {
+                log::error!("failed to receive lifx packet: {}", err);
+                EXECUTOR.sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+impl Device {
+    fn handle(&mut self, socket: &UdpSocket, data: &[u8], src: SocketAddr) {
+        let packet = match Packet::parse(data) {
+            Ok(packet) => packet,
+            Err(err) => {
+                log::debug!("invalid lifx packet from {}: {}", src, err);
+                return;
+            }
+        };
+
+        let header = packet.header;
+        let addressed = header.target[..6] == self.mac || header.target[..6] == [0; 6];
+        if !addressed {
+            return;
+        }
+
+        let (response, is_state) = match self.respond(packet.message) {
+            Some(response) => response,
+            None => return,
+        };
+
+        let mut responses = Vec::new();
+        if header.ack_required {
+            responses.push(Message::Acknowledgement);
+        }
+        // Get messages are always answered, set messages only on request.
+        if !is_state || header.res_required {
+            responses.extend(response);
+        }
+
+        let mut target = [0; 8];
+        target[..6].copy_from_slice(&self.mac);
+        for message in responses {
+            let packet = Packet {
+                header: Header {
+                    tagged: false,
+                    source: header.source,
+                    target,
+                    ack_required: false,
+                    res_required: false,
+                    sequence: header.sequence,
+                },
+                message,
+            };
+            if let Err(err) = socket.send_to(&packet.encode(), src) {
+                log::warn!("failed to send lifx response to {}: {}", src, err);
+            }
+        }
+    }
+
+    /// Handle `message` and return the responses, and whether they are only sent on
+    /// request because `message` changed the state.
+    ///
+    /// Returns `None` for messages the device doesn't answer.
+    fn respond(&mut self, message: Message) -> Option<(Vec<Message>, bool)> {
+        let responses = match message {
+            Message::GetService => vec![Message::StateService {
+                service: SERVICE_UDP,
+                port: PORT as u32,
+            }],
+            Message::GetHostFirmware => vec![Message::StateHostFirmware {
+                build: 0,
+                version_minor: FIRMWARE_VERSION.1,
+                version_major: FIRMWARE_VERSION.0,
+            }],
+            Message::GetVersion => vec![Message::StateVersion {
+                vendor: VENDOR,
+                product: PRODUCT,
+            }],
+            Message::GetLabel => vec![Message::StateLabel {
+                label: self.config.label.clone(),
+            }],
+            Message::EchoRequest { payload } => vec![Message::EchoResponse { payload }],
+            Message::GetPower => vec![Message::StatePower {
+                level: power(&state::get()),
+            }],
+            Message::LightGetPower => vec![Message::LightStatePower {
+                level: power(&state::get()),
+            }],
+            Message::LightGet => vec![self.light_state(&state::get())],
+            Message::GetExtendedColorZones => self.zones_state(),
+            Message::SetPower { level } => {
+                let state = self.set_power(level, None);
+                return Some((
+                    vec![Message::StatePower {
+                        level: power(&state),
+                    }],
+                    true,
+                ));
+            }
+            Message::LightSetPower { level, duration } => {
+                let state = self.set_power(level, Some(duration));
+                return Some((
+                    vec![Message::LightStatePower {
+                        level: power(&state),
+                    }],
+                    true,
+                ));
+            }
+            Message::LightSetColor { color, duration } => {
+                let state = self.set_color(color, duration);
+                return Some((vec![self.light_state(&state)], true));
+            }
+            Message::SetExtendedColorZones {
+                apply,
+                index,
+                colors,
+                ..
+            } => {
+                self.set_zones(apply, index, &colors);
+                return Some((self.zones_state(), true));
+            }
+            Message::Unknown(message_type) => {
+                log::debug!("unsupported lifx message type {}", message_type);
+                return None;
+            }
+            // State messages are only sent by devices.
+            _ => return None,
+        };
+
+        Some((responses, false))
+    }
+
+    fn set_power(&mut self, level: u16, duration: Option<u32>) -> LightState {
+        frame::release(SOURCE_NAME);
+        update(|state| {
+            state.on = level != 0;
+            if let Some(duration) = duration {
+                state.transition = Duration::from_millis(duration as u64);
+            }
+        })
+    }
+
+    fn set_color(&mut self, color: Hsbk, duration: u32) -> LightState {
+        self.kelvin = color.kelvin;
+        frame::release(SOURCE_NAME);
+
+        let pixel = hsbk_to_pixel(color);
+        update(|state| {
+            state.brightness = (color.brightness / 257) as u8;
+            state.transition = Duration::from_millis(duration as u64);
+            for segment in &mut state.segments {
+                segment.colors[0] = pixel;
+                if !segment.effect.uses_colors() {
+                    segment.effect = Effect::Solid;
+                }
+            }
+        })
+    }
+
+    fn set_zones(&mut self, apply: Apply, index: u16, colors: &[Hsbk]) {
+        let len = frame::len();
+        self.zones.resize(len, Hsbk::default());
+
+        if apply != Apply::ApplyOnly {
+            let start = (index as usize).min(len);
+            let end = (start + colors.len()).min(len);
+            self.zones[start..end].copy_from_slice(&colors[..end - start]);
+        }
+        if apply == Apply::NoApply {
+            return;
+        }
+
+        let zones = &self.zones;
+        frame::write(SOURCE_NAME, |pixels| {
+            for (pixel, zone) in pixels.iter_mut().zip(zones) {
+                let mut color = [hsbk_to_pixel(*zone)];
+                effect::dim(&mut color, (zone.brightness / 257) as u8);
+                *pixel = color[0];
+            }
+        });
+        frame::present(SOURCE_NAME, self.config.zones_timeout);
+    }
+
+    fn light_state(&self, state: &LightState) -> Message {
+        let color = state
+            .segments
+            .first()
+            .map_or(Pixel::new(0, 0, 0), |s| s.colors[0]);
+
+        Message::LightState {
+            color: Hsbk {
+                brightness: state.brightness as u16 * 257,
+                ..pixel_to_hsbk(color, self.kelvin)
+            },
+            power: power(state),
+            label: self.config.label.clone(),
+        }
+    }
+
+    /// The zones in as many messages as needed, pixels without a zone set are reported
+    /// as they are shown by the light state.
+    fn zones_state(&self) -> Vec<Message> {
+        let len = frame::len();
+        let mut zones = self.zones.clone();
+        if zones.len() < len {
+            let mut pixels = vec![Pixel::new(0, 0, 0); len];
+            let state = state::get();
+            state::render(&state, Duration::ZERO, &mut pixels);
+            zones.extend(
+                pixels[zones.len()..]
+                    .iter()
+                    .map(|pixel| pixel_to_hsbk(*pixel, self.kelvin)),
+            );
+        }
+        zones.truncate(len);
+
+        zones
+            .chunks(EXTENDED_ZONES)
+            .enumerate()
+            .map(|(i, colors)| Message::StateExtendedColorZones {
+                count: len as u16,
+                index: (i * EXTENDED_ZONES) as u16,
+                colors: colors.to_vec(),
+            })
+            .collect()
+    }
+}
+
+/// Change the light state with `f`, which can't fail.
+fn update(f: impl FnOnce(&mut LightState)) -> LightState {
+    let result = state::update::<Infallible>(|state| {
+        f(state);
+        Ok(())
+    });
+    match result {
+        Ok(state) => state,
+        Err(never) => match never {},
+    }
+}
+
+fn power(state: &LightState) -> u16 {
+    if state.on {
+        u16::MAX
+    } else {
+        0
+    }
+}
+
+/// The color of `color` at full brightness, unsaturated colors are white of the color
+/// temperature.
+fn hsbk_to_pixel(color: Hsbk) -> Pixel {
+    let hue = color.hue as f32 * 360.0 / 65536.0;
+    let saturation = color.saturation as f32 / 65535.0;
+    effect::blend(
+        color::from_temperature(color.kelvin),
+        color::from_hs(hue, 1.0),
+        saturation,
+    )
+}
+
+fn pixel_to_hsbk(pixel: Pixel, kelvin: u16) -> Hsbk {
+    let (hue, saturation, value) = color::to_hsv(pixel);
+    Hsbk {
+        hue: (hue / 360.0 * 65536.0) as u16,
+        saturation: (saturation * 65535.0).round() as u16,
+        brightness: (value * 65535.0).round() as u16,
+        kelvin,
+    }
+}
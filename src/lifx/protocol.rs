@@ -0,0 +1,698 @@
+//! Encoding and decoding of LIFX LAN protocol packets.
+//!
+//! A packet is a 36 byte header followed by the payload of its message type, all fields
+//! are little-endian. Only the messages of the device, light and multizone types that the
+//! emulated strip handles are supported, others are decoded as [`Message::Unknown`].
+
+/// The UDP port of the LIFX LAN protocol.
+pub const PORT: u16 = 56700;
+/// The length of the header.
+pub const HEADER_LEN: usize = 36;
+/// The amount of zones in one extended color zones message.
+pub const EXTENDED_ZONES: usize = 82;
+/// The service of [`Message::StateService`] that means UDP.
+pub const SERVICE_UDP: u8 = 1;
+
+const PROTOCOL: u16 = 1024;
+const FLAG_ADDRESSABLE: u16 = 1 << 12;
+const FLAG_TAGGED: u16 = 1 << 13;
+const FLAG_RES_REQUIRED: u8 = 1 << 0;
+const FLAG_ACK_REQUIRED: u8 = 1 << 1;
+const LABEL_LEN: usize = 32;
+const ECHO_LEN: usize = 64;
+
+const GET_SERVICE: u16 = 2;
+const STATE_SERVICE: u16 = 3;
+const GET_HOST_FIRMWARE: u16 = 14;
+const STATE_HOST_FIRMWARE: u16 = 15;
+const GET_POWER: u16 = 20;
+const SET_POWER: u16 = 21;
+const STATE_POWER: u16 = 22;
+const GET_LABEL: u16 = 23;
+const STATE_LABEL: u16 = 25;
+const GET_VERSION: u16 = 32;
+const STATE_VERSION: u16 = 33;
+const ACKNOWLEDGEMENT: u16 = 45;
+const ECHO_REQUEST: u16 = 58;
+const ECHO_RESPONSE: u16 = 59;
+const LIGHT_GET: u16 = 101;
+const LIGHT_SET_COLOR: u16 = 102;
+const LIGHT_STATE: u16 = 107;
+const LIGHT_GET_POWER: u16 = 116;
+const LIGHT_SET_POWER: u16 = 117;
+const LIGHT_STATE_POWER: u16 = 118;
+const SET_EXTENDED_COLOR_ZONES: u16 = 510;
+const GET_EXTENDED_COLOR_ZONES: u16 = 511;
+const STATE_EXTENDED_COLOR_ZONES: u16 = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ParseError {
+    #[error("packet is too short")]
+    Truncated,
+    #[error("packet size {0} doesn't match the received length")]
+    InvalidSize(u16),
+    #[error("unsupported protocol {0}")]
+    UnsupportedProtocol(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Header {
+    /// Whether the packet is addressed to all devices, then `target` is zero.
+    pub tagged: bool,
+    /// The id of the client, which is copied into responses.
+    pub source: u32,
+    /// The MAC address of the device, followed by two zero bytes.
+    pub target: [u8; 8],
+    pub ack_required: bool,
+    pub res_required: bool,
+    /// The sequence number of the client, which is copied into responses.
+    pub sequence: u8,
+}
+
+/// A color as hue, saturation, brightness and color temperature in kelvin.
+///
+/// Hue, saturation and brightness use the whole range of `u16`. The color temperature
+/// is only used for unsaturated colors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Hsbk {
+    pub hue: u16,
+    pub saturation: u16,
+    pub brightness: u16,
+    pub kelvin: u16,
+}
+
+/// Whether a [`Message::SetExtendedColorZones`] is shown.
+///
+/// The variants are named like the `MultiZoneApplicationRequest` values of the protocol.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Apply {
+    /// Only store the zones, until a later message applies them.
+    NoApply,
+    /// Store the zones and show all stored zones.
+    Apply,
+    /// Show all stored zones without storing the zones of this message.
+    ApplyOnly,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    GetService,
+    StateService {
+        service: u8,
+        port: u32,
+    },
+    GetHostFirmware,
+    StateHostFirmware {
+        build: u64,
+        version_minor: u16,
+        version_major: u16,
+    },
+    GetPower,
+    SetPower {
+        level: u16,
+    },
+    StatePower {
+        level: u16,
+    },
+    GetLabel,
+    StateLabel {
+        label: String,
+    },
+    GetVersion,
+    StateVersion {
+        vendor: u32,
+        product: u32,
+    },
+    Acknowledgement,
+    EchoRequest {
+        payload: [u8; ECHO_LEN],
+    },
+    EchoResponse {
+        payload: [u8; ECHO_LEN],
+    },
+    LightGet,
+    LightSetColor {
+        color: Hsbk,
+        /// The transition time in milliseconds.
+        duration: u32,
+    },
+    LightState {
+        color: Hsbk,
+        power: u16,
+        label: String,
+    },
+    LightGetPower,
+    LightSetPower {
+        level: u16,
+        /// The transition time in milliseconds.
+        duration: u32,
+    },
+    LightStatePower {
+        level: u16,
+    },
+    SetExtendedColorZones {
+        /// The transition time in milliseconds.
+        duration: u32,
+        apply: Apply,
+        /// The index of the first zone of `colors`.
+        index: u16,
+        colors: Vec<Hsbk>,
+    },
+    GetExtendedColorZones,
+    StateExtendedColorZones {
+        /// The amount of zones of the device.
+        count: u16,
+        /// The index of the first zone of `colors`.
+        index: u16,
+        colors: Vec<Hsbk>,
+    },
+    /// A message the device doesn't handle, with its type.
+    Unknown(u16),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub header: Header,
+    pub message: Message,
+}
+
+impl Packet {
+    pub fn parse(data: &[u8]) -> Result<Self, ParseError> {
+        let header = data.get(..HEADER_LEN).ok_or(ParseError::Truncated)?;
+        let mut reader = Reader(header);
+
+        let size = reader.u16();
+        if size as usize != data.len() {
+            return Err(ParseError::InvalidSize(size));
+        }
+        let protocol = reader.u16();
+        if protocol & 0x0fff != PROTOCOL {
+            return Err(ParseError::UnsupportedProtocol(protocol & 0x0fff));
+        }
+        let source = reader.u32();
+        let target = reader.array();
+        reader.skip(6);
+        let flags = reader.u8();
+        let sequence = reader.u8();
+        reader.skip(8);
+        let message_type = reader.u16();
+
+        let header = Header {
+            tagged: protocol & FLAG_TAGGED != 0,
+            source,
+            target,
+            ack_required: flags & FLAG_ACK_REQUIRED != 0,
+            res_required: flags & FLAG_RES_REQUIRED != 0,
+            sequence,
+        };
+        let message = Message::decode(message_type, &data[HEADER_LEN..])?;
+
+        Ok(Packet { header, message })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        let message_type = self.message.encode(&mut payload);
+
+        let mut protocol = PROTOCOL | FLAG_ADDRESSABLE;
+        if self.header.tagged {
+            protocol |= FLAG_TAGGED;
+        }
+        let mut flags = 0;
+        if self.header.ack_required {
+            flags |= FLAG_ACK_REQUIRED;
+        }
+        if self.header.res_required {
+            flags |= FLAG_RES_REQUIRED;
+        }
+
+        let mut out = Vec::with_capacity(HEADER_LEN + payload.len());
+        out.extend(((HEADER_LEN + payload.len()) as u16).to_le_bytes());
+        out.extend(protocol.to_le_bytes());
+        out.extend(self.header.source.to_le_bytes());
+        out.extend(self.header.target);
+        out.extend([0; 6]);
+        out.extend([flags, self.header.sequence]);
+        out.extend([0; 8]);
+        out.extend(message_type.to_le_bytes());
+        out.extend([0; 2]);
+        out.extend(payload);
+        out
+    }
+}
+
+impl Message {
+    fn decode(message_type: u16, payload: &[u8]) -> Result<Self, ParseError> {
+        let mut reader = Reader(payload);
+        let message = match message_type {
+            GET_SERVICE => Message::GetService,
+            STATE_SERVICE => {
+                reader.check(5)?;
+                Message::StateService {
+                    service: reader.u8(),
+                    port: reader.u32(),
+                }
+            }
+            GET_HOST_FIRMWARE => Message::GetHostFirmware,
+            STATE_HOST_FIRMWARE => {
+                reader.check(20)?;
+                let build = reader.u64();
+                reader.skip(8);
+                Message::StateHostFirmware {
+                    build,
+                    version_minor: reader.u16(),
+                    version_major: reader.u16(),
+                }
+            }
+            GET_POWER => Message::GetPower,
+            SET_POWER | STATE_POWER | LIGHT_STATE_POWER => {
+                reader.check(2)?;
+                let level = reader.u16();
+                match message_type {
+                    SET_POWER => Message::SetPower { level },
+                    STATE_POWER => Message::StatePower { level },
+                    _ => Message::LightStatePower { level },
+                }
+            }
+            GET_LABEL => Message::GetLabel,
+            STATE_LABEL => {
+                reader.check(LABEL_LEN)?;
+                Message::StateLabel {
+                    label: reader.label(),
+                }
+            }
+            GET_VERSION => Message::GetVersion,
+            STATE_VERSION => {
+                reader.check(12)?;
+                Message::StateVersion {
+                    vendor: reader.u32(),
+                    product: reader.u32(),
+                }
+            }
+            ACKNOWLEDGEMENT => Message::Acknowledgement,
+            ECHO_REQUEST | ECHO_RESPONSE => {
+                reader.check(ECHO_LEN)?;
+                let payload = reader.array();
+                match message_type {
+                    ECHO_REQUEST => Message::EchoRequest { payload },
+                    _ => Message::EchoResponse { payload },
+                }
+            }
+            LIGHT_GET => Message::LightGet,
+            LIGHT_SET_COLOR => {
+                reader.check(13)?;
+                reader.skip(1);
+                Message::LightSetColor {
+                    color: reader.hsbk(),
+                    duration: reader.u32(),
+                }
+            }
+            LIGHT_STATE => {
+                reader.check(52)?;
+                let color = reader.hsbk();
+                reader.skip(2);
+                Message::LightState {
+                    color,
+                    power: reader.u16(),
+                    label: reader.label(),
+                }
+            }
+            LIGHT_GET_POWER => Message::LightGetPower,
+            LIGHT_SET_POWER => {
+                reader.check(6)?;
+                Message::LightSetPower {
+                    level: reader.u16(),
+                    duration: reader.u32(),
+                }
+            }
+            SET_EXTENDED_COLOR_ZONES => {
+                reader.check(8 + EXTENDED_ZONES * 8)?;
+                let duration = reader.u32();
+                let apply = match reader.u8() {
+                    0 => Apply::NoApply,
+                    2 => Apply::ApplyOnly,
+                    _ => Apply::Apply,
+                };
+                let index = reader.u16();
+                let colors = reader.zones();
+                Message::SetExtendedColorZones {
+                    duration,
+                    apply,
+                    index,
+                    colors,
+                }
+            }
+            GET_EXTENDED_COLOR_ZONES => Message::GetExtendedColorZones,
+            STATE_EXTENDED_COLOR_ZONES => {
+                reader.check(5 + EXTENDED_ZONES * 8)?;
+                let count = reader.u16();
+                let index = reader.u16();
+                let colors = reader.zones();
+                Message::StateExtendedColorZones {
+                    count,
+                    index,
+                    colors,
+                }
+            }
+            message_type => Message::Unknown(message_type),
+        };
+
+        Ok(message)
+    }
+
+    /// Append the payload to `out` and return the message type.
+    ///
+    /// # Panics
+    /// If the message has more than [`EXTENDED_ZONES`] colors, or is
+    /// [`Message::Unknown`].
+    fn encode(&self, out: &mut Vec<u8>) -> u16 {
+        match self {
+            Message::GetService => GET_SERVICE,
+            Message::StateService { service, port } => {
+                out.push(*service);
+                out.extend(port.to_le_bytes());
+                STATE_SERVICE
+            }
+            Message::GetHostFirmware => GET_HOST_FIRMWARE,
+            Message::StateHostFirmware {
+                build,
+                version_minor,
+                version_major,
+            } => {
+                out.extend(build.to_le_bytes());
+                out.extend([0; 8]);
+                out.extend(version_minor.to_le_bytes());
+                out.extend(version_major.to_le_bytes());
+                STATE_HOST_FIRMWARE
+            }
+            Message::GetPower => GET_POWER,
+            Message::SetPower { level } => {
+                out.extend(level.to_le_bytes());
+                SET_POWER
+            }
+            Message::StatePower { level } => {
+                out.extend(level.to_le_bytes());
+                STATE_POWER
+            }
+            Message::GetLabel => GET_LABEL,
+            Message::StateLabel { label } => {
+                write_label(out, label);
+                STATE_LABEL
+            }
+            Message::GetVersion => GET_VERSION,
+            Message::StateVersion { vendor, product } => {
+                out.extend(vendor.to_le_bytes());
+                out.extend(product.to_le_bytes());
+                out.extend([0; 4]);
+                STATE_VERSION
+            }
+            Message::Acknowledgement => ACKNOWLEDGEMENT,
+            Message::EchoRequest { payload } => {
+                out.extend(payload);
+                ECHO_REQUEST
+            }
+            Message::EchoResponse { payload } => {
+                out.extend(payload);
+                ECHO_RESPONSE
+            }
+            Message::LightGet => LIGHT_GET,
+            Message::LightSetColor { color, duration } => {
+                out.push(0);
+                write_hsbk(out, color);
+                out.extend(duration.to_le_bytes());
+                LIGHT_SET_COLOR
+            }
+            Message::LightState {
+                color,
+                power,
+                label,
+            } => {
+                write_hsbk(out, color);
+                out.extend([0; 2]);
+                out.extend(power.to_le_bytes());
+                write_label(out, label);
+                out.extend([0; 8]);
+                LIGHT_STATE
+            }
+            Message::LightGetPower => LIGHT_GET_POWER,
+            Message::LightSetPower { level, duration } => {
+                out.extend(level.to_le_bytes());
+                out.extend(duration.to_le_bytes());
+                LIGHT_SET_POWER
+            }
+            Message::LightStatePower { level } => {
+                out.extend(level.to_le_bytes());
+                LIGHT_STATE_POWER
+            }
+            Message::SetExtendedColorZones {
+                duration,
+                apply,
+                index,
+                colors,
+            } => {
+                out.extend(duration.to_le_bytes());
+                out.push(match apply {
+                    Apply::NoApply => 0,
+                    Apply::Apply => 1,
+                    Apply::ApplyOnly => 2,
+                });
+                out.extend(index.to_le_bytes());
+                write_zones(out, colors);
+                SET_EXTENDED_COLOR_ZONES
+            }
+            Message::GetExtendedColorZones => GET_EXTENDED_COLOR_ZONES,
+            Message::StateExtendedColorZones {
+                count,
+                index,
+                colors,
+            } => {
+                out.extend(count.to_le_bytes());
+                out.extend(index.to_le_bytes());
+                write_zones(out, colors);
+                STATE_EXTENDED_COLOR_ZONES
+            }
+            Message::Unknown(message_type) => {
+                panic!("can't encode unknown message type {}", message_type)
+            }
+        }
+    }
+}
+
+fn write_hsbk(out: &mut Vec<u8>, color: &Hsbk) {
+    out.extend(color.hue.to_le_bytes());
+    out.extend(color.saturation.to_le_bytes());
+    out.extend(color.brightness.to_le_bytes());
+    out.extend(color.kelvin.to_le_bytes());
+}
+
+/// Write the amount of `colors` and always [`EXTENDED_ZONES`] colors.
+fn write_zones(out: &mut Vec<u8>, colors: &[Hsbk]) {
+    assert!(colors.len() <= EXTENDED_ZONES, "too many zones");
+
+    out.push(colors.len() as u8);
+    for color in colors {
+        write_hsbk(out, color);
+    }
+    out.resize(out.len() + (EXTENDED_ZONES - colors.len()) * 8, 0);
+}
+
+/// Write `label` as UTF-8, truncated or padded with zeros to 32 bytes.
+fn write_label(out: &mut Vec<u8>, label: &str) {
+    let mut bytes = [0; LABEL_LEN];
+    let len = label.len().min(LABEL_LEN);
+    bytes[..len].copy_from_slice(&label.as_bytes()[..len]);
+    out.extend(bytes);
+}
+
+/// Reads little-endian fields, the length must be checked with [`Reader::check`] before.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn check(&self, len: usize) -> Result<(), ParseError> {
+        if self.0.len() < len {
+            return Err(ParseError::Truncated);
+        }
+        Ok(())
+    }
+
+    fn skip(&mut self, len: usize) {
+        self.0 = &self.0[len..];
+    }
+
+    fn array<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(&self.0[..N]);
+        self.skip(N);
+        bytes
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.array::<1>()[0]
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.array())
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.array())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.array())
+    }
+
+    fn hsbk(&mut self) -> Hsbk {
+        Hsbk {
+            hue: self.u16(),
+            saturation: self.u16(),
+            brightness: self.u16(),
+            kelvin: self.u16(),
+        }
+    }
+
+    /// A label up to the first zero byte, invalid UTF-8 is replaced.
+    fn label(&mut self) -> String {
+        let bytes: [u8; LABEL_LEN] = self.array();
+        let len = bytes.iter().position(|&b| b == 0).unwrap_or(LABEL_LEN);
+        String::from_utf8_lossy(&bytes[..len]).into_owned()
+    }
+
+    /// The amount of colors and the [`EXTENDED_ZONES`] colors, of which only the used
+    /// ones are returned.
+    fn zones(&mut self) -> Vec<Hsbk> {
+        let count = (self.u8() as usize).min(EXTENDED_ZONES);
+        let colors = (0..count).map(|_| self.hsbk()).collect();
+        self.skip((EXTENDED_ZONES - count) * 8);
+        colors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(message: Message) {
+        let packet = Packet {
+            header: Header {
+                tagged: false,
+                source: 0x1234_5678,
+                target: [1, 2, 3, 4, 5, 6, 0, 0],
+                ack_required: true,
+                res_required: false,
+                sequence: 42,
+            },
+            message,
+        };
+
+        let data = packet.encode();
+        assert_eq!(Packet::parse(&data), Ok(packet));
+    }
+
+    #[test]
+    fn roundtrip_messages() {
+        let color = Hsbk {
+            hue: 21845,
+            saturation: 65535,
+            brightness: 32768,
+            kelvin: 3500,
+        };
+
+        roundtrip(Message::GetService);
+        roundtrip(Message::StateService {
+            service: SERVICE_UDP,
+            port: PORT as u32,
+        });
+        roundtrip(Message::StateHostFirmware {
+            build: 1_600_000_000,
+            version_minor: 77,
+            version_major: 2,
+        });
+        roundtrip(Message::SetPower { level: 65535 });
+        roundtrip(Message::StatePower { level: 0 });
+        roundtrip(Message::StateLabel {
+            label: "Strip".into(),
+        });
+        roundtrip(Message::StateVersion {
+            vendor: 1,
+            product: 32,
+        });
+        roundtrip(Message::EchoRequest { payload: [7; 64] });
+        roundtrip(Message::LightSetColor {
+            color,
+            duration: 1000,
+        });
+        roundtrip(Message::LightState {
+            color,
+            power: 65535,
+            label: "Strip".into(),
+        });
+        roundtrip(Message::LightSetPower {
+            level: 0,
+            duration: 500,
+        });
+        roundtrip(Message::LightStatePower { level: 65535 });
+        roundtrip(Message::SetExtendedColorZones {
+            duration: 0,
+            apply: Apply::NoApply,
+            index: 82,
+            colors: vec![color; 3],
+        });
+        roundtrip(Message::StateExtendedColorZones {
+            count: 120,
+            index: 0,
+            colors: vec![color; EXTENDED_ZONES],
+        });
+    }
+
+    #[test]
+    fn parse_header() {
+        // A tagged GetService as sent by clients for discovery.
+        let data = [
+            0x24, 0x00, 0x00, 0x34, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x05, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+        ];
+        let packet = Packet::parse(&data).unwrap();
+
+        assert_eq!(
+            packet.header,
+            Header {
+                tagged: true,
+                source: 1,
+                target: [0; 8],
+                ack_required: false,
+                res_required: true,
+                sequence: 5,
+            }
+        );
+        assert_eq!(packet.message, Message::GetService);
+        assert_eq!(packet.encode(), data);
+    }
+
+    #[test]
+    fn reject_invalid() {
+        let mut data = Packet {
+            header: Header::default(),
+            message: Message::LightSetColor {
+                color: Hsbk::default(),
+                duration: 0,
+            },
+        }
+        .encode();
+
+        assert_eq!(
+            Packet::parse(&data[..40]),
+            Err(ParseError::InvalidSize(data.len() as u16))
+        );
+        data[0] = 40;
+        assert_eq!(Packet::parse(&data[..40]), Err(ParseError::Truncated));
+        data[0] = data.len() as u8;
+        data[3] = 0x10;
+        assert_eq!(
+            Packet::parse(&data),
+            Err(ParseError::UnsupportedProtocol(0x0000))
+        );
+    }
+}
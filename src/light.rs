@@ -1,96 +1,435 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::AtomicBool;
 use std::time::Duration;
 
-use embedded_svc::channel::asynch::Receiver;
-// use embedded_svc::executor::asynch::{Executor, WaitableExecutor};
-use embedded_svc::timer::asynch::{OnceTimer, PeriodicTimer};
-use esp_idf_hal::gpio::OutputPin;
-use esp_idf_hal::units::{FromValueType, MicroSecondsU64};
-use esp_idf_hal::{self, rmt};
-// use esp_idf_svc::executor::asynch::isr::tasks_spawner;
-use esp_idf_sys::EspError;
-use futures::channel::mpsc::{self, channel, Sender};
-use futures::{pin_mut, select, FutureExt, StreamExt};
-use heapless::mpmc::MpMcQueue;
-use palette::convert::IntoColorUnclamped;
-use palette::rgb::Rgb;
-use palette::Packed;
-
-use crate::driver::ws2811::{Color, ColorGroup, Ws2811};
+use futures::channel::mpsc::Sender;
+
+use crate::utils::executor::stats::ExecutorStats;
 use crate::utils::executor::Executor;
-use crate::utils::timer::EspTimer;
 
-#[derive(Debug, thiserror::Error)]
+// The light service itself only runs on the ESP32, the modules below are platform
+// independent.
+#[cfg(target_os = "espidf")]
+use {
+    crate::driver::ws2811::{Color, ColorGroup, LedTimings, Ws2811, NEOPIXEL},
+    crate::utils::health::{self, Heartbeat, ServiceConfig},
+    crate::utils::thread::{self, SpawnError, ThreadConfig},
+    crate::utils::timer::MissedTickBehavior,
+    crate::utils::ResultExt,
+    esp_idf_hal::cpu::Core,
+    esp_idf_hal::gpio::OutputPin,
+    esp_idf_hal::{self, rmt},
+    esp_idf_sys::EspError,
+    futures::channel::mpsc::{self, channel},
+    futures::channel::oneshot,
+    futures::SinkExt,
+    futures::{pin_mut, select, FutureExt, StreamExt},
+    std::fmt,
+    std::sync::atomic::Ordering,
+    std::sync::Arc,
+    std::time::Instant,
+};
+
+pub mod color;
+pub mod effect;
+pub mod frame;
+pub mod state;
+
+/// The error of [`start`], with the peripherals to start the service again.
+#[cfg(target_os = "espidf")]
+#[derive(thiserror::Error)]
 #[error("failed to start light service")]
-pub struct StartError(#[from] InitError);
+pub struct StartError<P> {
+    #[source]
+    pub error: InitError,
+    /// The pin and RMT channel given to [`start`], `None` if the RMT driver failed to
+    /// take them.
+    pub peripherals: Option<(P, rmt::CHANNEL0)>,
+}
 
+#[cfg(target_os = "espidf")]
+impl<P> fmt::Debug for StartError<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StartError")
+            .field("error", &self.error)
+            .field("peripherals", &self.peripherals.is_some())
+            .finish()
+    }
+}
+
+#[cfg(target_os = "espidf")]
 #[derive(Debug, thiserror::Error)]
 pub enum InitError {
     #[error("failed to initialize rmt peripheral")]
     Rmt(#[source] EspError),
+    #[error("failed to spawn light thread")]
+    Thread(#[from] SpawnError),
+    #[error("light service is already running")]
+    AlreadyRunning,
+}
+
+#[cfg(target_os = "espidf")]
+#[derive(Debug, thiserror::Error)]
+pub enum ShutdownError {
+    #[error("light service stopped unexpectedly")]
+    Stopped,
+    #[error("failed to release rmt peripheral")]
+    Rmt(#[source] EspError),
+}
+
+#[cfg(target_os = "espidf")]
+#[derive(thiserror::Error)]
+pub enum RestartError<P> {
+    #[error("failed to stop light service for a restart")]
+    Shutdown(#[from] ShutdownError),
+    #[error("failed to start light service again after stopping it")]
+    Start(#[from] StartError<P>),
+}
+
+#[cfg(target_os = "espidf")]
+impl<P> fmt::Debug for RestartError<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestartError::Shutdown(err) => f.debug_tuple("Shutdown").field(err).finish(),
+            RestartError::Start(err) => f.debug_tuple("Start").field(err).finish(),
+        }
+    }
+}
+
+/// Only sent by the [`LightService`], so that the peripherals are released on shutdown.
+pub(crate) enum Message {
+    /// Stop the light service, sent by [`LightService::shutdown`].
+    Shutdown,
+}
+
+pub(crate) type MessageSender = Sender<Message>;
+
+#[cfg(target_os = "espidf")]
+#[derive(Clone)]
+pub struct Config {
+    /// The amount of LEDs on the strip.
+    pub num_leds: u16,
+    /// The timings of the LED protocol.
+    pub timings: LedTimings,
+    /// The amount of frames rendered per second.
+    pub target_fps: u32,
+    /// The thread the light service runs on.
+    pub thread: ThreadConfig,
+}
+
+#[cfg(target_os = "espidf")]
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            num_leds: 10,
+            timings: NEOPIXEL,
+            target_fps: 60,
+            // Rendering runs on the APP CPU, networking on the PRO CPU.
+            thread: ThreadConfig {
+                name: "light",
+                core: Some(Core::Core1),
+                priority: 10,
+                stack_size: 8 * 1024,
+            },
+        }
+    }
+}
+
+/// Timing measurements of the last frame.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameStats {
+    /// The time it took to compute the colors of the frame.
+    pub render_time: Duration,
+    /// The time it took to send the frame to the strip.
+    pub transmit_time: Duration,
+    /// The time between the start of the previous and this frame.
+    pub frame_time: Duration,
+}
+
+static FRAME_STATS: spin::Mutex<FrameStats> = spin::Mutex::new(FrameStats {
+    render_time: Duration::ZERO,
+    transmit_time: Duration::ZERO,
+    frame_time: Duration::ZERO,
+});
+
+static EXECUTOR: Executor = Executor::new();
+/// Whether [`EXECUTOR`] is currently used by a light service thread.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// The name of the light service in the [`health`] monitor.
+pub const SERVICE_NAME: &str = "light";
+
+/// Get the timing measurements of the last frame shown by the light service.
+pub fn frame_stats() -> FrameStats {
+    *FRAME_STATS.lock()
+}
+
+/// Get the per-task statistics of the light service executor.
+pub fn executor_stats() -> Option<ExecutorStats> {
+    EXECUTOR.stats()
+}
+
+/// A handle to the running light service.
+///
+/// If the handle and all [`MessageSender`]s are dropped, the service stops and the
+/// peripherals are dropped with it.
+#[cfg(target_os = "espidf")]
+pub struct LightService<P: OutputPin> {
+    sender: MessageSender,
+    stopped: oneshot::Receiver<Ws2811<P>>,
+    /// Keeps the service registered if it stops on its own, so that it misses its
+    /// heartbeat and gets restarted.
+    _heartbeat: Arc<Heartbeat>,
+}
+
+#[cfg(target_os = "espidf")]
+impl<P: OutputPin + Send + 'static> LightService<P> {
+    /// Get a sender to send messages to the light service.
+    pub(crate) fn sender(&self) -> MessageSender {
+        self.sender.clone()
+    }
+
+    /// Stop the light service and return the pin and RMT channel.
+    ///
+    /// The strip is turned off before the service stops.
+    pub async fn shutdown(mut self) -> Result<(P, rmt::CHANNEL0), ShutdownError> {
+        // Fails if the service already stopped, which is detected below.
+        let _ = self.sender.send(Message::Shutdown).await;
+
+        let ws2811 = self.stopped.await.map_err(|_| ShutdownError::Stopped)?;
+        ws2811.release().map_err(ShutdownError::Rmt)
+    }
+
+    /// Stop the light service and start it again with `config`.
+    ///
+    /// The service is started on the pin returned by `pin`, which gets the pin the
+    /// service used until now. Return it to keep using the same pin.
+    pub async fn restart<Q, F>(
+        self,
+        pin: F,
+        config: Config,
+    ) -> Result<LightService<Q>, RestartError<Q>>
+    where
+        Q: OutputPin + Send + 'static,
+        F: FnOnce(P) -> Q,
+    {
+        let (old_pin, rmt_channel) = self.shutdown().await?;
+
+        Ok(start(pin(old_pin), rmt_channel, config)?)
+    }
 }
 
-pub enum Message {}
+/// Start the light service on its own thread.
+///
+/// If the service can't be started, the error returns `pin` and `rmt_channel` unless
+/// the RMT driver failed to take them.
+#[cfg(target_os = "espidf")]
+pub fn start<P: OutputPin + Send + 'static>(
+    pin: P,
+    rmt_channel: rmt::CHANNEL0,
+    config: Config,
+) -> Result<LightService<P>, StartError<P>> {
+    if RUNNING.swap(true, Ordering::SeqCst) {
+        return Err(StartError {
+            error: InitError::AlreadyRunning,
+            peripherals: Some((pin, rmt_channel)),
+        });
+    }
 
-pub type MessageSender = Sender<Message>;
+    start_thread(pin, rmt_channel, config).map_err(|err| {
+        RUNNING.store(false, Ordering::SeqCst);
+        err
+    })
+}
 
-pub fn start(
-    pin: impl OutputPin + 'static,
+#[cfg(target_os = "espidf")]
+fn start_thread<P: OutputPin + Send + 'static>(
+    pin: P,
     rmt_channel: rmt::CHANNEL0,
-) -> Result<MessageSender, StartError> where
-{
+    config: Config,
+) -> Result<LightService<P>, StartError<P>> {
     let (sender, receiver) = channel(2);
+    let (stopped_send, stopped) = oneshot::channel();
+
+    let mut ws2811 = Ws2811::new(pin, rmt_channel).map_err(|err| StartError {
+        error: InitError::Rmt(err),
+        peripherals: None,
+    })?;
+    if let Err(err) = ws2811.set_led_timings(&config.timings) {
+        return Err(release(ws2811, InitError::Rmt(err)));
+    }
+
+    frame::resize(config.num_leds as usize);
+    state::resize(config.num_leds as usize);
+
+    // A task that takes longer than a frame makes the strip stutter.
+    EXECUTOR.enable_stats(Duration::from_secs(1) / config.target_fps.max(1));
+
+    // A frame is rendered at least every second while the service runs, even if the
+    // strip hangs or the executor isn't woken anymore.
+    let heartbeat = Arc::new(health::register(ServiceConfig {
+        name: SERVICE_NAME,
+        timeout: Duration::from_secs(2),
+        max_restarts: 2,
+    }));
+    let thread_heartbeat = heartbeat.clone();
 
-    let ws2811 = Ws2811::new(pin, rmt_channel).map_err(InitError::Rmt)?;
-    let timer = EspTimer::new();
+    // The thread takes the driver, so it's shared to get it back if the thread can't be
+    // spawned.
+    let ws2811 = Arc::new(spin::Mutex::new(Some(ws2811)));
+    let thread_ws2811 = ws2811.clone();
 
-    static EXECUTOR: Executor = Executor::new();
+    let thread_config = config.thread.clone();
+    let spawned = thread::spawn(&thread_config, move || {
+        let ws2811 = thread_ws2811.lock().take().expect("light driver taken");
+        drop(thread_ws2811);
 
-    std::thread::spawn(move || {
-        let task = run(ws2811, receiver, timer);
-        pin_mut!(task);
+        let mut ws2811_out = None;
+        {
+            let task = async {
+                ws2811_out =
+                    Some(run(ws2811, receiver, config, &thread_heartbeat, &EXECUTOR).await);
+            };
+            pin_mut!(task);
 
-        EXECUTOR.run::<2>(&mut [&mut task]);
+            EXECUTOR.run::<2>(&mut [&mut task]);
+        }
+
+        // Only allow a new service to start once the executor isn't used anymore.
+        RUNNING.store(false, Ordering::SeqCst);
+        if let Some(ws2811) = ws2811_out {
+            let _ = stopped_send.send(ws2811);
+        }
 
         log::info!("light service shut down");
     });
 
-    Ok(sender)
+    if let Err(err) = spawned {
+        // The thread never ran, so the driver is still there.
+        let ws2811 = ws2811.lock().take().expect("light driver taken");
+        return Err(release(ws2811, InitError::Thread(err)));
+    }
+
+    Ok(LightService {
+        sender,
+        stopped,
+        _heartbeat: heartbeat,
+    })
 }
 
+/// Release the peripherals of `ws2811` into the error of [`start`].
+#[cfg(target_os = "espidf")]
+fn release<P: OutputPin>(ws2811: Ws2811<P>, error: InitError) -> StartError<P> {
+    StartError {
+        error,
+        peripherals: ws2811.release().into_error_log(),
+    }
+}
+
+#[cfg(target_os = "espidf")]
 async fn run<P: OutputPin>(
     mut ws2811: Ws2811<P>,
     mut msg_recv: mpsc::Receiver<Message>,
-    mut timer: EspTimer,
-) {
-    let mut col = palette::Hsv::<_, f32>::new(0., 1.0, 1.0);
-    let offset = 360_f32 / (5_f32 * 60_f32);
-    let mut color_group = ColorGroup {
-        color: Color(0),
-        num_leds: 10,
-    };
+    config: Config,
+    heartbeat: &Heartbeat,
+    executor: &'static Executor,
+) -> Ws2811<P> {
+    let target_fps = config.target_fps.max(1);
+    let num_leds = config.num_leds as usize;
 
-    loop {
-        let rgb_col: Rgb = col.into_color_unclamped();
-        let rgb_col: Rgb<_, u8> = rgb_col.into_format();
-        let rgb_col: Packed = rgb_col.into();
-        color_group.color = rgb_col.color.into();
-        col.hue += offset;
+    let mut state_recv = state::receiver();
+    let mut light_state = state_recv.get();
+    // The pixels shown when the light state last changed and when that happened.
+    let mut transition: Option<(Vec<frame::Pixel>, Instant)> = None;
+    let mut pixels = vec![frame::Pixel::new(0, 0, 0); num_leds];
+    let start = Instant::now();
 
-        ws2811.show(std::iter::once(color_group)).unwrap();
+    let mut interval = executor.interval(Duration::from_secs(1) / target_fps);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
-        let sleep = timer.after(16.ms().into()).unwrap();
+    let mut last_frame_start: Option<Instant> = None;
+    let mut frame_count = 0_u32;
+    let mut realtime_pixels = Vec::with_capacity(num_leds);
 
+    loop {
         let msg = select! {
-            () = sleep.fuse() => continue,
+            _ = interval.next() => None,
             msg = msg_recv.next() => match msg {
                 None => {
                     break;
                 },
-                Some(msg) => msg
+                Some(msg) => Some(msg)
             }
         };
 
-        match msg {}
+        match msg {
+            Some(Message::Shutdown) => break,
+            None => (),
+        }
+
+        heartbeat.beat();
+        let frame_start = Instant::now();
+
+        let realtime = frame::read_active(&mut realtime_pixels);
+        if state_recv.has_changed() {
+            light_state = state_recv.get();
+            transition = Some((pixels.clone(), frame_start));
+        }
+        // The light state keeps running while an input is active, so that it continues
+        // seamlessly once the input stops.
+        state::render(&light_state, frame_start - start, &mut pixels);
+        if let Some((from, transition_start)) = &transition {
+            let elapsed = frame_start - *transition_start;
+            if elapsed >= light_state.transition {
+                transition = None;
+            } else {
+                let progress = elapsed.as_secs_f32() / light_state.transition.as_secs_f32();
+                for (pixel, from) in pixels.iter_mut().zip(from) {
+                    *pixel = effect::blend(*from, *pixel, progress);
+                }
+            }
+        }
+
+        let render_end = Instant::now();
+        let shown = if realtime { &realtime_pixels } else { &pixels };
+        let result = ws2811.show(shown.iter().map(|p| ColorGroup {
+            color: Color(u32::from_be_bytes([0, p.red, p.green, p.blue])),
+            num_leds: 1,
+        }));
+        if let Err(err) = result {
+            // The service misses its heartbeat and is restarted by the health monitor.
+            log::error!("failed to show frame, stopping light service: {}", err);
+            break;
+        }
+        let transmit_end = Instant::now();
+
+        let stats = FrameStats {
+            render_time: render_end - frame_start,
+            transmit_time: transmit_end - render_end,
+            frame_time: last_frame_start
+                .map(|last| frame_start - last)
+                .unwrap_or_default(),
+        };
+        *FRAME_STATS.lock() = stats;
+        last_frame_start = Some(frame_start);
+
+        frame_count += 1;
+        if frame_count >= target_fps {
+            frame_count = 0;
+            log::debug!(
+                "frame: {:?} (render: {:?}, transmit: {:?})",
+                stats.frame_time,
+                stats.render_time,
+                stats.transmit_time
+            );
+        }
     }
+
+    ws2811
+        .show(std::iter::once(ColorGroup {
+            color: Color(0),
+            num_leds: config.num_leds,
+        }))
+        .into_error_log();
+
+    ws2811
 }
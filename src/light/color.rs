@@ -0,0 +1,70 @@
+//! Conversions between the color models of the control APIs and [`Pixel`]s.
+
+use palette::convert::{FromColorUnclamped, IntoColorUnclamped};
+use palette::{Hsv, LinSrgb, Srgb, Yxy};
+
+use super::frame::Pixel;
+
+/// The color of hue `hue` in degrees and `saturation` from 0 to 1 at full brightness.
+pub fn from_hs(hue: f32, saturation: f32) -> Pixel {
+    let rgb: Srgb = Hsv::new(hue, saturation.clamp(0.0, 1.0), 1.0).into_color_unclamped();
+    rgb.into_format()
+}
+
+/// The hue in degrees, the saturation and the value from 0 to 1 of `color`.
+pub fn to_hsv(color: Pixel) -> (f32, f32, f32) {
+    let hsv = Hsv::from_color_unclamped(color.into_format::<f32>());
+    (hsv.hue.to_positive_degrees(), hsv.saturation, hsv.value)
+}
+
+/// The brightest color with the CIE 1931 chromaticity `x`, `y`, colors outside of the sRGB gamut
+/// are clipped.
+pub fn from_xy(x: f32, y: f32) -> Pixel {
+    let y = y.max(0.001);
+    let rgb: LinSrgb = Yxy::new(x, y, 1.0).into_color_unclamped();
+    let rgb = LinSrgb::new(rgb.red.max(0.0), rgb.green.max(0.0), rgb.blue.max(0.0));
+    let max = rgb.red.max(rgb.green).max(rgb.blue).max(f32::EPSILON);
+
+    Srgb::from_linear(rgb / max).into_format()
+}
+
+/// The CIE 1931 chromaticity coordinates of `color`.
+pub fn to_xy(color: Pixel) -> (f32, f32) {
+    let yxy = Yxy::from_color_unclamped(color.into_format::<f32>().into_linear());
+    if yxy.luma <= 0.0 {
+        // The white point of sRGB.
+        return (0.3127, 0.329);
+    }
+    (yxy.x, yxy.y)
+}
+
+/// The color of a black body at `kelvin`, after Tanner Helland's approximation.
+pub fn from_temperature(kelvin: u16) -> Pixel {
+    let temp = kelvin as f32 / 100.0;
+
+    let red = if temp <= 66.0 {
+        255.0
+    } else {
+        329.698_73 * (temp - 60.0).powf(-0.133_204_76)
+    };
+    let green = if temp <= 66.0 {
+        99.470_8 * temp.max(1.0).ln() - 161.119_57
+    } else {
+        288.122_17 * (temp - 60.0).powf(-0.075_514_85)
+    };
+    let blue = if temp >= 66.0 {
+        255.0
+    } else if temp <= 19.0 {
+        0.0
+    } else {
+        138.517_73 * (temp - 10.0).ln() - 305.044_8
+    };
+
+    let channel = |value: f32| value.round().clamp(0.0, 255.0) as u8;
+    Pixel::new(channel(red), channel(green), channel(blue))
+}
+
+/// The color temperature in kelvin of a mired value.
+pub fn mireds_to_kelvin(mireds: u16) -> u16 {
+    (1_000_000 / mireds.max(16) as u32) as u16
+}
@@ -0,0 +1,161 @@
+//! The effects a [`Segment`] of the light state can show.
+
+use std::f32::consts::TAU;
+use std::time::Duration;
+
+use palette::convert::IntoColorUnclamped;
+use palette::{Hsv, Srgb};
+
+use super::frame::Pixel;
+use super::state::Segment;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    /// The primary color.
+    Solid,
+    /// Alternate between the primary and secondary color, the intensity is the duty
+    /// cycle.
+    Blink,
+    /// Fade between the primary and secondary color.
+    Breathe,
+    /// Fill the segment with the primary color from the start, then with the secondary
+    /// color.
+    Wipe,
+    /// Cycle the whole segment through all hues.
+    ColorLoop,
+    /// A moving rainbow, the intensity is the amount of hues shown at once.
+    Rainbow,
+}
+
+impl Effect {
+    /// All effects, the index of an effect is its id in the APIs.
+    pub const ALL: [Effect; 6] = [
+        Effect::Solid,
+        Effect::Blink,
+        Effect::Breathe,
+        Effect::Wipe,
+        Effect::ColorLoop,
+        Effect::Rainbow,
+    ];
+
+    pub fn from_index(index: usize) -> Option<Effect> {
+        Self::ALL.get(index).copied()
+    }
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Effect::Solid => "Solid",
+            Effect::Blink => "Blink",
+            Effect::Breathe => "Breathe",
+            Effect::Wipe => "Wipe",
+            Effect::ColorLoop => "Colorloop",
+            Effect::Rainbow => "Rainbow",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Effect> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|effect| effect.name() == name)
+    }
+
+    /// Whether the effect shows the colors of its segment.
+    pub fn uses_colors(self) -> bool {
+        !matches!(self, Effect::ColorLoop | Effect::Rainbow)
+    }
+
+    /// Render the effect of `segment` at `time` into the pixels of the segment.
+    pub(crate) fn render(self, segment: &Segment, time: Duration, pixels: &mut [Pixel]) {
+        let [primary, secondary, _] = segment.colors;
+        let phase = phase(time, segment.speed);
+
+        match self {
+            Effect::Solid => pixels.fill(primary),
+            Effect::Blink => {
+                let duty = segment.intensity as f32 / 255.0;
+                pixels.fill(if phase < duty { primary } else { secondary });
+            }
+            Effect::Breathe => {
+                let level = (1.0 - (phase * TAU).cos()) / 2.0;
+                pixels.fill(blend(secondary, primary, level));
+            }
+            Effect::Wipe => {
+                // The first half of a cycle wipes in the primary, the second half the
+                // secondary color.
+                let (front, back) = if phase < 0.5 {
+                    (primary, secondary)
+                } else {
+                    (secondary, primary)
+                };
+                let lit = ((phase * 2.0).fract() * pixels.len() as f32) as usize;
+                for (i, pixel) in pixels.iter_mut().enumerate() {
+                    *pixel = if i < lit { front } else { back };
+                }
+            }
+            Effect::ColorLoop => pixels.fill(hue(phase * 360.0)),
+            Effect::Rainbow => {
+                // An intensity of 128 shows all hues once over the segment.
+                let spread = 360.0 * segment.intensity.max(1) as f32 / 128.0;
+                let len = pixels.len() as f32;
+                for (i, pixel) in pixels.iter_mut().enumerate() {
+                    *pixel = hue(phase * 360.0 + i as f32 * spread / len);
+                }
+            }
+        }
+    }
+}
+
+/// The duration of one cycle of an effect, from 10 s at speed 0 to 200 ms at 255.
+fn cycle(speed: u8) -> Duration {
+    Duration::from_millis(10_000 - speed as u64 * 9_800 / 255)
+}
+
+/// The speed whose cycle is closest to `cycle`.
+pub fn speed(cycle: Duration) -> u8 {
+    let millis = cycle.as_millis().clamp(200, 10_000) as u64;
+    ((10_000 - millis) * 255 / 9_800) as u8
+}
+
+/// How far the current cycle has progressed at `time`, from 0 to 1.
+fn phase(time: Duration, speed: u8) -> f32 {
+    let cycle = cycle(speed).as_millis();
+    (time.as_millis() % cycle) as f32 / cycle as f32
+}
+
+fn hue(hue: f32) -> Pixel {
+    let rgb: Srgb = Hsv::new(hue, 1.0, 1.0).into_color_unclamped();
+    rgb.into_format()
+}
+
+/// Mix `from` and `to`, `amount` is the share of `to` from 0 to 1.
+pub(crate) fn blend(from: Pixel, to: Pixel, amount: f32) -> Pixel {
+    let amount = amount.clamp(0.0, 1.0);
+    let channel =
+        |from: u8, to: u8| (from as f32 + (to as f32 - from as f32) * amount).round() as u8;
+    Pixel::new(
+        channel(from.red, to.red),
+        channel(from.green, to.green),
+        channel(from.blue, to.blue),
+    )
+}
+
+/// Scale the brightness of `pixels` by `brightness` / 255.
+pub(crate) fn dim(pixels: &mut [Pixel], brightness: u8) {
+    if brightness == 255 {
+        return;
+    }
+
+    let channel = |value: u8| ((value as u16 * brightness as u16 + 127) / 255) as u8;
+    for pixel in pixels {
+        *pixel = Pixel::new(
+            channel(pixel.red),
+            channel(pixel.green),
+            channel(pixel.blue),
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! The frame buffer written by realtime inputs (see [`crate::input`]).
+//!
+//! Every input writes pixels into its own staging buffer with [`write`] and makes them
+//! visible with [`present`], which also sets how long the frame stays valid. Inputs
+//! don't see the pixels other inputs staged, so a frame is never a mix of several
+//! inputs. While a frame is valid the light service shows it instead of its own light
+//! state. Once it times out, or the input calls [`release`], the light service falls
+//! back to its light state again.
+//!
+//! The lock is only held to move buffers in and out and to copy the presented frame, the
+//! pixels are written without holding it.
+
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use palette::Srgb;
+
+/// The color of one LED.
+pub type Pixel = Srgb<u8>;
+
+struct FrameBuffer {
+    len: usize,
+    /// The staging buffer of every input that wrote to the frame buffer.
+    staged: Vec<(&'static str, Vec<Pixel>)>,
+    shown: Vec<Pixel>,
+    /// The input that presented `shown` and until when it is valid.
+    active: Option<(&'static str, Instant)>,
+}
+
+impl FrameBuffer {
+    /// Take the staging buffer of `source` out of the frame buffer.
+    fn take_staged(&mut self, source: &'static str) -> Vec<Pixel> {
+        let mut staged = match self.staged.iter_mut().find(|(name, _)| *name == source) {
+            Some((_, staged)) => std::mem::take(staged),
+            None => Vec::new(),
+        };
+        staged.resize(self.len, Pixel::new(0, 0, 0));
+        staged
+    }
+
+    /// Put the staging buffer of `source` back, after it was written.
+    fn put_staged(&mut self, source: &'static str, mut staged: Vec<Pixel>) {
+        // The strip may have been resized in between.
+        staged.resize(self.len, Pixel::new(0, 0, 0));
+        match self.staged.iter_mut().find(|(name, _)| *name == source) {
+            Some((_, slot)) => *slot = staged,
+            None => self.staged.push((source, staged)),
+        }
+    }
+}
+
+static FRAME: Mutex<FrameBuffer> = Mutex::new(FrameBuffer {
+    len: 0,
+    staged: Vec::new(),
+    shown: Vec::new(),
+    active: None,
+});
+
+/// Lock the frame buffer, which stays usable if an input panicked while holding it.
+fn lock() -> MutexGuard<'static, FrameBuffer> {
+    FRAME.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Set the amount of pixels, called when the light service starts.
+pub(crate) fn resize(len: usize) {
+    let mut frame = lock();
+    frame.len = len;
+    for (_, staged) in &mut frame.staged {
+        staged.resize(len, Pixel::new(0, 0, 0));
+    }
+    frame.shown.resize(len, Pixel::new(0, 0, 0));
+}
+
+/// The amount of pixels of the strip.
+pub fn len() -> usize {
+    lock().len
+}
+
+/// Write to the staging buffer of `source`, the pixels are shown once [`present`] is
+/// called.
+///
+/// The buffer keeps the pixels of the previous writes of `source`. `f` runs without
+/// holding the lock, an input must not write from several threads at once.
+pub fn write<R>(source: &'static str, f: impl FnOnce(&mut [Pixel]) -> R) -> R {
+    let mut staged = lock().take_staged(source);
+    let result = f(&mut staged);
+    lock().put_staged(source, staged);
+
+    result
+}
+
+/// Show the staging buffer of `source` for at most `timeout`, or until the next call to
+/// `present`.
+pub fn present(source: &'static str, timeout: Duration) {
+    let mut frame = lock();
+    let frame = &mut *frame;
+
+    match frame.staged.iter().find(|(name, _)| *name == source) {
+        Some((_, staged)) if staged.len() == frame.shown.len() => {
+            frame.shown.copy_from_slice(staged)
+        }
+        // Nothing was written yet, or `source` is writing on another thread.
+        _ => frame.shown.fill(Pixel::new(0, 0, 0)),
+    }
+    frame.active = Some((source, Instant::now() + timeout));
+}
+
+/// Stop showing the frame of `source` immediately, if it is the one that is shown.
+pub fn release(source: &'static str) {
+    let mut frame = lock();
+    if matches!(frame.active, Some((active, _)) if active == source) {
+        frame.active = None;
+    }
+}
+
+/// The input whose frame is currently shown.
+pub fn active_source() -> Option<&'static str> {
+    let frame = lock();
+    frame
+        .active
+        .filter(|(_, deadline)| Instant::now() < *deadline)
+        .map(|(source, _)| source)
+}
+
+/// Copy the shown frame into `pixels` if it is still valid.
+///
+/// Returns `false` if no input is active, `pixels` is left untouched in that case.
+pub(crate) fn read_active(pixels: &mut Vec<Pixel>) -> bool {
+    let mut frame = lock();
+    match frame.active {
+        Some((_, deadline)) if Instant::now() < deadline => {
+            pixels.clear();
+            pixels.extend_from_slice(&frame.shown);
+            true
+        }
+        Some(_) => {
+            frame.active = None;
+            false
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stage_frames_per_source() {
+        resize(3);
+        let red = Pixel::new(255, 0, 0);
+        let blue = Pixel::new(0, 0, 255);
+        let off = Pixel::new(0, 0, 0);
+
+        // Interleaved writes of two inputs don't mix.
+        write("first", |pixels| pixels[0] = red);
+        write("second", |pixels| pixels[1] = blue);
+        write("first", |pixels| pixels[2] = red);
+
+        present("second", Duration::from_secs(10));
+        let mut pixels = Vec::new();
+        assert!(read_active(&mut pixels));
+        assert_eq!(pixels, [off, blue, off]);
+        assert_eq!(active_source(), Some("second"));
+
+        present("first", Duration::from_secs(10));
+        assert!(read_active(&mut pixels));
+        assert_eq!(pixels, [red, off, red]);
+
+        // Releasing another input keeps the frame.
+        release("second");
+        assert_eq!(active_source(), Some("first"));
+        release("first");
+        assert!(!read_active(&mut pixels));
+        assert_eq!(active_source(), None);
+    }
+}
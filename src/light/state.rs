@@ -0,0 +1,205 @@
+//! The light state, shown by the light service while no realtime input is active.
+//!
+//! The state is shared by all control APIs, which change it with [`update`]. The light
+//! service and other interested tasks are notified of changes through a [`Watch`], see
+//! [`receiver`]. Presets are snapshots of the state, they are kept in RAM and lost on
+//! reboot.
+
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use super::effect::{self, Effect};
+use super::frame::Pixel;
+use crate::utils::sync::{Watch, WatchReceiver};
+
+/// The maximum amount of segments.
+pub const MAX_SEGMENTS: usize = 8;
+/// The maximum amount of presets.
+pub const MAX_PRESETS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LightState {
+    pub on: bool,
+    /// The brightness of the whole strip, from 0 to 255.
+    pub brightness: u8,
+    /// How long the strip fades to a new state.
+    pub transition: Duration,
+    /// Ranges of the strip, later segments are drawn over earlier ones. Pixels outside of
+    /// all segments are off.
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    /// The index of the first pixel.
+    pub start: usize,
+    /// The index after the last pixel.
+    pub stop: usize,
+    pub on: bool,
+    /// The brightness of the segment, from 0 to 255.
+    pub brightness: u8,
+    /// The primary, secondary and tertiary color, which are used depending on the
+    /// effect.
+    pub colors: [Pixel; 3],
+    pub effect: Effect,
+    /// The speed of the effect, from 0 to 255.
+    pub speed: u8,
+    /// The meaning depends on the effect, from 0 to 255.
+    pub intensity: u8,
+}
+
+impl Segment {
+    /// A segment with a solid amber color.
+    pub fn new(start: usize, stop: usize) -> Self {
+        Segment {
+            start,
+            stop,
+            on: true,
+            brightness: 255,
+            colors: [
+                Pixel::new(255, 160, 0),
+                Pixel::new(0, 0, 0),
+                Pixel::new(0, 0, 0),
+            ],
+            effect: Effect::Solid,
+            speed: 128,
+            intensity: 128,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.stop.saturating_sub(self.start)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("all {} presets are used", MAX_PRESETS)]
+pub struct PresetsFull;
+
+static STATE: spin::Lazy<Watch<LightState>> = spin::Lazy::new(|| {
+    Watch::new(LightState {
+        on: true,
+        brightness: 128,
+        transition: Duration::from_millis(700),
+        segments: Vec::new(),
+    })
+});
+/// Serializes [`update`]s, so that concurrent changes aren't lost.
+static UPDATE: Mutex<()> = Mutex::new(());
+static PRESETS: Mutex<Vec<(u16, LightState)>> = Mutex::new(Vec::new());
+
+/// Lock the presets, which stay usable if a thread panicked while holding them.
+fn presets() -> MutexGuard<'static, Vec<(u16, LightState)>> {
+    PRESETS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Get the current light state.
+pub fn get() -> LightState {
+    STATE.get()
+}
+
+/// Create a receiver that is notified when the light state changes.
+pub fn receiver() -> WatchReceiver<'static, LightState> {
+    STATE.receiver()
+}
+
+/// Change the light state with `f`, the state is left unchanged if `f` fails.
+///
+/// Returns the new state.
+pub fn update<E>(f: impl FnOnce(&mut LightState) -> Result<(), E>) -> Result<LightState, E> {
+    // A panic in `f` leaves the state unchanged, so a poisoned lock can be used.
+    let _guard = UPDATE.lock().unwrap_or_else(PoisonError::into_inner);
+
+    let mut state = STATE.get();
+    f(&mut state)?;
+    if state != STATE.get() {
+        STATE.send(state.clone());
+    }
+
+    Ok(state)
+}
+
+/// Fit the segments to a strip of `len` pixels, called when the light service starts.
+///
+/// Without any segment, one segment cycling through all hues covers the whole strip.
+pub(crate) fn resize(len: usize) {
+    let _ = update::<()>(|state| {
+        for segment in &mut state.segments {
+            segment.stop = segment.stop.min(len);
+        }
+        state.segments.retain(|s| !s.is_empty());
+
+        if state.segments.is_empty() {
+            state.segments.push(Segment {
+                effect: Effect::ColorLoop,
+                ..Segment::new(0, len)
+            });
+        }
+        Ok(())
+    });
+}
+
+/// Render the light state at `time` into `pixels`.
+pub fn render(state: &LightState, time: Duration, pixels: &mut [Pixel]) {
+    pixels.fill(Pixel::new(0, 0, 0));
+    if !state.on {
+        return;
+    }
+
+    for segment in state.segments.iter().filter(|s| s.on) {
+        let stop = segment.stop.min(pixels.len());
+        let pixels = match pixels.get_mut(segment.start..stop) {
+            Some(pixels) if !pixels.is_empty() => pixels,
+            _ => continue,
+        };
+
+        segment.effect.render(segment, time, pixels);
+        effect::dim(pixels, segment.brightness);
+    }
+    effect::dim(pixels, state.brightness);
+}
+
+/// Save `state` as preset `id`, replacing an existing preset with the same id.
+pub fn save_preset(id: u16, state: LightState) -> Result<(), PresetsFull> {
+    let mut presets = presets();
+    if let Some((_, preset)) = presets.iter_mut().find(|(preset, _)| *preset == id) {
+        *preset = state;
+    } else if presets.len() < MAX_PRESETS {
+        presets.push((id, state));
+    } else {
+        return Err(PresetsFull);
+    }
+    Ok(())
+}
+
+/// Check that [`save_preset`] has room for preset `id`, without saving anything.
+///
+/// Presets are never removed, so a preset that fits stays saveable unless other presets
+/// are saved in between.
+pub fn check_preset(id: u16) -> Result<(), PresetsFull> {
+    let presets = presets();
+    if presets.len() < MAX_PRESETS || presets.iter().any(|(preset, _)| *preset == id) {
+        Ok(())
+    } else {
+        Err(PresetsFull)
+    }
+}
+
+/// Get the state saved as preset `id`.
+pub fn preset(id: u16) -> Option<LightState> {
+    let presets = presets();
+    presets
+        .iter()
+        .find(|(preset, _)| *preset == id)
+        .map(|(_, state)| state.clone())
+}
+
+/// The id of a preset that equals `state`.
+pub fn active_preset(state: &LightState) -> Option<u16> {
+    let presets = presets();
+    presets.iter().find(|(_, s)| s == state).map(|(id, _)| *id)
+}
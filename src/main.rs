@@ -1,31 +1,56 @@
 #![feature(generic_associated_types)]
+// On other targets only the platform independent modules are built, for their tests.
+#![cfg_attr(not(target_os = "espidf"), allow(dead_code, unused_imports))]
 
-use std::sync::Arc;
-use std::time::Duration;
-
-use embedded_svc::timer::asynch::TimerService;
-use embedded_svc::utils::asyncify::timer::AsyncTimerService;
-use embedded_svc::utils::asyncify::Asyncify;
-use embedded_svc::wifi::{self, Wifi};
-use esp_idf_hal::prelude::Peripherals;
-use esp_idf_svc::netif::EspNetifStack;
-use esp_idf_svc::nvs::EspDefaultNvs;
-use esp_idf_svc::sysloop::EspSysLoopStack;
-use esp_idf_svc::timer::{EspISRTimerService, EspTaskTimerService};
-use esp_idf_svc::wifi::EspWifi;
-use esp_idf_sys as _;
-
-use crate::utils::ResultExt;
+#[cfg(target_os = "espidf")]
+use {
+    crate::input::universe::UniverseMap,
+    crate::utils::health::{self, ServiceConfig},
+    crate::utils::thread::{self, ThreadConfig},
+    crate::utils::ResultExt,
+    embedded_svc::timer::asynch::TimerService,
+    embedded_svc::utils::asyncify::timer::AsyncTimerService,
+    embedded_svc::utils::asyncify::Asyncify,
+    embedded_svc::wifi::{self, Wifi},
+    esp_idf_hal::gpio::OutputPin,
+    esp_idf_hal::prelude::Peripherals,
+    esp_idf_hal::rmt,
+    esp_idf_svc::netif::EspNetifStack,
+    esp_idf_svc::nvs::EspDefaultNvs,
+    esp_idf_svc::sysloop::EspSysLoopStack,
+    esp_idf_svc::timer::{EspISRTimerService, EspTaskTimerService},
+    esp_idf_svc::wifi::EspWifi,
+    esp_idf_sys as _,
+    std::sync::mpsc::{self, RecvTimeoutError},
+    std::sync::Arc,
+    std::time::{Duration, Instant},
+};
 
+mod api;
+#[cfg(target_os = "espidf")]
 mod driver;
 mod hue;
+mod input;
+mod lifx;
 mod light;
+mod mqtt;
 mod utils;
+mod yeelight;
+
+/// The name of the wifi connection in the [`health`] monitor.
+#[cfg(target_os = "espidf")]
+const WIFI_SERVICE: &str = "wifi";
+/// How long stopping the light service for a restart may take.
+#[cfg(target_os = "espidf")]
+const LIGHT_RESTART_TIMEOUT: Duration = Duration::from_secs(5);
 
+#[cfg(target_os = "espidf")]
 fn main() {
     esp_idf_sys::link_patches();
     utils::set_panic_hook();
     esp_idf_svc::log::EspLogger::initialize_default();
+    utils::crash::init();
+    utils::coredump::init();
 
     log::info!("Starting...");
 
@@ -35,26 +60,167 @@ fn main() {
     // let mut timers: AsyncTimerService<EspTaskTimerService, _> =
     //     EspTaskTimerService::new().unwrap().into_async();
 
-    let light_channel = light::start(
+    // The peripherals of a failed start are kept to try again on the next restart.
+    let mut light_peripherals = None;
+    let mut light_service = light::start(
         peripherals.pins.gpio5.into_output().unwrap(),
         peripherals.rmt.channel0,
+        light::Config::default(),
     )
+    .map_err(|mut err| {
+        light_peripherals = err.peripherals.take();
+        err
+    })
     .into_error_log();
 
     let netif = Arc::new(EspNetifStack::new().expect("failed to create netif"));
     let sysloop = Arc::new(EspSysLoopStack::new().expect("failed to create sysloop"));
     let mut wifi = EspWifi::new(netif, sysloop, nvs.clone()).expect("could not initialize WiFi");
 
-    wifi.set_configuration(&wifi::Configuration::Client(wifi::ClientConfiguration {
+    let wifi_config = wifi::Configuration::Client(wifi::ClientConfiguration {
         ssid: "Wokwi-GUEST".into(),
         password: "".into(),
         channel: Some(6),
         auth_method: wifi::AuthMethod::None,
         ..Default::default()
-    }))
-    .expect("failed to set wifi config");
+    });
+    wifi.set_configuration(&wifi_config).expect("failed to set wifi config");
+
+    let hue_config = hue::Config::default();
+    let _server = api::start(&hue_config).into_error_log();
+    utils::memory::start(utils::memory::Config::default()).into_error_log();
+
+    let num_leds = light::Config::default().num_leds as usize;
+    input::e131::start(input::e131::Config {
+        universes: UniverseMap::contiguous(1, 1, num_leds, 3),
+        ..Default::default()
+    })
+    .into_error_log();
+    // Art-Net port addresses start at zero, E1.31 universes at one.
+    input::artnet::start(input::artnet::Config {
+        universes: UniverseMap::contiguous(0, 1, num_leds, 3),
+        ..Default::default()
+    })
+    .into_error_log();
+    input::ddp::start(input::ddp::Config::default()).into_error_log();
+    input::serial::start(input::serial::Config::default()).into_error_log();
+    input::wled::start(input::wled::Config::default()).into_error_log();
+    mqtt::start(mqtt::Config::default()).into_error_log();
+
+    // Services that only poll their sockets share the network executor.
+    if let Some(spawner) = utils::net::start_executor().into_error_log() {
+        input::opc::start(input::opc::Config::default(), &spawner).into_error_log();
+        lifx::start(lifx::Config::default(), &spawner).into_error_log();
+        yeelight::start(yeelight::Config::default(), &spawner).into_error_log();
+        hue::start(&spawner).into_error_log();
+    }
+
+    // The heartbeat of the wifi connection, reconnecting includes getting an IP address.
+    let wifi_heartbeat = health::register(ServiceConfig {
+        name: WIFI_SERVICE,
+        timeout: Duration::from_secs(30),
+        max_restarts: 3,
+    });
 
+    // Services are restarted on this thread, since it owns them.
+    let (restart_send, restart_recv) = mpsc::channel();
+    health::start(
+        &ThreadConfig {
+            name: "health",
+            core: None,
+            priority: 15,
+            stack_size: 4 * 1024,
+        },
+        Duration::from_secs(1),
+        move |service| {
+            let _ = restart_send.send(service);
+        },
+    )
+    .into_error_log();
+
+    let mut last_error_summary = Instant::now();
+    let mut logged_errors = 0;
     loop {
-        std::thread::sleep(Duration::from_millis(100));
+        match restart_recv.recv_timeout(Duration::from_secs(1)) {
+            Ok(light::SERVICE_NAME) => {
+                light_service = restart_light(light_service.take(), &mut light_peripherals);
+            }
+            Ok(WIFI_SERVICE) => {
+                wifi.set_configuration(&wifi_config).into_error_log();
+            }
+            // The health monitor isn't running.
+            Err(RecvTimeoutError::Disconnected) => std::thread::sleep(Duration::from_secs(1)),
+            Ok(_) | Err(RecvTimeoutError::Timeout) => (),
+        }
+
+        let wifi_connected = matches!(
+            wifi.get_status(),
+            wifi::Status(
+                wifi::ClientStatus::Started(wifi::ClientConnectionStatus::Connected(
+                    wifi::ClientIpStatus::Done(_)
+                )),
+                _
+            )
+        );
+        if wifi_connected {
+            wifi_heartbeat.beat();
+        }
+
+        // Log the error counts to the serial console whenever new errors occurred.
+        if last_error_summary.elapsed() >= Duration::from_secs(60) {
+            last_error_summary = Instant::now();
+
+            let errors = utils::errors::total();
+            if errors != logged_errors {
+                logged_errors = errors;
+                utils::errors::log_summary();
+            }
+        }
     }
 }
+
+/// Restart the light service on the same pin, or start it on the peripherals of a failed
+/// start.
+///
+/// Gives up if the light thread hangs, so that the services and the wifi heartbeat on
+/// this thread keep running. The health monitor reboots the chip once the light service
+/// used up its restarts.
+#[cfg(target_os = "espidf")]
+fn restart_light<P: OutputPin + Send + 'static>(
+    service: Option<light::LightService<P>>,
+    peripherals: &mut Option<(P, rmt::CHANNEL0)>,
+) -> Option<light::LightService<P>> {
+    let result = match (service, peripherals.take()) {
+        (Some(service), _) => {
+            let restart = service.restart(|pin| pin, light::Config::default());
+            match thread::block_on_timeout(restart, LIGHT_RESTART_TIMEOUT) {
+                Some(result) => result,
+                None => {
+                    log::error!(
+                        "light service didn't stop within {:?}",
+                        LIGHT_RESTART_TIMEOUT
+                    );
+                    return None;
+                }
+            }
+        }
+        (None, Some((pin, rmt_channel))) => {
+            light::start(pin, rmt_channel, light::Config::default()).map_err(Into::into)
+        }
+        (None, None) => return None,
+    };
+
+    result
+        .map_err(|mut err| {
+            if let light::RestartError::Start(err) = &mut err {
+                *peripherals = err.peripherals.take();
+            }
+            err
+        })
+        .into_error_log()
+}
+
+#[cfg(not(target_os = "espidf"))]
+fn main() {
+    eprintln!("esp32-hue only runs on the ESP32, use `cargo test` to test it on the host");
+}
@@ -0,0 +1,185 @@
+//! An MQTT client that exposes the light state to Home Assistant.
+//!
+//! After connecting, the client announces the light with a retained discovery config,
+//! publishes `online` to its retained availability topic and the current state to its
+//! retained state topic. Commands on the command topic change the light state, and every
+//! change of the light state is published, also if another API made it. The broker
+//! publishes `offline` as the last will once the connection is lost. The client
+//! reconnects with an increasing delay, and announces the light again when Home
+//! Assistant restarts.
+
+use std::time::Duration;
+
+use self::client::{Client, Message};
+use self::codec::{Connect, Will};
+use self::homeassistant::{ColorMode, Command, Topics};
+use crate::light::state::{self, LightState};
+use crate::utils::net;
+use crate::utils::thread::{self, Core, SpawnError, ThreadConfig};
+
+pub mod client;
+pub mod codec;
+pub mod homeassistant;
+
+/// The delay before the first reconnection attempt, it doubles with every failed one.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// How long connecting and subscribing may take.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for messages, before checking for changes of the light state.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, thiserror::Error)]
+#[error("failed to start mqtt client")]
+pub struct StartError(#[from] SpawnError);
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The host name or IP address of the broker.
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// The prefix of the Home Assistant discovery topics.
+    pub discovery_prefix: String,
+    pub keep_alive: Duration,
+    /// The longest delay between reconnection attempts.
+    pub max_reconnect_delay: Duration,
+    /// The thread the client runs on.
+    pub thread: ThreadConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: "homeassistant.local".into(),
+            port: 1883,
+            username: None,
+            password: None,
+            discovery_prefix: "homeassistant".into(),
+            keep_alive: Duration::from_secs(30),
+            max_reconnect_delay: Duration::from_secs(60),
+            thread: ThreadConfig {
+                name: "mqtt",
+                core: Some(Core::Core0),
+                priority: 5,
+                stack_size: 8 * 1024,
+            },
+        }
+    }
+}
+
+/// Start the client, which connects in the background.
+pub fn start(config: Config) -> Result<(), StartError> {
+    let thread_config = config.thread.clone();
+    thread::spawn(&thread_config, move || run(config))?;
+
+    Ok(())
+}
+
+fn run(config: Config) {
+    let id: String = net::station_mac()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let topics = Topics::new(&config.discovery_prefix, &id);
+
+    let mut delay = MIN_RECONNECT_DELAY;
+    loop {
+        if let Err(err) = session(&config, &topics, &mut delay) {
+            log::warn!(
+                "mqtt connection to {}:{} failed: {}",
+                config.host,
+                config.port,
+                err
+            );
+        }
+
+        std::thread::sleep(delay);
+        delay = (delay * 2).min(config.max_reconnect_delay);
+    }
+}
+
+/// Connect to the broker and serve the light until the connection fails.
+///
+/// `delay` is reset once the broker accepted the connection.
+fn session(config: &Config, topics: &Topics, delay: &mut Duration) -> Result<(), client::Error> {
+    let connect = Connect {
+        client_id: &topics.id,
+        keep_alive: config.keep_alive.as_secs().min(u16::MAX as u64) as u16,
+        clean_session: true,
+        will: Some(Will {
+            topic: &topics.availability,
+            payload: b"offline",
+            retain: true,
+        }),
+        username: config.username.as_deref(),
+        password: config.password.as_deref(),
+    };
+    let mut client = Client::connect((config.host.as_str(), config.port), &connect, TIMEOUT)?;
+    *delay = MIN_RECONNECT_DELAY;
+    log::info!("connected to mqtt broker {}:{}", config.host, config.port);
+
+    client.subscribe(&[&topics.command, &topics.status], TIMEOUT)?;
+    announce(&mut client, topics)?;
+
+    let mut receiver = state::receiver();
+    let mut color_mode = ColorMode::Hs;
+    publish_state(&mut client, topics, &receiver.get(), color_mode)?;
+
+    loop {
+        match client.poll(POLL_INTERVAL)? {
+            Some(Message { topic, payload, .. }) if topic == topics.command => {
+                handle_command(&payload, &mut color_mode);
+                // Home Assistant expects a state after every command, even if nothing
+                // changed.
+                publish_state(&mut client, topics, &receiver.get(), color_mode)?;
+            }
+            Some(Message { topic, payload, .. }) if topic == topics.status => {
+                if payload == b"online" {
+                    announce(&mut client, topics)?;
+                    publish_state(&mut client, topics, &receiver.get(), color_mode)?;
+                }
+            }
+            Some(_) | None => (),
+        }
+
+        if receiver.has_changed() {
+            publish_state(&mut client, topics, &receiver.get(), color_mode)?;
+        }
+    }
+}
+
+fn handle_command(payload: &[u8], color_mode: &mut ColorMode) {
+    let command: Command = match serde_json::from_slice(payload) {
+        Ok(command) => command,
+        Err(err) => {
+            log::warn!("invalid mqtt light command: {}", err);
+            return;
+        }
+    };
+
+    let mut new_mode = *color_mode;
+    match state::update(|state| homeassistant::apply(&command, state, &mut new_mode)) {
+        Ok(_) => *color_mode = new_mode,
+        Err(err) => log::warn!("failed to apply mqtt light command: {}", err),
+    }
+}
+
+/// Publish the discovery config and that the light is available.
+fn announce(client: &mut Client, topics: &Topics) -> Result<(), client::Error> {
+    let config = homeassistant::discovery_config(topics);
+    client.publish(&topics.discovery, config.to_string().as_bytes(), true)?;
+    client.publish(&topics.availability, b"online", true)
+}
+
+fn publish_state(
+    client: &mut Client,
+    topics: &Topics,
+    state: &LightState,
+    color_mode: ColorMode,
+) -> Result<(), client::Error> {
+    let state = homeassistant::State::new(state, color_mode);
+    // Serializing plain structs doesn't fail.
+    let payload = serde_json::to_vec(&state).unwrap_or_default();
+    client.publish(&topics.state, &payload, true)
+}
@@ -0,0 +1,378 @@
+//! A blocking MQTT 3.1.1 client on a TCP stream.
+//!
+//! The client only publishes with qos 0 and expects subscriptions with qos 0, which is
+//! all the Home Assistant integration needs. It only uses `std`, so it also runs on the
+//! host, e.g. against a local mosquitto broker.
+
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use super::codec::{self, Connect, DecodeError, Packet, Publish};
+
+/// The size of the receive buffer, larger messages are rejected.
+const MAX_PACKET_LEN: usize = 4096;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("mqtt connection failed")]
+    Io(#[from] io::Error),
+    #[error("invalid packet from broker")]
+    Decode(#[from] DecodeError),
+    #[error("packet from broker is larger than {} bytes", MAX_PACKET_LEN)]
+    TooLarge,
+    #[error("broker refused the connection with code {0}")]
+    Refused(u8),
+    #[error("broker rejected the subscription")]
+    SubscribeRejected,
+    #[error("broker didn't respond in time")]
+    Timeout,
+    #[error("broker closed the connection")]
+    Closed,
+}
+
+/// A message received on a subscribed topic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+/// A packet of the broker that the client handles, without borrowing the buffer.
+enum Received {
+    ConnAck { return_code: u8 },
+    Publish(Message),
+    SubAck { packet_id: u16, rejected: bool },
+    PingResp,
+    Other,
+}
+
+pub struct Client {
+    stream: TcpStream,
+    /// Received bytes that don't form a whole packet yet.
+    buf: Vec<u8>,
+    /// Messages received while waiting for another packet.
+    messages: VecDeque<Message>,
+    /// Zero disables the keep alive mechanism.
+    keep_alive: Duration,
+    last_sent: Instant,
+    /// When a PINGREQ was sent that wasn't answered yet.
+    ping_sent: Option<Instant>,
+    next_packet_id: u16,
+}
+
+impl Client {
+    /// Connect to the broker at `addr` and wait at most `timeout` for it to accept the
+    /// connection.
+    pub fn connect(
+        addr: impl ToSocketAddrs,
+        connect: &Connect<'_>,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "broker address not found"))?;
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_nodelay(true)?;
+
+        let mut client = Client {
+            stream,
+            buf: Vec::with_capacity(MAX_PACKET_LEN),
+            messages: VecDeque::new(),
+            keep_alive: Duration::from_secs(connect.keep_alive as u64),
+            last_sent: Instant::now(),
+            ping_sent: None,
+            next_packet_id: 1,
+        };
+
+        let mut out = Vec::new();
+        connect.encode(&mut out);
+        client.send(&out)?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            match client.receive(deadline)? {
+                Some(Received::ConnAck { return_code: 0 }) => return Ok(client),
+                Some(Received::ConnAck { return_code }) => return Err(Error::Refused(return_code)),
+                Some(_) => (),
+                None => return Err(Error::Timeout),
+            }
+        }
+    }
+
+    /// Subscribe to `filters` with qos 0 and wait at most `timeout` for the broker to
+    /// acknowledge them.
+    pub fn subscribe(&mut self, filters: &[&str], timeout: Duration) -> Result<(), Error> {
+        let packet_id = self.next_packet_id();
+        let filters: Vec<_> = filters.iter().map(|filter| (*filter, 0)).collect();
+
+        let mut out = Vec::new();
+        codec::encode_subscribe(&mut out, packet_id, &filters);
+        self.send(&out)?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.receive(deadline)? {
+                Some(Received::SubAck {
+                    packet_id: id,
+                    rejected,
+                }) if id == packet_id => {
+                    return if rejected {
+                        Err(Error::SubscribeRejected)
+                    } else {
+                        Ok(())
+                    };
+                }
+                Some(Received::Publish(message)) => self.messages.push_back(message),
+                Some(_) => (),
+                None => return Err(Error::Timeout),
+            }
+        }
+    }
+
+    /// Publish `payload` to `topic` with qos 0.
+    pub fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<(), Error> {
+        let mut out = Vec::with_capacity(topic.len() + payload.len() + 8);
+        Publish {
+            topic,
+            payload,
+            qos: 0,
+            retain,
+            packet_id: None,
+        }
+        .encode(&mut out);
+
+        self.send(&out)
+    }
+
+    /// Wait at most `timeout` for a message, and keep the connection alive.
+    ///
+    /// Fails with [`Error::Timeout`] if the broker doesn't answer a ping within the keep
+    /// alive interval. No pings are sent if the keep alive interval is zero.
+    pub fn poll(&mut self, timeout: Duration) -> Result<Option<Message>, Error> {
+        if let Some(message) = self.messages.pop_front() {
+            return Ok(Some(message));
+        }
+
+        match self.ping_sent {
+            _ if self.keep_alive.is_zero() => (),
+            Some(sent) if sent.elapsed() >= self.keep_alive => return Err(Error::Timeout),
+            Some(_) => (),
+            // Pinging after half the interval leaves enough time for the response.
+            None if self.last_sent.elapsed() >= self.keep_alive / 2 => {
+                let mut out = Vec::new();
+                codec::encode_pingreq(&mut out);
+                self.send(&out)?;
+                self.ping_sent = Some(Instant::now());
+            }
+            None => (),
+        }
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.receive(deadline)? {
+                Some(Received::Publish(message)) => return Ok(Some(message)),
+                Some(_) => (),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Close the connection, the broker discards the will.
+    pub fn disconnect(mut self) -> Result<(), Error> {
+        let mut out = Vec::new();
+        codec::encode_disconnect(&mut out);
+        self.send(&out)
+    }
+
+    fn next_packet_id(&mut self) -> u16 {
+        let id = self.next_packet_id;
+        // Packet identifiers must not be zero.
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        id
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.stream.write_all(data)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Receive the next packet, or `None` if none arrived before `deadline`.
+    fn receive(&mut self, deadline: Instant) -> Result<Option<Received>, Error> {
+        loop {
+            if let Some(received) = self.decode()? {
+                return Ok(Some(received));
+            }
+            if self.buf.len() >= MAX_PACKET_LEN {
+                return Err(Error::TooLarge);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            // A zero read timeout would block forever.
+            let timeout = (deadline - now).max(Duration::from_millis(1));
+            self.stream.set_read_timeout(Some(timeout))?;
+
+            let mut chunk = [0; 512];
+            let free = (MAX_PACKET_LEN - self.buf.len()).min(chunk.len());
+            match self.stream.read(&mut chunk[..free]) {
+                Ok(0) => return Err(Error::Closed),
+                Ok(len) => self.buf.extend_from_slice(&chunk[..len]),
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Ok(None)
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Decode the first packet in the receive buffer and remove it.
+    fn decode(&mut self) -> Result<Option<Received>, Error> {
+        let (received, len, puback) = match codec::decode(&self.buf)? {
+            None => return Ok(None),
+            Some((packet, len)) => {
+                let mut puback = None;
+                let received = match packet {
+                    Packet::ConnAck { return_code, .. } => Received::ConnAck { return_code },
+                    Packet::Publish(publish) => {
+                        if publish.qos == 1 {
+                            puback = publish.packet_id;
+                        }
+                        Received::Publish(Message {
+                            topic: publish.topic.to_owned(),
+                            payload: publish.payload.to_vec(),
+                            retain: publish.retain,
+                        })
+                    }
+                    Packet::SubAck {
+                        packet_id,
+                        return_codes,
+                    } => Received::SubAck {
+                        packet_id,
+                        rejected: return_codes.iter().any(|&code| code & 0x80 != 0),
+                    },
+                    Packet::PingResp => Received::PingResp,
+                    Packet::Other(_) => Received::Other,
+                };
+                (received, len, puback)
+            }
+        };
+        self.buf.drain(..len);
+
+        if let Received::PingResp = received {
+            self.ping_sent = None;
+        }
+        // Brokers only send qos 1 if a subscription asked for it, acknowledge it anyway.
+        if let Some(packet_id) = puback {
+            let mut out = Vec::new();
+            codec::encode_puback(&mut out, packet_id);
+            self.send(&out)?;
+        }
+
+        Ok(Some(received))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::mqtt::codec::Will;
+
+    #[test]
+    fn no_pings_without_keep_alive() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let broker = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 256];
+            let _ = stream.read(&mut buf).unwrap();
+            // CONNACK, connection accepted.
+            stream.write_all(&[0x20, 2, 0, 0]).unwrap();
+
+            // Everything the client sends until it disconnects.
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let connect = Connect {
+            client_id: "no-keep-alive",
+            keep_alive: 0,
+            clean_session: true,
+            will: None,
+            username: None,
+            password: None,
+        };
+        let mut client = Client::connect(addr, &connect, Duration::from_secs(5)).unwrap();
+        for _ in 0..3 {
+            assert_eq!(client.poll(Duration::from_millis(10)).unwrap(), None);
+        }
+        client.disconnect().unwrap();
+
+        let mut disconnect = Vec::new();
+        codec::encode_disconnect(&mut disconnect);
+        assert_eq!(broker.join().unwrap(), disconnect);
+    }
+
+    /// Run with `cargo test -- --ignored` while a broker, e.g. mosquitto, listens on
+    /// localhost.
+    #[test]
+    #[ignore = "needs an mqtt broker on localhost:1883"]
+    fn local_broker() {
+        let timeout = Duration::from_secs(5);
+        let connect = |client_id| Connect {
+            client_id,
+            keep_alive: 2,
+            clean_session: true,
+            will: Some(Will {
+                topic: "esp32-hue-test/availability",
+                payload: b"offline",
+                retain: false,
+            }),
+            username: None,
+            password: None,
+        };
+
+        let mut publisher =
+            Client::connect("localhost:1883", &connect("publisher"), timeout).unwrap();
+        publisher
+            .publish("esp32-hue-test/retained", b"retained", true)
+            .unwrap();
+
+        let mut client =
+            Client::connect("localhost:1883", &connect("subscriber"), timeout).unwrap();
+        client.subscribe(&["esp32-hue-test/#"], timeout).unwrap();
+        let message = client.poll(timeout).unwrap().unwrap();
+        assert_eq!(message.payload, b"retained");
+        assert!(message.retain);
+
+        publisher
+            .publish("esp32-hue-test/live", b"live", false)
+            .unwrap();
+        let message = client.poll(timeout).unwrap().unwrap();
+        assert_eq!(message.topic, "esp32-hue-test/live");
+        assert!(!message.retain);
+
+        // Keep the connections alive longer than the keep alive interval.
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(3) {
+            assert_eq!(client.poll(Duration::from_millis(100)).unwrap(), None);
+            assert_eq!(publisher.poll(Duration::from_millis(10)).unwrap(), None);
+        }
+
+        publisher
+            .publish("esp32-hue-test/retained", b"", true)
+            .unwrap();
+        publisher.disconnect().unwrap();
+        client.disconnect().unwrap();
+    }
+}
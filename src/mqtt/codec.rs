@@ -0,0 +1,375 @@
+//! Encoding and decoding of the MQTT 3.1.1 packets used by the client.
+//!
+//! Only what a client needs is supported: CONNECT, PUBLISH, PUBACK, SUBSCRIBE, PINGREQ and
+//! DISCONNECT are encoded, CONNACK, PUBLISH, SUBACK and PINGRESP are decoded. Other
+//! packets from the broker are decoded as [`Packet::Other`].
+
+use std::str;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+const FLAG_USERNAME: u8 = 1 << 7;
+const FLAG_PASSWORD: u8 = 1 << 6;
+const FLAG_WILL_RETAIN: u8 = 1 << 5;
+const FLAG_WILL: u8 = 1 << 2;
+const FLAG_CLEAN_SESSION: u8 = 1 << 1;
+
+/// The largest value of the remaining length, which is encoded in at most 4 bytes.
+const MAX_REMAINING_LEN: usize = 268_435_455;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum DecodeError {
+    #[error("invalid remaining length")]
+    InvalidLength,
+    #[error("packet is too short for its type")]
+    Truncated,
+    #[error("topic is not valid utf-8")]
+    InvalidTopic,
+    #[error("invalid qos {0}")]
+    InvalidQos(u8),
+}
+
+/// A message the client leaves with the broker, which is published once the connection
+/// is lost without a DISCONNECT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Will<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub retain: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Connect<'a> {
+    pub client_id: &'a str,
+    /// The keep alive interval in seconds.
+    pub keep_alive: u16,
+    pub clean_session: bool,
+    pub will: Option<Will<'a>>,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Publish<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub qos: u8,
+    pub retain: bool,
+    /// The packet identifier, only present with a qos above 0.
+    pub packet_id: Option<u16>,
+}
+
+/// A packet sent by the broker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Packet<'a> {
+    ConnAck {
+        session_present: bool,
+        /// `0` if the connection was accepted.
+        return_code: u8,
+    },
+    Publish(Publish<'a>),
+    SubAck {
+        packet_id: u16,
+        /// The granted qos per topic filter, `0x80` for a rejected filter.
+        return_codes: &'a [u8],
+    },
+    PingResp,
+    /// A packet the client doesn't handle, with its type.
+    Other(u8),
+}
+
+impl Connect<'_> {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let mut flags = 0;
+        if self.clean_session {
+            flags |= FLAG_CLEAN_SESSION;
+        }
+        if let Some(will) = &self.will {
+            // The will is published with qos 0.
+            flags |= FLAG_WILL;
+            if will.retain {
+                flags |= FLAG_WILL_RETAIN;
+            }
+        }
+        if self.username.is_some() {
+            flags |= FLAG_USERNAME;
+        }
+        if self.password.is_some() {
+            flags |= FLAG_PASSWORD;
+        }
+
+        let mut body = Vec::new();
+        write_bytes(&mut body, b"MQTT");
+        // Protocol level 4 is MQTT 3.1.1.
+        body.push(4);
+        body.push(flags);
+        body.extend(self.keep_alive.to_be_bytes());
+        write_bytes(&mut body, self.client_id.as_bytes());
+        if let Some(will) = &self.will {
+            write_bytes(&mut body, will.topic.as_bytes());
+            write_bytes(&mut body, will.payload);
+        }
+        if let Some(username) = self.username {
+            write_bytes(&mut body, username.as_bytes());
+        }
+        if let Some(password) = self.password {
+            write_bytes(&mut body, password.as_bytes());
+        }
+
+        write_packet(out, CONNECT << 4, &body);
+    }
+}
+
+impl Publish<'_> {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let mut body = Vec::with_capacity(self.topic.len() + self.payload.len() + 4);
+        write_bytes(&mut body, self.topic.as_bytes());
+        if let Some(packet_id) = self.packet_id {
+            body.extend(packet_id.to_be_bytes());
+        }
+        body.extend(self.payload);
+
+        let header = PUBLISH << 4 | (self.qos & 0b11) << 1 | self.retain as u8;
+        write_packet(out, header, &body);
+    }
+}
+
+/// Encode a SUBSCRIBE of `filters`, which are topic filters with their maximum qos.
+pub fn encode_subscribe(out: &mut Vec<u8>, packet_id: u16, filters: &[(&str, u8)]) {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    for (filter, qos) in filters {
+        write_bytes(&mut body, filter.as_bytes());
+        body.push(*qos);
+    }
+
+    // The reserved flags of SUBSCRIBE must be `0b0010`.
+    write_packet(out, SUBSCRIBE << 4 | 0b0010, &body);
+}
+
+pub fn encode_puback(out: &mut Vec<u8>, packet_id: u16) {
+    write_packet(out, PUBACK << 4, &packet_id.to_be_bytes());
+}
+
+pub fn encode_pingreq(out: &mut Vec<u8>) {
+    write_packet(out, PINGREQ << 4, &[]);
+}
+
+pub fn encode_disconnect(out: &mut Vec<u8>) {
+    write_packet(out, DISCONNECT << 4, &[]);
+}
+
+/// Decode the packet at the start of `buf`.
+///
+/// Returns the packet and its length, or `None` if `buf` doesn't contain the whole
+/// packet yet.
+pub fn decode(buf: &[u8]) -> Result<Option<(Packet<'_>, usize)>, DecodeError> {
+    let header = match buf.first() {
+        Some(&header) => header,
+        None => return Ok(None),
+    };
+    let (remaining_len, len_bytes) = match decode_remaining_len(&buf[1..])? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+
+    let body_start = 1 + len_bytes;
+    let packet_len = body_start + remaining_len;
+    let body = match buf.get(body_start..packet_len) {
+        Some(body) => body,
+        None => return Ok(None),
+    };
+
+    let packet = match header >> 4 {
+        CONNACK => {
+            let body = body.get(..2).ok_or(DecodeError::Truncated)?;
+            Packet::ConnAck {
+                session_present: body[0] & 1 != 0,
+                return_code: body[1],
+            }
+        }
+        PUBLISH => Packet::Publish(decode_publish(header, body)?),
+        SUBACK => {
+            let packet_id = body.get(..2).ok_or(DecodeError::Truncated)?;
+            Packet::SubAck {
+                packet_id: u16::from_be_bytes([packet_id[0], packet_id[1]]),
+                return_codes: &body[2..],
+            }
+        }
+        PINGRESP => Packet::PingResp,
+        packet_type => Packet::Other(packet_type),
+    };
+
+    Ok(Some((packet, packet_len)))
+}
+
+fn decode_publish(header: u8, body: &[u8]) -> Result<Publish<'_>, DecodeError> {
+    let qos = (header >> 1) & 0b11;
+    if qos > 2 {
+        return Err(DecodeError::InvalidQos(qos));
+    }
+
+    let topic_len = body.get(..2).ok_or(DecodeError::Truncated)?;
+    let topic_len = u16::from_be_bytes([topic_len[0], topic_len[1]]) as usize;
+    let topic = body.get(2..2 + topic_len).ok_or(DecodeError::Truncated)?;
+    let topic = str::from_utf8(topic).map_err(|_| DecodeError::InvalidTopic)?;
+
+    let mut payload = &body[2 + topic_len..];
+    let packet_id = if qos > 0 {
+        let packet_id = payload.get(..2).ok_or(DecodeError::Truncated)?;
+        let packet_id = u16::from_be_bytes([packet_id[0], packet_id[1]]);
+        payload = &payload[2..];
+        Some(packet_id)
+    } else {
+        None
+    };
+
+    Ok(Publish {
+        topic,
+        payload,
+        qos,
+        retain: header & 1 != 0,
+        packet_id,
+    })
+}
+
+/// Decode the variable length encoding of the remaining length.
+///
+/// Returns the length and the amount of bytes it took, or `None` if more bytes are
+/// needed.
+fn decode_remaining_len(buf: &[u8]) -> Result<Option<(usize, usize)>, DecodeError> {
+    let mut len = 0;
+    for (i, &byte) in buf.iter().enumerate().take(4) {
+        len |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((len, i + 1)));
+        }
+    }
+
+    if buf.len() >= 4 {
+        Err(DecodeError::InvalidLength)
+    } else {
+        Ok(None)
+    }
+}
+
+fn write_packet(out: &mut Vec<u8>, header: u8, body: &[u8]) {
+    assert!(body.len() <= MAX_REMAINING_LEN, "mqtt packet is too large");
+
+    out.push(header);
+    let mut len = body.len();
+    loop {
+        let mut byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    out.extend(body);
+}
+
+/// Write `bytes` prefixed with their length, as used for strings and binary data.
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend((bytes.len() as u16).to_be_bytes());
+    out.extend(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_connect() {
+        let mut out = Vec::new();
+        Connect {
+            client_id: "id",
+            keep_alive: 30,
+            clean_session: true,
+            will: Some(Will {
+                topic: "a/b",
+                payload: b"offline",
+                retain: true,
+            }),
+            username: Some("u"),
+            password: None,
+        }
+        .encode(&mut out);
+
+        let mut expected = vec![0x10, 31, 0, 4];
+        expected.extend(b"MQTT");
+        expected.extend([4, 0b1010_0110, 0, 30, 0, 2]);
+        expected.extend(b"id");
+        expected.extend([0, 3]);
+        expected.extend(b"a/b");
+        expected.extend([0, 7]);
+        expected.extend(b"offline");
+        expected.extend([0, 1, b'u']);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn publish_roundtrip() {
+        let publish = Publish {
+            topic: "light/state",
+            payload: &[b'x'; 200],
+            qos: 1,
+            retain: true,
+            packet_id: Some(7),
+        };
+        let mut out = Vec::new();
+        publish.encode(&mut out);
+
+        // The remaining length of 215 takes two bytes.
+        assert_eq!(out[..3], [0x33, 0xd7, 0x01]);
+        assert_eq!(
+            decode(&out),
+            Ok(Some((Packet::Publish(publish), out.len())))
+        );
+        assert_eq!(decode(&out[..out.len() - 1]), Ok(None));
+        assert_eq!(decode(&out[..2]), Ok(None));
+    }
+
+    #[test]
+    fn decode_broker_packets() {
+        let mut buf = vec![0x20, 2, 0, 5, 0x90, 4, 0, 1, 0, 0x80, 0xd0, 0];
+        buf.extend([0xb0, 2, 0, 1]);
+
+        let (packet, len) = decode(&buf).unwrap().unwrap();
+        assert_eq!(
+            packet,
+            Packet::ConnAck {
+                session_present: false,
+                return_code: 5
+            }
+        );
+        let buf = &buf[len..];
+        let (packet, len) = decode(buf).unwrap().unwrap();
+        assert_eq!(
+            packet,
+            Packet::SubAck {
+                packet_id: 1,
+                return_codes: &[0, 0x80]
+            }
+        );
+        let buf = &buf[len..];
+        let (packet, len) = decode(buf).unwrap().unwrap();
+        assert_eq!(packet, Packet::PingResp);
+        assert_eq!(decode(&buf[len..]).unwrap().unwrap().0, Packet::Other(11));
+
+        assert_eq!(
+            decode(&[0x30, 0xff, 0xff, 0xff, 0xff]),
+            Err(DecodeError::InvalidLength)
+        );
+    }
+}
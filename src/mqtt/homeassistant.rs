@@ -0,0 +1,327 @@
+//! The messages of the Home Assistant MQTT light with the JSON schema.
+//!
+//! The light announces itself with a retained [`discovery_config`] and supports the
+//! brightness, the `hs`, `xy` and `color_temp` color modes, the effects of the light
+//! state and transitions. A color or color temperature sets the primary color of all
+//! segments, an effect is set on all segments. The reported color is the primary color of
+//! the first segment.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::light::color;
+use crate::light::effect::Effect;
+use crate::light::frame::Pixel;
+use crate::light::state::LightState;
+
+/// The coldest supported color temperature, 6500 K.
+pub const MIN_MIREDS: u16 = 153;
+/// The warmest supported color temperature, 2000 K.
+pub const MAX_MIREDS: u16 = 500;
+
+/// The topics of one light.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topics {
+    /// The unique id of the light, also used as the MQTT client id.
+    pub id: String,
+    pub command: String,
+    pub state: String,
+    pub availability: String,
+    pub discovery: String,
+    /// The topic on which Home Assistant announces that it (re)started.
+    pub status: String,
+}
+
+impl Topics {
+    /// The topics of the light `id`, below `esp32-hue/<id>` and the discovery prefix.
+    pub fn new(discovery_prefix: &str, id: &str) -> Self {
+        let base = format!("esp32-hue/{}", id);
+        Topics {
+            id: format!("esp32-hue-{}", id),
+            command: format!("{}/set", base),
+            state: format!("{}/state", base),
+            availability: format!("{}/availability", base),
+            discovery: format!("{}/light/esp32-hue-{}/config", discovery_prefix, id),
+            status: format!("{}/status", discovery_prefix),
+        }
+    }
+}
+
+/// The discovery config that makes Home Assistant add the light.
+pub fn discovery_config(topics: &Topics) -> serde_json::Value {
+    let effects: Vec<_> = Effect::ALL.iter().map(|effect| effect.name()).collect();
+
+    json!({
+        "name": "esp32-hue",
+        "unique_id": topics.id,
+        "schema": "json",
+        "command_topic": topics.command,
+        "state_topic": topics.state,
+        "availability_topic": topics.availability,
+        "brightness": true,
+        "brightness_scale": 255,
+        "supported_color_modes": ["color_temp", "hs", "xy"],
+        "min_mireds": MIN_MIREDS,
+        "max_mireds": MAX_MIREDS,
+        "effect": true,
+        "effect_list": effects,
+        "device": {
+            "identifiers": [topics.id],
+            "name": "esp32-hue",
+            "manufacturer": "esp32-hue",
+            "model": "ESP32 LED strip",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        },
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OnOff {
+    #[serde(rename = "ON")]
+    On,
+    #[serde(rename = "OFF")]
+    Off,
+}
+
+/// A command from Home Assistant, fields it may send but the light doesn't support
+/// (e.g. `flash`) are ignored.
+#[derive(Debug, Default, Deserialize)]
+pub struct Command {
+    pub state: Option<OnOff>,
+    pub brightness: Option<u8>,
+    pub color: Option<Color>,
+    /// The color temperature in mireds.
+    pub color_temp: Option<u16>,
+    pub effect: Option<String>,
+    /// The transition time in seconds.
+    pub transition: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Color {
+    /// The hue in degrees and saturation in percent.
+    Hs { h: f32, s: f32 },
+    /// CIE 1931 chromaticity coordinates.
+    Xy { x: f32, y: f32 },
+}
+
+/// The color mode the light was last set with, which is reported in the state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    Hs,
+    Xy,
+    /// The color temperature in mireds.
+    ColorTemp(u16),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CommandError {
+    #[error("effect `{0}` doesn't exist")]
+    UnknownEffect(String),
+}
+
+/// Apply `command` to `state`, `mode` is set to the color mode of the command.
+pub fn apply(
+    command: &Command,
+    state: &mut LightState,
+    mode: &mut ColorMode,
+) -> Result<(), CommandError> {
+    let effect = match &command.effect {
+        Some(name) => {
+            Some(Effect::from_name(name).ok_or_else(|| CommandError::UnknownEffect(name.clone()))?)
+        }
+        None => None,
+    };
+
+    if let Some(on) = command.state {
+        state.on = on == OnOff::On;
+    }
+    if let Some(brightness) = command.brightness {
+        state.brightness = brightness;
+    }
+    if let Some(transition) = command.transition {
+        state.transition = Duration::from_secs_f32(transition.max(0.0));
+    }
+
+    let color = match (command.color, command.color_temp) {
+        (Some(Color::Hs { h, s }), _) => {
+            *mode = ColorMode::Hs;
+            Some(color::from_hs(h, s / 100.0))
+        }
+        (Some(Color::Xy { x, y }), _) => {
+            *mode = ColorMode::Xy;
+            Some(color::from_xy(x, y))
+        }
+        (None, Some(mireds)) => {
+            let mireds = mireds.clamp(MIN_MIREDS, MAX_MIREDS);
+            *mode = ColorMode::ColorTemp(mireds);
+            Some(temperature(mireds))
+        }
+        (None, None) => None,
+    };
+
+    for segment in &mut state.segments {
+        if let Some(color) = color {
+            segment.colors[0] = color;
+            // Setting a color shows it, unless an effect is set at the same time.
+            if !segment.effect.uses_colors() {
+                segment.effect = Effect::Solid;
+            }
+        }
+        if let Some(effect) = effect {
+            segment.effect = effect;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct State {
+    pub state: OnOff,
+    pub brightness: u8,
+    pub color_mode: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<ColorState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color_temp: Option<u16>,
+    pub effect: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ColorState {
+    pub h: f32,
+    pub s: f32,
+    pub x: f32,
+    pub y: f32,
+}
+
+impl State {
+    /// The state message of `state`, reported in color `mode` if it still applies.
+    pub fn new(state: &LightState, mode: ColorMode) -> Self {
+        let segment = state.segments.first();
+        let color = segment.map_or(Pixel::new(0, 0, 0), |s| s.colors[0]);
+        let effect = segment.map_or(Effect::Solid, |s| s.effect);
+
+        // Another API may have changed the color since the color temperature was set.
+        let mode = match mode {
+            ColorMode::ColorTemp(mireds) if temperature(mireds) != color => ColorMode::Hs,
+            mode => mode,
+        };
+        let (color_mode, color, color_temp) = match mode {
+            ColorMode::ColorTemp(mireds) => ("color_temp", None, Some(mireds)),
+            ColorMode::Hs | ColorMode::Xy => {
+                let (h, saturation, _) = color::to_hsv(color);
+                let s = saturation * 100.0;
+                let (x, y) = color::to_xy(color);
+                let color_mode = if mode == ColorMode::Hs { "hs" } else { "xy" };
+                (color_mode, Some(ColorState { h, s, x, y }), None)
+            }
+        };
+
+        State {
+            state: if state.on { OnOff::On } else { OnOff::Off },
+            brightness: state.brightness,
+            color_mode,
+            color,
+            color_temp,
+            effect: effect.name(),
+        }
+    }
+}
+
+/// The color of the color temperature `mireds`.
+fn temperature(mireds: u16) -> Pixel {
+    color::from_temperature(color::mireds_to_kelvin(mireds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::state::Segment;
+
+    fn state() -> LightState {
+        LightState {
+            on: true,
+            brightness: 128,
+            transition: Duration::from_millis(700),
+            segments: vec![Segment {
+                effect: Effect::ColorLoop,
+                ..Segment::new(0, 10)
+            }],
+        }
+    }
+
+    fn apply_json(state: &mut LightState, mode: &mut ColorMode, json: &str) {
+        let command: Command = serde_json::from_str(json).unwrap();
+        apply(&command, state, mode).unwrap();
+    }
+
+    #[test]
+    fn apply_commands() {
+        let mut state = state();
+        let mut mode = ColorMode::Hs;
+
+        apply_json(
+            &mut state,
+            &mut mode,
+            r#"{"state":"ON","brightness":50,"color":{"h":120,"s":100},"transition":1.5}"#,
+        );
+        assert_eq!(state.brightness, 50);
+        assert_eq!(state.transition, Duration::from_millis(1500));
+        assert_eq!(state.segments[0].colors[0], Pixel::new(0, 255, 0));
+        assert_eq!(state.segments[0].effect, Effect::Solid);
+
+        apply_json(
+            &mut state,
+            &mut mode,
+            r#"{"color":{"x":0.3127,"y":0.329},"effect":"Breathe","flash":"short"}"#,
+        );
+        assert_eq!(mode, ColorMode::Xy);
+        assert_eq!(state.segments[0].colors[0], Pixel::new(255, 255, 255));
+        assert_eq!(state.segments[0].effect, Effect::Breathe);
+
+        apply_json(
+            &mut state,
+            &mut mode,
+            r#"{"state":"OFF","color_temp":1000}"#,
+        );
+        assert!(!state.on);
+        assert_eq!(mode, ColorMode::ColorTemp(MAX_MIREDS));
+
+        let command: Command = serde_json::from_str(r#"{"effect":"Disco"}"#).unwrap();
+        assert!(apply(&command, &mut state, &mut mode).is_err());
+    }
+
+    #[test]
+    fn report_state() {
+        let mut state = state();
+        let mut mode = ColorMode::Hs;
+        apply_json(&mut state, &mut mode, r#"{"color_temp":250}"#);
+
+        let json = serde_json::to_value(State::new(&state, mode)).unwrap();
+        assert_eq!(json["state"], "ON");
+        assert_eq!(json["color_mode"], "color_temp");
+        assert_eq!(json["color_temp"], 250);
+        assert_eq!(json["effect"], "Solid");
+
+        // The color was changed by another API, so the color temperature is outdated.
+        state.segments[0].colors[0] = Pixel::new(255, 0, 0);
+        let json = serde_json::to_value(State::new(&state, mode)).unwrap();
+        assert_eq!(json["color_mode"], "hs");
+        assert_eq!(json["color"]["h"], 0.0);
+        assert_eq!(json["color"]["s"], 100.0);
+        assert!(json.get("color_temp").is_none());
+    }
+
+    #[test]
+    fn color_temperatures() {
+        assert_eq!(temperature(MIN_MIREDS), Pixel::new(255, 255, 251));
+        let warm = temperature(MAX_MIREDS);
+        assert_eq!(warm.red, 255);
+        assert!(warm.blue < warm.green && warm.green < warm.red);
+    }
+}
@@ -1,20 +1,40 @@
+#[cfg(target_os = "espidf")]
 use std::fmt::Write;
 
+#[cfg(target_os = "espidf")]
 use esp_idf_hal::cpu::Core;
 
+#[cfg(target_os = "espidf")]
 mod backtrace;
+#[cfg(target_os = "espidf")]
+pub mod coredump;
+#[cfg(target_os = "espidf")]
+pub mod crash;
+#[cfg(target_os = "espidf")]
+pub mod errors;
 pub mod executor;
+pub mod health;
+pub mod memory;
+pub mod net;
+pub mod sync;
+pub mod thread;
 pub mod timer;
 
+#[cfg(target_os = "espidf")]
 pub trait ResultExt<T, E> {
     fn into_error_log(self) -> Option<T>;
 }
 
+#[cfg(target_os = "espidf")]
 impl<T, E: std::error::Error> ResultExt<T, E> for Result<T, E> {
+    /// Log the error with its sources and record it in the [`errors`] telemetry.
     #[track_caller]
     fn into_error_log(self) -> Option<T> {
-        let caller = core::panic::Location::caller().file();
+        let location = core::panic::Location::caller();
+        let caller = location.file();
         self.map_err(|err| {
+            errors::record(location, &err);
+
             let mut msg = String::new();
             let mut source = err.source();
 
@@ -35,6 +55,9 @@ impl<T, E: std::error::Error> ResultExt<T, E> for Result<T, E> {
     }
 }
 
+/// Print the panic with a backtrace, store it in the [`crash`] record and the
+/// [`coredump`] crash log, and restart.
+#[cfg(target_os = "espidf")]
 pub fn set_panic_hook() {
     std::panic::set_hook(Box::new(|panic_info| {
         let core = esp_idf_hal::cpu::core();
@@ -49,13 +72,25 @@ pub fn set_panic_hook() {
         );
         println!("\r\nBacktrace:");
         for frame in backtrace::Backtrace::new().take(100) {
-            println!("{} ", frame);
+            match frame.symbol() {
+                Some(symbol) => {
+                    println!("{} {}+{:#x}", frame, symbol.name, symbol.offset(frame.pc))
+                }
+                None => println!("{} ", frame),
+            }
         }
 
-        loop {}
+        crash::record_panic(panic_info, if core == Core::Core1 { 1 } else { 0 });
+        coredump::record_panic(format_args!("{}", panic_info));
+
+        println!("\r\nRestarting...");
+        unsafe {
+            esp_idf_sys::esp_restart();
+        }
     }))
 }
 
+#[cfg(target_os = "espidf")]
 pub fn dbg_log_char(c: u8) {
     let arr = [c, 0];
     unsafe {
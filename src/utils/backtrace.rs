@@ -18,9 +18,42 @@ use esp_idf_sys::{
     SOC_RTC_IRAM_HIGH,
     SOC_RTC_IRAM_LOW, esp_backtrace_get_start,
 };
+use symtab::{Symbol, SymbolTable};
+
+/// The space reserved in flash for the symbol table.
+#[cfg(feature = "embedded-symbols")]
+const SYMBOL_TABLE_SIZE: usize = 128 * 1024;
+
+/// The symbol table of this firmware, filled by `tools/src/bin/embed-symbols.rs` after
+/// linking.
+#[cfg(feature = "embedded-symbols")]
+#[no_mangle]
+#[used]
+static ESP32_HUE_SYMTAB: [u8; SYMBOL_TABLE_SIZE] = symtab::reserve::<SYMBOL_TABLE_SIZE>();
+
+/// Get the symbol table embedded in flash.
+///
+/// Returns `None` if the firmware was built without the `embedded-symbols` feature or
+/// the table wasn't filled after linking.
+pub fn symbol_table() -> Option<SymbolTable<'static>> {
+    #[cfg(feature = "embedded-symbols")]
+    {
+        // The table is patched after linking, so the compiler must not assume that it
+        // still contains its initial value.
+        let table: *const [u8] = &ESP32_HUE_SYMTAB[..];
+        let table = unsafe { &*core::ptr::read_volatile(&table) };
+
+        SymbolTable::parse(table).filter(|t| !t.is_empty())
+    }
+    #[cfg(not(feature = "embedded-symbols"))]
+    {
+        None
+    }
+}
 
 /// A frame in the backtrace
-#[derive(Debug)]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
 pub struct BacktraceFrame {
     /// The address of the last instruction in the backtrace (program counter).
     pub pc: u32,
@@ -49,6 +82,11 @@ impl BacktraceFrame {
 
         sp_in_dram && self.sp != 0 && is_pointer_executable(self.pc as usize)
     }
+
+    /// Find the function of `pc` in the embedded [`symbol_table`].
+    pub fn symbol(&self) -> Option<Symbol<'static>> {
+        symbol_table()?.lookup(self.pc)
+    }
 }
 
 /// Wether or not the supplied address is in an executable memory space.
@@ -95,6 +133,19 @@ impl Backtrace {
         frame
     }
 
+    /// Create a backtrace from a saved context, e.g. the frame a suspended task was
+    /// switched out with.
+    ///
+    /// The window registers of the context must already be spilled onto the stack.
+    pub fn from_registers(pc: u32, sp: u32, next_pc: u32) -> Backtrace {
+        Backtrace {
+            pc,
+            sp,
+            next_pc,
+            last: false,
+        }
+    }
+
     /// Convert the PC register value to its true address
     ///
     /// The address of the current instruction is not stored as an exact u32
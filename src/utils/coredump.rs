@@ -0,0 +1,340 @@
+//! Crash data stored in flash: the ESP-IDF core dump and a log of all task backtraces.
+//!
+//! ESP-IDF writes a core dump to the `coredump` partition on CPU exceptions and watchdog
+//! timeouts, it contains the stacks of all tasks but needs the ELF and `espcoredump.py`
+//! to be read. Additionally the backtrace of every task is written to the `crashlog`
+//! partition in the format of the [`crashlog`] crate, both on hard faults (by wrapping
+//! `esp_panic_handler` at link time, see `build.rs`) and on Rust panics. Both are served
+//! as one archive by `GET /debug/coredump` and turned into a report on the host with
+//! `tools/src/bin/parse-crash.rs`.
+
+use core::ffi::c_void;
+use core::fmt::Arguments;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use crashlog::{Cause, CrashLog, Writer, TASK_NAME_LEN};
+use esp_idf_sys::{self as sys, esp, EspError};
+
+use super::backtrace::Backtrace;
+use super::crash::write_truncated;
+
+/// The label of the partition the crash log is written to, see `partitions.csv`.
+const PARTITION_LABEL: &[u8] = b"crashlog\0";
+/// The crash log occupies one flash sector, the first word is the length of the log.
+const SECTOR_SIZE: usize = 4096;
+/// The maximum amount of tasks in the crash log.
+const MAX_TASKS: usize = 24;
+/// The maximum amount of frames per task, keeps all tasks within one sector.
+const MAX_FRAMES: usize = 16;
+
+static CRASHLOG_PARTITION: AtomicPtr<sys::esp_partition_t> = AtomicPtr::new(ptr::null_mut());
+static COREDUMP_PARTITION: AtomicPtr<sys::esp_partition_t> = AtomicPtr::new(ptr::null_mut());
+/// Set while a crash log is written, so that a fault during writing doesn't recurse.
+static RECORDING: AtomicBool = AtomicBool::new(false);
+/// The crash log is built here, since the heap can't be used during a fault.
+static mut BUFFER: [u8; SECTOR_SIZE] = [0; SECTOR_SIZE];
+
+extern "C" {
+    fn __real_esp_panic_handler(info: *mut c_void);
+}
+
+/// The `panic_info_t` passed to `esp_panic_handler`, see `esp_private/panic_internal.h`.
+///
+/// Only `frame` is used, the other fields keep the layout.
+#[allow(dead_code)]
+#[repr(C)]
+struct PanicInfo {
+    core: i32,
+    exception: i32,
+    reason: *const c_void,
+    description: *const c_void,
+    details: *const c_void,
+    state: *const c_void,
+    addr: *const c_void,
+    /// The `XtExcFrame` of the exception.
+    frame: *const c_void,
+    pseudo_excause: bool,
+}
+
+/// Called by ESP-IDF on CPU exceptions, watchdog timeouts and `abort()`, before the core
+/// dump is written.
+///
+/// The other core is already stalled and the scheduler doesn't run anymore.
+#[no_mangle]
+unsafe extern "C" fn __wrap_esp_panic_handler(info: *mut c_void) {
+    // The OS flash guards would wait on locks that are never released now.
+    sys::spi_flash_guard_set(&sys::g_flash_guard_no_os_ops);
+    // The backtrace of the faulting task starts at the exception, not in this handler.
+    let backtrace = match (info as *const PanicInfo).as_ref() {
+        Some(info) if !info.frame.is_null() => exception_context(info.frame as *const u32),
+        _ => None,
+    };
+    record(
+        Cause::Fault,
+        backtrace,
+        format_args!("CPU exception or watchdog timeout"),
+    );
+
+    __real_esp_panic_handler(info);
+}
+
+/// Find the crash data partitions and log whether they contain data.
+///
+/// Must be called once at startup, crashes before are not logged to flash.
+pub fn init() {
+    unsafe {
+        let crashlog = sys::esp_partition_find_first(
+            sys::esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+            sys::esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+            PARTITION_LABEL.as_ptr() as *const _,
+        );
+        let coredump = sys::esp_partition_find_first(
+            sys::esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+            sys::esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_COREDUMP,
+            ptr::null(),
+        );
+
+        CRASHLOG_PARTITION.store(crashlog as *mut _, Ordering::SeqCst);
+        COREDUMP_PARTITION.store(coredump as *mut _, Ordering::SeqCst);
+    }
+
+    if CRASHLOG_PARTITION.load(Ordering::SeqCst).is_null() {
+        log::warn!("no crashlog partition, task backtraces are not stored on crashes");
+    }
+
+    match crash_log() {
+        Ok(Some(data)) => match CrashLog::parse(&data) {
+            Ok(log) => log::warn!(
+                "crash log stored: {:?} after {} ms: {}",
+                log.cause,
+                log.uptime_ms,
+                log.message
+            ),
+            Err(err) => log::warn!("invalid crash log stored: {}", err),
+        },
+        Ok(None) => (),
+        Err(err) => log::error!("failed to read crash log: {}", err),
+    }
+    if let Ok(Some(core_dump)) = core_dump() {
+        log::warn!("core dump of {} bytes stored", core_dump.size);
+    }
+}
+
+/// Write the backtraces of all tasks to the crash log, called from the panic hook.
+pub(super) fn record_panic(message: Arguments<'_>) {
+    record(Cause::Panic, None, message);
+}
+
+/// Write the crash log, the current task's backtrace starts at `current` if given.
+fn record(cause: Cause, current: Option<Backtrace>, message: Arguments<'_>) {
+    if RECORDING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let partition = CRASHLOG_PARTITION.load(Ordering::SeqCst);
+    if partition.is_null() {
+        return;
+    }
+
+    let mut message_buf = [0; 128];
+    let message_len = write_truncated(&mut message_buf, message) as usize;
+    let message = core::str::from_utf8(&message_buf[..message_len]).unwrap_or_default();
+    let uptime_ms = (unsafe { sys::esp_timer_get_time() } / 1000) as u32;
+
+    // Safe because `RECORDING` is only reset by a reboot.
+    let buffer = unsafe { &mut *ptr::addr_of_mut!(BUFFER) };
+    let (len_buf, log_buf) = buffer.split_at_mut(4);
+    let mut writer = Writer::new(log_buf, cause, uptime_ms, message);
+    write_tasks(&mut writer, current);
+    let len = writer.finish().len() as u32;
+    len_buf.copy_from_slice(&len.to_le_bytes());
+
+    unsafe {
+        let _ = esp!(sys::esp_partition_erase_range(partition, 0, SECTOR_SIZE as _))
+            .and_then(|_| {
+                esp!(sys::esp_partition_write(
+                    partition,
+                    0,
+                    buffer.as_ptr() as *const _,
+                    len as usize + 4,
+                ))
+            });
+    }
+}
+
+/// Add the current task and the saved context of all other tasks to `writer`.
+///
+/// The backtrace of the current task starts at `backtrace`, or here if it's `None`.
+fn write_tasks(writer: &mut Writer<'_>, backtrace: Option<Backtrace>) {
+    let current = unsafe { sys::xTaskGetCurrentTaskHandle() };
+    writer.add_task(
+        task_name(current),
+        backtrace
+            .unwrap_or_else(Backtrace::new)
+            .take(MAX_FRAMES)
+            .map(|frame| (frame.pc, frame.sp)),
+    );
+
+    let mut snapshots: [sys::TaskSnapshot_t; MAX_TASKS] = unsafe { core::mem::zeroed() };
+    let mut tcb_size = 0;
+    let count = unsafe {
+        sys::uxTaskGetSnapshotAll(snapshots.as_mut_ptr(), MAX_TASKS as _, &mut tcb_size)
+    } as usize;
+
+    for snapshot in &snapshots[..count.min(MAX_TASKS)] {
+        let task = snapshot.pxTCB as sys::TaskHandle_t;
+        if task == current || snapshot.pxTopOfStack.is_null() {
+            continue;
+        }
+
+        let backtrace = unsafe { saved_context(snapshot.pxTopOfStack as *const u32) };
+        let frames = backtrace
+            .into_iter()
+            .flatten()
+            .take(MAX_FRAMES)
+            .map(|frame| (frame.pc, frame.sp));
+        if !writer.add_task(task_name(task), frames) {
+            break;
+        }
+    }
+}
+
+/// Get the backtrace of the frame a task was switched out with.
+///
+/// Returns `None` if the frame doesn't look valid.
+unsafe fn saved_context(top_of_stack: *const u32) -> Option<Backtrace> {
+    // The frame layouts are `XtSolFrame` for tasks that yielded (`exit` is zero) and
+    // `XtExcFrame` for interrupted tasks, see `xtensa_context.h`.
+    if *top_of_stack != 0 {
+        return exception_context(top_of_stack);
+    }
+
+    let pc = *top_of_stack.add(1);
+    let (a0, a1) = (*top_of_stack.add(4), *top_of_stack.add(5));
+    (pc != 0 && a1 != 0).then(|| Backtrace::from_registers(pc, a1, a0))
+}
+
+/// Get the backtrace starting at the PC, SP (`a1`) and return address (`a0`) of an
+/// `XtExcFrame`.
+///
+/// Returns `None` if the frame doesn't look valid.
+unsafe fn exception_context(frame: *const u32) -> Option<Backtrace> {
+    let pc = *frame.add(1);
+    let (a0, a1) = (*frame.add(3), *frame.add(4));
+    (pc != 0 && a1 != 0).then(|| Backtrace::from_registers(pc, a1, a0))
+}
+
+fn task_name<'a>(task: sys::TaskHandle_t) -> &'a [u8] {
+    if task.is_null() {
+        return b"";
+    }
+
+    unsafe {
+        let name = sys::pcTaskGetTaskName(task);
+        if name.is_null() {
+            return b"";
+        }
+        let len = (0..TASK_NAME_LEN)
+            .find(|&i| *name.add(i) == 0)
+            .unwrap_or(TASK_NAME_LEN);
+        core::slice::from_raw_parts(name as *const u8, len)
+    }
+}
+
+/// Read the crash log stored in flash, `None` if there is none.
+pub fn crash_log() -> Result<Option<Vec<u8>>, EspError> {
+    let partition = CRASHLOG_PARTITION.load(Ordering::SeqCst);
+    if partition.is_null() {
+        return Ok(None);
+    }
+
+    let mut len = [0; 4];
+    unsafe {
+        esp!(sys::esp_partition_read(
+            partition,
+            0,
+            len.as_mut_ptr() as *mut _,
+            len.len()
+        ))?;
+    }
+    // An erased sector reads as `0xff`.
+    let len = u32::from_le_bytes(len) as usize;
+    if len == 0 || len > SECTOR_SIZE - 4 {
+        return Ok(None);
+    }
+
+    let mut data = vec![0; len];
+    unsafe {
+        esp!(sys::esp_partition_read(
+            partition,
+            4,
+            data.as_mut_ptr() as *mut _,
+            len
+        ))?;
+    }
+
+    Ok(CrashLog::parse(&data).is_ok().then(|| data))
+}
+
+/// The ESP-IDF core dump stored in flash.
+#[derive(Debug, Clone, Copy)]
+pub struct CoreDump {
+    address: usize,
+    /// The size of the core dump in bytes.
+    pub size: usize,
+}
+
+impl CoreDump {
+    /// Read `buf.len()` bytes of the core dump starting at `offset`.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), EspError> {
+        assert!(offset + buf.len() <= self.size, "read past the core dump");
+
+        unsafe {
+            esp!(sys::esp_flash_read(
+                ptr::null_mut(),
+                buf.as_mut_ptr() as *mut _,
+                (self.address + offset) as u32,
+                buf.len() as u32,
+            ))
+        }
+    }
+}
+
+/// Get the core dump stored in flash, `None` if there is no valid one.
+pub fn core_dump() -> Result<Option<CoreDump>, EspError> {
+    if COREDUMP_PARTITION.load(Ordering::SeqCst).is_null() {
+        return Ok(None);
+    }
+
+    let mut address = 0;
+    let mut size = 0;
+    // Also fails if the partition is erased or the checksum doesn't match.
+    match unsafe { sys::esp_core_dump_image_get(&mut address, &mut size) } {
+        sys::ESP_OK => Ok(Some(CoreDump { address, size })),
+        sys::ESP_ERR_NOT_FOUND | sys::ESP_ERR_INVALID_SIZE | sys::ESP_ERR_INVALID_CRC => {
+            Ok(None)
+        }
+        err => Err(EspError::from(err).unwrap()),
+    }
+}
+
+/// Erase the crash log and the core dump.
+pub fn erase() -> Result<(), EspError> {
+    for partition in [&CRASHLOG_PARTITION, &COREDUMP_PARTITION] {
+        let partition = partition.load(Ordering::SeqCst);
+        if partition.is_null() {
+            continue;
+        }
+
+        unsafe {
+            esp!(sys::esp_partition_erase_range(
+                partition,
+                0,
+                (*partition).size as _
+            ))?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,288 @@
+//! Panic reports that survive a reboot.
+//!
+//! The panic hook stores a crash record in RTC memory that is not initialized on boot and
+//! restarts the chip. On the next boot [`init`] validates the record, logs it and keeps it
+//! for [`last_crash`]. The reset reasons since the last power-on are kept the same way.
+
+use core::fmt::Write;
+use core::mem::{size_of, MaybeUninit};
+use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
+
+use esp_idf_sys as sys;
+use serde::Serialize;
+
+use super::backtrace::{Backtrace, BacktraceFrame};
+
+/// Marks a valid record, the checksum guards against a partially written record.
+const MAGIC: u32 = 0x4352_5348;
+/// The amount of backtrace frames stored in a record.
+pub const MAX_FRAMES: usize = 32;
+/// The amount of reset reasons kept in the history.
+pub const MAX_RESET_HISTORY: usize = 16;
+
+#[repr(C)]
+struct CrashRecord {
+    magic: u32,
+    checksum: u32,
+    core: u32,
+    line: u32,
+    column: u32,
+    message_len: u32,
+    file_len: u32,
+    frame_count: u32,
+    message: [u8; 256],
+    file: [u8; 96],
+    frames: [BacktraceFrame; MAX_FRAMES],
+}
+
+#[repr(C)]
+struct ResetHistory {
+    magic: u32,
+    checksum: u32,
+    len: u32,
+    /// The raw `esp_reset_reason_t`s, oldest first.
+    reasons: [u32; MAX_RESET_HISTORY],
+}
+
+#[link_section = ".rtc_noinit"]
+static mut CRASH_RECORD: MaybeUninit<CrashRecord> = MaybeUninit::uninit();
+#[link_section = ".rtc_noinit"]
+static mut RESET_HISTORY: MaybeUninit<ResetHistory> = MaybeUninit::uninit();
+
+static STATE: spin::Mutex<Option<BootState>> = spin::Mutex::new(None);
+
+struct BootState {
+    last_crash: Option<CrashReport>,
+    reset_history: Vec<ResetReason>,
+}
+
+/// A panic of the previous boot.
+#[derive(Debug, Clone, Serialize)]
+pub struct CrashReport {
+    /// The panic message including its location.
+    pub message: String,
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+    /// The core that panicked (`0` is PRO, `1` is APP).
+    pub core: u32,
+    pub backtrace: Vec<FrameReport>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FrameReport {
+    pub pc: u32,
+    pub sp: u32,
+    /// The function of `pc` with offset, if the firmware contains a symbol table.
+    pub symbol: Option<String>,
+}
+
+impl From<&BacktraceFrame> for FrameReport {
+    fn from(frame: &BacktraceFrame) -> Self {
+        FrameReport {
+            pc: frame.pc,
+            sp: frame.sp,
+            symbol: frame
+                .symbol()
+                .map(|s| format!("{}+{:#x}", s.name, s.offset(frame.pc))),
+        }
+    }
+}
+
+/// Why the chip was reset, see `esp_reset_reason_t`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResetReason {
+    Unknown,
+    PowerOn,
+    External,
+    Software,
+    Panic,
+    InterruptWatchdog,
+    TaskWatchdog,
+    Watchdog,
+    DeepSleep,
+    Brownout,
+    Sdio,
+}
+
+impl From<sys::esp_reset_reason_t> for ResetReason {
+    #[allow(non_upper_case_globals)]
+    fn from(reason: sys::esp_reset_reason_t) -> Self {
+        match reason {
+            sys::esp_reset_reason_t_ESP_RST_POWERON => ResetReason::PowerOn,
+            sys::esp_reset_reason_t_ESP_RST_EXT => ResetReason::External,
+            sys::esp_reset_reason_t_ESP_RST_SW => ResetReason::Software,
+            sys::esp_reset_reason_t_ESP_RST_PANIC => ResetReason::Panic,
+            sys::esp_reset_reason_t_ESP_RST_INT_WDT => ResetReason::InterruptWatchdog,
+            sys::esp_reset_reason_t_ESP_RST_TASK_WDT => ResetReason::TaskWatchdog,
+            sys::esp_reset_reason_t_ESP_RST_WDT => ResetReason::Watchdog,
+            sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => ResetReason::DeepSleep,
+            sys::esp_reset_reason_t_ESP_RST_BROWNOUT => ResetReason::Brownout,
+            sys::esp_reset_reason_t_ESP_RST_SDIO => ResetReason::Sdio,
+            _ => ResetReason::Unknown,
+        }
+    }
+}
+
+/// Load the crash record and reset reason of the previous boot.
+///
+/// Must be called once at startup, before the first panic can happen.
+pub fn init() {
+    let reason = unsafe { sys::esp_reset_reason() };
+    let reset_history = unsafe { push_reset_reason(reason) };
+    let last_crash = unsafe { take_crash_record() };
+
+    log::info!("reset reason: {:?}", ResetReason::from(reason));
+    if let Some(crash) = &last_crash {
+        log::error!(
+            "previous boot panicked on core {}: {}",
+            crash.core,
+            crash.message
+        );
+        for frame in &crash.backtrace {
+            match &frame.symbol {
+                Some(symbol) => log::error!("  {:#010x}:{:#010x} {}", frame.pc, frame.sp, symbol),
+                None => log::error!("  {:#010x}:{:#010x}", frame.pc, frame.sp),
+            }
+        }
+    }
+
+    *STATE.lock() = Some(BootState {
+        last_crash,
+        reset_history,
+    });
+}
+
+/// The panic of the previous boot, if it ended with a panic.
+pub fn last_crash() -> Option<CrashReport> {
+    STATE.lock().as_ref()?.last_crash.clone()
+}
+
+/// The reset reasons since the last power-on, oldest first.
+pub fn reset_history() -> Vec<ResetReason> {
+    STATE
+        .lock()
+        .as_ref()
+        .map(|s| s.reset_history.clone())
+        .unwrap_or_default()
+}
+
+/// Store the panic in RTC memory, called from the panic hook.
+pub(super) fn record_panic(panic_info: &PanicInfo, core: u32) {
+    // Safe because the record is only written from the panic hook, which doesn't run
+    // concurrently with `init`.
+    let record = unsafe { &mut *(addr_of_mut!(CRASH_RECORD) as *mut CrashRecord) };
+
+    record.magic = 0;
+    record.core = core;
+    record.message_len = write_truncated(&mut record.message, format_args!("{}", panic_info));
+
+    let (file, line, column) = panic_info
+        .location()
+        .map(|l| (l.file(), l.line(), l.column()))
+        .unwrap_or(("<unknown>", 0, 0));
+    record.file_len = write_truncated(&mut record.file, format_args!("{}", file));
+    record.line = line;
+    record.column = column;
+
+    let mut frame_count = 0;
+    for (slot, frame) in record.frames.iter_mut().zip(Backtrace::new()) {
+        *slot = frame;
+        frame_count += 1;
+    }
+    record.frame_count = frame_count;
+
+    record.checksum = checksum(record);
+    record.magic = MAGIC;
+}
+
+unsafe fn take_crash_record() -> Option<CrashReport> {
+    let record = &mut *(addr_of_mut!(CRASH_RECORD) as *mut CrashRecord);
+    if core::ptr::read_volatile(&record.magic) != MAGIC || record.checksum != checksum(record) {
+        return None;
+    }
+    // Only report the crash once.
+    record.magic = 0;
+
+    let message_len = (record.message_len as usize).min(record.message.len());
+    let file_len = (record.file_len as usize).min(record.file.len());
+    let frame_count = (record.frame_count as usize).min(MAX_FRAMES);
+
+    Some(CrashReport {
+        message: String::from_utf8_lossy(&record.message[..message_len]).into_owned(),
+        file: String::from_utf8_lossy(&record.file[..file_len]).into_owned(),
+        line: record.line,
+        column: record.column,
+        core: record.core,
+        backtrace: record.frames[..frame_count]
+            .iter()
+            .map(FrameReport::from)
+            .collect(),
+    })
+}
+
+unsafe fn push_reset_reason(reason: sys::esp_reset_reason_t) -> Vec<ResetReason> {
+    let history = &mut *(addr_of_mut!(RESET_HISTORY) as *mut ResetHistory);
+
+    let valid = core::ptr::read_volatile(&history.magic) == MAGIC
+        && history.checksum == checksum(history)
+        && history.len as usize <= MAX_RESET_HISTORY;
+    // The RTC memory is lost on power-on, so the history starts over.
+    if !valid || reason == sys::esp_reset_reason_t_ESP_RST_POWERON {
+        history.len = 0;
+    }
+
+    if history.len as usize == MAX_RESET_HISTORY {
+        history.reasons.copy_within(1.., 0);
+        history.len -= 1;
+    }
+    history.reasons[history.len as usize] = reason as u32;
+    history.len += 1;
+
+    history.checksum = checksum(history);
+    history.magic = MAGIC;
+
+    history.reasons[..history.len as usize]
+        .iter()
+        .map(|&r| ResetReason::from(r as sys::esp_reset_reason_t))
+        .collect()
+}
+
+/// FNV-1a over all bytes of `value` after the `magic` and `checksum` fields.
+fn checksum<T>(value: &T) -> u32 {
+    let bytes = unsafe {
+        core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>())
+    };
+
+    bytes[8..].iter().fold(0x811c_9dc5, |hash, &b| {
+        (hash ^ b as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Write `args` into `buf` and truncate it if it doesn't fit, returns the written length.
+pub(super) fn write_truncated(buf: &mut [u8], args: core::fmt::Arguments<'_>) -> u32 {
+    struct Writer<'a> {
+        buf: &'a mut [u8],
+        len: usize,
+    }
+
+    impl Write for Writer<'_> {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            let remaining = self.buf.len() - self.len;
+            let mut count = s.len().min(remaining);
+            while !s.is_char_boundary(count) {
+                count -= 1;
+            }
+
+            self.buf[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+            self.len += count;
+            Ok(())
+        }
+    }
+
+    let mut writer = Writer { buf, len: 0 };
+    let _ = writer.write_fmt(args);
+    writer.len as u32
+}
@@ -0,0 +1,154 @@
+//! Telemetry of the errors logged with [`ResultExt::into_error_log`](super::ResultExt).
+//!
+//! The last [`MAX_EVENTS`] errors are kept in a ring buffer, and the number of errors is
+//! counted per call site since boot. Both are served by `GET /debug/errors` and a
+//! summary is logged by [`log_summary`].
+
+use core::panic::Location;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use serde::Serialize;
+
+/// The amount of errors kept in the ring buffer.
+pub const MAX_EVENTS: usize = 32;
+/// The amount of call sites that are counted, errors of further call sites are only
+/// counted in [`ErrorStats::total`].
+pub const MAX_CALL_SITES: usize = 64;
+
+static LOG: Mutex<ErrorLog> = Mutex::new(ErrorLog {
+    events: Vec::new(),
+    next: 0,
+    call_sites: Vec::new(),
+    total: 0,
+});
+
+/// Lock the error log, which stays usable if a thread panicked while holding it.
+fn lock() -> MutexGuard<'static, ErrorLog> {
+    LOG.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+struct ErrorLog {
+    /// A ring buffer, `next` is the index of the oldest event once it is full.
+    events: Vec<ErrorEvent>,
+    next: usize,
+    call_sites: Vec<(&'static Location<'static>, CallSiteCount)>,
+    total: u32,
+}
+
+/// An error logged with [`ResultExt::into_error_log`](super::ResultExt).
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorEvent {
+    /// The time since boot in milliseconds.
+    pub timestamp_ms: u64,
+    /// The location `into_error_log` was called at.
+    pub location: String,
+    pub message: String,
+    /// The sources of the error, outermost first.
+    pub causes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CallSiteCount {
+    pub location: String,
+    pub count: u32,
+    /// The time of the last error at this call site in milliseconds since boot.
+    pub last_timestamp_ms: u64,
+}
+
+/// A snapshot of the error telemetry.
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorStats {
+    /// The amount of errors logged since boot.
+    pub total: u32,
+    /// The errors per call site, the most frequent first.
+    pub call_sites: Vec<CallSiteCount>,
+    /// The last errors, oldest first.
+    pub recent: Vec<ErrorEvent>,
+}
+
+/// Record a logged error.
+pub(super) fn record(location: &'static Location<'static>, err: &dyn std::error::Error) {
+    let timestamp_ms = uptime_ms();
+    let mut causes = Vec::new();
+    let mut source = err.source();
+    while let Some(err) = source {
+        causes.push(err.to_string());
+        source = err.source();
+    }
+
+    let event = ErrorEvent {
+        timestamp_ms,
+        location: location.to_string(),
+        message: err.to_string(),
+        causes,
+    };
+
+    let mut log = lock();
+    log.total = log.total.saturating_add(1);
+
+    match log.call_sites.iter().position(|(l, _)| *l == location) {
+        Some(index) => {
+            let count = &mut log.call_sites[index].1;
+            count.count = count.count.saturating_add(1);
+            count.last_timestamp_ms = timestamp_ms;
+        }
+        None if log.call_sites.len() < MAX_CALL_SITES => {
+            let count = CallSiteCount {
+                location: event.location.clone(),
+                count: 1,
+                last_timestamp_ms: timestamp_ms,
+            };
+            log.call_sites.push((location, count));
+        }
+        None => (),
+    }
+
+    if log.events.len() < MAX_EVENTS {
+        log.events.push(event);
+    } else {
+        let next = log.next;
+        log.events[next] = event;
+        log.next = (next + 1) % MAX_EVENTS;
+    }
+}
+
+/// Get a snapshot of the recorded errors.
+pub fn stats() -> ErrorStats {
+    let log = lock();
+
+    let mut call_sites: Vec<_> = log.call_sites.iter().map(|(_, c)| c.clone()).collect();
+    call_sites.sort_by_key(|c| core::cmp::Reverse(c.count));
+
+    let (newer, older) = log.events.split_at(log.next);
+    let recent = older.iter().chain(newer).cloned().collect();
+
+    ErrorStats {
+        total: log.total,
+        call_sites,
+        recent,
+    }
+}
+
+/// The amount of errors logged since boot.
+pub fn total() -> u32 {
+    lock().total
+}
+
+/// Log the error count per call site.
+pub fn log_summary() {
+    let stats = stats();
+
+    log::info!("{} errors since boot", stats.total);
+    for call_site in &stats.call_sites {
+        log::info!(
+            "  {}: {} times, last at {} ms",
+            call_site.location,
+            call_site.count,
+            call_site.last_timestamp_ms
+        );
+    }
+}
+
+fn uptime_ms() -> u64 {
+    (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u64
+}
@@ -2,21 +2,39 @@ use core::future::Future;
 use core::mem::ManuallyDrop;
 use core::pin::Pin;
 use core::ptr::NonNull;
-use core::sync::atomic::{AtomicBool, Ordering};
-use core::task::{self, Waker};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::task::{self, Context, Poll, Waker};
 use std::marker::PhantomData;
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use esp_idf_hal::interrupt;
+use futures::channel::mpsc;
+use futures::future::BoxFuture;
+use futures::StreamExt;
 use heapless::{spsc, Vec};
 
+use self::backend::{Backend, DefaultBackend, Notifier};
+use self::stats::{ExecutorStats, Instrumentation};
+use super::timer::TimerQueue;
+
+pub mod backend;
+pub mod spawner;
+pub mod stats;
+
 /// A minimal executor.
-pub struct Executor {
+///
+/// The thread running the executor is put to sleep and woken up using the park/notify
+/// mechanism of the [`Backend`] `B`. The executor also owns the deadline queue of all
+/// timers created with [`Executor::sleep`] and friends.
+pub struct Executor<B: Backend = DefaultBackend> {
     state: spin::Mutex<ExecutorState>,
+    pub(crate) timers: spin::Mutex<TimerQueue>,
+    instrumentation: spin::Mutex<Option<Instrumentation>>,
+    _backend: PhantomData<fn() -> B>,
 }
 
-unsafe impl Sync for Executor {}
+unsafe impl<B: Backend> Sync for Executor<B> {}
 
 pub struct ExecutorState {
     enqueue_task: Option<NonNull<(dyn FnMut(TaskId) + Send)>>,
@@ -32,42 +50,105 @@ impl ExecutorState {
     }
 }
 
-impl Executor {
+impl<B: Backend> Executor<B> {
     /// Create a new [`Executor`], the executor must live forever to be useful.
     pub const fn new() -> Self {
         Executor {
             state: spin::Mutex::new(ExecutorState { enqueue_task: None }),
+            timers: spin::Mutex::new(TimerQueue::new()),
+            instrumentation: spin::Mutex::new(None),
+            _backend: PhantomData,
         }
     }
 
-    /// Run the exeuctor with the given `tasks`.
+    /// Record per-task statistics and log a warning for every poll that takes longer than
+    /// `stall_threshold`.
+    ///
+    /// Must be called before [`Executor::run`].
+    pub fn enable_stats(&self, stall_threshold: Duration) {
+        *self.instrumentation.lock() = Some(Instrumentation::new(stall_threshold));
+    }
+
+    /// Get a snapshot of the per-task statistics if they are enabled.
+    pub fn stats(&self) -> Option<ExecutorStats> {
+        self.instrumentation
+            .lock()
+            .as_ref()
+            .map(Instrumentation::snapshot)
+    }
+
+    /// Let the other tasks of this executor run before the current task continues.
+    ///
+    /// Tasks that do a lot of work without waiting for anything should call this in
+    /// between, so they don't starve the other tasks.
+    pub fn yield_now(&self) -> YieldNow {
+        YieldNow { yielded: false }
+    }
+
+    /// Run the executor with the given `tasks` on the current thread until all of them
+    /// completed.
     ///
-    /// TODO: document behavior
+    /// Every task is polled once at the start and afterwards only after it was woken. In
+    /// between, the thread waits in [`Backend::wait`] for a woken task or the next timer
+    /// deadline. The task queue holds `N - 1` tasks, so `N` must be larger than the
+    /// number of tasks. The executor must not run on several threads at the same time.
     pub fn run<const N: usize>(
         &'static self,
         tasks: &mut [&mut (dyn Future<Output = ()> + Unpin)],
     ) {
+        let tasks = tasks
+            .iter_mut()
+            .map(|task| Box::pin(&mut **task) as Pin<Box<dyn Future<Output = ()> + '_>>)
+            .collect();
+        self.run_tasks::<N>(tasks, None);
+    }
+
+    /// Run `tasks` and every task received from `spawned` until all of them completed and
+    /// all senders of `spawned` were dropped.
+    ///
+    /// Every task gets its own slot and [`TaskHandle`], the id of a task is the index of
+    /// its slot. A slot is reused once its task completed and it was removed from the
+    /// queue. The queue holds the tasks and the spawn task [`SPAWN_TASK`], so at most
+    /// `N - 2` tasks run at once when spawning, further spawned tasks wait in `spawned`
+    /// until a task completed.
+    fn run_tasks<'a, const N: usize>(
+        &'static self,
+        tasks: std::vec::Vec<Pin<Box<dyn Future<Output = ()> + 'a>>>,
+        mut spawned: Option<mpsc::UnboundedReceiver<BoxFuture<'static, ()>>>,
+    ) {
+        let max_tasks = if spawned.is_some() { N - 2 } else { N - 1 };
+        assert!(tasks.len() <= max_tasks, "task queue full");
+
         let mut queue = spsc::Queue::<TaskId, N>::new();
         let (mut send, mut receive) = queue.split();
-        let task_handles: Vec<TaskHandle, N> = tasks
-            .iter()
+        let mut slots: std::vec::Vec<Slot<'a, B>> = tasks
+            .into_iter()
             .enumerate()
-            .map(|(id, _)| {
-                send.enqueue(id as TaskId).expect("task queue full");
-                TaskHandle::new(self, id as TaskId)
+            .map(|(id, future)| {
+                send.enqueue(id).expect("task queue full");
+                let handle = TaskHandle::new(self, id);
+                handle.0.is_queued.store(true, Ordering::Relaxed);
+                Slot::Running(future, handle)
             })
             .collect();
+        let spawn_handle = TaskHandle::new(self, SPAWN_TASK);
+        if spawned.is_some() {
+            send.enqueue(SPAWN_TASK).expect("task queue full");
+            spawn_handle.0.is_queued.store(true, Ordering::Relaxed);
+        }
 
-        let thread_handle =
-            NonNull::new(interrupt::task::current().expect("in interrupt")).unwrap();
+        if let Some(instrumentation) = &mut *self.instrumentation.lock() {
+            instrumentation.reset(slots.len());
+        }
+
+        let mut backend = B::current();
+        let notifier = backend.notifier();
 
         let mut enqueue_task = {
-            let thread_handle = thread_handle.clone();
+            let notifier = notifier.clone();
             move |task_id: TaskId| {
                 send.enqueue(task_id).expect("task queue full");
-                unsafe {
-                    interrupt::task::notify(thread_handle.as_ptr(), 1);
-                }
+                notifier.notify();
             }
         };
 
@@ -75,7 +156,7 @@ impl Executor {
             let mut state = self.state.lock();
             // Safe to share with other threads since we make sure that `enqueue_task`
             // isn't called again when `tasks_enqueued` goes out-of-scope, the thread
-            // associated with `thread_handle` doesn't exist anymore, and `enqueue_task`
+            // associated with `notifier` doesn't exist anymore, and `enqueue_task`
             // is called uniquely (only one thread at a time).
             //
             // This is done by having anyone wanting to call this closure acquire the
@@ -85,24 +166,69 @@ impl Executor {
                 unsafe { std::mem::transmute(&mut enqueue_task as &mut (dyn FnMut(TaskId))) };
         }
 
-        unsafe {
-            interrupt::task::notify(thread_handle.as_ptr(), 1);
-        }
+        notifier.notify();
+
+        let mut pending_futures = slots.len();
+        while pending_futures > 0 || spawned.is_some() {
+            let next_deadline = self.timers.lock().next_deadline();
+            backend.wait(next_deadline);
 
-        let mut pending_futures = tasks.len();
-        while pending_futures > 0 {
-            interrupt::task::wait_notification(None);
+            // Collect the wakers first, since waking them locks `Executor::state`.
+            let expired: Vec<Waker, EXPIRED_BATCH> = self
+                .timers
+                .lock()
+                .take_expired(B::now(), EXPIRED_BATCH)
+                .collect();
+            for waker in expired {
+                waker.wake();
+            }
 
             while let Some(task_id) = receive.dequeue() {
-                let handle = &task_handles[task_id];
+                if task_id == SPAWN_TASK {
+                    spawn_handle.0.is_queued.store(false, Ordering::Relaxed);
+                    if let Some(receiver) = &mut spawned {
+                        let waker = spawn_handle.as_waker();
+                        let spawn = self.spawn_tasks(receiver, &mut slots, max_tasks, &waker);
+                        pending_futures += spawn.spawned;
+                        if spawn.closed {
+                            spawned = None;
+                        }
+                    }
+                    continue;
+                }
+
+                let slot = &mut slots[task_id];
+                let (future, handle) = match slot {
+                    Slot::Running(future, handle) => (future, handle),
+                    // The entry of a completed task.
+                    Slot::Draining | Slot::Free => {
+                        *slot = Slot::Free;
+                        continue;
+                    }
+                };
                 handle.0.is_queued.store(false, Ordering::Relaxed);
 
                 let waker = handle.as_waker();
                 let mut context = task::Context::from_waker(&waker);
-                let fut = &mut *tasks[task_id];
 
-                if Pin::new(fut).poll(&mut context).is_ready() {
+                let poll_start = self.instrumentation.lock().is_some().then(Instant::now);
+
+                let ready = future.as_mut().poll(&mut context).is_ready();
+
+                if let Some(poll_start) = poll_start {
+                    self.record_poll(handle, poll_start.elapsed());
+                }
+
+                if ready {
                     pending_futures -= 1;
+                    // Wakers of the task may outlive it, they never enqueue it again
+                    // since it stays queued. If it was enqueued during the poll, the
+                    // slot is freed once the entry is dequeued.
+                    let queued = handle.0.is_queued.swap(true, Ordering::SeqCst);
+                    *slot = if queued { Slot::Draining } else { Slot::Free };
+                    if spawned.is_some() {
+                        spawn_handle.as_waker().wake_by_ref();
+                    }
                 }
             }
         }
@@ -112,28 +238,122 @@ impl Executor {
             state.enqueue_task = None;
         }
     }
+
+    /// Move the tasks received from `receiver` into free slots.
+    fn spawn_tasks<'a>(
+        &'static self,
+        receiver: &mut mpsc::UnboundedReceiver<BoxFuture<'static, ()>>,
+        slots: &mut std::vec::Vec<Slot<'a, B>>,
+        max_tasks: usize,
+        waker: &Waker,
+    ) -> Spawn {
+        let mut context = task::Context::from_waker(waker);
+        let mut spawn = Spawn {
+            spawned: 0,
+            closed: false,
+        };
+
+        loop {
+            let id = match slots.iter().position(|slot| matches!(slot, Slot::Free)) {
+                Some(id) => id,
+                None if slots.len() < max_tasks => {
+                    slots.push(Slot::Free);
+                    slots.len() - 1
+                }
+                // Woken again once a task completed.
+                None => return spawn,
+            };
+
+            let future = match receiver.poll_next_unpin(&mut context) {
+                task::Poll::Ready(Some(future)) => future,
+                task::Poll::Ready(None) => {
+                    spawn.closed = true;
+                    return spawn;
+                }
+                task::Poll::Pending => return spawn,
+            };
+
+            let handle = TaskHandle::new(self, id);
+            handle.0.is_queued.store(true, Ordering::Relaxed);
+            slots[id] = Slot::Running(future, handle);
+            spawn.spawned += 1;
+
+            if let Some(instrumentation) = &mut *self.instrumentation.lock() {
+                instrumentation.start_task(id);
+            }
+            self.state.lock().enqueue_task(id);
+        }
+    }
+
+    fn record_poll(&self, handle: &TaskHandle<B>, duration: Duration) {
+        let wakes = handle.0.wake_count.load(Ordering::Relaxed);
+        let stalled = match &mut *self.instrumentation.lock() {
+            Some(instrumentation) => instrumentation.record_poll(handle.0.id, duration, wakes),
+            None => false,
+        };
+
+        if stalled {
+            log::warn!(
+                "task {} stalled the executor for {:?}",
+                handle.0.id,
+                duration
+            );
+        }
+    }
+}
+
+/// The maximum amount of expired timers handled per executor wake-up, remaining ones are
+/// handled in the next iteration.
+const EXPIRED_BATCH: usize = 8;
+
+/// The id of the task that moves spawned tasks into free slots.
+const SPAWN_TASK: TaskId = TaskId::MAX;
+
+/// A task slot of [`Executor::run_tasks`].
+enum Slot<'a, B: Backend> {
+    Running(Pin<Box<dyn Future<Output = ()> + 'a>>, TaskHandle<B>),
+    /// The task completed, but it is still in the queue.
+    Draining,
+    Free,
+}
+
+/// The result of [`Executor::spawn_tasks`].
+struct Spawn {
+    /// How many tasks were spawned.
+    spawned: usize,
+    /// Whether all senders were dropped.
+    closed: bool,
 }
 
-/// A handle to a task given to [`Executor::run`].
-#[derive(Clone)]
-struct TaskHandle(Arc<TaskHandleData>);
+/// A handle to a task run by an [`Executor`].
+struct TaskHandle<B: Backend>(Arc<TaskHandleData<B>>);
+
+impl<B: Backend> Clone for TaskHandle<B> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
 
 type TaskId = usize;
 
-struct TaskHandleData {
-    executor: &'static Executor,
+struct TaskHandleData<B: Backend> {
+    executor: &'static Executor<B>,
     id: TaskId,
     is_queued: AtomicBool,
+    wake_count: AtomicU32,
 }
 
-impl TaskHandleData {
+impl<B: Backend> TaskHandleData<B> {
     #[inline]
     fn enqueue_task(&self) {
+        self.wake_count.fetch_add(1, Ordering::Relaxed);
+
         // Only enqueue the task once.
         //
-        // This field gets reset by [`Executor::run`] once the task has been dequeued.
-        // Having this field here also means that the `Arc<TaskHandleData>` must be unique
-        // per task, which is fufilled by only letting [`Executor::run`] give out
+        // This field gets reset by [`Executor::run`] once the task has been dequeued, and
+        // stays set once the task completed. Having this field here also means that the
+        // `Arc<TaskHandleData>` must be unique per task, which is fufilled by only
+        // letting [`Executor::run`] give out
         // [`TaskHandle`]s ([`TaskHandle::new`] must be private).
         if let Ok(_) =
             self.is_queued
@@ -145,20 +365,28 @@ impl TaskHandleData {
     }
 
     #[inline]
-    unsafe fn into_raw_waker(data: *const TaskHandleData) -> task::RawWaker {
-        task::RawWaker::new(data as *const (), &TaskHandle::WAKER_VTABLE)
+    unsafe fn into_raw_waker(data: *const TaskHandleData<B>) -> task::RawWaker {
+        task::RawWaker::new(data as *const (), &TaskHandle::<B>::WAKER_VTABLE)
     }
 }
 
 /// A [`Waker`] from a [`TaskHandle`] reference.
-#[derive(Clone)]
 #[repr(transparent)]
-struct AsWaker<'a> {
+struct AsWaker<'a, B: Backend> {
     waker: ManuallyDrop<Waker>,
-    _ref: PhantomData<&'a TaskHandle>,
+    _ref: PhantomData<&'a TaskHandle<B>>,
 }
 
-impl Deref for AsWaker<'_> {
+impl<B: Backend> Clone for AsWaker<'_, B> {
+    fn clone(&self) -> Self {
+        AsWaker {
+            waker: self.waker.clone(),
+            _ref: PhantomData,
+        }
+    }
+}
+
+impl<B: Backend> Deref for AsWaker<'_, B> {
     type Target = Waker;
 
     #[inline]
@@ -167,13 +395,14 @@ impl Deref for AsWaker<'_> {
     }
 }
 
-impl TaskHandle {
+impl<B: Backend> TaskHandle<B> {
     #[inline]
-    fn new(executor: &'static Executor, id: usize) -> Self {
+    fn new(executor: &'static Executor<B>, id: usize) -> Self {
         Self(Arc::new(TaskHandleData {
             executor,
             id,
             is_queued: AtomicBool::new(false),
+            wake_count: AtomicU32::new(0),
         }))
     }
 
@@ -186,7 +415,7 @@ impl TaskHandle {
 
     /// Create a waker without increasing the reference count.
     #[inline]
-    pub fn as_waker(&self) -> AsWaker<'_> {
+    pub fn as_waker(&self) -> AsWaker<'_, B> {
         let arc_data = Arc::as_ptr(&self.0);
         AsWaker {
             waker: ManuallyDrop::new(unsafe {
@@ -197,26 +426,136 @@ impl TaskHandle {
     }
 
     const WAKER_VTABLE: task::RawWakerVTable = task::RawWakerVTable::new(
-        TaskHandle::waker_clone,
-        TaskHandle::waker_wake,
-        TaskHandle::waker_wake_by_ref,
-        TaskHandle::waker_drop,
+        Self::waker_clone,
+        Self::waker_wake,
+        Self::waker_wake_by_ref,
+        Self::waker_drop,
     );
 
     unsafe fn waker_clone(arc_data: *const ()) -> task::RawWaker {
-        let arc_data = arc_data as *const TaskHandleData;
+        let arc_data = arc_data as *const TaskHandleData<B>;
         Arc::increment_strong_count(arc_data);
         TaskHandleData::into_raw_waker(arc_data)
     }
     unsafe fn waker_wake(arc_data: *const ()) {
-        let arc_data = Arc::from_raw(arc_data as *const TaskHandleData);
+        let arc_data = Arc::from_raw(arc_data as *const TaskHandleData<B>);
         arc_data.enqueue_task();
     }
     unsafe fn waker_wake_by_ref(arc_data: *const ()) {
-        let arc_data = &*(arc_data as *const TaskHandleData);
+        let arc_data = &*(arc_data as *const TaskHandleData<B>);
         arc_data.enqueue_task();
     }
     unsafe fn waker_drop(arc_data: *const ()) {
-        drop(Arc::from_raw(arc_data as *const TaskHandleData));
+        drop(Arc::from_raw(arc_data as *const TaskHandleData<B>));
+    }
+}
+
+/// Future returned by [`Executor::yield_now`].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        // Requeue the task behind all tasks that were woken in the meantime.
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::channel::{mpsc, oneshot};
+    use futures::executor::block_on;
+    use futures::StreamExt;
+
+    use super::spawner::MAX_TASKS;
+    use super::Executor;
+    use crate::utils::thread::ThreadConfig;
+
+    static EXECUTOR: Executor = Executor::new();
+    static STATS_EXECUTOR: Executor = Executor::new();
+    static YIELD_EXECUTOR: Executor = Executor::new();
+
+    #[test]
+    fn run_spawned_tasks() {
+        let spawner = EXECUTOR.start(&ThreadConfig::default()).unwrap();
+
+        let (value_send, value_recv) = oneshot::channel();
+        let (result_send, result_recv) = oneshot::channel();
+        spawner
+            .spawn(async move {
+                // Woken by another thread.
+                let value: u32 = value_recv.await.unwrap();
+                // Woken by the timer queue.
+                EXECUTOR.sleep(Duration::from_millis(10)).await;
+                let _ = result_send.send(value + 1);
+            })
+            .unwrap();
+
+        value_send.send(41).unwrap();
+        assert_eq!(block_on(result_recv), Ok(42));
+    }
+
+    #[test]
+    fn spawned_tasks_reuse_slots() {
+        STATS_EXECUTOR.enable_stats(Duration::from_secs(1));
+        let spawner = STATS_EXECUTOR.start(&ThreadConfig::default()).unwrap();
+
+        let task_count = MAX_TASKS + 4;
+        let (done_send, done_recv) = mpsc::unbounded();
+        for i in 0..task_count {
+            let done_send = done_send.clone();
+            spawner
+                .spawn(async move {
+                    STATS_EXECUTOR.sleep(Duration::from_millis(5)).await;
+                    done_send.unbounded_send(i).unwrap();
+                })
+                .unwrap();
+        }
+        drop(done_send);
+
+        let mut done: Vec<usize> = block_on(done_recv.collect());
+        done.sort_unstable();
+        assert_eq!(done, (0..task_count).collect::<Vec<_>>());
+
+        // Every slot was used, the last poll of a task may not be recorded yet.
+        let stats = STATS_EXECUTOR.stats().unwrap();
+        assert_eq!(stats.tasks.len(), MAX_TASKS);
+        assert!(stats.tasks.iter().all(|task| task.polls >= 1));
+    }
+
+    #[test]
+    fn yield_to_other_tasks() {
+        let spawner = YIELD_EXECUTOR.start(&ThreadConfig::default()).unwrap();
+
+        // The busy task only finishes if the other task runs while it yields.
+        let flag = Arc::new(AtomicBool::new(false));
+        let (result_send, result_recv) = oneshot::channel();
+        let busy_flag = flag.clone();
+        spawner
+            .spawn(async move {
+                while !busy_flag.load(Ordering::Relaxed) {
+                    YIELD_EXECUTOR.yield_now().await;
+                }
+                let _ = result_send.send(());
+            })
+            .unwrap();
+        spawner
+            .spawn(async move { flag.store(true, Ordering::Relaxed) })
+            .unwrap();
+
+        assert_eq!(block_on(result_recv), Ok(()));
     }
 }
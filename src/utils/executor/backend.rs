@@ -0,0 +1,223 @@
+//! Thread park/notify mechanisms used by the [`Executor`](super::Executor).
+//!
+//! The executor thread sleeps in [`Backend::wait`] until a task is woken, which calls
+//! [`Notifier::notify`], or until the next timer deadline is reached. On the ESP32 this
+//! is done with FreeRTOS task notifications, which may also be sent from an ISR, and a
+//! single `esp_timer` per executor. On the host (feature `std-executor`) the thread is
+//! parked using [`std::thread::park_timeout`].
+
+use std::time::Instant;
+
+/// A handle that can wake up the thread waiting in [`Backend::wait`].
+pub trait Notifier: Clone + Send + 'static {
+    /// Wake up the executor thread.
+    ///
+    /// If the thread is not currently waiting, the next call to [`Backend::wait`] will
+    /// return immediately.
+    fn notify(&self);
+}
+
+/// The park/notify mechanism of an executor thread.
+pub trait Backend: 'static {
+    type Notifier: Notifier;
+
+    /// Create the backend for the current thread.
+    ///
+    /// Must be called on the thread that will later call [`Backend::wait`].
+    fn current() -> Self;
+
+    /// Get a [`Notifier`] that wakes up this thread.
+    fn notifier(&self) -> Self::Notifier;
+
+    /// Block the current thread until it is notified or `deadline` is reached.
+    ///
+    /// Returns immediately if `deadline` is in the past.
+    fn wait(&mut self, deadline: Option<Instant>);
+
+    /// The current time, which the deadlines of timers are compared with.
+    #[inline]
+    fn now() -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(not(feature = "std-executor"))]
+pub type DefaultBackend = freertos::FreeRtos;
+#[cfg(feature = "std-executor")]
+pub type DefaultBackend = std_thread::StdThread;
+
+#[cfg(not(feature = "std-executor"))]
+pub mod freertos {
+    use core::ptr::NonNull;
+    use std::time::Instant;
+
+    use esp_idf_hal::interrupt;
+    use esp_idf_sys as sys;
+    use sys::c_types::c_void;
+    use sys::{esp_nofail, tskTaskControlBlock};
+
+    use super::{Backend, Notifier};
+
+    /// Uses FreeRTOS task notifications and an `esp_timer` for deadlines.
+    ///
+    /// The timer is deleted when this backend is dropped.
+    pub struct FreeRtos {
+        task: TaskNotifier,
+        timer: sys::esp_timer_handle_t,
+    }
+
+    /// A handle to a FreeRTOS task, safe to use from ISRs.
+    #[derive(Clone, Copy)]
+    pub struct TaskNotifier(NonNull<tskTaskControlBlock>);
+
+    // Safe because task notifications can be sent from any thread or ISR.
+    unsafe impl Send for TaskNotifier {}
+
+    impl Notifier for TaskNotifier {
+        #[inline]
+        fn notify(&self) {
+            unsafe {
+                interrupt::task::notify(self.0.as_ptr(), 1);
+            }
+        }
+    }
+
+    impl Backend for FreeRtos {
+        type Notifier = TaskNotifier;
+
+        fn current() -> Self {
+            let task = interrupt::task::current().expect("in interrupt");
+
+            #[cfg(esp_idf_esp_timer_supports_isr_dispatch_method)]
+            let dispatch_method = sys::esp_timer_dispatch_t_ESP_TIMER_ISR;
+            #[cfg(not(esp_idf_esp_timer_supports_isr_dispatch_method))]
+            let dispatch_method = sys::esp_timer_dispatch_t_ESP_TIMER_TASK;
+
+            // The task handle is given directly as the callback argument, so the timer
+            // doesn't depend on the address of this struct.
+            let mut timer: sys::esp_timer_handle_t = core::ptr::null_mut();
+            unsafe {
+                esp_nofail!(sys::esp_timer_create(
+                    &sys::esp_timer_create_args_t {
+                        callback: Some(Self::handle_timer),
+                        name: b"executor\0" as *const _ as *const _,
+                        arg: task as *mut c_void,
+                        dispatch_method,
+                        skip_unhandled_events: true,
+                    },
+                    &mut timer,
+                ));
+            }
+
+            FreeRtos {
+                task: TaskNotifier(NonNull::new(task).unwrap()),
+                timer,
+            }
+        }
+
+        #[inline]
+        fn notifier(&self) -> TaskNotifier {
+            self.task
+        }
+
+        fn wait(&mut self, deadline: Option<Instant>) {
+            let deadline = match deadline {
+                Some(deadline) => deadline,
+                None => {
+                    interrupt::task::wait_notification(None);
+                    return;
+                }
+            };
+
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            // The timer has a resolution of one microsecond.
+            let timeout_us = timeout.as_micros() as u64;
+            if timeout_us == 0 {
+                return;
+            }
+
+            unsafe {
+                esp_nofail!(sys::esp_timer_start_once(self.timer, timeout_us));
+            }
+
+            interrupt::task::wait_notification(None);
+
+            // Fails with `ESP_ERR_INVALID_STATE` if the timer already expired.
+            unsafe {
+                sys::esp_timer_stop(self.timer);
+            }
+        }
+    }
+
+    impl FreeRtos {
+        extern "C" fn handle_timer(arg: *mut c_void) {
+            unsafe {
+                interrupt::task::notify(arg as *mut tskTaskControlBlock, 1);
+            }
+
+            #[cfg(esp_idf_esp_timer_supports_isr_dispatch_method)]
+            unsafe {
+                sys::esp_timer_isr_dispatch_need_yield();
+            }
+        }
+    }
+
+    impl Drop for FreeRtos {
+        fn drop(&mut self) {
+            unsafe {
+                sys::esp_timer_stop(self.timer);
+                esp_nofail!(sys::esp_timer_delete(self.timer));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std-executor")]
+pub mod std_thread {
+    use std::thread::{self, Thread};
+    use std::time::Instant;
+
+    use super::{Backend, Notifier};
+
+    /// Uses [`thread::park`] and [`Thread::unpark`].
+    pub struct StdThread {
+        thread: ThreadNotifier,
+    }
+
+    #[derive(Clone)]
+    pub struct ThreadNotifier(Thread);
+
+    impl Notifier for ThreadNotifier {
+        #[inline]
+        fn notify(&self) {
+            self.0.unpark();
+        }
+    }
+
+    impl Backend for StdThread {
+        type Notifier = ThreadNotifier;
+
+        fn current() -> Self {
+            StdThread {
+                thread: ThreadNotifier(thread::current()),
+            }
+        }
+
+        #[inline]
+        fn notifier(&self) -> ThreadNotifier {
+            self.thread.clone()
+        }
+
+        fn wait(&mut self, deadline: Option<Instant>) {
+            match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if !timeout.is_zero() {
+                        thread::park_timeout(timeout);
+                    }
+                }
+                None => thread::park(),
+            }
+        }
+    }
+}
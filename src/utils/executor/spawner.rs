@@ -0,0 +1,58 @@
+//! Spawning tasks onto an [`Executor`] running on another thread or core.
+
+use core::future::Future;
+
+use futures::channel::mpsc;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+
+use super::backend::Backend;
+use super::Executor;
+use crate::utils::thread::{self, SpawnError, ThreadConfig};
+
+#[derive(Debug, thiserror::Error)]
+#[error("the executor has shut down")]
+pub struct ExecutorShutDown;
+
+/// A handle to spawn tasks onto an executor started with [`Executor::start`].
+///
+/// The spawner can be cloned and sent to any thread, the executor shuts down once all
+/// spawners are dropped and all spawned tasks completed. To communicate with the
+/// spawned tasks, use any of the channels in `futures::channel` or
+/// [`crate::utils::sync`], which all work across cores.
+#[derive(Clone)]
+pub struct Spawner {
+    sender: mpsc::UnboundedSender<BoxFuture<'static, ()>>,
+}
+
+impl Spawner {
+    /// Spawn `future` onto the executor.
+    pub fn spawn(
+        &self,
+        future: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<(), ExecutorShutDown> {
+        self.sender
+            .unbounded_send(future.boxed())
+            .map_err(|_| ExecutorShutDown)
+    }
+}
+
+/// The maximum amount of spawned tasks that run at once, further tasks wait until a task
+/// completed.
+pub const MAX_TASKS: usize = 16;
+
+impl<B: Backend> Executor<B> {
+    /// Start a new thread with `config` that runs this executor.
+    ///
+    /// Tasks are added with the returned [`Spawner`], every spawned future is a task of
+    /// its own.
+    pub fn start(&'static self, config: &ThreadConfig) -> Result<Spawner, SpawnError> {
+        let (sender, receiver) = mpsc::unbounded();
+
+        thread::spawn(config, move || {
+            self.run_tasks::<{ MAX_TASKS + 2 }>(Vec::new(), Some(receiver));
+        })?;
+
+        Ok(Spawner { sender })
+    }
+}
@@ -0,0 +1,93 @@
+//! Optional per-task instrumentation of the [`Executor`](super::Executor).
+
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// A snapshot of the statistics of all tasks of an executor.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExecutorStats {
+    /// Polls longer than this are logged as a stall.
+    pub stall_threshold_us: u64,
+    /// The statistics of every task, indexed by task id.
+    pub tasks: Vec<TaskStats>,
+}
+
+/// The statistics of a single task.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TaskStats {
+    /// The index of the task given to [`Executor::run`](super::Executor::run), or the
+    /// slot of a spawned task, which is reused once the task completed.
+    pub id: usize,
+    /// How many times the task was polled.
+    pub polls: u64,
+    /// How many times the task was woken, multiple wakes may result in a single poll.
+    pub wakes: u32,
+    /// The sum of the durations of all polls in microseconds.
+    pub total_poll_time_us: u64,
+    /// The longest poll in microseconds.
+    pub max_poll_time_us: u64,
+    /// How many polls took longer than the stall threshold.
+    pub stalls: u32,
+}
+
+pub(super) struct Instrumentation {
+    stall_threshold: Duration,
+    tasks: Vec<TaskStats>,
+}
+
+impl Instrumentation {
+    pub fn new(stall_threshold: Duration) -> Self {
+        Instrumentation {
+            stall_threshold,
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Reset the statistics for `task_count` tasks.
+    pub fn reset(&mut self, task_count: usize) {
+        self.tasks = (0..task_count)
+            .map(|id| TaskStats {
+                id,
+                ..Default::default()
+            })
+            .collect();
+    }
+
+    /// Reset the statistics of `task_id` for a newly spawned task.
+    pub fn start_task(&mut self, task_id: usize) {
+        if self.tasks.len() <= task_id {
+            self.tasks.resize_with(task_id + 1, Default::default);
+        }
+        self.tasks[task_id] = TaskStats {
+            id: task_id,
+            ..Default::default()
+        };
+    }
+
+    /// Record a poll of `task_id` that took `duration`, returns `true` if the poll
+    /// exceeded the stall threshold.
+    pub fn record_poll(&mut self, task_id: usize, duration: Duration, wakes: u32) -> bool {
+        let stalled = duration > self.stall_threshold;
+        if let Some(task) = self.tasks.get_mut(task_id) {
+            let micros = duration.as_micros() as u64;
+
+            task.polls += 1;
+            task.wakes = wakes;
+            task.total_poll_time_us += micros;
+            task.max_poll_time_us = task.max_poll_time_us.max(micros);
+            if stalled {
+                task.stalls += 1;
+            }
+        }
+
+        stalled
+    }
+
+    pub fn snapshot(&self) -> ExecutorStats {
+        ExecutorStats {
+            stall_threshold_us: self.stall_threshold.as_micros() as u64,
+            tasks: self.tasks.clone(),
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Supervision of services with heartbeats and the ESP task watchdog.
+//!
+//! Every service registers a heartbeat deadline with [`register`] and calls
+//! [`Heartbeat::beat`] regularly. The supervisor thread started with [`start`] feeds the
+//! task watchdog only while all services are healthy. A service that missed its deadline
+//! is restarted by the restart handler given to [`start`], and if that doesn't help the
+//! chip is rebooted (see [`Monitor`]). If the supervisor or a restart hangs, the task
+//! watchdog reboots the chip.
+//!
+//! The supervisor only runs on the ESP32, the rest is platform independent.
+
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use self::policy::{Monitor, ServiceId, SystemClock};
+pub use self::policy::ServiceConfig;
+
+#[cfg(target_os = "espidf")]
+use {
+    self::policy::Action,
+    super::thread::{self, SpawnError, ThreadConfig},
+    esp_idf_sys::{self as sys, esp_nofail},
+    std::time::Duration,
+};
+
+pub mod policy;
+
+static MONITOR: Mutex<Monitor<SystemClock>> = Mutex::new(Monitor::new(SystemClock));
+
+/// Lock the monitor, which stays usable if a thread panicked while holding it.
+fn lock() -> MutexGuard<'static, Monitor<SystemClock>> {
+    MONITOR.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A registered service, the service stops being supervised when dropped.
+pub struct Heartbeat(ServiceId);
+
+impl Heartbeat {
+    /// Tell the supervisor that the service is alive.
+    pub fn beat(&self) {
+        lock().beat(self.0);
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        lock().unregister(self.0);
+    }
+}
+
+/// Start supervising a service, its deadline starts now.
+pub fn register(config: ServiceConfig) -> Heartbeat {
+    Heartbeat(lock().register(config))
+}
+
+/// Start the supervisor thread, which checks all services every `period`.
+///
+/// `restart` is called with the name of a service that must be restarted, it should
+/// only start the restart and return quickly.
+#[cfg(target_os = "espidf")]
+pub fn start<F>(
+    config: &ThreadConfig,
+    period: Duration,
+    mut restart: F,
+) -> Result<(), SpawnError>
+where
+    F: FnMut(&'static str) + Send + 'static,
+{
+    thread::spawn(config, move || {
+        unsafe {
+            esp_nofail!(sys::esp_task_wdt_add(core::ptr::null_mut()));
+        }
+
+        loop {
+            // The restart handler is called without holding the lock, since it may
+            // register services.
+            let check = lock().check();
+
+            for action in check.actions {
+                match action {
+                    Action::Restart(name) => {
+                        log::warn!("service `{}` missed its heartbeat, restarting it", name);
+                        restart(name);
+                    }
+                    Action::Reboot(name) => {
+                        log::error!("service `{}` couldn't be recovered, rebooting", name);
+                        unsafe {
+                            sys::esp_restart();
+                        }
+                    }
+                }
+            }
+
+            if check.healthy {
+                unsafe {
+                    sys::esp_task_wdt_reset();
+                }
+            }
+
+            std::thread::sleep(period);
+        }
+    })
+}
@@ -0,0 +1,301 @@
+//! The supervision policy, independent of the watchdog and threads so that it can be
+//! tested with a [`Clock`] that is advanced manually.
+
+use std::time::{Duration, Instant};
+
+/// A source of the current time.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// The clock of the system, [`Instant::now`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// How a service is supervised.
+#[derive(Debug, Clone)]
+pub struct ServiceConfig {
+    /// A unique name of the service, which is also given to the restart handler.
+    pub name: &'static str,
+    /// The maximum time between two heartbeats.
+    pub timeout: Duration,
+    /// The amount of restarts before the chip is rebooted.
+    ///
+    /// The count is reset once the service was healthy for [`STABLE_TIMEOUTS`] times
+    /// its timeout after a restart.
+    pub max_restarts: u32,
+}
+
+/// After a restart the service must be healthy for this many timeouts before its restart
+/// count is reset.
+pub const STABLE_TIMEOUTS: u32 = 10;
+
+/// Identifies a registered service.
+pub type ServiceId = usize;
+
+/// What the supervisor must do for an unhealthy service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Restart the service with the given name.
+    Restart(&'static str),
+    /// The service with the given name couldn't be recovered, reboot the chip.
+    Reboot(&'static str),
+}
+
+/// The result of [`Monitor::check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    /// Whether all active services sent a heartbeat in time.
+    pub healthy: bool,
+    pub actions: Vec<Action>,
+}
+
+struct Service {
+    config: ServiceConfig,
+    /// Whether the service is running and must send heartbeats.
+    active: bool,
+    last_beat: Instant,
+    restarts: u32,
+    last_restart: Option<Instant>,
+}
+
+/// Tracks the heartbeats of services and decides how to recover them.
+///
+/// A service that missed its heartbeat deadline is restarted and gets another timeout
+/// to send a heartbeat. Once it was restarted [`ServiceConfig::max_restarts`] times
+/// without becoming stable, a reboot is requested.
+pub struct Monitor<C: Clock> {
+    clock: C,
+    services: Vec<Service>,
+}
+
+impl<C: Clock> Monitor<C> {
+    pub const fn new(clock: C) -> Self {
+        Monitor {
+            clock,
+            services: Vec::new(),
+        }
+    }
+
+    /// Register a service, or activate it again if a service with the same name was
+    /// registered before.
+    ///
+    /// The restart count of a service is kept when it is registered again, since a
+    /// restarted service usually registers itself on start.
+    pub fn register(&mut self, config: ServiceConfig) -> ServiceId {
+        let now = self.clock.now();
+
+        match self.services.iter().position(|s| s.config.name == config.name) {
+            Some(id) => {
+                let service = &mut self.services[id];
+                service.config = config;
+                service.active = true;
+                service.last_beat = now;
+                id
+            }
+            None => {
+                self.services.push(Service {
+                    config,
+                    active: true,
+                    last_beat: now,
+                    restarts: 0,
+                    last_restart: None,
+                });
+                self.services.len() - 1
+            }
+        }
+    }
+
+    /// Stop supervising the service until it is registered again.
+    pub fn unregister(&mut self, id: ServiceId) {
+        self.services[id].active = false;
+    }
+
+    /// Record a heartbeat of the service.
+    pub fn beat(&mut self, id: ServiceId) {
+        self.services[id].last_beat = self.clock.now();
+    }
+
+    /// Check the heartbeat deadlines of all services.
+    ///
+    /// Must be called regularly, at least once per the shortest timeout.
+    pub fn check(&mut self) -> Check {
+        let now = self.clock.now();
+        let mut check = Check {
+            healthy: true,
+            actions: Vec::new(),
+        };
+
+        for service in self.services.iter_mut().filter(|s| s.active) {
+            let timeout = service.config.timeout;
+
+            if now.saturating_duration_since(service.last_beat) <= timeout {
+                let stable = matches!(
+                    service.last_restart,
+                    Some(t) if now - t >= timeout * STABLE_TIMEOUTS
+                );
+                if stable {
+                    service.restarts = 0;
+                    service.last_restart = None;
+                }
+                continue;
+            }
+
+            check.healthy = false;
+            if service.restarts < service.config.max_restarts {
+                service.restarts += 1;
+                service.last_restart = Some(now);
+                // Give the restarted service another timeout to send a heartbeat.
+                service.last_beat = now;
+                check.actions.push(Action::Restart(service.config.name));
+            } else {
+                check.actions.push(Action::Reboot(service.config.name));
+            }
+        }
+
+        check
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct MockClock(Rc<Cell<Instant>>);
+
+    impl MockClock {
+        fn advance(&self, duration: Duration) {
+            self.0.set(self.0.get() + duration);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    fn monitor() -> (Monitor<MockClock>, MockClock) {
+        let clock = MockClock(Rc::new(Cell::new(Instant::now())));
+        (Monitor::new(clock.clone()), clock)
+    }
+
+    fn config(name: &'static str, max_restarts: u32) -> ServiceConfig {
+        ServiceConfig {
+            name,
+            timeout: Duration::from_secs(2),
+            max_restarts,
+        }
+    }
+
+    fn healthy() -> Check {
+        Check {
+            healthy: true,
+            actions: vec![],
+        }
+    }
+
+    #[test]
+    fn healthy_while_beating() {
+        let (mut monitor, clock) = monitor();
+        let light = monitor.register(config("light", 1));
+
+        for _ in 0..10 {
+            clock.advance(Duration::from_secs(1));
+            monitor.beat(light);
+            assert_eq!(monitor.check(), healthy());
+        }
+    }
+
+    #[test]
+    fn restart_then_reboot() {
+        let (mut monitor, clock) = monitor();
+        let light = monitor.register(config("light", 2));
+        let wifi = monitor.register(config("wifi", 2));
+
+        clock.advance(Duration::from_secs(3));
+        monitor.beat(wifi);
+        assert_eq!(
+            monitor.check(),
+            Check {
+                healthy: false,
+                actions: vec![Action::Restart("light")],
+            }
+        );
+
+        // The restart grants another timeout.
+        clock.advance(Duration::from_secs(1));
+        monitor.beat(wifi);
+        assert_eq!(monitor.check(), healthy());
+
+        clock.advance(Duration::from_secs(2));
+        monitor.beat(wifi);
+        assert_eq!(monitor.check().actions, vec![Action::Restart("light")]);
+
+        clock.advance(Duration::from_secs(3));
+        monitor.beat(wifi);
+        assert_eq!(
+            monitor.check(),
+            Check {
+                healthy: false,
+                actions: vec![Action::Reboot("light")],
+            }
+        );
+        let _ = light;
+    }
+
+    #[test]
+    fn restart_count_is_reset_when_stable() {
+        let (mut monitor, clock) = monitor();
+        let light = monitor.register(config("light", 1));
+
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(monitor.check().actions, vec![Action::Restart("light")]);
+
+        for _ in 0..STABLE_TIMEOUTS * 2 {
+            clock.advance(Duration::from_secs(1));
+            monitor.beat(light);
+            assert_eq!(monitor.check(), healthy());
+        }
+
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(monitor.check().actions, vec![Action::Restart("light")]);
+    }
+
+    #[test]
+    fn register_again_keeps_restarts() {
+        let (mut monitor, clock) = monitor();
+        let light = monitor.register(config("light", 1));
+
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(monitor.check().actions, vec![Action::Restart("light")]);
+
+        // The restarted service registers itself again.
+        monitor.unregister(light);
+        assert_eq!(monitor.register(config("light", 1)), light);
+
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(monitor.check().actions, vec![Action::Reboot("light")]);
+    }
+
+    #[test]
+    fn inactive_services_are_ignored() {
+        let (mut monitor, clock) = monitor();
+        let light = monitor.register(config("light", 0));
+        monitor.unregister(light);
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(monitor.check(), healthy());
+    }
+}
@@ -0,0 +1,294 @@
+//! Monitoring of the heap and of the task stacks.
+//!
+//! The thread started with [`start`] samples the free heap, the largest free block, the
+//! minimum free heap since boot and the stack high-water mark of every FreeRTOS task
+//! every [`Config::period`]. The samples are logged, served by `GET /debug/memory` and
+//! compared against the low-water marks of the [`Config`], which raise an [`Alarm`].
+
+use std::time::Duration;
+
+#[cfg(target_os = "espidf")]
+use esp_idf_sys as sys;
+use serde::Serialize;
+
+use super::thread::ThreadConfig;
+#[cfg(target_os = "espidf")]
+use super::thread::{self, SpawnError};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The time between two samples.
+    pub period: Duration,
+    /// Raise [`Alarm::LowHeap`] if less heap is free, in bytes.
+    pub min_free_heap: usize,
+    /// Raise [`Alarm::Fragmented`] if the largest free block is smaller, in bytes.
+    pub min_largest_free_block: usize,
+    /// Raise [`Alarm::LowStack`] if a task ever had less free stack, in bytes.
+    pub min_free_stack: usize,
+    /// The thread the monitor runs on.
+    pub thread: ThreadConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            period: Duration::from_secs(30),
+            min_free_heap: 16 * 1024,
+            min_largest_free_block: 8 * 1024,
+            min_free_stack: 512,
+            thread: ThreadConfig {
+                name: "memory",
+                core: None,
+                priority: 2,
+                stack_size: 4 * 1024,
+            },
+        }
+    }
+}
+
+/// The memory usage at one point in time.
+#[derive(Debug, Clone, Serialize)]
+pub struct MemorySample {
+    /// The free heap in bytes.
+    pub free_heap: usize,
+    /// The largest block that can currently be allocated, in bytes.
+    pub largest_free_block: usize,
+    /// The minimum free heap since boot in bytes.
+    pub min_free_heap: usize,
+    /// How much of the free heap can't be allocated in one block, in percent.
+    pub fragmentation: u8,
+    pub tasks: Vec<TaskStack>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskStack {
+    pub name: String,
+    /// The minimum free stack since the task was created, in bytes.
+    pub stack_high_water_mark: usize,
+}
+
+/// A low-water mark of the [`Config`] that was reached.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Alarm {
+    LowHeap,
+    Fragmented,
+    LowStack { task: String },
+}
+
+/// A sample with the alarms it raised.
+#[derive(Debug, Clone, Serialize)]
+pub struct MemoryReport {
+    #[serde(flatten)]
+    pub sample: MemorySample,
+    pub alarms: Vec<Alarm>,
+}
+
+static LAST_REPORT: spin::Mutex<Option<MemoryReport>> = spin::Mutex::new(None);
+
+/// The report of the last sample, `None` if the monitor doesn't run.
+pub fn last_report() -> Option<MemoryReport> {
+    LAST_REPORT.lock().clone()
+}
+
+/// Sample the current memory usage.
+#[cfg(target_os = "espidf")]
+pub fn sample() -> MemorySample {
+    let caps = sys::MALLOC_CAP_8BIT;
+    let (free_heap, largest_free_block, min_free_heap) = unsafe {
+        (
+            sys::heap_caps_get_free_size(caps) as usize,
+            sys::heap_caps_get_largest_free_block(caps) as usize,
+            sys::heap_caps_get_minimum_free_size(caps) as usize,
+        )
+    };
+    let fragmentation = match free_heap {
+        0 => 0,
+        _ => 100 - (largest_free_block * 100 / free_heap) as u8,
+    };
+
+    MemorySample {
+        free_heap,
+        largest_free_block,
+        min_free_heap,
+        fragmentation,
+        tasks: task_stacks(),
+    }
+}
+
+/// Get the stack high-water mark of all tasks, needs
+/// `CONFIG_FREERTOS_USE_TRACE_FACILITY`.
+#[cfg(target_os = "espidf")]
+fn task_stacks() -> Vec<TaskStack> {
+    // Leave room for tasks created in between.
+    let capacity = unsafe { sys::uxTaskGetNumberOfTasks() } as usize + 4;
+    let mut statuses: Vec<sys::TaskStatus_t> = Vec::with_capacity(capacity);
+
+    unsafe {
+        let count =
+            sys::uxTaskGetSystemState(statuses.as_mut_ptr(), capacity as _, core::ptr::null_mut());
+        statuses.set_len(count as usize);
+    }
+
+    let mut tasks: Vec<_> = statuses
+        .iter()
+        .map(|status| {
+            let name = unsafe { std::ffi::CStr::from_ptr(status.pcTaskName) };
+            TaskStack {
+                name: name.to_string_lossy().into_owned(),
+                // The stack is counted in bytes on the ESP32.
+                stack_high_water_mark: status.usStackHighWaterMark as usize,
+            }
+        })
+        .collect();
+    tasks.sort_by(|a, b| a.name.cmp(&b.name));
+
+    tasks
+}
+
+/// The low-water marks of `config` that `sample` reached.
+fn alarms(sample: &MemorySample, config: &Config) -> Vec<Alarm> {
+    let mut alarms = Vec::new();
+
+    if sample.free_heap < config.min_free_heap {
+        alarms.push(Alarm::LowHeap);
+    }
+    if sample.largest_free_block < config.min_largest_free_block {
+        alarms.push(Alarm::Fragmented);
+    }
+    alarms.extend(
+        sample
+            .tasks
+            .iter()
+            .filter(|t| t.stack_high_water_mark < config.min_free_stack)
+            .map(|t| Alarm::LowStack {
+                task: t.name.clone(),
+            }),
+    );
+
+    alarms
+}
+
+/// The `alarms` that the `previous` sample didn't raise yet.
+fn raised<'a>(alarms: &'a [Alarm], previous: &'a [Alarm]) -> impl Iterator<Item = &'a Alarm> {
+    alarms.iter().filter(move |alarm| !previous.contains(alarm))
+}
+
+/// Start sampling the memory usage every [`Config::period`].
+#[cfg(target_os = "espidf")]
+pub fn start(config: Config) -> Result<(), SpawnError> {
+    let thread_config = config.thread.clone();
+
+    thread::spawn(&thread_config, move || loop {
+        let sample = sample();
+        let alarms = alarms(&sample, &config);
+
+        log::info!(
+            "heap: {} bytes free (min {}), largest block {} bytes, {}% fragmented",
+            sample.free_heap,
+            sample.min_free_heap,
+            sample.largest_free_block,
+            sample.fragmentation
+        );
+        for task in &sample.tasks {
+            log::debug!(
+                "  task `{}`: {} bytes stack free",
+                task.name,
+                task.stack_high_water_mark
+            );
+        }
+
+        // Only log alarms when they are raised, not on every sample.
+        let previous = LAST_REPORT
+            .lock()
+            .as_ref()
+            .map(|r| r.alarms.clone())
+            .unwrap_or_default();
+        for alarm in raised(&alarms, &previous) {
+            match alarm {
+                Alarm::LowHeap => log::warn!(
+                    "low heap: {} bytes free, alarm below {}",
+                    sample.free_heap,
+                    config.min_free_heap
+                ),
+                Alarm::Fragmented => log::warn!(
+                    "fragmented heap: largest block {} bytes, alarm below {}",
+                    sample.largest_free_block,
+                    config.min_largest_free_block
+                ),
+                Alarm::LowStack { task } => log::warn!(
+                    "task `{}` almost overflowed its stack, alarm below {} bytes free",
+                    task,
+                    config.min_free_stack
+                ),
+            }
+        }
+
+        *LAST_REPORT.lock() = Some(MemoryReport { sample, alarms });
+
+        std::thread::sleep(config.period);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory_sample(
+        free_heap: usize,
+        largest_free_block: usize,
+        stacks: &[usize],
+    ) -> MemorySample {
+        MemorySample {
+            free_heap,
+            largest_free_block,
+            min_free_heap: free_heap,
+            fragmentation: 0,
+            tasks: stacks
+                .iter()
+                .enumerate()
+                .map(|(i, &stack_high_water_mark)| TaskStack {
+                    name: format!("task{}", i),
+                    stack_high_water_mark,
+                })
+                .collect(),
+        }
+    }
+
+    fn low_stack(task: &str) -> Alarm {
+        Alarm::LowStack { task: task.into() }
+    }
+
+    #[test]
+    fn alarm_below_thresholds() {
+        let config = Config::default();
+
+        // The thresholds themselves are still fine.
+        let sample = memory_sample(16 * 1024, 8 * 1024, &[512, 2048]);
+        assert_eq!(alarms(&sample, &config), []);
+
+        let sample = memory_sample(16 * 1024 - 1, 8 * 1024 - 1, &[511, 2048]);
+        assert_eq!(
+            alarms(&sample, &config),
+            [Alarm::LowHeap, Alarm::Fragmented, low_stack("task0")]
+        );
+    }
+
+    #[test]
+    fn raise_alarms_once() {
+        let config = Config::default();
+        let healthy = alarms(&memory_sample(64 * 1024, 32 * 1024, &[2048, 2048]), &config);
+        let low_heap = alarms(&memory_sample(8 * 1024, 8 * 1024, &[2048, 2048]), &config);
+        let low_task_stack = alarms(&memory_sample(8 * 1024, 8 * 1024, &[2048, 100]), &config);
+
+        assert!(raised(&low_heap, &healthy).eq([&Alarm::LowHeap]));
+        // Repeated samples don't raise the alarm again, only new ones.
+        assert_eq!(raised(&low_heap, &low_heap).count(), 0);
+        assert!(raised(&low_task_stack, &low_heap).eq([&low_stack("task1")]));
+
+        // Alarms are raised again after the memory usage recovered.
+        assert_eq!(healthy, []);
+        assert_eq!(raised(&healthy, &low_task_stack).count(), 0);
+        assert!(raised(&low_heap, &healthy).eq([&Alarm::LowHeap]));
+    }
+}
@@ -0,0 +1,81 @@
+//! The addresses of the wifi station interface, announced by discovery protocols, and
+//! the executor shared by the network services.
+//!
+//! Network services run on core 0 with the wifi driver, the light is rendered on core 1.
+//! Services that only poll sockets share one [`EXECUTOR`] instead of a thread each.
+
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+#[cfg(target_os = "espidf")]
+use esp_idf_sys::{self as sys, esp};
+
+use super::executor::spawner::Spawner;
+use super::executor::stats::ExecutorStats;
+use super::executor::Executor;
+use super::thread::{Core, SpawnError, ThreadConfig};
+
+/// The executor shared by the network services that poll non-blocking sockets.
+pub static EXECUTOR: Executor = Executor::new();
+
+/// Polls longer than this are logged as a stall of [`EXECUTOR`].
+const STALL_THRESHOLD: Duration = Duration::from_millis(100);
+
+/// Start the thread of [`EXECUTOR`], network services spawn their tasks with the
+/// returned [`Spawner`].
+pub fn start_executor() -> Result<Spawner, SpawnError> {
+    EXECUTOR.enable_stats(STALL_THRESHOLD);
+    EXECUTOR.start(&ThreadConfig {
+        name: "network",
+        core: Some(Core::Core0),
+        priority: 5,
+        stack_size: 16 * 1024,
+    })
+}
+
+/// Get the per-task statistics of the shared network executor.
+pub fn executor_stats() -> Option<ExecutorStats> {
+    EXECUTOR.stats()
+}
+
+/// The IPv4 address of the wifi station, `None` if it isn't connected.
+#[cfg(target_os = "espidf")]
+pub fn station_ip() -> Option<Ipv4Addr> {
+    unsafe {
+        let netif = sys::esp_netif_get_handle_from_ifkey(b"WIFI_STA_DEF\0".as_ptr() as *const _);
+        if netif.is_null() {
+            return None;
+        }
+
+        let mut info: sys::esp_netif_ip_info_t = core::mem::zeroed();
+        if esp!(sys::esp_netif_get_ip_info(netif, &mut info)).is_err() {
+            return None;
+        }
+
+        // The address is stored in network byte order.
+        let ip = Ipv4Addr::from(info.ip.addr.to_le_bytes());
+        (!ip.is_unspecified()).then_some(ip)
+    }
+}
+
+/// The MAC address of the wifi station.
+#[cfg(target_os = "espidf")]
+pub fn station_mac() -> [u8; 6] {
+    let mut mac = [0; 6];
+    unsafe {
+        sys::esp_read_mac(mac.as_mut_ptr(), sys::esp_mac_type_t_ESP_MAC_WIFI_STA);
+    }
+    mac
+}
+
+/// Hosts have no wifi station, the network services run unconnected in tests.
+#[cfg(not(target_os = "espidf"))]
+pub fn station_ip() -> Option<Ipv4Addr> {
+    None
+}
+
+/// Hosts have no wifi station, see [`station_ip`].
+#[cfg(not(target_os = "espidf"))]
+pub fn station_mac() -> [u8; 6] {
+    [0; 6]
+}
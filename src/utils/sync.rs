@@ -0,0 +1,17 @@
+//! Async synchronization primitives that work with any executor.
+//!
+//! The state of every primitive is protected by a [`blocking::Mutex`], which is a
+//! critical section on the ESP32. This makes it safe to notify tasks from an ISR with
+//! [`Notify`]. Nothing is allocated or freed and no waker is called inside the critical
+//! section, values of [`Watch`] and [`broadcast`] channels are shared with an `Arc`.
+
+pub mod blocking;
+pub mod broadcast;
+mod mutex;
+mod notify;
+mod wait_queue;
+mod watch;
+
+pub use mutex::{Lock, Mutex, MutexGuard};
+pub use notify::{Notified, Notify};
+pub use watch::{Changed, Watch, WatchReceiver};
@@ -0,0 +1,51 @@
+//! A mutex that blocks for a very short time and can be used from ISRs.
+
+use core::cell::UnsafeCell;
+
+/// A mutex for short critical sections that may also be locked from an ISR.
+///
+/// On the ESP32 this disables interrupts on the current core and spins on a lock shared
+/// between both cores, so the closure passed to [`Mutex::lock`] must not block or call
+/// into FreeRTOS. With the `std-executor` feature a [`std::sync::Mutex`] is used instead.
+pub struct Mutex<T> {
+    #[cfg(not(feature = "std-executor"))]
+    cs: esp_idf_hal::interrupt::CriticalSection,
+    #[cfg(feature = "std-executor")]
+    cs: std::sync::Mutex<()>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            #[cfg(not(feature = "std-executor"))]
+            cs: esp_idf_hal::interrupt::CriticalSection::new(),
+            #[cfg(feature = "std-executor")]
+            cs: std::sync::Mutex::new(()),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Run `f` with exclusive access to the data.
+    #[inline]
+    pub fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        #[cfg(not(feature = "std-executor"))]
+        let _guard = self.cs.enter();
+        #[cfg(feature = "std-executor")]
+        let _guard = self.cs.lock().unwrap_or_else(|err| err.into_inner());
+
+        // Safe because we are the only ones holding the lock.
+        f(unsafe { &mut *self.data.get() })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
@@ -0,0 +1,335 @@
+//! A bounded multi-producer, multi-consumer channel where every receiver sees every
+//! message.
+//!
+//! The channel keeps the last `capacity` messages. A receiver that falls further behind
+//! gets [`RecvError::Lagged`] and continues with the oldest message still available.
+//! Messages are shared with an [`Arc`], so that receivers clone them outside of the
+//! critical section.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use std::sync::Arc;
+
+use super::blocking;
+use super::wait_queue::{self, WaitQueue, WaiterId};
+
+#[derive(Debug, thiserror::Error)]
+#[error("channel has no receivers")]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum RecvError {
+    #[error("all senders were dropped")]
+    Closed,
+    #[error("receiver lagged behind and skipped {0} messages")]
+    Lagged(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum TryRecvError {
+    #[error("no message available")]
+    Empty,
+    #[error("all senders were dropped")]
+    Closed,
+    #[error("receiver lagged behind and skipped {0} messages")]
+    Lagged(u64),
+}
+
+/// Create a channel that keeps the last `capacity` messages.
+///
+/// Panics if `capacity` is zero.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "broadcast channel capacity must be non-zero");
+
+    let shared = Arc::new(blocking::Mutex::new(State {
+        buffer: (0..capacity).map(|_| None).collect(),
+        next_seq: 0,
+        senders: 1,
+        receivers: 1,
+        waiters: WaitQueue::new(),
+    }));
+
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared, next: 0 },
+    )
+}
+
+type Shared<T> = Arc<blocking::Mutex<State<T>>>;
+
+struct State<T> {
+    /// Ring buffer where the message with sequence number `n` is at `n % capacity`.
+    buffer: Vec<Option<Arc<T>>>,
+    /// The sequence number of the next message sent.
+    next_seq: u64,
+    senders: usize,
+    receivers: usize,
+    waiters: WaitQueue,
+}
+
+impl<T> State<T> {
+    fn oldest_seq(&self) -> u64 {
+        self.next_seq.saturating_sub(self.buffer.len() as u64)
+    }
+
+    fn slot(&mut self, seq: u64) -> &mut Option<Arc<T>> {
+        let len = self.buffer.len() as u64;
+        &mut self.buffer[(seq % len) as usize]
+    }
+}
+
+pub struct Sender<T> {
+    shared: Shared<T>,
+}
+
+impl<T> Sender<T> {
+    /// Send `value` to all receivers, returns the amount of receivers.
+    ///
+    /// If the channel is full the oldest message is overwritten. Allocates, so it can't
+    /// be called from an ISR.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let value = Arc::new(value);
+        let result = self.shared.lock(|s| {
+            if s.receivers == 0 {
+                return Err(value);
+            }
+
+            let seq = s.next_seq;
+            let old = s.slot(seq).replace(value);
+            s.next_seq += 1;
+
+            Ok((s.receivers, old))
+        });
+
+        let (receivers, old) = result.map_err(|value| {
+            // Nobody else has seen the message.
+            SendError(Arc::try_unwrap(value).ok().expect("unsent message shared"))
+        })?;
+        drop(old);
+        wait_queue::wake_all(&self.shared, |s| &mut s.waiters);
+
+        Ok(receivers)
+    }
+
+    /// Create a receiver that will see all messages sent after this call.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let next = self.shared.lock(|s| {
+            s.receivers += 1;
+            s.next_seq
+        });
+
+        Receiver {
+            shared: self.shared.clone(),
+            next,
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.lock(|s| s.receivers)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock(|s| s.senders += 1);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let closed = self.shared.lock(|s| {
+            s.senders -= 1;
+            s.senders == 0
+        });
+
+        if closed {
+            wait_queue::wake_all(&self.shared, |s| &mut s.waiters);
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Shared<T>,
+    /// The sequence number of the next message to receive.
+    next: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Receive the next message without waiting.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let next = &mut self.next;
+        let message = self.shared.lock(|s| {
+            let oldest = s.oldest_seq();
+            if *next < oldest {
+                let skipped = oldest - *next;
+                *next = oldest;
+                return Err(TryRecvError::Lagged(skipped));
+            }
+
+            if *next < s.next_seq {
+                let seq = *next;
+                *next += 1;
+                Ok(s.slot(seq).clone().expect("message slot empty"))
+            } else if s.senders == 0 {
+                Err(TryRecvError::Closed)
+            } else {
+                Err(TryRecvError::Empty)
+            }
+        })?;
+
+        Ok(T::clone(&message))
+    }
+
+    /// Wait for the next message.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv {
+            receiver: self,
+            waiter: None,
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock(|s| s.receivers += 1);
+        Receiver {
+            shared: self.shared.clone(),
+            next: self.next,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.lock(|s| s.receivers -= 1);
+    }
+}
+
+/// The future returned by [`Receiver::recv`].
+#[must_use = "futures do nothing unless polled"]
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+    waiter: Option<WaiterId>,
+}
+
+impl<T: Clone> Future for Recv<'_, T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        if this.waiter.is_none() {
+            wait_queue::reserve(&this.receiver.shared, |s| &mut s.waiters);
+        }
+
+        let result = loop {
+            match this.receiver.try_recv() {
+                Ok(value) => break Ok(value),
+                Err(TryRecvError::Closed) => break Err(RecvError::Closed),
+                Err(TryRecvError::Lagged(n)) => break Err(RecvError::Lagged(n)),
+                Err(TryRecvError::Empty) => (),
+            }
+
+            // Register and check again, a message could have been sent in between.
+            let next = this.receiver.next;
+            let waiter = &mut this.waiter;
+            let mut replaced = None;
+            let registered = this.receiver.shared.lock(|s| {
+                if next < s.next_seq || s.senders == 0 {
+                    return false;
+                }
+                match waiter.map(|id| s.waiters.update(id, cx.waker())) {
+                    Some(Ok(old)) => replaced = old,
+                    _ => *waiter = s.waiters.push(cx.waker()),
+                }
+                true
+            });
+            drop(replaced);
+
+            if registered {
+                if this.waiter.is_none() {
+                    // Another task filled the reserved space of the queue, try again.
+                    cx.waker().wake_by_ref();
+                }
+                return Poll::Pending;
+            }
+        };
+
+        if let Some(id) = this.waiter.take() {
+            this.receiver.shared.lock(|s| s.waiters.remove(id));
+        }
+        Poll::Ready(result)
+    }
+}
+
+impl<T> Drop for Recv<'_, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.waiter {
+            self.receiver.shared.lock(|s| s.waiters.remove(id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::task::noop_waker_ref;
+    use futures::FutureExt;
+
+    use super::*;
+
+    fn poll<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        future.poll_unpin(&mut Context::from_waker(noop_waker_ref()))
+    }
+
+    #[test]
+    fn lagging_receiver_skips_messages() {
+        let (sender, mut receiver) = channel(2);
+        let mut late = sender.subscribe();
+        sender.send(1).unwrap();
+        assert_eq!(receiver.try_recv(), Ok(1));
+
+        sender.send(2).unwrap();
+        sender.send(3).unwrap();
+        assert_eq!(late.try_recv(), Err(TryRecvError::Lagged(1)));
+        assert_eq!(late.try_recv(), Ok(2));
+        assert_eq!(poll(&mut late.recv()), Poll::Ready(Ok(3)));
+        assert_eq!(receiver.try_recv(), Ok(2));
+        assert_eq!(receiver.try_recv(), Ok(3));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+
+        sender.send(4).unwrap();
+        sender.send(5).unwrap();
+        sender.send(6).unwrap();
+        assert_eq!(
+            poll(&mut receiver.recv()),
+            Poll::Ready(Err(RecvError::Lagged(1)))
+        );
+        assert_eq!(poll(&mut receiver.recv()), Poll::Ready(Ok(5)));
+    }
+
+    #[test]
+    fn close() {
+        let (sender, mut receiver) = channel(2);
+        let second_sender = sender.clone();
+        sender.send(1).unwrap();
+
+        assert_eq!(poll(&mut receiver.recv()), Poll::Ready(Ok(1)));
+        let mut recv = receiver.recv();
+        assert_eq!(poll(&mut recv), Poll::Pending);
+        drop(sender);
+        assert_eq!(poll(&mut recv), Poll::Pending);
+        drop(second_sender);
+        assert_eq!(poll(&mut recv), Poll::Ready(Err(RecvError::Closed)));
+        drop(recv);
+
+        let (sender, receiver) = channel(2);
+        drop(receiver);
+        assert!(matches!(sender.send(1), Err(SendError(1))));
+    }
+}
@@ -0,0 +1,233 @@
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use super::blocking;
+use super::wait_queue::{self, WaitQueue, WaiterId};
+
+/// An async mutex, the lock is held across `.await` points without blocking the thread.
+///
+/// Tasks waiting for the lock are woken in the order they started waiting.
+///
+/// The state is guarded by a critical section but locking from an ISR is not supported.
+pub struct Mutex<T: ?Sized> {
+    state: blocking::Mutex<MutexState>,
+    data: UnsafeCell<T>,
+}
+
+struct MutexState {
+    locked: bool,
+    waiters: WaitQueue,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            state: blocking::Mutex::new(MutexState {
+                locked: false,
+                waiters: WaitQueue::new(),
+            }),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Wait until the lock is acquired.
+    pub fn lock(&self) -> Lock<'_, T> {
+        Lock {
+            mutex: self,
+            waiter: None,
+        }
+    }
+
+    /// Acquire the lock if it is currently not held.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.state
+            .lock(|s| !std::mem::replace(&mut s.locked, true))
+            .then(|| MutexGuard { mutex: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn unlock(&self) {
+        let waker = self.state.lock(|s| {
+            s.locked = false;
+            s.waiters.pop()
+        });
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Wake the next waiter if nobody holds the lock.
+    fn unlock_if_free(&self) {
+        let waker = self
+            .state
+            .lock(|s| if s.locked { None } else { s.waiters.pop() });
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Mutex::new(T::default())
+    }
+}
+
+/// The future returned by [`Mutex::lock`].
+#[must_use = "futures do nothing unless polled"]
+pub struct Lock<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    waiter: Option<WaiterId>,
+}
+
+impl<'a, T: ?Sized> Future for Lock<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        if this.waiter.is_none() {
+            wait_queue::reserve(&this.mutex.state, |s| &mut s.waiters);
+        }
+
+        let mut replaced = None;
+        let acquired = this.mutex.state.lock(|s| {
+            if let Some(id) = this.waiter {
+                if let Ok(old) = s.waiters.update(id, cx.waker()) {
+                    // Still waiting for our turn.
+                    replaced = old;
+                    return false;
+                }
+                this.waiter = None;
+            }
+
+            if !s.locked {
+                s.locked = true;
+                true
+            } else {
+                this.waiter = s.waiters.push(cx.waker());
+                false
+            }
+        });
+        drop(replaced);
+
+        if acquired {
+            Poll::Ready(MutexGuard { mutex: this.mutex })
+        } else {
+            if this.waiter.is_none() {
+                // Another task filled the reserved space of the queue, try again.
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for Lock<'_, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.waiter {
+            let removed = self.mutex.state.lock(|s| s.waiters.remove(id));
+            // We were woken by an unlock but won't take the lock, pass it on.
+            if removed.is_none() {
+                self.mutex.unlock_if_free();
+            }
+        }
+    }
+}
+
+/// Gives access to the data of a locked [`Mutex`], the lock is released when dropped.
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::task::noop_waker_ref;
+    use futures::{pin_mut, FutureExt};
+
+    use super::*;
+    use crate::utils::executor::Executor;
+
+    fn poll<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        future.poll_unpin(&mut Context::from_waker(noop_waker_ref()))
+    }
+
+    #[test]
+    fn waiters_lock_in_order() {
+        static EXECUTOR: Executor = Executor::new();
+        static MUTEX: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+        let holder = async {
+            let _guard = MUTEX.lock().await;
+            EXECUTOR.sleep(Duration::from_millis(20)).await;
+        };
+        // The waiters start waiting in the reverse order of their ids.
+        let waiter = |id: u32| async move {
+            EXECUTOR
+                .sleep(Duration::from_millis(5 * (4 - id) as u64))
+                .await;
+            MUTEX.lock().await.push(id);
+        };
+        let (first, second, third) = (waiter(1), waiter(2), waiter(3));
+        pin_mut!(holder, first, second, third);
+        EXECUTOR.run::<5>(&mut [&mut holder, &mut first, &mut second, &mut third]);
+
+        assert_eq!(*MUTEX.try_lock().unwrap(), [3, 2, 1]);
+    }
+
+    #[test]
+    fn dropped_waiter_passes_lock_on() {
+        let mutex = Mutex::new(());
+        let guard = mutex.try_lock().unwrap();
+        let mut first = mutex.lock();
+        let mut second = mutex.lock();
+        assert!(poll(&mut first).is_pending());
+        assert!(poll(&mut second).is_pending());
+
+        // Wakes `first`, which never takes the lock.
+        drop(guard);
+        drop(first);
+        let guard = poll(&mut second);
+        assert!(guard.is_ready());
+        assert!(mutex.try_lock().is_none());
+    }
+}
@@ -0,0 +1,229 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use super::blocking;
+use super::wait_queue::{self, NotQueued, WaitQueue, WaiterId};
+
+/// Notifies one or all waiting tasks.
+///
+/// If [`Notify::notify_one`] is called while no task is waiting, a single permit is
+/// stored and the next call to [`Notify::notified`] completes immediately.
+pub struct Notify {
+    state: blocking::Mutex<NotifyState>,
+}
+
+struct NotifyState {
+    permit: bool,
+    waiters: WaitQueue,
+    /// Incremented by every call to [`Notify::notify_waiters`].
+    generation: u64,
+    /// The waiters registered before this id were woken by [`Notify::notify_waiters`].
+    ///
+    /// They stay queued until they are polled or dropped, so that a waiter that isn't
+    /// queued anymore was always woken by [`Notify::notify_one`].
+    woken_before: WaiterId,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            state: blocking::Mutex::new(NotifyState {
+                permit: false,
+                waiters: WaitQueue::new(),
+                generation: 0,
+                woken_before: 0,
+            }),
+        }
+    }
+
+    /// Wake the first waiting task or store a permit if there is none.
+    ///
+    /// Can be called from an ISR.
+    pub fn notify_one(&self) {
+        let waker = self.state.lock(|s| {
+            let waker = s.waiters.pop_from(s.woken_before);
+            if waker.is_none() {
+                s.permit = true;
+            }
+            waker
+        });
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Wake all currently waiting tasks without storing a permit.
+    ///
+    /// Can be called from an ISR.
+    pub fn notify_waiters(&self) {
+        let id_limit = self.state.lock(|s| {
+            s.generation = s.generation.wrapping_add(1);
+            s.woken_before = s.waiters.next_id();
+            s.woken_before
+        });
+
+        // The wakers are cloned one at a time and called outside of the critical section.
+        let mut after = None;
+        while let Some((id, waker)) = self.state.lock(|s| s.waiters.next_before(after, id_limit)) {
+            after = Some(id);
+            waker.wake();
+        }
+    }
+
+    /// Wait for a notification.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            state: NotifiedState::Init,
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Notify::new()
+    }
+}
+
+enum NotifiedState {
+    Init,
+    Waiting { id: WaiterId, generation: u64 },
+    Done,
+}
+
+/// The future returned by [`Notify::notified`].
+#[must_use = "futures do nothing unless polled"]
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    state: NotifiedState,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+
+        if let NotifiedState::Init = this.state {
+            wait_queue::reserve(&this.notify.state, |s| &mut s.waiters);
+        }
+
+        let mut stale = None;
+        let ready = this.notify.state.lock(|s| match this.state {
+            NotifiedState::Init => {
+                if s.permit {
+                    s.permit = false;
+                    return true;
+                }
+                if let Some(id) = s.waiters.push(cx.waker()) {
+                    this.state = NotifiedState::Waiting {
+                        id,
+                        generation: s.generation,
+                    };
+                }
+                false
+            }
+            // Woken by `notify_waiters`, which leaves the waiter queued.
+            NotifiedState::Waiting { id, generation } if generation != s.generation => {
+                stale = s.waiters.remove(id);
+                true
+            }
+            NotifiedState::Waiting { id, .. } => match s.waiters.update(id, cx.waker()) {
+                Ok(old) => {
+                    stale = old;
+                    false
+                }
+                Err(NotQueued) => true,
+            },
+            NotifiedState::Done => true,
+        });
+        drop(stale);
+
+        if ready {
+            this.state = NotifiedState::Done;
+            Poll::Ready(())
+        } else {
+            if let NotifiedState::Init = this.state {
+                // Another task filled the reserved space of the queue, try again.
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if let NotifiedState::Waiting { id, .. } = self.state {
+            // If we were removed by `notify_one` but never completed, the notification
+            // must be passed on so that it isn't lost.
+            let removed = self.notify.state.lock(|s| s.waiters.remove(id));
+
+            if removed.is_none() {
+                self.notify.notify_one();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::task::noop_waker_ref;
+    use futures::FutureExt;
+
+    use super::*;
+
+    fn poll<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        future.poll_unpin(&mut Context::from_waker(noop_waker_ref()))
+    }
+
+    #[test]
+    fn notify_one_stores_a_permit() {
+        let notify = Notify::new();
+        notify.notify_one();
+        notify.notify_one();
+
+        assert!(poll(&mut notify.notified()).is_ready());
+        assert!(poll(&mut notify.notified()).is_pending());
+    }
+
+    #[test]
+    fn notify_waiters_wakes_waiting_tasks() {
+        let notify = Notify::new();
+        let mut first = notify.notified();
+        let mut second = notify.notified();
+        assert!(poll(&mut first).is_pending());
+        assert!(poll(&mut second).is_pending());
+
+        notify.notify_waiters();
+        let mut later = notify.notified();
+        assert!(poll(&mut later).is_pending());
+        assert!(poll(&mut first).is_ready());
+        assert!(poll(&mut second).is_ready());
+
+        // Only `later` is still waiting.
+        notify.notify_one();
+        assert!(poll(&mut later).is_ready());
+    }
+
+    #[test]
+    fn dropped_waiter_passes_notification_on() {
+        let notify = Notify::new();
+        let mut first = notify.notified();
+        let mut second = notify.notified();
+        assert!(poll(&mut first).is_pending());
+        assert!(poll(&mut second).is_pending());
+
+        // `first` is woken by both, `second` only by `notify_waiters`.
+        notify.notify_one();
+        notify.notify_waiters();
+        drop(first);
+        assert!(poll(&mut second).is_ready());
+
+        // The notification of `first` was stored as a permit.
+        assert!(poll(&mut notify.notified()).is_ready());
+        assert!(poll(&mut notify.notified()).is_pending());
+    }
+}
@@ -0,0 +1,170 @@
+use core::task::Waker;
+
+use super::blocking;
+
+/// The capacity of a queue once the first waiter is registered.
+const MIN_CAPACITY: usize = 4;
+
+/// Identifies a waiter registered in a [`WaitQueue`].
+pub type WaiterId = u64;
+
+/// The waiter isn't in the queue anymore, it has been notified.
+#[derive(Debug)]
+pub struct NotQueued;
+
+/// A FIFO queue of waiting tasks.
+///
+/// Waiters are removed from the queue when they are notified. A future that finds its
+/// [`WaiterId`] missing from the queue therefore knows that it has been notified.
+///
+/// The queue never allocates inside the critical section, it grows in [`reserve`]
+/// before a waiter is added.
+pub struct WaitQueue {
+    waiters: Vec<(WaiterId, Waker)>,
+    next_id: WaiterId,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Add a new waiter at the end of the queue.
+    ///
+    /// Returns `None` if the queue is full, the waiter must call [`reserve`] and try
+    /// again.
+    pub fn push(&mut self, waker: &Waker) -> Option<WaiterId> {
+        if self.waiters.len() == self.waiters.capacity() {
+            return None;
+        }
+
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.waiters.push((id, waker.clone()));
+
+        Some(id)
+    }
+
+    /// Update the waker of `id` and return the replaced one.
+    ///
+    /// The replaced waker must be dropped outside of the critical section, dropping it
+    /// may free the task.
+    pub fn update(&mut self, id: WaiterId, waker: &Waker) -> Result<Option<Waker>, NotQueued> {
+        match self.waiters.iter_mut().find(|(i, _)| *i == id) {
+            Some((_, w)) if w.will_wake(waker) => Ok(None),
+            Some((_, w)) => Ok(Some(core::mem::replace(w, waker.clone()))),
+            None => Err(NotQueued),
+        }
+    }
+
+    /// Remove `id` from the queue and return its waker, `None` if `id` wasn't queued.
+    ///
+    /// Like in [`update`](Self::update) the waker must be dropped outside of the
+    /// critical section.
+    pub fn remove(&mut self, id: WaiterId) -> Option<Waker> {
+        let index = self.waiters.iter().position(|(i, _)| *i == id)?;
+        Some(self.waiters.remove(index).1)
+    }
+
+    /// Remove the first waiter and return its waker.
+    pub fn pop(&mut self) -> Option<Waker> {
+        if self.waiters.is_empty() {
+            None
+        } else {
+            Some(self.waiters.remove(0).1)
+        }
+    }
+
+    /// Remove the first waiter if it was registered before `id_limit`.
+    ///
+    /// Used to only wake the waiters that existed when waking started.
+    pub fn pop_before(&mut self, id_limit: WaiterId) -> Option<Waker> {
+        match self.waiters.first() {
+            Some((id, _)) if is_before(*id, id_limit) => self.pop(),
+            _ => None,
+        }
+    }
+
+    /// Remove the first waiter that was registered at or after `id_start`.
+    pub fn pop_from(&mut self, id_start: WaiterId) -> Option<Waker> {
+        let index = self
+            .waiters
+            .iter()
+            .position(|(id, _)| !is_before(*id, id_start))?;
+
+        Some(self.waiters.remove(index).1)
+    }
+
+    /// Get the first waiter after `after` that was registered before `id_limit`,
+    /// without removing it.
+    pub fn next_before(
+        &self,
+        after: Option<WaiterId>,
+        id_limit: WaiterId,
+    ) -> Option<(WaiterId, Waker)> {
+        self.waiters
+            .iter()
+            .find(|(id, _)| match after {
+                Some(after) => is_before(after, *id),
+                None => true,
+            })
+            .filter(|(id, _)| is_before(*id, id_limit))
+            .map(|(id, waker)| (*id, waker.clone()))
+    }
+
+    /// The id the next registered waiter will get.
+    pub fn next_id(&self) -> WaiterId {
+        self.next_id
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+}
+
+/// Whether the waiter `id` was registered before `other`.
+fn is_before(id: WaiterId, other: WaiterId) -> bool {
+    (id.wrapping_sub(other) as i64) < 0
+}
+
+/// Make room for at least one more waiter in the queue returned by `queue`.
+///
+/// The new buffer is allocated and the old one freed outside of the critical section.
+pub fn reserve<S>(mutex: &blocking::Mutex<S>, queue: impl Fn(&mut S) -> &mut WaitQueue) {
+    loop {
+        let (len, capacity) = mutex.lock(|s| {
+            let waiters = &queue(s).waiters;
+            (waiters.len(), waiters.capacity())
+        });
+        if len < capacity {
+            return;
+        }
+
+        let mut waiters = Vec::with_capacity((capacity * 2).max(MIN_CAPACITY));
+        mutex.lock(|s| {
+            let queue = queue(s);
+            // Other tasks may have registered in between.
+            if queue.waiters.len() < waiters.capacity() {
+                waiters.append(&mut queue.waiters);
+                core::mem::swap(&mut queue.waiters, &mut waiters);
+            }
+        });
+        // Either the old buffer or the unused new one.
+        drop(waiters);
+    }
+}
+
+/// Wake all waiters of the queue returned by `queue` that are registered at the time of
+/// calling.
+///
+/// The wakers are taken out one at a time and called outside of the critical section,
+/// which doesn't need any allocation and is thus safe to call from an ISR.
+pub fn wake_all<S>(mutex: &blocking::Mutex<S>, queue: impl Fn(&mut S) -> &mut WaitQueue) {
+    let id_limit = mutex.lock(|s| queue(s).next_id());
+    while let Some(waker) = mutex.lock(|s| queue(s).pop_before(id_limit)) {
+        waker.wake();
+    }
+}
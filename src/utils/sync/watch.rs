@@ -0,0 +1,238 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use std::sync::Arc;
+
+use super::blocking;
+use super::wait_queue::{self, WaitQueue, WaiterId};
+
+/// Holds the latest value and notifies receivers when it changes.
+///
+/// Receivers only see the most recent value, intermediate values sent while a receiver
+/// wasn't looking are skipped. The value is shared with an [`Arc`], so that it's cloned
+/// outside of the critical section.
+pub struct Watch<T> {
+    state: blocking::Mutex<WatchState<T>>,
+}
+
+struct WatchState<T> {
+    value: Arc<T>,
+    version: u64,
+    waiters: WaitQueue,
+}
+
+impl<T> Watch<T> {
+    pub fn new(value: T) -> Self {
+        Watch {
+            state: blocking::Mutex::new(WatchState {
+                value: Arc::new(value),
+                version: 0,
+                waiters: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// Replace the value and notify all receivers.
+    ///
+    /// Allocates, so it can't be called from an ISR.
+    pub fn send(&self, value: T) {
+        let value = Arc::new(value);
+        let old = self.state.lock(|s| {
+            s.version = s.version.wrapping_add(1);
+            core::mem::replace(&mut s.value, value)
+        });
+        drop(old);
+
+        wait_queue::wake_all(&self.state, |s| &mut s.waiters);
+    }
+
+    /// Create a receiver that considers the current value as seen.
+    pub fn receiver(&self) -> WatchReceiver<'_, T> {
+        WatchReceiver {
+            watch: self,
+            version: self.state.lock(|s| s.version),
+        }
+    }
+
+    /// Get a copy of the current value.
+    pub fn get(&self) -> T
+    where
+        T: Clone,
+    {
+        let value = self.state.lock(|s| s.value.clone());
+        T::clone(&value)
+    }
+}
+
+impl<T: Default> Default for Watch<T> {
+    fn default() -> Self {
+        Watch::new(T::default())
+    }
+}
+
+/// Waits for changes of a [`Watch`].
+pub struct WatchReceiver<'a, T> {
+    watch: &'a Watch<T>,
+    version: u64,
+}
+
+impl<T> Clone for WatchReceiver<'_, T> {
+    fn clone(&self) -> Self {
+        WatchReceiver {
+            watch: self.watch,
+            version: self.version,
+        }
+    }
+}
+
+impl<'a, T> WatchReceiver<'a, T> {
+    /// Get a copy of the current value and mark it as seen.
+    pub fn get(&mut self) -> T
+    where
+        T: Clone,
+    {
+        let (value, version) = self.watch.state.lock(|s| (s.value.clone(), s.version));
+        self.version = version;
+
+        T::clone(&value)
+    }
+
+    /// Whether the value changed since it was last seen by this receiver.
+    pub fn has_changed(&self) -> bool {
+        self.watch.state.lock(|s| s.version != self.version)
+    }
+
+    /// Wait until the value changes and mark it as seen.
+    ///
+    /// Completes immediately if the value already changed since it was last seen.
+    pub fn changed(&mut self) -> Changed<'_, 'a, T> {
+        Changed {
+            receiver: self,
+            waiter: None,
+        }
+    }
+}
+
+/// The future returned by [`WatchReceiver::changed`].
+#[must_use = "futures do nothing unless polled"]
+pub struct Changed<'r, 'a, T> {
+    receiver: &'r mut WatchReceiver<'a, T>,
+    waiter: Option<WaiterId>,
+}
+
+impl<T> Future for Changed<'_, '_, T> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        let seen = this.receiver.version;
+
+        if this.waiter.is_none() {
+            wait_queue::reserve(&this.receiver.watch.state, |s| &mut s.waiters);
+        }
+
+        let mut stale = None;
+        let version = this.receiver.watch.state.lock(|s| {
+            if s.version != seen {
+                stale = this.waiter.take().and_then(|id| s.waiters.remove(id));
+                return Some(s.version);
+            }
+
+            match this.waiter.map(|id| s.waiters.update(id, cx.waker())) {
+                Some(Ok(old)) => stale = old,
+                _ => this.waiter = s.waiters.push(cx.waker()),
+            }
+            None
+        });
+        drop(stale);
+
+        match version {
+            Some(version) => {
+                this.receiver.version = version;
+                Poll::Ready(())
+            }
+            None => {
+                if this.waiter.is_none() {
+                    // Another task filled the reserved space of the queue, try again.
+                    cx.waker().wake_by_ref();
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for Changed<'_, '_, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.waiter {
+            self.receiver.watch.state.lock(|s| s.waiters.remove(id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::oneshot;
+    use futures::executor::block_on;
+    use futures::task::noop_waker_ref;
+    use futures::FutureExt;
+
+    use super::*;
+    use crate::utils::executor::Executor;
+    use crate::utils::thread::ThreadConfig;
+
+    fn poll<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        future.poll_unpin(&mut Context::from_waker(noop_waker_ref()))
+    }
+
+    #[test]
+    fn changed_skips_intermediate_values() {
+        let watch = Watch::new(0);
+        let mut receiver = watch.receiver();
+
+        let mut changed = receiver.changed();
+        assert!(poll(&mut changed).is_pending());
+        watch.send(1);
+        watch.send(2);
+        assert!(poll(&mut changed).is_ready());
+        drop(changed);
+
+        assert!(!receiver.has_changed());
+        assert_eq!(receiver.get(), 2);
+        assert!(poll(&mut receiver.changed()).is_pending());
+
+        // A value sent while not waiting is noticed immediately.
+        watch.send(3);
+        assert!(poll(&mut receiver.changed()).is_ready());
+        assert_eq!(watch.get(), 3);
+    }
+
+    #[test]
+    fn changed_wakes_task() {
+        static EXECUTOR: Executor = Executor::new();
+        static WATCH: spin::Lazy<Watch<u32>> = spin::Lazy::new(|| Watch::new(0));
+
+        let spawner = EXECUTOR.start(&ThreadConfig::default()).unwrap();
+        let (waiting_send, waiting_recv) = oneshot::channel();
+        let (value_send, value_recv) = oneshot::channel();
+        let mut receiver = WATCH.receiver();
+        spawner
+            .spawn(async move {
+                let mut changed = receiver.changed();
+                futures::future::poll_fn(|cx| {
+                    assert!(changed.poll_unpin(cx).is_pending());
+                    Poll::Ready(())
+                })
+                .await;
+                let _ = waiting_send.send(());
+
+                changed.await;
+                let _ = value_send.send(receiver.get());
+            })
+            .unwrap();
+
+        block_on(waiting_recv).unwrap();
+        WATCH.send(7);
+        assert_eq!(block_on(value_recv), Ok(7));
+    }
+}
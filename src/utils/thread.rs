@@ -0,0 +1,173 @@
+//! Threads with explicit core affinity, priority and stack size.
+
+use core::future::Future;
+use core::task::{Context, Poll};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::task::ArcWake;
+
+#[cfg(target_os = "espidf")]
+pub use esp_idf_hal::cpu::Core;
+
+/// The cores of the ESP32, other targets ignore them.
+#[cfg(not(target_os = "espidf"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Core {
+    Core0,
+    Core1,
+}
+
+/// The configuration of a thread created with [`spawn`].
+#[derive(Debug, Clone)]
+pub struct ThreadConfig {
+    /// The name of the thread, truncated to 15 characters by FreeRTOS.
+    pub name: &'static str,
+    /// The core the thread is pinned to, or `None` to let the scheduler decide.
+    pub core: Option<Core>,
+    /// The FreeRTOS priority of the thread.
+    pub priority: u8,
+    /// The stack size in bytes.
+    pub stack_size: usize,
+}
+
+impl Default for ThreadConfig {
+    fn default() -> Self {
+        ThreadConfig {
+            name: "rust-thread",
+            core: None,
+            priority: 5,
+            stack_size: 8 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("failed to create thread `{0}`")]
+pub struct SpawnError(pub &'static str);
+
+/// Spawn a new thread with `config` that runs `f`.
+///
+/// On the ESP32 this creates a FreeRTOS task pinned to [`ThreadConfig::core`]. With the
+/// `std-executor` feature a [`std::thread`] is created and the core and priority are
+/// ignored.
+#[cfg(not(feature = "std-executor"))]
+pub fn spawn<F>(config: &ThreadConfig, f: F) -> Result<(), SpawnError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    use std::ffi::CString;
+
+    use esp_idf_sys as sys;
+    use sys::c_types::c_void;
+
+    unsafe extern "C" fn thread_main(arg: *mut c_void) {
+        let f = Box::from_raw(arg as *mut Box<dyn FnOnce() + Send>);
+        f();
+
+        // A FreeRTOS task must never return.
+        sys::vTaskDelete(core::ptr::null_mut());
+    }
+
+    let f: Box<Box<dyn FnOnce() + Send>> = Box::new(Box::new(f));
+    // FreeRTOS copies the name into the task control block.
+    let name = CString::new(config.name).unwrap_or_default();
+    let core_id = match config.core {
+        Some(Core::Core0) => 0,
+        Some(Core::Core1) => 1,
+        None => sys::tskNO_AFFINITY as i32,
+    };
+
+    let arg = Box::into_raw(f);
+    let result = unsafe {
+        sys::xTaskCreatePinnedToCore(
+            Some(thread_main),
+            name.as_ptr(),
+            config.stack_size as u32,
+            arg as *mut c_void,
+            config.priority as u32,
+            core::ptr::null_mut(),
+            core_id,
+        )
+    };
+
+    // `pdPASS`
+    if result != 1 {
+        drop(unsafe { Box::from_raw(arg) });
+        return Err(SpawnError(config.name));
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "std-executor")]
+pub fn spawn<F>(config: &ThreadConfig, f: F) -> Result<(), SpawnError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    std::thread::Builder::new()
+        .name(config.name.into())
+        .stack_size(config.stack_size)
+        .spawn(f)
+        .map(|_| ())
+        .map_err(|_| SpawnError(config.name))
+}
+
+/// Run `future` on the current thread until it completes, returns `None` if it didn't
+/// complete within `timeout`.
+///
+/// The future is dropped on a timeout.
+pub fn block_on_timeout<F: Future>(future: F, timeout: Duration) -> Option<F::Output> {
+    struct ThreadWaker(std::thread::Thread);
+
+    impl ArcWake for ThreadWaker {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.unpark();
+        }
+    }
+
+    let deadline = Instant::now() + timeout;
+    let waker = futures::task::waker(Arc::new(ThreadWaker(std::thread::current())));
+    let mut context = Context::from_waker(&waker);
+
+    futures::pin_mut!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return Some(output);
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        std::thread::park_timeout(deadline - now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::channel::oneshot;
+
+    use super::block_on_timeout;
+
+    #[test]
+    fn block_on_with_timeout() {
+        assert_eq!(block_on_timeout(async { 42 }, Duration::ZERO), Some(42));
+
+        // Woken by another thread.
+        let (send, recv) = oneshot::channel();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            send.send(42).unwrap();
+        });
+        assert_eq!(
+            block_on_timeout(recv, Duration::from_secs(10)),
+            Some(Ok(42))
+        );
+
+        let (_send, recv) = oneshot::channel::<u32>();
+        assert_eq!(block_on_timeout(recv, Duration::from_millis(10)), None);
+    }
+}
@@ -1,84 +1,532 @@
+//! Timers driven by the [`Executor`].
+//!
+//! Every executor owns a single deadline queue and sleeps in its
+//! [`Backend`](super::executor::backend::Backend) until either a task is woken or the
+//! earliest deadline is reached. The futures in this module register their deadline in
+//! the queue of the executor they were created from and remove it again when dropped.
+//! The current time is read with [`Backend::now`], so the timers can be tested with a
+//! clock that is advanced manually.
+
 use core::future::Future;
-use std::ptr::NonNull;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 
-use esp_idf_hal::units::MicroSecondsU64;
-use esp_idf_sys as sys;
-use sys::c_types::c_void;
-use sys::{esp, esp_nofail, esp_timer_create, EspError};
+use futures::stream::FusedStream;
+use futures::Stream;
 
-type EspTimerHandle = Option<NonNull<sys::esp_timer>>;
+use super::executor::backend::{Backend, DefaultBackend};
+use super::executor::Executor;
 
-#[derive(Default)]
-pub struct EspTimer {
-    handle: EspTimerHandle,
-    waker: Option<core::task::Waker>,
+/// A key that uniquely identifies a registered deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct TimerKey {
+    deadline: Instant,
+    id: u64,
 }
 
-unsafe impl Send for EspTimer {}
+struct TimerEntry {
+    key: TimerKey,
+    waker: Waker,
+}
 
-impl EspTimer {
+/// A queue of deadlines sorted by the earliest deadline first.
+pub(crate) struct TimerQueue {
+    entries: Vec<TimerEntry>,
+    next_id: u64,
+}
+
+impl TimerQueue {
     pub const fn new() -> Self {
-        EspTimer {
-            handle: None,
-            waker: None,
-        }
-    }
-
-    fn init(&mut self) -> Result<(), EspError> {
-        #[cfg(esp_idf_esp_timer_supports_isr_dispatch_method)]
-        let dispatch_method = sys::esp_timer_dispatch_t_ESP_TIMER_ISR;
-        #[cfg(not(esp_idf_esp_timer_supports_isr_dispatch_method))]
-        let dispatch_method = esp_timer_dispatch_t_ESP_TIMER_TASK;
-
-        unsafe {
-            esp!(esp_timer_create(
-                &sys::esp_timer_create_args_t {
-                    callback: Some(Self::handle_callback),
-                    name: b"EspTimer\0" as *const _ as *const _, // TODO
-                    arg: self as *mut _ as *mut _,
-                    dispatch_method,
-                    skip_unhandled_events: false, // TODO
-                },
-                std::mem::transmute(&mut self.handle),
-            ))
-        }
-    }
-
-    pub fn after<'a>(
-        &'a mut self,
-        timeout: MicroSecondsU64,
-    ) -> Result<impl Future<Output = ()> + 'a, EspError> {
-        if let None = &self.handle {
-            self.init()?;
-        }
-
-        Ok(futures::future::poll_fn(move |ctx| {
-            if let None = &self.waker {
-                self.waker = Some(ctx.waker().clone());
-                unsafe {
-                    esp_nofail!(sys::esp_timer_start_once(
-                        self.handle.unwrap().as_ptr(),
-                        timeout.0
-                    ));
-                }
+        TimerQueue {
+            entries: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Register a new deadline that wakes `waker` once reached.
+    pub fn insert(&mut self, deadline: Instant, waker: Waker) -> TimerKey {
+        let key = TimerKey {
+            deadline,
+            id: self.next_id,
+        };
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let index = self
+            .entries
+            .binary_search_by(|e| e.key.cmp(&key))
+            .unwrap_or_else(|i| i);
+        self.entries.insert(index, TimerEntry { key, waker });
+
+        key
+    }
 
-                core::task::Poll::Pending
-            } else {
-                self.waker = None;
-                core::task::Poll::Ready(())
+    /// Replace the waker of the deadline `key`.
+    ///
+    /// Returns `false` if there is no such deadline (i.e. it has already expired).
+    pub fn update(&mut self, key: TimerKey, waker: &Waker) -> bool {
+        match self.entries.binary_search_by(|e| e.key.cmp(&key)) {
+            Ok(index) => {
+                let entry = &mut self.entries[index];
+                if !entry.waker.will_wake(waker) {
+                    entry.waker = waker.clone();
+                }
+                true
             }
-        }))
+            Err(_) => false,
+        }
+    }
+
+    /// Remove the deadline `key` if it is still registered.
+    pub fn remove(&mut self, key: TimerKey) {
+        if let Ok(index) = self.entries.binary_search_by(|e| e.key.cmp(&key)) {
+            self.entries.remove(index);
+        }
+    }
+
+    /// The earliest registered deadline.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.entries.first().map(|e| e.key.deadline)
+    }
+
+    /// Remove at most `max` deadlines that are before or at `now` and return their wakers.
+    pub fn take_expired(&mut self, now: Instant, max: usize) -> impl Iterator<Item = Waker> + '_ {
+        let count = self
+            .entries
+            .iter()
+            .take_while(|e| e.key.deadline <= now)
+            .take(max)
+            .count();
+
+        self.entries.drain(..count).map(|e| e.waker)
+    }
+}
+
+/// The error returned by [`Timeout`] when the deadline elapsed first.
+#[derive(Debug, thiserror::Error)]
+#[error("deadline has elapsed")]
+pub struct Elapsed;
+
+/// A future that completes once its deadline is reached.
+///
+/// Created by [`Executor::sleep`] and [`Executor::sleep_until`]. Dropping the future
+/// removes the deadline from the executor.
+#[must_use = "futures do nothing unless polled"]
+pub struct Sleep<B: Backend = DefaultBackend> {
+    executor: &'static Executor<B>,
+    deadline: Instant,
+    key: Option<TimerKey>,
+}
+
+impl<B: Backend> Sleep<B> {
+    pub(crate) fn new(executor: &'static Executor<B>, deadline: Instant) -> Self {
+        Sleep {
+            executor,
+            deadline,
+            key: None,
+        }
+    }
+
+    /// The instant at which this future completes.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
     }
 
-    extern "C" fn handle_callback(arg: *mut c_void) {
-        let this = unsafe { &mut *(arg as *mut EspTimer) };
-        if let Some(waker) = &this.waker {
-            waker.wake_by_ref();
+    /// Change the deadline of this future, it can be polled again afterwards.
+    pub fn reset(&mut self, deadline: Instant) {
+        self.cancel();
+        self.deadline = deadline;
+    }
+
+    fn cancel(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.executor.timers.lock().remove(key);
+        }
+    }
+}
+
+impl<B: Backend> Future for Sleep<B> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+
+        if B::now() >= this.deadline {
+            this.cancel();
+            return Poll::Ready(());
+        }
+
+        let mut timers = this.executor.timers.lock();
+        match this.key {
+            Some(key) if timers.update(key, cx.waker()) => (),
+            _ => this.key = Some(timers.insert(this.deadline, cx.waker().clone())),
         }
 
-        #[cfg(esp_idf_esp_timer_supports_isr_dispatch_method)]
-        unsafe {
-            sys::esp_timer_isr_dispatch_need_yield();
+        Poll::Pending
+    }
+}
+
+impl<B: Backend> Drop for Sleep<B> {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// A future that completes with the output of `F` or with [`Elapsed`] if `F` didn't
+/// complete before the deadline.
+///
+/// Created by [`Executor::timeout`].
+#[must_use = "futures do nothing unless polled"]
+pub struct Timeout<F, B: Backend = DefaultBackend> {
+    future: F,
+    sleep: Sleep<B>,
+}
+
+impl<F, B: Backend> Timeout<F, B> {
+    pub(crate) fn new(future: F, sleep: Sleep<B>) -> Self {
+        Timeout { future, sleep }
+    }
+
+    /// Get the inner future.
+    pub fn into_inner(self) -> F {
+        self.future
+    }
+}
+
+impl<F: Future, B: Backend> Future for Timeout<F, B> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safe because `future` is never moved out of a pinned `Timeout` and `sleep` is
+        // `Unpin`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+
+        Pin::new(&mut this.sleep).poll(cx).map(|()| Err(Elapsed))
+    }
+}
+
+/// What an [`Interval`] does when a tick was missed.
+///
+/// A tick is missed if the task polling the interval was busy for longer than a period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissedTickBehavior {
+    /// Complete all missed ticks as fast as possible, then continue with the original
+    /// schedule.
+    Burst,
+    /// Schedule the next tick one period after the missed tick completed, this shifts
+    /// all following deadlines.
+    Delay,
+    /// Drop the missed ticks and continue with the next tick of the original schedule.
+    Skip,
+}
+
+impl Default for MissedTickBehavior {
+    fn default() -> Self {
+        MissedTickBehavior::Burst
+    }
+}
+
+/// A stream that yields at fixed absolute deadlines, `period` apart.
+///
+/// Because the deadlines don't depend on when a tick was handled, the time spent between
+/// ticks doesn't cause the interval to drift. Created by [`Executor::interval`] and
+/// [`Executor::interval_at`].
+#[must_use = "streams do nothing unless polled"]
+pub struct Interval<B: Backend = DefaultBackend> {
+    sleep: Sleep<B>,
+    period: Duration,
+    missed_tick_behavior: MissedTickBehavior,
+}
+
+impl<B: Backend> Interval<B> {
+    /// The time between two ticks.
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    pub fn missed_tick_behavior(&self) -> MissedTickBehavior {
+        self.missed_tick_behavior
+    }
+
+    pub fn set_missed_tick_behavior(&mut self, behavior: MissedTickBehavior) {
+        self.missed_tick_behavior = behavior;
+    }
+
+    /// Restart the interval, the next tick completes one period from now.
+    pub fn reset(&mut self) {
+        self.sleep.reset(B::now() + self.period);
+    }
+
+    /// Poll for the next tick, returns the deadline of the tick.
+    pub fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<Instant> {
+        if Pin::new(&mut self.sleep).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let deadline = self.sleep.deadline();
+        let now = B::now();
+        let mut next = deadline + self.period;
+
+        if next <= now {
+            next = match self.missed_tick_behavior {
+                MissedTickBehavior::Burst => next,
+                MissedTickBehavior::Delay => now + self.period,
+                MissedTickBehavior::Skip => {
+                    let period = self.period.as_nanos().max(1);
+                    let missed = (now - deadline).as_nanos() / period;
+                    deadline + self.period * (missed as u32 + 1)
+                }
+            };
         }
+        self.sleep.reset(next);
+
+        Poll::Ready(deadline)
+    }
+
+    /// Wait for the next tick, see [`Interval::poll_tick`].
+    pub async fn tick(&mut self) -> Instant {
+        futures::future::poll_fn(|cx| self.poll_tick(cx)).await
+    }
+}
+
+impl<B: Backend> Stream for Interval<B> {
+    type Item = Instant;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Instant>> {
+        self.get_mut().poll_tick(cx).map(Some)
+    }
+}
+
+impl<B: Backend> FusedStream for Interval<B> {
+    fn is_terminated(&self) -> bool {
+        false
+    }
+}
+
+impl<B: Backend> Executor<B> {
+    /// Sleep until `deadline` is reached.
+    ///
+    /// The returned future must be polled by a task of this executor, otherwise the
+    /// deadline may only be noticed the next time the executor wakes up.
+    pub fn sleep_until(&'static self, deadline: Instant) -> Sleep<B> {
+        Sleep::new(self, deadline)
+    }
+
+    /// Sleep for `duration`, see [`Executor::sleep_until`].
+    pub fn sleep(&'static self, duration: Duration) -> Sleep<B> {
+        self.sleep_until(B::now() + duration)
+    }
+
+    /// Wait for `future` to complete but at most for `duration`.
+    pub fn timeout<F: Future>(&'static self, future: F, duration: Duration) -> Timeout<F, B> {
+        Timeout::new(future, self.sleep(duration))
+    }
+
+    /// Create an [`Interval`] whose first tick completes immediately.
+    pub fn interval(&'static self, period: Duration) -> Interval<B> {
+        self.interval_at(B::now(), period)
+    }
+
+    /// Create an [`Interval`] whose first tick completes at `start`.
+    ///
+    /// Panics if `period` is zero.
+    pub fn interval_at(&'static self, start: Instant, period: Duration) -> Interval<B> {
+        assert!(!period.is_zero(), "interval period must be non-zero");
+
+        Interval {
+            sleep: self.sleep_until(start),
+            period,
+            missed_tick_behavior: MissedTickBehavior::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use futures::future::{self, FutureExt};
+    use futures::task::{self, ArcWake};
+
+    use super::*;
+    use crate::utils::executor::backend::Notifier;
+
+    thread_local! {
+        static NOW: Cell<Option<Instant>> = const { Cell::new(None) };
+    }
+
+    /// A backend whose clock only moves with [`MockBackend::advance`], separately for
+    /// every test thread. The executor itself is never run.
+    struct MockBackend;
+
+    #[derive(Clone)]
+    struct MockNotifier;
+
+    impl Notifier for MockNotifier {
+        fn notify(&self) {}
+    }
+
+    impl Backend for MockBackend {
+        type Notifier = MockNotifier;
+
+        fn current() -> Self {
+            MockBackend
+        }
+
+        fn notifier(&self) -> MockNotifier {
+            MockNotifier
+        }
+
+        fn wait(&mut self, _deadline: Option<Instant>) {
+            unimplemented!("the mock executor is never run")
+        }
+
+        fn now() -> Instant {
+            NOW.with(|now| match now.get() {
+                Some(now) => now,
+                None => {
+                    now.set(Some(Instant::now()));
+                    now.get().unwrap()
+                }
+            })
+        }
+    }
+
+    impl MockBackend {
+        fn advance(duration: Duration) {
+            let now = Self::now() + duration;
+            NOW.with(|cell| cell.set(Some(now)));
+        }
+    }
+
+    /// A waker that records its id when woken.
+    struct RecordWaker(usize, Arc<Mutex<Vec<usize>>>);
+
+    impl ArcWake for RecordWaker {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.1.lock().unwrap().push(arc_self.0);
+        }
+    }
+
+    fn poll<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        future.poll_unpin(&mut Context::from_waker(task::noop_waker_ref()))
+    }
+
+    #[test]
+    fn queue_order() {
+        let start = Instant::now();
+        let woken = Arc::new(Mutex::new(Vec::new()));
+        let waker = |id| task::waker(Arc::new(RecordWaker(id, woken.clone())));
+
+        let mut queue = TimerQueue::new();
+        let ms = Duration::from_millis;
+        queue.insert(start + ms(30), waker(0));
+        queue.insert(start + ms(10), waker(1));
+        let removed = queue.insert(start + ms(20), waker(2));
+        queue.insert(start + ms(20), waker(3));
+        queue.insert(start + ms(10), waker(4));
+        queue.remove(removed);
+        assert_eq!(queue.next_deadline(), Some(start + ms(10)));
+
+        // Equal deadlines expire in the order they were inserted.
+        queue.take_expired(start + ms(20), 2).for_each(Waker::wake);
+        assert_eq!(*woken.lock().unwrap(), [1, 4]);
+        assert_eq!(queue.next_deadline(), Some(start + ms(20)));
+
+        queue.take_expired(start + ms(25), 8).for_each(Waker::wake);
+        assert_eq!(*woken.lock().unwrap(), [1, 4, 3]);
+        assert_eq!(queue.next_deadline(), Some(start + ms(30)));
+    }
+
+    #[test]
+    fn sleep_is_removed_when_dropped() {
+        static EXECUTOR: Executor<MockBackend> = Executor::new();
+
+        let mut sleep = EXECUTOR.sleep(Duration::from_millis(10));
+        assert_eq!(poll(&mut sleep), Poll::Pending);
+        assert_eq!(
+            EXECUTOR.timers.lock().next_deadline(),
+            Some(sleep.deadline())
+        );
+        drop(sleep);
+        assert_eq!(EXECUTOR.timers.lock().next_deadline(), None);
+
+        let mut sleep = EXECUTOR.sleep(Duration::from_millis(10));
+        assert_eq!(poll(&mut sleep), Poll::Pending);
+        MockBackend::advance(Duration::from_millis(10));
+        assert_eq!(poll(&mut sleep), Poll::Ready(()));
+        assert_eq!(EXECUTOR.timers.lock().next_deadline(), None);
+    }
+
+    #[test]
+    fn timeout() {
+        static EXECUTOR: Executor<MockBackend> = Executor::new();
+
+        let mut ready = EXECUTOR.timeout(future::ready(5), Duration::from_millis(10));
+        assert!(matches!(poll(&mut ready), Poll::Ready(Ok(5))));
+
+        let mut pending = EXECUTOR.timeout(future::pending::<()>(), Duration::from_millis(10));
+        assert!(poll(&mut pending).is_pending());
+        MockBackend::advance(Duration::from_millis(9));
+        assert!(poll(&mut pending).is_pending());
+        MockBackend::advance(Duration::from_millis(1));
+        assert!(matches!(poll(&mut pending), Poll::Ready(Err(Elapsed))));
+        drop(pending);
+        assert_eq!(EXECUTOR.timers.lock().next_deadline(), None);
+    }
+
+    fn poll_tick(interval: &mut Interval<MockBackend>) -> Poll<Instant> {
+        interval.poll_tick(&mut Context::from_waker(task::noop_waker_ref()))
+    }
+
+    /// Start an interval with a period of 10 ms, miss its ticks for 35 ms and return it
+    /// with its start and the ticks that complete immediately afterwards.
+    fn miss_ticks(
+        executor: &'static Executor<MockBackend>,
+        behavior: MissedTickBehavior,
+    ) -> (Interval<MockBackend>, Instant, Vec<Instant>) {
+        let start = MockBackend::now();
+        let mut interval = executor.interval(Duration::from_millis(10));
+        interval.set_missed_tick_behavior(behavior);
+        assert_eq!(poll_tick(&mut interval), Poll::Ready(start));
+        assert_eq!(poll_tick(&mut interval), Poll::Pending);
+
+        MockBackend::advance(Duration::from_millis(35));
+        let mut ticks = Vec::new();
+        while let Poll::Ready(tick) = poll_tick(&mut interval) {
+            ticks.push(tick);
+        }
+
+        (interval, start, ticks)
+    }
+
+    #[test]
+    fn missed_ticks() {
+        static EXECUTOR: Executor<MockBackend> = Executor::new();
+        let ms = Duration::from_millis;
+
+        let (mut interval, start, ticks) = miss_ticks(&EXECUTOR, MissedTickBehavior::Burst);
+        assert_eq!(ticks, [start + ms(10), start + ms(20), start + ms(30)]);
+        MockBackend::advance(ms(5));
+        assert_eq!(poll_tick(&mut interval), Poll::Ready(start + ms(40)));
+
+        let (mut interval, start, ticks) = miss_ticks(&EXECUTOR, MissedTickBehavior::Delay);
+        assert_eq!(ticks, [start + ms(10)]);
+        MockBackend::advance(ms(5));
+        assert_eq!(poll_tick(&mut interval), Poll::Pending);
+        MockBackend::advance(ms(5));
+        assert_eq!(poll_tick(&mut interval), Poll::Ready(start + ms(45)));
+
+        let (mut interval, start, ticks) = miss_ticks(&EXECUTOR, MissedTickBehavior::Skip);
+        assert_eq!(ticks, [start + ms(10)]);
+        MockBackend::advance(ms(5));
+        assert_eq!(poll_tick(&mut interval), Poll::Ready(start + ms(40)));
     }
 }
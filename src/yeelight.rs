@@ -0,0 +1,351 @@
+//! An emulation of a Yeelight color bulb on the Yeelight LAN protocol.
+//!
+//! The light answers discovery requests on the multicast group of the protocol, and
+//! clients control it over TCP connections. Every connection is notified about changed
+//! properties, also if another API changed the light state. Color flows are shown with
+//! the effects of the light state, a flow with a limited amount of steps ends with its
+//! action after it would have run through them.
+//!
+//! Like the OPC server, the service runs on the shared network [`EXECUTOR`] and polls
+//! non-blocking sockets while idle.
+
+use std::convert::Infallible;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use self::protocol::{
+    ColorMode, Flow, FlowAction, Method, Props, Request, DISCOVERY_ADDR, DISCOVERY_PORT, PORT,
+};
+use crate::light::frame::Pixel;
+use crate::light::state::{self, LightState};
+use crate::utils::executor::spawner::{ExecutorShutDown, Spawner};
+use crate::utils::net::{self, EXECUTOR};
+
+pub mod protocol;
+
+/// How often idle connections are checked for requests and changed properties.
+const POLL_PERIOD: Duration = Duration::from_millis(50);
+/// How often the listener and the discovery socket are checked, and color flows whether
+/// they ended.
+const ACCEPT_PERIOD: Duration = Duration::from_millis(100);
+/// Requests are at most a few hundred bytes, longer lines close the connection.
+const MAX_LINE_LEN: usize = 4096;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StartError {
+    #[error("failed to bind yeelight socket")]
+    Bind(#[source] io::Error),
+    #[error("failed to spawn yeelight task")]
+    Task(#[from] ExecutorShutDown),
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The name of the light reported to clients.
+    pub name: String,
+    /// Further connections are closed immediately.
+    pub max_clients: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            name: "esp32-hue".into(),
+            max_clients: 4,
+        }
+    }
+}
+
+struct Server {
+    config: Config,
+    /// The id of the light in discovery responses.
+    id: u64,
+    color_mode: spin::Mutex<ColorMode>,
+    flow: spin::Mutex<Option<ActiveFlow>>,
+    clients: AtomicUsize,
+}
+
+/// A color flow that is shown.
+struct ActiveFlow {
+    flow: Flow,
+    /// When the flow ends, `None` if it runs forever.
+    end: Option<Instant>,
+    /// The segments that show the flow, the flow was stopped once they changed.
+    segments: Vec<state::Segment>,
+    /// The light state before the flow started.
+    previous: LightState,
+}
+
+/// Start answering discovery requests and accepting Yeelight clients on the executor of
+/// `spawner`.
+pub fn start(config: Config, spawner: &Spawner) -> Result<(), StartError> {
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, PORT)).map_err(StartError::Bind)?;
+    listener.set_nonblocking(true).map_err(StartError::Bind)?;
+
+    let discovery =
+        UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT)).map_err(StartError::Bind)?;
+    discovery
+        .join_multicast_v4(&DISCOVERY_ADDR, &Ipv4Addr::UNSPECIFIED)
+        .map_err(StartError::Bind)?;
+    discovery.set_nonblocking(true).map_err(StartError::Bind)?;
+
+    let mut mac = [0; 8];
+    mac[2..].copy_from_slice(&net::station_mac());
+    let server = Arc::new(Server {
+        config,
+        id: u64::from_be_bytes(mac),
+        color_mode: spin::Mutex::new(ColorMode::Rgb),
+        flow: spin::Mutex::new(None),
+        clients: AtomicUsize::new(0),
+    });
+
+    spawner.spawn(discover(discovery, server.clone()))?;
+    spawner.spawn(watch_flow(server.clone()))?;
+    spawner.spawn(accept(listener, spawner.clone(), server))?;
+
+    Ok(())
+}
+
+async fn discover(socket: UdpSocket, server: Arc<Server>) {
+    let mut buf = [0; 512];
+    loop {
+        let (len, src) = match socket.recv_from(&mut buf) {
+            Ok(request) => request,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                EXECUTOR.sleep(ACCEPT_PERIOD).await;
+                continue;
+            }
+            Err(err) => {
+                log::error!("failed to receive yeelight discovery request: {}", err);
+                EXECUTOR.sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        if !protocol::is_search(&buf[..len]) {
+            continue;
+        }
+        let ip = match net::station_ip() {
+            Some(ip) => ip,
+            None => continue,
+        };
+
+        let response = protocol::search_response(server.id, ip, &server.props(&state::get()));
+        if let Err(err) = socket.send_to(response.as_bytes(), src) {
+            log::warn!("failed to answer yeelight discovery from {}: {}", src, err);
+        }
+    }
+}
+
+async fn accept(listener: TcpListener, spawner: Spawner, server: Arc<Server>) {
+    loop {
+        let (stream, addr) = match listener.accept() {
+            Ok(client) => client,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                EXECUTOR.sleep(ACCEPT_PERIOD).await;
+                continue;
+            }
+            Err(err) => {
+                log::error!("failed to accept yeelight client: {}", err);
+                EXECUTOR.sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        if server.clients.load(Ordering::Relaxed) >= server.config.max_clients {
+            log::warn!("rejected yeelight client {}, too many clients", addr);
+            continue;
+        }
+        if let Err(err) = stream.set_nonblocking(true) {
+            log::error!("failed to set up yeelight client {}: {}", addr, err);
+            continue;
+        }
+
+        server.clients.fetch_add(1, Ordering::Relaxed);
+        if spawner.spawn(serve(stream, addr, server.clone())).is_err() {
+            return;
+        }
+    }
+}
+
+async fn serve(stream: TcpStream, addr: SocketAddr, server: Arc<Server>) {
+    log::info!("yeelight client {} connected", addr);
+
+    if let Err(err) = server.serve(&stream).await {
+        log::warn!("yeelight client {} failed: {}", addr, err);
+    }
+
+    log::info!("yeelight client {} disconnected", addr);
+    server.clients.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// End color flows once they ran through their steps, and forget flows that another API
+/// replaced.
+async fn watch_flow(server: Arc<Server>) {
+    loop {
+        EXECUTOR.sleep(ACCEPT_PERIOD).await;
+
+        let mut flow = server.flow.lock();
+        let ended = match &*flow {
+            Some(active) => matches!(active.end, Some(end) if Instant::now() >= end),
+            None => continue,
+        };
+        if ended {
+            if let Some(active) = flow.take() {
+                end_flow(active);
+            }
+        } else if !server.is_flowing(&flow, &state::get()) {
+            *flow = None;
+        }
+    }
+}
+
+impl Server {
+    /// Answer the requests of a client and notify it about changed properties, until it
+    /// disconnects.
+    async fn serve(&self, mut stream: &TcpStream) -> io::Result<()> {
+        let mut receiver = state::receiver();
+        let mut props = self.props(&receiver.get());
+
+        let mut buf = Vec::new();
+        let mut chunk = [0; 512];
+        loop {
+            match stream.read(&mut chunk) {
+                Ok(0) => return Ok(()),
+                Ok(len) => {
+                    buf.extend_from_slice(&chunk[..len]);
+                    while let Some(end) = buf.iter().position(|&b| b == b'\n') {
+                        let line: Vec<u8> = buf.drain(..=end).collect();
+                        if let Some(response) = self.handle(&line) {
+                            send(stream, &response)?;
+                        }
+                    }
+                    if buf.len() > MAX_LINE_LEN {
+                        return Err(io::Error::new(ErrorKind::InvalidData, "request too long"));
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    EXECUTOR.sleep(POLL_PERIOD).await;
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+
+            // The flow is checked too, since it changes without the light state when it's
+            // stopped.
+            let new_props = self.props(&receiver.get());
+            let changes = new_props.changes(&props);
+            if !changes.is_empty() {
+                send(stream, &protocol::notification(changes))?;
+                props = new_props;
+            }
+        }
+    }
+
+    /// Handle one request line and return the response, `None` if it isn't a request.
+    fn handle(&self, line: &[u8]) -> Option<String> {
+        let request: Request = match serde_json::from_slice(line) {
+            Ok(request) => request,
+            Err(err) => {
+                log::debug!("invalid yeelight request: {}", err);
+                return None;
+            }
+        };
+
+        let result = Method::parse(&request).map(|method| self.call(method));
+        Some(protocol::response(&request.id, result))
+    }
+
+    fn call(&self, method: Method) -> Vec<String> {
+        match method {
+            Method::GetProp(names) => {
+                let props = self.props(&state::get());
+                return names
+                    .iter()
+                    .map(|name| props.get(name).unwrap_or_default())
+                    .collect();
+            }
+            Method::StopCf => {
+                if let Some(active) = self.flow.lock().take() {
+                    end_flow(active);
+                }
+            }
+            Method::StartCf(ref flow) => {
+                let mut active_flow = self.flow.lock();
+                // A new flow recovers the state from before the first one.
+                let previous = match active_flow.take() {
+                    Some(active) => active.previous,
+                    None => state::get(),
+                };
+                let state = self.update(&method);
+                *active_flow = Some(ActiveFlow {
+                    flow: flow.clone(),
+                    // A flow that ends too far in the future practically runs forever.
+                    end: flow
+                        .duration()
+                        .and_then(|duration| Instant::now().checked_add(duration)),
+                    segments: state.segments,
+                    previous,
+                });
+            }
+            // Setting the power keeps a flow running, like on a bulb.
+            Method::SetPower { .. } | Method::Toggle => {
+                self.update(&method);
+            }
+            _ => {
+                *self.flow.lock() = None;
+                self.update(&method);
+            }
+        }
+
+        vec!["ok".into()]
+    }
+
+    fn update(&self, method: &Method) -> LightState {
+        let mut color_mode = self.color_mode.lock();
+        let result = state::update::<Infallible>(|state| {
+            protocol::apply(method, state, &mut color_mode);
+            Ok(())
+        });
+        match result {
+            Ok(state) => state,
+            Err(never) => match never {},
+        }
+    }
+
+    fn props(&self, state: &LightState) -> Props {
+        let flowing = self.is_flowing(&self.flow.lock(), state);
+        Props::new(state, *self.color_mode.lock(), flowing, &self.config.name)
+    }
+
+    fn is_flowing(&self, flow: &Option<ActiveFlow>, state: &LightState) -> bool {
+        matches!(flow, Some(active) if active.segments == state.segments)
+    }
+}
+
+/// Apply the action of a color flow that ended.
+fn end_flow(active: ActiveFlow) {
+    let last_color = active.flow.last_color();
+    let _ = state::update::<()>(|state| {
+        match active.flow.action {
+            FlowAction::Recover => {
+                state.brightness = active.previous.brightness;
+                state.segments = active.previous.segments.clone();
+            }
+            FlowAction::Stay => {
+                let color = last_color.unwrap_or(Pixel::new(0, 0, 0));
+                protocol::set_color(state, color, Duration::ZERO);
+            }
+            FlowAction::Off => state.on = false,
+        }
+        Ok(())
+    });
+}
+
+/// Send a message, a client that doesn't read its messages is disconnected.
+fn send(mut stream: &TcpStream, message: &str) -> io::Result<()> {
+    stream.write_all(message.as_bytes())
+}
@@ -0,0 +1,802 @@
+//! The messages of the Yeelight LAN protocol and how its methods change the light state.
+//!
+//! Requests and responses are JSON objects, one per line. Like the other control APIs, a
+//! color sets the primary color of all segments, and a color flow is shown with the
+//! effect that comes closest to it.
+
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::light::color;
+use crate::light::effect::{self, Effect};
+use crate::light::frame::Pixel;
+use crate::light::state::LightState;
+
+/// The TCP port of the control protocol.
+pub const PORT: u16 = 55443;
+/// The UDP port of the discovery protocol.
+pub const DISCOVERY_PORT: u16 = 1982;
+/// The multicast group of the discovery protocol.
+pub const DISCOVERY_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+
+/// The coldest supported color temperature in kelvin.
+pub const MAX_CT: u16 = 6500;
+/// The warmest supported color temperature in kelvin.
+pub const MIN_CT: u16 = 1700;
+/// The color temperature reported until a client sets one.
+const DEFAULT_CT: u16 = 4000;
+/// The shortest transition of the `smooth` effect.
+const MIN_DURATION: Duration = Duration::from_millis(30);
+/// The shortest step of a color flow.
+const MIN_FLOW_STEP: Duration = Duration::from_millis(50);
+/// The longest step of a color flow.
+const MAX_FLOW_STEP: Duration = Duration::from_secs(24 * 60 * 60);
+/// The most steps a color flow runs, longer flows can run forever with a count of zero.
+const MAX_FLOW_COUNT: u32 = 1_000_000;
+
+/// The methods in the `support` field of discovery responses.
+const SUPPORTED_METHODS: &str =
+    "get_prop set_power toggle set_bright set_rgb set_hsv set_ct_abx start_cf stop_cf";
+/// The properties in the order of [`Props::get`].
+pub const PROPS: [&str; 9] = [
+    "power",
+    "bright",
+    "ct",
+    "rgb",
+    "hue",
+    "sat",
+    "color_mode",
+    "flowing",
+    "name",
+];
+
+#[derive(Debug, Deserialize)]
+pub struct Request {
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum MethodError {
+    #[error("method not supported")]
+    Unsupported,
+    #[error("invalid params")]
+    InvalidParams,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Method {
+    GetProp(Vec<String>),
+    SetPower {
+        on: bool,
+        transition: Duration,
+    },
+    Toggle,
+    /// The brightness from 1 to 100.
+    SetBright {
+        brightness: u8,
+        transition: Duration,
+    },
+    SetRgb {
+        rgb: u32,
+        transition: Duration,
+    },
+    /// The hue in degrees and the saturation from 0 to 100.
+    SetHsv {
+        hue: u16,
+        saturation: u8,
+        transition: Duration,
+    },
+    /// The color temperature in kelvin.
+    SetCtAbx {
+        ct: u16,
+        transition: Duration,
+    },
+    StartCf(Flow),
+    StopCf,
+}
+
+impl Method {
+    pub fn parse(request: &Request) -> Result<Self, MethodError> {
+        let params = Params(&request.params);
+        let method = match request.method.as_str() {
+            "get_prop" => Method::GetProp(
+                request
+                    .params
+                    .iter()
+                    .map(|p| p.as_str().map(String::from))
+                    .collect::<Option<_>>()
+                    .ok_or(MethodError::InvalidParams)?,
+            ),
+            "set_power" => Method::SetPower {
+                on: match params.str(0)? {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(MethodError::InvalidParams),
+                },
+                transition: params.transition(1)?,
+            },
+            "toggle" => Method::Toggle,
+            "set_bright" => Method::SetBright {
+                brightness: params.int(0, 1, 100)? as u8,
+                transition: params.transition(1)?,
+            },
+            "set_rgb" => Method::SetRgb {
+                rgb: params.int(0, 0, 0xff_ffff)? as u32,
+                transition: params.transition(1)?,
+            },
+            "set_hsv" => Method::SetHsv {
+                hue: params.int(0, 0, 359)? as u16,
+                saturation: params.int(1, 0, 100)? as u8,
+                transition: params.transition(2)?,
+            },
+            "set_ct_abx" => Method::SetCtAbx {
+                ct: params.int(0, MIN_CT as i64, MAX_CT as i64)? as u16,
+                transition: params.transition(1)?,
+            },
+            "start_cf" => Method::StartCf(Flow {
+                count: params.int(0, 0, MAX_FLOW_COUNT as i64)? as u32,
+                action: match params.int(1, 0, 2)? {
+                    0 => FlowAction::Recover,
+                    1 => FlowAction::Stay,
+                    _ => FlowAction::Off,
+                },
+                steps: parse_flow(params.str(2)?)?,
+            }),
+            "stop_cf" => Method::StopCf,
+            _ => return Err(MethodError::Unsupported),
+        };
+
+        Ok(method)
+    }
+}
+
+/// A color flow of `start_cf`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Flow {
+    /// The amount of steps until the flow stops, zero to run forever.
+    pub count: u32,
+    pub action: FlowAction,
+    pub steps: Vec<FlowStep>,
+}
+
+/// What happens once a color flow stops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowAction {
+    /// Restore the state from before the flow.
+    Recover,
+    /// Keep the last color of the flow.
+    Stay,
+    Off,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlowStep {
+    /// The transition to the color of the step, or how long to keep the previous one.
+    pub duration: Duration,
+    /// The color of the step, `None` to keep the previous color.
+    pub color: Option<Pixel>,
+    /// The brightness from 1 to 100, `None` to keep the previous brightness.
+    pub brightness: Option<u8>,
+}
+
+/// The effect that shows a [`Flow`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlowEffect {
+    pub effect: Effect,
+    pub colors: [Pixel; 2],
+    pub speed: u8,
+    pub intensity: u8,
+}
+
+impl Flow {
+    /// The effect that comes closest to the flow.
+    ///
+    /// One color is shown solid and two colors blink if they change suddenly or breathe
+    /// otherwise, more colors cycle through all hues. A cycle of the effect lasts as long
+    /// as the whole flow.
+    pub fn effect(&self) -> FlowEffect {
+        let cycle = self.cycle();
+        let mut colors: Vec<Pixel> = Vec::new();
+        for color in self.steps.iter().filter_map(|step| step.color) {
+            if !colors.contains(&color) {
+                colors.push(color);
+            }
+        }
+        let primary = colors.first().copied().unwrap_or(Pixel::new(0, 0, 0));
+        let secondary = colors.get(1).copied().unwrap_or(primary);
+
+        let effect = match colors.len() {
+            0 | 1 => Effect::Solid,
+            2 => {
+                let sudden = self
+                    .steps
+                    .iter()
+                    .all(|step| step.color.is_none() || step.duration <= MIN_FLOW_STEP);
+                if sudden {
+                    Effect::Blink
+                } else {
+                    Effect::Breathe
+                }
+            }
+            _ => Effect::ColorLoop,
+        };
+
+        // The share of the cycle in which the primary color is shown, from its step up to
+        // the next color.
+        let mut primary_time = Duration::ZERO;
+        let mut showing_primary = false;
+        for step in &self.steps {
+            if let Some(color) = step.color {
+                showing_primary = color == primary;
+            }
+            if showing_primary {
+                primary_time += step.duration;
+            }
+        }
+        let duty = primary_time.as_secs_f32() / cycle.as_secs_f32().max(f32::EPSILON);
+
+        FlowEffect {
+            effect,
+            colors: [primary, secondary],
+            speed: effect::speed(cycle),
+            intensity: (duty * 255.0).round() as u8,
+        }
+    }
+
+    /// The first brightness set by the flow.
+    pub fn brightness(&self) -> Option<u8> {
+        self.steps.iter().find_map(|step| step.brightness)
+    }
+
+    /// How long the flow runs, `None` if it runs forever.
+    ///
+    /// Saturates at [`Duration::MAX`] instead of overflowing.
+    pub fn duration(&self) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        if self.steps.is_empty() {
+            return Some(Duration::ZERO);
+        }
+
+        let cycles = self.count / self.steps.len() as u32;
+        let remaining = self.count as usize % self.steps.len();
+        let rest = sum(self.steps[..remaining].iter().map(|step| step.duration));
+        let duration = self
+            .cycle()
+            .checked_mul(cycles)
+            .map_or(Duration::MAX, |duration| duration.saturating_add(rest));
+        Some(duration)
+    }
+
+    /// The color shown once the flow stopped.
+    pub fn last_color(&self) -> Option<Pixel> {
+        if self.count == 0 || self.steps.is_empty() {
+            return None;
+        }
+
+        // The last executed step, and the ones after it if an earlier cycle ran them.
+        let last = (self.count as usize - 1) % self.steps.len();
+        let (current, later) = self.steps.split_at(last + 1);
+        let later = if self.count as usize > last + 1 {
+            later
+        } else {
+            &[]
+        };
+        current
+            .iter()
+            .rev()
+            .chain(later.iter().rev())
+            .find_map(|step| step.color)
+    }
+
+    /// How long all steps take once.
+    fn cycle(&self) -> Duration {
+        sum(self.steps.iter().map(|step| step.duration))
+    }
+}
+
+/// Sum up durations, saturating at [`Duration::MAX`].
+fn sum(durations: impl Iterator<Item = Duration>) -> Duration {
+    durations.fold(Duration::ZERO, Duration::saturating_add)
+}
+
+/// Parse the flow expression of `start_cf`, groups of duration, mode, value and
+/// brightness.
+fn parse_flow(expression: &str) -> Result<Vec<FlowStep>, MethodError> {
+    let values = expression
+        .split(',')
+        .map(|value| value.trim().parse::<i64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| MethodError::InvalidParams)?;
+    if values.is_empty() || values.len() % 4 != 0 {
+        return Err(MethodError::InvalidParams);
+    }
+
+    let steps = values
+        .chunks(4)
+        .map(|step| {
+            let (duration, mode, value, brightness) = (step[0], step[1], step[2], step[3]);
+            let range = MIN_FLOW_STEP.as_millis() as i64..=MAX_FLOW_STEP.as_millis() as i64;
+            if !range.contains(&duration) {
+                return Err(MethodError::InvalidParams);
+            }
+
+            let color = match mode {
+                1 if (0..=0xff_ffff).contains(&value) => Some(from_rgb(value as u32)),
+                2 if (MIN_CT as i64..=MAX_CT as i64).contains(&value) => {
+                    Some(color::from_temperature(value as u16))
+                }
+                7 => None,
+                _ => return Err(MethodError::InvalidParams),
+            };
+            let brightness = match brightness {
+                -1 => None,
+                1..=100 => Some(brightness as u8),
+                _ if mode == 7 => None,
+                _ => return Err(MethodError::InvalidParams),
+            };
+
+            Ok(FlowStep {
+                duration: Duration::from_millis(duration as u64),
+                color,
+                brightness,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if steps.iter().all(|step| step.color.is_none()) {
+        return Err(MethodError::InvalidParams);
+    }
+    Ok(steps)
+}
+
+/// The color mode the light was last set with, which is reported in the properties.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    Rgb,
+    /// The color temperature in kelvin.
+    Ct(u16),
+    Hsv,
+}
+
+/// Apply a method that changes the light state to `state`, `mode` is set to the color
+/// mode of the method.
+///
+/// Other methods don't change the light state and are ignored.
+pub fn apply(method: &Method, state: &mut LightState, mode: &mut ColorMode) {
+    match *method {
+        Method::SetPower { on, transition } => {
+            state.on = on;
+            state.transition = transition;
+        }
+        Method::Toggle => state.on = !state.on,
+        Method::SetBright {
+            brightness,
+            transition,
+        } => {
+            state.brightness = from_percent(brightness);
+            state.transition = transition;
+        }
+        Method::SetRgb { rgb, transition } => {
+            *mode = ColorMode::Rgb;
+            set_color(state, from_rgb(rgb), transition);
+        }
+        Method::SetHsv {
+            hue,
+            saturation,
+            transition,
+        } => {
+            *mode = ColorMode::Hsv;
+            let color = color::from_hs(hue as f32, saturation as f32 / 100.0);
+            set_color(state, color, transition);
+        }
+        Method::SetCtAbx { ct, transition } => {
+            *mode = ColorMode::Ct(ct);
+            set_color(state, color::from_temperature(ct), transition);
+        }
+        Method::StartCf(ref flow) => {
+            let effect = flow.effect();
+            if let Some(brightness) = flow.brightness() {
+                state.brightness = from_percent(brightness);
+            }
+            for segment in &mut state.segments {
+                segment.effect = effect.effect;
+                segment.colors[0] = effect.colors[0];
+                segment.colors[1] = effect.colors[1];
+                segment.speed = effect.speed;
+                segment.intensity = effect.intensity;
+            }
+            *mode = ColorMode::Rgb;
+        }
+        Method::GetProp(_) | Method::StopCf => {}
+    }
+}
+
+/// Show `color` on all segments, which also stops effects like on a bulb.
+pub fn set_color(state: &mut LightState, color: Pixel, transition: Duration) {
+    state.transition = transition;
+    for segment in &mut state.segments {
+        segment.colors[0] = color;
+        segment.effect = Effect::Solid;
+    }
+}
+
+/// The properties of the light.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Props {
+    pub on: bool,
+    /// The brightness from 1 to 100.
+    pub brightness: u8,
+    pub ct: u16,
+    pub rgb: u32,
+    pub hue: u16,
+    pub saturation: u8,
+    pub mode: ColorMode,
+    pub flowing: bool,
+    pub name: String,
+}
+
+impl Props {
+    /// The properties of `state`, reported in color `mode` if it still applies.
+    pub fn new(state: &LightState, mode: ColorMode, flowing: bool, name: &str) -> Self {
+        let color = state
+            .segments
+            .first()
+            .map_or(Pixel::new(0, 0, 0), |s| s.colors[0]);
+        // Another API may have changed the color since the color temperature was set.
+        let mode = match mode {
+            ColorMode::Ct(ct) if color::from_temperature(ct) != color => ColorMode::Rgb,
+            mode => mode,
+        };
+        let (hue, saturation, _) = color::to_hsv(color);
+
+        Props {
+            on: state.on,
+            brightness: ((state.brightness as u16 * 100 + 127) / 255).max(1) as u8,
+            ct: match mode {
+                ColorMode::Ct(ct) => ct,
+                _ => DEFAULT_CT,
+            },
+            rgb: u32::from_be_bytes([0, color.red, color.green, color.blue]),
+            hue: hue.round() as u16 % 360,
+            saturation: (saturation * 100.0).round() as u8,
+            mode,
+            flowing,
+            name: name.into(),
+        }
+    }
+
+    /// The value of property `name`, as the protocol reports it.
+    pub fn get(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "power" => if self.on { "on" } else { "off" }.to_string(),
+            "bright" => self.brightness.to_string(),
+            "ct" => self.ct.to_string(),
+            "rgb" => self.rgb.to_string(),
+            "hue" => self.hue.to_string(),
+            "sat" => self.saturation.to_string(),
+            "color_mode" => match self.mode {
+                ColorMode::Rgb => "1",
+                ColorMode::Ct(_) => "2",
+                ColorMode::Hsv => "3",
+            }
+            .to_string(),
+            "flowing" => (self.flowing as u8).to_string(),
+            "name" => self.name.clone(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// The properties that differ from `old`.
+    pub fn changes(&self, old: &Props) -> Map<String, Value> {
+        PROPS
+            .iter()
+            .filter_map(|&name| {
+                let value = self.get(name)?;
+                (old.get(name).as_ref() != Some(&value)).then(|| (name.into(), value.into()))
+            })
+            .collect()
+    }
+}
+
+/// The response to the request with `id`, terminated by a line break.
+pub fn response(id: &Value, result: Result<Vec<String>, MethodError>) -> String {
+    let response = match result {
+        Ok(result) => json!({ "id": id, "result": result }),
+        Err(err) => json!({ "id": id, "error": { "code": -1, "message": err.to_string() } }),
+    };
+    format!("{}\r\n", response)
+}
+
+/// The notification about changed properties, terminated by a line break.
+pub fn notification(changes: Map<String, Value>) -> String {
+    format!("{}\r\n", json!({ "method": "props", "params": changes }))
+}
+
+/// Whether `data` is a discovery request for Yeelight bulbs.
+pub fn is_search(data: &[u8]) -> bool {
+    let request = String::from_utf8_lossy(data);
+    request.starts_with("M-SEARCH")
+        && request
+            .lines()
+            .any(|line| line.starts_with("ST:") && line[3..].trim() == "wifi_bulb")
+}
+
+/// The answer to a discovery request, `id` identifies the light and `ip` is its address.
+pub fn search_response(id: u64, ip: Ipv4Addr, props: &Props) -> String {
+    let mut response = format!(
+        "HTTP/1.1 200 OK\r\n\
+         Cache-Control: max-age=3600\r\n\
+         Date: \r\n\
+         Ext: \r\n\
+         Location: yeelight://{}:{}\r\n\
+         Server: POSIX UPnP/1.0 YGLC/1\r\n\
+         id: 0x{:016x}\r\n\
+         model: color\r\n\
+         fw_ver: 18\r\n\
+         support: {}\r\n",
+        ip, PORT, id, SUPPORTED_METHODS
+    );
+    for name in PROPS.iter().filter(|&&name| name != "flowing") {
+        if let Some(value) = props.get(name) {
+            response += &format!("{}: {}\r\n", name, value);
+        }
+    }
+    response + "\r\n"
+}
+
+fn from_rgb(rgb: u32) -> Pixel {
+    let [_, red, green, blue] = rgb.to_be_bytes();
+    Pixel::new(red, green, blue)
+}
+
+fn from_percent(brightness: u8) -> u8 {
+    ((brightness.min(100) as u16 * 255 + 50) / 100) as u8
+}
+
+struct Params<'a>(&'a [Value]);
+
+impl Params<'_> {
+    fn str(&self, index: usize) -> Result<&str, MethodError> {
+        self.0
+            .get(index)
+            .and_then(Value::as_str)
+            .ok_or(MethodError::InvalidParams)
+    }
+
+    /// An integer from `min` to `max`, some clients send them as strings.
+    fn int(&self, index: usize, min: i64, max: i64) -> Result<i64, MethodError> {
+        let value = match self.0.get(index) {
+            Some(Value::Number(number)) => number.as_i64(),
+            Some(Value::String(string)) => string.trim().parse().ok(),
+            _ => None,
+        };
+        value
+            .filter(|value| (min..=max).contains(value))
+            .ok_or(MethodError::InvalidParams)
+    }
+
+    /// The effect and duration at `index`, the transition is sudden without them.
+    fn transition(&self, index: usize) -> Result<Duration, MethodError> {
+        match self.0.get(index).and_then(Value::as_str) {
+            None | Some("sudden") => Ok(Duration::ZERO),
+            Some("smooth") => {
+                let duration = self.int(index + 1, 0, u32::MAX as i64)?;
+                Ok(Duration::from_millis(duration as u64).max(MIN_DURATION))
+            }
+            Some(_) => Err(MethodError::InvalidParams),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::state::Segment;
+
+    fn state() -> LightState {
+        LightState {
+            on: true,
+            brightness: 128,
+            transition: Duration::from_millis(700),
+            segments: vec![Segment {
+                effect: Effect::ColorLoop,
+                ..Segment::new(0, 10)
+            }],
+        }
+    }
+
+    fn parse(json: &str) -> Result<Method, MethodError> {
+        let request: Request = serde_json::from_str(json).unwrap();
+        Method::parse(&request)
+    }
+
+    #[test]
+    fn apply_methods() {
+        let mut state = state();
+        let mut mode = ColorMode::Hsv;
+
+        let method = parse(r#"{"id":1,"method":"set_rgb","params":[65280,"smooth",500]}"#);
+        apply(&method.unwrap(), &mut state, &mut mode);
+        assert_eq!(mode, ColorMode::Rgb);
+        assert_eq!(state.transition, Duration::from_millis(500));
+        assert_eq!(state.segments[0].colors[0], Pixel::new(0, 255, 0));
+        assert_eq!(state.segments[0].effect, Effect::Solid);
+
+        let method = parse(r#"{"id":2,"method":"set_bright","params":[100,"sudden",0]}"#);
+        apply(&method.unwrap(), &mut state, &mut mode);
+        assert_eq!(state.brightness, 255);
+        assert_eq!(state.transition, Duration::ZERO);
+
+        apply(
+            &parse(r#"{"id":3,"method":"toggle"}"#).unwrap(),
+            &mut state,
+            &mut mode,
+        );
+        assert!(!state.on);
+
+        let method = parse(r#"{"id":4,"method":"set_ct_abx","params":[2700,"smooth",10]}"#);
+        apply(&method.unwrap(), &mut state, &mut mode);
+        assert_eq!(mode, ColorMode::Ct(2700));
+        assert_eq!(state.transition, MIN_DURATION);
+
+        assert_eq!(
+            parse(r#"{"id":5,"method":"set_hsv","params":[360,50,"sudden",0]}"#),
+            Err(MethodError::InvalidParams)
+        );
+        assert_eq!(
+            parse(r#"{"id":6,"method":"set_scene","params":["color",65280,70]}"#),
+            Err(MethodError::Unsupported)
+        );
+    }
+
+    #[test]
+    fn color_flows() {
+        let method = parse(
+            r#"{"id":1,"method":"start_cf","params":[4,2,"1000,1,16711680,80,1000,1,255,-1"]}"#,
+        );
+        let flow = match method.unwrap() {
+            Method::StartCf(flow) => flow,
+            method => panic!("unexpected method {:?}", method),
+        };
+        assert_eq!(flow.action, FlowAction::Off);
+        assert_eq!(flow.duration(), Some(Duration::from_secs(4)));
+        assert_eq!(flow.last_color(), Some(Pixel::new(0, 0, 255)));
+        assert_eq!(
+            flow.effect(),
+            FlowEffect {
+                effect: Effect::Breathe,
+                colors: [Pixel::new(255, 0, 0), Pixel::new(0, 0, 255)],
+                speed: effect::speed(Duration::from_secs(2)),
+                intensity: 128,
+            }
+        );
+
+        let mut state = state();
+        let mut mode = ColorMode::Hsv;
+        apply(&Method::StartCf(flow), &mut state, &mut mode);
+        assert_eq!(state.brightness, 204);
+        assert_eq!(state.segments[0].effect, Effect::Breathe);
+
+        // Sudden changes with pauses blink, the primary color is shown for a quarter.
+        let flow = Flow {
+            count: 0,
+            action: FlowAction::Recover,
+            steps: parse_flow("50,1,255,100, 450,7,0,0, 50,1,65280,100, 1450,7,0,0").unwrap(),
+        };
+        assert_eq!(flow.duration(), None);
+        let effect = flow.effect();
+        assert_eq!(effect.effect, Effect::Blink);
+        assert_eq!(effect.intensity, 64);
+
+        assert_eq!(parse_flow("1000,1,255"), Err(MethodError::InvalidParams));
+        assert_eq!(parse_flow("20,1,255,100"), Err(MethodError::InvalidParams));
+        assert_eq!(parse_flow("500,7,0,0"), Err(MethodError::InvalidParams));
+    }
+
+    #[test]
+    fn long_color_flows() {
+        // The longest flow is computed without running through its steps.
+        let request = format!(
+            r#"{{"id":1,"method":"start_cf","params":[{},0,"{},1,255,100,{},7,0,0,50,1,65280,100"]}}"#,
+            MAX_FLOW_COUNT,
+            MAX_FLOW_STEP.as_millis(),
+            MAX_FLOW_STEP.as_millis(),
+        );
+        let flow = match parse(&request).unwrap() {
+            Method::StartCf(flow) => flow,
+            method => panic!("unexpected method {:?}", method),
+        };
+        let cycle = MAX_FLOW_STEP * 2 + MIN_FLOW_STEP;
+        let cycles = MAX_FLOW_COUNT / 3;
+        assert_eq!(flow.duration(), Some(cycle * cycles + MAX_FLOW_STEP));
+        // The flow stops after the sleep step, the last color is from the first step.
+        assert_eq!(flow.last_color(), Some(Pixel::new(0, 0, 255)));
+
+        // The last color comes from the previous cycle if no later step had one.
+        let flow = Flow {
+            count: 4,
+            action: FlowAction::Stay,
+            steps: parse_flow("1000,7,0,0, 1000,1,255,100, 1000,7,0,0").unwrap(),
+        };
+        assert_eq!(flow.last_color(), Some(Pixel::new(0, 0, 255)));
+        let flow = Flow { count: 1, ..flow };
+        assert_eq!(flow.last_color(), None);
+
+        // Durations that don't fit saturate.
+        let flow = Flow {
+            count: 3,
+            action: FlowAction::Stay,
+            steps: vec![
+                FlowStep {
+                    duration: Duration::MAX,
+                    color: Some(Pixel::new(0, 0, 255)),
+                    brightness: None,
+                };
+                2
+            ],
+        };
+        assert_eq!(flow.duration(), Some(Duration::MAX));
+
+        let too_long = format!("{},1,255,100", MAX_FLOW_STEP.as_millis() + 1);
+        assert_eq!(parse_flow(&too_long), Err(MethodError::InvalidParams));
+        assert_eq!(
+            parse_flow("9223372036854775807,1,255,100"),
+            Err(MethodError::InvalidParams)
+        );
+        assert_eq!(
+            parse(r#"{"id":2,"method":"start_cf","params":[4294967295,0,"1000,1,255,100"]}"#),
+            Err(MethodError::InvalidParams)
+        );
+    }
+
+    #[test]
+    fn props() {
+        let mut state = state();
+        state.segments[0].colors[0] = Pixel::new(255, 0, 0);
+        let old = Props::new(&state, ColorMode::Ct(2700), false, "strip");
+        assert_eq!(old.mode, ColorMode::Rgb);
+        assert_eq!(old.get("rgb").as_deref(), Some("16711680"));
+        assert_eq!(old.get("bright").as_deref(), Some("50"));
+        assert_eq!(old.get("sat").as_deref(), Some("100"));
+        assert_eq!(old.get("model"), None);
+
+        state.on = false;
+        let new = Props::new(&state, ColorMode::Rgb, true, "strip");
+        assert_eq!(
+            notification(new.changes(&old)),
+            "{\"method\":\"props\",\"params\":{\"flowing\":\"1\",\"power\":\"off\"}}\r\n"
+        );
+
+        assert_eq!(
+            response(&json!(7), Ok(vec!["ok".into()])),
+            "{\"id\":7,\"result\":[\"ok\"]}\r\n"
+        );
+    }
+
+    #[test]
+    fn discovery() {
+        let search = b"M-SEARCH * HTTP/1.1\r\n\
+            HOST: 239.255.255.250:1982\r\n\
+            MAN: \"ssdp:discover\"\r\n\
+            ST: wifi_bulb\r\n";
+        assert!(is_search(search));
+        assert!(!is_search(b"M-SEARCH * HTTP/1.1\r\nST: ssdp:all\r\n"));
+
+        let props = Props::new(&state(), ColorMode::Rgb, false, "strip");
+        let response = search_response(0x15243f, Ipv4Addr::new(192, 168, 1, 239), &props);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Location: yeelight://192.168.1.239:55443\r\n"));
+        assert!(response.contains("id: 0x000000000015243f\r\n"));
+        assert!(response.contains("power: on\r\n"));
+        assert!(response.ends_with("name: strip\r\n\r\n"));
+    }
+}
@@ -0,0 +1,157 @@
+use std::string::String;
+use std::vec::Vec;
+
+use crate::{ENTRY_SIZE, HEADER_SIZE, MAGIC, MAX_NAME_LEN};
+
+/// Builds an encoded [`SymbolTable`](crate::SymbolTable).
+#[derive(Default)]
+pub struct Builder {
+    symbols: Vec<(u32, u32, String)>,
+}
+
+/// The result of [`Builder::encode`].
+pub struct Encoded {
+    /// The encoded table, exactly as long as the requested capacity.
+    pub data: Vec<u8>,
+    /// The amount of symbols in the table.
+    pub included: usize,
+    /// The amount of symbols that were not included, because they didn't fit or were
+    /// duplicates.
+    pub dropped: usize,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a function, symbols added first are preferred if not all of them fit.
+    ///
+    /// Names longer than [`MAX_NAME_LEN`] are truncated.
+    pub fn add(&mut self, start: u32, size: u32, name: &str) {
+        let mut len = name.len().min(MAX_NAME_LEN);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        self.symbols.push((start, size, name[..len].into()));
+    }
+
+    /// Encode as many symbols as fit into `capacity` bytes.
+    ///
+    /// Symbols that start at the same address as an already included symbol are
+    /// skipped. Returns `None` if `capacity` is smaller than the header.
+    pub fn encode(&self, capacity: usize) -> Option<Encoded> {
+        let mut remaining = capacity.checked_sub(HEADER_SIZE)?;
+
+        let mut included: Vec<&(u32, u32, String)> = Vec::new();
+        let mut starts = std::collections::BTreeSet::new();
+        for symbol in &self.symbols {
+            let size = ENTRY_SIZE + 1 + symbol.2.len();
+            if size > remaining || !starts.insert(symbol.0) {
+                continue;
+            }
+
+            remaining -= size;
+            included.push(symbol);
+        }
+        included.sort_by_key(|(start, _, _)| *start);
+
+        let mut data = Vec::with_capacity(capacity);
+        data.extend_from_slice(&MAGIC);
+        data.extend_from_slice(&(included.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(capacity as u32).to_le_bytes());
+
+        let mut names = Vec::new();
+        for (start, size, name) in &included {
+            data.extend_from_slice(&start.to_le_bytes());
+            data.extend_from_slice(&size.to_le_bytes());
+            data.extend_from_slice(&(names.len() as u32).to_le_bytes());
+
+            names.push(name.len() as u8);
+            names.extend_from_slice(name.as_bytes());
+        }
+        data.extend_from_slice(&names);
+        data.resize(capacity, 0);
+
+        Some(Encoded {
+            data,
+            included: included.len(),
+            dropped: self.symbols.len() - included.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+
+    use super::*;
+    use crate::{reserve, Symbol, SymbolTable};
+
+    #[test]
+    fn round_trip() {
+        let long_name: String = "a".repeat(MAX_NAME_LEN + 10);
+
+        let mut builder = Builder::new();
+        builder.add(0x400d_2000, 0x40, "esp32_hue::light::run");
+        builder.add(0x400d_1000, 0x20, "app_main");
+        builder.add(0x400d_1000, 0x10, "duplicate");
+        builder.add(0x400d_3000, 0x08, &long_name);
+
+        let encoded = builder.encode(1024).unwrap();
+        assert_eq!(encoded.data.len(), 1024);
+        assert_eq!((encoded.included, encoded.dropped), (3, 1));
+
+        let table = SymbolTable::parse(&encoded.data).unwrap();
+        assert_eq!(table.len(), 3);
+        assert_eq!(
+            table.lookup(0x400d_1004),
+            Some(Symbol {
+                name: "app_main",
+                start: 0x400d_1000,
+                size: 0x20,
+            })
+        );
+        let symbol = table.lookup(0x400d_203f).unwrap();
+        assert_eq!(symbol.name, "esp32_hue::light::run");
+        assert_eq!(symbol.offset(0x400d_203f), 0x3f);
+        assert_eq!(
+            table.lookup(0x400d_3000).unwrap().name,
+            &long_name[..MAX_NAME_LEN]
+        );
+
+        // Before the first, between and after the functions.
+        assert_eq!(table.lookup(0x400d_0fff), None);
+        assert_eq!(table.lookup(0x400d_1020), None);
+        assert_eq!(table.lookup(0x400d_3008), None);
+
+        let empty = reserve::<64>();
+        assert!(SymbolTable::parse(&empty).unwrap().is_empty());
+        assert!(SymbolTable::parse(&empty[1..]).is_none());
+    }
+
+    #[test]
+    fn truncate_to_capacity() {
+        let mut builder = Builder::new();
+        builder.add(0x3000, 0x10, "first");
+        builder.add(0x1000, 0x10, "second");
+        builder.add(0x2000, 0x10, "third");
+
+        // Only the symbols added first fit.
+        let capacity = HEADER_SIZE + 2 * ENTRY_SIZE + 1 + "first".len() + 1 + "second".len();
+        let encoded = builder.encode(capacity).unwrap();
+        assert_eq!(encoded.data.len(), capacity);
+        assert_eq!((encoded.included, encoded.dropped), (2, 1));
+
+        let table = SymbolTable::parse(&encoded.data).unwrap();
+        let names: Vec<_> = table.iter().map(|symbol| symbol.name).collect();
+        assert_eq!(names, ["second", "first"]);
+        assert_eq!(table.lookup(0x2000), None);
+
+        assert!(builder.encode(HEADER_SIZE - 1).is_none());
+        let encoded = builder.encode(HEADER_SIZE).unwrap();
+        assert_eq!((encoded.included, encoded.dropped), (0, 3));
+        assert!(SymbolTable::parse(&encoded.data).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+//! A compact table of function address ranges and names.
+//!
+//! The firmware reserves space for the table in flash, which is filled by the
+//! `embed-symbols` tool after linking (see `tools/`). This allows backtraces to be
+//! symbolized on the device without the ELF file.
+//!
+//! Layout (all integers are little-endian):
+//!
+//! | offset | size         | content                                               |
+//! |--------|--------------|-------------------------------------------------------|
+//! | 0      | 8            | [`MAGIC`]                                             |
+//! | 8      | 4            | entry count `n`                                       |
+//! | 12     | 4            | capacity of the reserved space in bytes               |
+//! | 16     | 12 * `n`     | entries sorted by start address: start, size, name offset (each `u32`) |
+//! | ...    | ...          | names, each a `u8` length followed by UTF-8 bytes     |
+//!
+//! Name offsets are relative to the start of the name area.
+
+#![no_std]
+
+#[cfg(feature = "std")]
+extern crate std;
+
+#[cfg(feature = "std")]
+mod builder;
+
+#[cfg(feature = "std")]
+pub use builder::{Builder, Encoded};
+
+/// Identifies a symbol table, the last byte is the format version.
+pub const MAGIC: [u8; 8] = *b"SYMTAB\x00\x01";
+pub const HEADER_SIZE: usize = 16;
+pub const ENTRY_SIZE: usize = 12;
+/// The maximum length of a symbol name in bytes, longer names are truncated.
+pub const MAX_NAME_LEN: usize = u8::MAX as usize;
+
+/// Create an empty table that reserves `N` bytes.
+pub const fn reserve<const N: usize>() -> [u8; N] {
+    assert!(N >= HEADER_SIZE);
+
+    let mut data = [0; N];
+    let mut i = 0;
+    while i < MAGIC.len() {
+        data[i] = MAGIC[i];
+        i += 1;
+    }
+
+    let capacity = (N as u32).to_le_bytes();
+    data[12] = capacity[0];
+    data[13] = capacity[1];
+    data[14] = capacity[2];
+    data[15] = capacity[3];
+
+    data
+}
+
+/// A function in the [`SymbolTable`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol<'a> {
+    /// The demangled name without hash.
+    pub name: &'a str,
+    /// The address of the first instruction.
+    pub start: u32,
+    /// The size in bytes.
+    pub size: u32,
+}
+
+impl Symbol<'_> {
+    /// The offset of `addr` from the start of this function.
+    pub fn offset(&self, addr: u32) -> u32 {
+        addr.wrapping_sub(self.start)
+    }
+
+    pub fn contains(&self, addr: u32) -> bool {
+        addr >= self.start && self.offset(addr) < self.size
+    }
+}
+
+/// A read-only view of an encoded symbol table.
+#[derive(Clone, Copy)]
+pub struct SymbolTable<'a> {
+    entries: &'a [u8],
+    names: &'a [u8],
+}
+
+impl<'a> SymbolTable<'a> {
+    /// Parse the table in `data`, returns `None` if `data` is not a valid table.
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE || data[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+
+        let count = read_u32(data, 8)? as usize;
+        let entries_end = HEADER_SIZE.checked_add(count.checked_mul(ENTRY_SIZE)?)?;
+
+        Some(SymbolTable {
+            entries: data.get(HEADER_SIZE..entries_end)?,
+            names: &data[entries_end..],
+        })
+    }
+
+    /// The amount of symbols in the table.
+    pub fn len(&self) -> usize {
+        self.entries.len() / ENTRY_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Get the `index`-th symbol sorted by address.
+    pub fn get(&self, index: usize) -> Option<Symbol<'a>> {
+        let offset = index.checked_mul(ENTRY_SIZE)?;
+        let start = read_u32(self.entries, offset)?;
+        let size = read_u32(self.entries, offset + 4)?;
+        let name_offset = read_u32(self.entries, offset + 8)? as usize;
+
+        let name_len = *self.names.get(name_offset)? as usize;
+        let name = self.names.get(name_offset + 1..name_offset + 1 + name_len)?;
+
+        Some(Symbol {
+            name: core::str::from_utf8(name).ok()?,
+            start,
+            size,
+        })
+    }
+
+    /// Find the function that contains `addr`.
+    pub fn lookup(&self, addr: u32) -> Option<Symbol<'a>> {
+        // Binary search for the last symbol that starts at or before `addr`.
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if read_u32(self.entries, mid * ENTRY_SIZE)? <= addr {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        let symbol = self.get(low.checked_sub(1)?)?;
+        symbol.contains(addr).then_some(symbol)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Symbol<'a>> + '_ {
+        (0..self.len()).filter_map(move |i| self.get(i))
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
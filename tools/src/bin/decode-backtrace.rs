@@ -0,0 +1,325 @@
+//! Symbolizes the backtraces in a serial log with file and line numbers.
+//!
+//! Usage: `decode-backtrace <elf> [log]`
+//!
+//! Reads the log from stdin if no file is given. Both the backtraces printed by the panic
+//! hook (`Backtrace:` followed by one `0x...:0x...` frame per line) and the single-line
+//! backtraces of the ESP-IDF panic handler are recognized. Frames that the firmware
+//! wouldn't consider sane (see `BacktraceFrame::is_sane` in `src/utils/backtrace.rs`) are
+//! flagged, since the backtrace usually ends or is corrupted there.
+
+use std::borrow::Cow;
+use std::io::Read;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+
+/// A frame in a backtrace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Frame {
+    pc: u32,
+    sp: u32,
+}
+
+impl Frame {
+    /// Parse a `0x<pc>:0x<sp>` frame.
+    fn parse(s: &str) -> Option<Frame> {
+        let (pc, sp) = s.split_once(':')?;
+        Some(Frame {
+            pc: parse_hex(pc)?,
+            sp: parse_hex(sp)?,
+        })
+    }
+
+    /// Same as `BacktraceFrame::is_sane` of the firmware, with the memory map of the
+    /// ESP32 (see `soc/soc.h` of ESP-IDF).
+    fn is_sane(&self) -> bool {
+        const SOC_DRAM_LOW: u32 = 0x3ffa_e000;
+        const SOC_DRAM_HIGH: u32 = 0x4000_0000;
+
+        let sp_in_dram = !(self.sp < SOC_DRAM_LOW + 0x10 || self.sp > SOC_DRAM_HIGH - 0x10);
+        sp_in_dram && self.sp != 0 && is_pointer_executable(self.pc)
+    }
+}
+
+fn is_pointer_executable(ptr: u32) -> bool {
+    const EXECUTABLE: [(u32, u32); 6] = [
+        // IROM
+        (0x400d_0000, 0x4040_0000),
+        // IRAM
+        (0x4008_0000, 0x400a_0000),
+        // IROM mask
+        (0x4000_0000, 0x4006_4f00),
+        // Cache APP
+        (0x4007_8000, 0x4008_0000),
+        // Cache PRO
+        (0x4007_0000, 0x4007_8000),
+        // RTC IRAM
+        (0x400c_0000, 0x400c_2000),
+    ];
+
+    EXECUTABLE
+        .iter()
+        .any(|&(low, high)| ptr >= low && ptr < high)
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    let digits = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X"))?;
+    u32::from_str_radix(digits, 16).ok()
+}
+
+/// A backtrace found in a log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Backtrace {
+    /// The line number of the `Backtrace:` header, starting at one.
+    line: usize,
+    frames: Vec<Frame>,
+}
+
+/// Find all backtraces in `log`.
+///
+/// A backtrace starts with a line containing `Backtrace:`, frames may follow on the same
+/// line or on the following lines, each line starting with a frame. Anything after the
+/// frames of a line (e.g. the symbol printed by the panic hook) is ignored. The backtrace
+/// ends at the first line that doesn't start with a frame.
+fn parse_backtraces(log: &str) -> Vec<Backtrace> {
+    let mut backtraces = Vec::new();
+    let mut current: Option<Backtrace> = None;
+
+    for (index, line) in log.lines().enumerate() {
+        if let Some((_, rest)) = line.split_once("Backtrace:") {
+            backtraces.extend(current.take().filter(|b| !b.frames.is_empty()));
+
+            let mut backtrace = Backtrace {
+                line: index + 1,
+                frames: Vec::new(),
+            };
+            backtrace.frames.extend(parse_frames(rest));
+            current = Some(backtrace);
+            continue;
+        }
+
+        let backtrace = match &mut current {
+            Some(backtrace) => backtrace,
+            None => continue,
+        };
+
+        let len = backtrace.frames.len();
+        backtrace.frames.extend(parse_frames(line));
+        // Allow blank lines between the header and the first frame.
+        let blank_before_frames = len == 0 && line.trim().is_empty();
+        if backtrace.frames.len() == len && !blank_before_frames {
+            backtraces.extend(current.take().filter(|b| !b.frames.is_empty()));
+        }
+    }
+    backtraces.extend(current.filter(|b| !b.frames.is_empty()));
+
+    backtraces
+}
+
+/// Parse the frames at the start of `line`.
+fn parse_frames(line: &str) -> impl Iterator<Item = Frame> + '_ {
+    line.split_whitespace().map_while(Frame::parse)
+}
+
+/// A symbolized location, inlined functions come first.
+struct Location {
+    function: Option<String>,
+    file: Option<String>,
+    line: Option<u32>,
+}
+
+type DwarfContext =
+    addr2line::Context<addr2line::gimli::EndianRcSlice<addr2line::gimli::RunTimeEndian>>;
+
+fn symbolize(context: &DwarfContext, pc: u32) -> Result<Vec<Location>> {
+    let mut locations = Vec::new();
+
+    let mut frames = context.find_frames(pc as u64)?;
+    while let Some(frame) = frames.next()? {
+        let function = frame
+            .function
+            .as_ref()
+            .and_then(|f| f.demangle().ok())
+            .map(|name| format!("{:#}", rustc_demangle::demangle(&name)));
+
+        locations.push(Location {
+            function,
+            file: frame.location.as_ref().and_then(|l| l.file).map(str::to_owned),
+            line: frame.location.as_ref().and_then(|l| l.line),
+        });
+    }
+
+    Ok(locations)
+}
+
+fn main() -> Result<()> {
+    let mut args = std::env::args_os().skip(1);
+    let elf_path: PathBuf = args
+        .next()
+        .ok_or_else(|| anyhow!("usage: decode-backtrace <elf> [log]"))?
+        .into();
+
+    let log = match args.next() {
+        Some(path) => {
+            let path = PathBuf::from(path);
+            let data = std::fs::read(&path)
+                .with_context(|| format!("failed to read `{}`", path.display()))?;
+            String::from_utf8_lossy(&data).into_owned()
+        }
+        None => {
+            let mut data = Vec::new();
+            std::io::stdin()
+                .read_to_end(&mut data)
+                .context("failed to read stdin")?;
+            String::from_utf8_lossy(&data).into_owned()
+        }
+    };
+
+    let elf_data = std::fs::read(&elf_path)
+        .with_context(|| format!("failed to read `{}`", elf_path.display()))?;
+    let elf = object::File::parse(&*elf_data).context("failed to parse elf")?;
+    let context = DwarfContext::new(&elf).context("failed to read debug info")?;
+
+    let backtraces = parse_backtraces(&log);
+    if backtraces.is_empty() {
+        println!("no backtraces found");
+    }
+
+    for backtrace in backtraces {
+        println!("backtrace at line {}:", backtrace.line);
+
+        for frame in &backtrace.frames {
+            let flag = if frame.is_sane() { "" } else { "  [not sane]" };
+            println!("  {:#010x}:{:#010x}{}", frame.pc, frame.sp, flag);
+
+            let locations = symbolize(&context, frame.pc).unwrap_or_default();
+            if locations.is_empty() {
+                println!("      ??");
+            }
+            for location in locations {
+                let function = location.function.as_deref().unwrap_or("??");
+                let file: Cow<str> = match (&location.file, location.line) {
+                    (Some(file), Some(line)) => format!("{file}:{line}").into(),
+                    (Some(file), None) => file.as_str().into(),
+                    _ => "??".into(),
+                };
+                println!("      {function}\n          at {file}");
+            }
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn frame(pc: u32, sp: u32) -> Frame {
+        Frame { pc, sp }
+    }
+
+    #[test]
+    fn panic_hook_output() {
+        let log = "\
+I (1234) esp32_hue: Starting...\r
+\r
+\r
+[Core::PRO(0)] *** panicked at 'oops', src/main.rs:10:5\r
+\r
+Backtrace:\r
+0x400d1a2b:0x3ffb5f20 esp32_hue::main+0x1b\r
+0x400d3c4d:0x3ffb5f40 \r
+0x40085e6f:0x3ffb5f60 \r
+\r
+Restarting...\r
+";
+
+        assert_eq!(
+            parse_backtraces(log),
+            vec![Backtrace {
+                line: 6,
+                frames: vec![
+                    frame(0x400d1a2b, 0x3ffb5f20),
+                    frame(0x400d3c4d, 0x3ffb5f40),
+                    frame(0x40085e6f, 0x3ffb5f60),
+                ],
+            }]
+        );
+    }
+
+    #[test]
+    fn esp_idf_single_line() {
+        let log = "\
+Guru Meditation Error: Core  1 panic'ed (LoadProhibited). Exception was unhandled.
+Backtrace:0x400d2f6a:0x3ffb7e80 0x400d10e1:0x3ffb7ea0 0x40087a11:0x3ffb7ec0 |<-CORRUPTED
+
+ELF file SHA256: 0123456789abcdef
+";
+
+        assert_eq!(
+            parse_backtraces(log),
+            vec![Backtrace {
+                line: 2,
+                frames: vec![
+                    frame(0x400d2f6a, 0x3ffb7e80),
+                    frame(0x400d10e1, 0x3ffb7ea0),
+                    frame(0x40087a11, 0x3ffb7ec0),
+                ],
+            }]
+        );
+    }
+
+    #[test]
+    fn multiple_backtraces_and_noise() {
+        let log = "\
+Backtrace:
+0x400d0001:0x3ffb0010
+some other output 0x400d0002:0x3ffb0020
+Backtrace: nothing here
+Backtrace:
+Backtrace:
+0x400d0003:0x3ffb0030
+0x12345678:0x00000000
+";
+
+        assert_eq!(
+            parse_backtraces(log),
+            vec![
+                Backtrace {
+                    line: 1,
+                    frames: vec![frame(0x400d0001, 0x3ffb0010)],
+                },
+                Backtrace {
+                    line: 6,
+                    frames: vec![
+                        frame(0x400d0003, 0x3ffb0030),
+                        frame(0x12345678, 0x00000000),
+                    ],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn sanity() {
+        assert!(frame(0x400d1a2b, 0x3ffb5f20).is_sane());
+        assert!(frame(0x40085e6f, 0x3ffb5f60).is_sane());
+        // `sp` outside of DRAM.
+        assert!(!frame(0x400d1a2b, 0x0000_0000).is_sane());
+        assert!(!frame(0x400d1a2b, 0x3fff_fff8).is_sane());
+        // `pc` not executable.
+        assert!(!frame(0x3ffb_0000, 0x3ffb5f20).is_sane());
+        assert!(!frame(0x0000_0000, 0x3ffb5f20).is_sane());
+    }
+
+    #[test]
+    fn invalid_frames() {
+        assert_eq!(Frame::parse("0x400d1a2b"), None);
+        assert_eq!(Frame::parse("400d1a2b:3ffb5f20"), None);
+        assert_eq!(Frame::parse("0x400d1a2b:0xzz"), None);
+        assert_eq!(Frame::parse("0x1:0x2"), Some(frame(1, 2)));
+    }
+}
@@ -0,0 +1,84 @@
+//! Fills the symbol table reserved in the firmware ELF with all functions of the ELF.
+//!
+//! Usage: `embed-symbols <elf>`
+//!
+//! The ELF is patched in place. If the firmware was built without the `embedded-symbols`
+//! feature, the ELF is left untouched.
+
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context, Result};
+use esp32_hue_tools::elf;
+use object::{Object, ObjectSection, ObjectSymbol};
+
+/// The name of the static in `src/utils/backtrace.rs` that reserves the table.
+const TABLE_SYMBOL: &str = "ESP32_HUE_SYMTAB";
+
+fn main() -> Result<()> {
+    let path: PathBuf = std::env::args_os()
+        .nth(1)
+        .ok_or_else(|| anyhow!("usage: embed-symbols <elf>"))?
+        .into();
+
+    let mut data =
+        std::fs::read(&path).with_context(|| format!("failed to read `{}`", path.display()))?;
+
+    let (file_offset, capacity, encoded) = {
+        let elf = object::File::parse(&*data).context("failed to parse elf")?;
+
+        let table = match elf.symbols().find(|s| s.name() == Ok(TABLE_SYMBOL)) {
+            Some(table) => table,
+            None => {
+                println!("no symbol table reserved in `{}`, skipping", path.display());
+                return Ok(());
+            }
+        };
+        let section = table
+            .section_index()
+            .map(|index| elf.section_by_index(index))
+            .transpose()?
+            .ok_or_else(|| anyhow!("`{TABLE_SYMBOL}` is not in a section"))?;
+        let (section_offset, _) = section
+            .file_range()
+            .ok_or_else(|| anyhow!("`{TABLE_SYMBOL}` is not stored in the file"))?;
+
+        let file_offset = section_offset + (table.address() - section.address());
+        let capacity = table.size() as usize;
+
+        let mut builder = symtab::Builder::new();
+        let mut functions = elf::functions(&elf);
+        // Prefer our own (Rust) functions over C functions if the table is too small.
+        functions.sort_by_key(|function| !function.is_rust);
+
+        for function in &functions {
+            builder.add(function.start, function.size, &function.name);
+        }
+
+        let encoded = builder
+            .encode(capacity)
+            .ok_or_else(|| anyhow!("`{TABLE_SYMBOL}` is too small ({capacity} bytes)"))?;
+
+        (file_offset as usize, capacity, encoded)
+    };
+
+    let target = data
+        .get_mut(file_offset..file_offset + capacity)
+        .ok_or_else(|| anyhow!("`{TABLE_SYMBOL}` is outside of the file"))?;
+    if target[..symtab::MAGIC.len()] != symtab::MAGIC {
+        bail!("`{TABLE_SYMBOL}` doesn't contain a symbol table");
+    }
+    target.copy_from_slice(&encoded.data);
+
+    std::fs::write(&path, &data)
+        .with_context(|| format!("failed to write `{}`", path.display()))?;
+
+    println!(
+        "embedded {} symbols into `{}` ({} did not fit into {} bytes)",
+        encoded.included,
+        path.display(),
+        encoded.dropped,
+        capacity
+    );
+
+    Ok(())
+}
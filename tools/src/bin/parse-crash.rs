@@ -0,0 +1,103 @@
+//! Turns the crash archive served by `GET /debug/coredump` into a readable report.
+//!
+//! Usage: `parse-crash <crash.bin> [elf]`
+//!
+//! Prints the backtraces of all tasks from the crash log, symbolized with the functions
+//! of the firmware ELF if given. The ESP-IDF core dump is written next to the archive as
+//! `<crash.bin>.core.elf`, it can be inspected with
+//! `espcoredump.py info_corefile --core <crash.bin>.core.elf <elf>`.
+
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use crashlog::{CrashLog, SectionKind};
+use esp32_hue_tools::elf::{self, Function};
+
+/// The functions of the firmware, sorted by start address.
+struct Functions(Vec<Function>);
+
+impl Functions {
+    fn load(path: &PathBuf) -> Result<Self> {
+        let data =
+            std::fs::read(path).with_context(|| format!("failed to read `{}`", path.display()))?;
+        let elf = object::File::parse(&*data).context("failed to parse elf")?;
+
+        let mut functions = elf::functions(&elf);
+        functions.sort_by_key(|function| function.start);
+
+        Ok(Functions(functions))
+    }
+
+    fn lookup(&self, addr: u32) -> Option<String> {
+        let index = self.0.partition_point(|function| function.start <= addr);
+        let function = self.0.get(index.checked_sub(1)?)?;
+        let offset = addr - function.start;
+
+        (offset < function.size).then(|| format!("{}+{:#x}", function.name, offset))
+    }
+}
+
+fn main() -> Result<()> {
+    let mut args = std::env::args_os().skip(1);
+    let path: PathBuf = args
+        .next()
+        .ok_or_else(|| anyhow!("usage: parse-crash <crash.bin> [elf]"))?
+        .into();
+    let functions = args
+        .next()
+        .map(|elf| Functions::load(&elf.into()))
+        .transpose()?;
+
+    let data =
+        std::fs::read(&path).with_context(|| format!("failed to read `{}`", path.display()))?;
+
+    for section in crashlog::archive_sections(&data).context("invalid crash archive")? {
+        match section.context("invalid crash archive")? {
+            (SectionKind::CrashLog, data) => {
+                print_crash_log(data, functions.as_ref()).context("invalid crash log")?
+            }
+            (SectionKind::CoreDump, data) => {
+                let mut core_path = path.clone().into_os_string();
+                core_path.push(".core.elf");
+                let core_path = PathBuf::from(core_path);
+
+                std::fs::write(&core_path, data)
+                    .with_context(|| format!("failed to write `{}`", core_path.display()))?;
+                println!(
+                    "core dump ({} bytes) written to `{}`\n",
+                    data.len(),
+                    core_path.display()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn print_crash_log(data: &[u8], functions: Option<&Functions>) -> Result<()> {
+    let log = CrashLog::parse(data)?;
+
+    println!(
+        "{:?} after {}.{:03} s: {}",
+        log.cause,
+        log.uptime_ms / 1000,
+        log.uptime_ms % 1000,
+        log.message
+    );
+
+    for task in log.tasks() {
+        let task = task?;
+        println!("\ntask `{}`:", task.name);
+
+        for (pc, sp) in task.frames() {
+            match functions.and_then(|f| f.lookup(pc)) {
+                Some(symbol) => println!("  {pc:#010x}:{sp:#010x} {symbol}"),
+                None => println!("  {pc:#010x}:{sp:#010x}"),
+            }
+        }
+    }
+    println!();
+
+    Ok(())
+}
@@ -0,0 +1,39 @@
+//! Reading the firmware ELF.
+
+use object::{Object, ObjectSymbol, SymbolKind};
+
+/// A function of the firmware.
+#[derive(Debug, Clone)]
+pub struct Function {
+    /// The address of the first instruction.
+    pub start: u32,
+    /// The size in bytes.
+    pub size: u32,
+    /// The demangled name without hash, or the symbol name of C functions.
+    pub name: String,
+    /// Whether the name was a mangled Rust name.
+    pub is_rust: bool,
+}
+
+/// All functions in the symbol table of `elf`, in the order of the symbol table.
+///
+/// Functions without a size, like assembly labels, are skipped.
+pub fn functions(elf: &object::File) -> Vec<Function> {
+    elf.symbols()
+        .filter(|s| s.kind() == SymbolKind::Text && s.size() > 0)
+        .filter_map(|s| {
+            let name = s.name().ok()?;
+            let (is_rust, name) = match rustc_demangle::try_demangle(name) {
+                Ok(demangled) => (true, format!("{demangled:#}")),
+                Err(_) => (false, name.to_owned()),
+            };
+
+            Some(Function {
+                start: s.address() as u32,
+                size: s.size() as u32,
+                name,
+                is_rust,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,3 @@
+//! Code shared by the host tools in `src/bin`.
+
+pub mod elf;